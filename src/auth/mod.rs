@@ -1,10 +1,102 @@
 pub mod util;
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
+/// Full access to the drive. Required to sync files that weren't uploaded by Ocean Drive
+pub const FULL_SCOPE: &str = "https://www.googleapis.com/auth/drive";
+/// Access only to the files that the app created or the user opened with it
+pub const FILE_SCOPE: &str = "https://www.googleapis.com/auth/drive.file";
+/// Scopes that don't let the app change anything, syncing needs one of the above too
+const READ_SCOPES: [&str; 4] = [
+    "https://www.googleapis.com/auth/drive.readonly",
+    "https://www.googleapis.com/auth/drive.metadata.readonly",
+    "https://www.googleapis.com/auth/drive.metadata",
+    "https://www.googleapis.com/auth/drive.appdata",
+];
+
 #[derive(Serialize, Deserialize)]
 pub struct Creds {
     pub client_id: String,
     pub client_secret: String,
+    /// OAuth scopes requested during authorization. Full drive access is used by default
+    /// `https://www.googleapis.com/auth/drive.file` gives access only to the files created by the
+    /// app. It's safer, but files added to the drive in other ways (web, other clients) won't sync
+    pub scopes: Option<Vec<String>>,
+}
+
+impl Creds {
+    /// Builds a scope string for the consent URL
+    pub fn scope(&self) -> String {
+        match &self.scopes {
+            Some(scopes) if !scopes.is_empty() => scopes.join(" "),
+            _ => FULL_SCOPE.to_string(),
+        }
+    }
+
+    /// Whether all files on the drive are visible to the app
+    pub fn has_full_access(&self) -> bool {
+        self.scope().split(' ').any(|s| s == FULL_SCOPE)
+    }
+
+    /// Fails if the scopes are not Drive ones or they don't let the app upload files
+    pub fn validate(&self) -> Result<()> {
+        let scope = self.scope();
+        if let Some(unknown) = scope
+            .split(' ')
+            .find(|s| *s != FULL_SCOPE && *s != FILE_SCOPE && !READ_SCOPES.contains(s))
+        {
+            bail!(
+                "Unknown OAuth scope {:?} in creds.toml. Use {:?} for the whole drive or {:?} for the files uploaded by Ocean Drive",
+                unknown,
+                FULL_SCOPE,
+                FILE_SCOPE
+            );
+        }
+        if !scope.split(' ').any(|s| s == FULL_SCOPE || s == FILE_SCOPE) {
+            bail!(
+                "Configured OAuth scopes don't let Ocean Drive upload files.\nTip: Add {:?} or {:?} to `scopes` in creds.toml",
+                FULL_SCOPE,
+                FILE_SCOPE
+            );
+        }
+
+        Ok(())
+    }
 }
 
 // VIM ROCKS!
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn creds(scopes: Option<&[&str]>) -> Creds {
+        Creds {
+            client_id: String::new(),
+            client_secret: String::new(),
+            scopes: scopes.map(|s| s.iter().map(|s| s.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn scope_is_built_from_config() {
+        assert_eq!(creds(None).scope(), FULL_SCOPE);
+        assert_eq!(creds(Some(&[])).scope(), FULL_SCOPE);
+        let both = creds(Some(&[FILE_SCOPE, READ_SCOPES[0]]));
+        assert_eq!(both.scope(), format!("{} {}", FILE_SCOPE, READ_SCOPES[0]));
+        assert!(!both.has_full_access());
+        assert!(creds(None).has_full_access());
+    }
+
+    #[test]
+    fn scopes_must_allow_uploads() {
+        assert!(creds(None).validate().is_ok());
+        assert!(creds(Some(&[FILE_SCOPE])).validate().is_ok());
+        assert!(creds(Some(&[READ_SCOPES[0]])).validate().is_err());
+        assert!(
+            creds(Some(&[FULL_SCOPE, "https://www.googleapis.com/auth/gmail"]))
+                .validate()
+                .is_err()
+        );
+    }
+}
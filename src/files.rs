@@ -1,9 +1,69 @@
 use anyhow::{bail, Result};
 use std::io::BufReader;
 use std::io::Read;
-use std::{fs, io::prelude::*, path::PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{
+    fs,
+    io::prelude::*,
+    path::{Path, PathBuf},
+};
 use toml;
 
+/// Config and state files are read into memory whole, even the versions of a huge drive take far
+/// less than this
+pub const DEFAULT_MAX_READ_MB: u64 = 256;
+
+static MAX_READ_BYTES: AtomicU64 = AtomicU64::new(DEFAULT_MAX_READ_MB * 1024 * 1024);
+
+/// Sets the limit of the files that are read whole (`max_state_file_mb` in the config)
+pub fn set_max_read_mb(mb: u64) {
+    MAX_READ_BYTES.store(mb.saturating_mul(1024 * 1024), Ordering::Relaxed);
+}
+
+/// Fails if the file is bigger than the limit, so a broken file can't take all the memory
+pub fn check_size(path: &Path) -> Result<()> {
+    let max = MAX_READ_BYTES.load(Ordering::Relaxed);
+    let size = match fs::metadata(path) {
+        Ok(meta) => meta.len(),
+        // Reading it will tell what's wrong
+        Err(_) => return Ok(()),
+    };
+
+    if size > max {
+        bail!(
+            "File '{}' is too big to be read ({} MB, the limit is {} MB). It's probably broken.\nTip: Check the file, or raise `max_state_file_mb` in the config",
+            path.display(),
+            size / 1024 / 1024,
+            max / 1024 / 1024
+        );
+    }
+
+    Ok(())
+}
+
+/// Reads the whole text file, if it's not bigger than the limit
+pub fn read_string(path: &Path) -> Result<String> {
+    check_size(path)?;
+
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(e) => bail!(
+            "Unable to read file contents. (File: '{}')\nDetails: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+pub fn read_json<T>(path: PathBuf) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let r = serde_json::from_str::<T>(&read_string(&path)?)?;
+
+    Ok(r)
+}
+
 pub fn read_bytes(path: PathBuf) -> Result<Vec<u8>> {
     let f = fs::OpenOptions::new().write(true).read(true).open(path)?;
     let mut reader = BufReader::new(f);
@@ -19,6 +79,8 @@ pub fn read_toml<'a, T>(path: PathBuf) -> Result<T>
 where
     T: serde::de::DeserializeOwned,
 {
+    check_size(&path)?;
+
     match fs::OpenOptions::new().read(true).open(&path) {
         Ok(mut f) => {
             let mut contents = String::new();
@@ -72,3 +134,34 @@ where
         ),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn oversized_file_is_rejected() {
+        let dir = std::env::temp_dir().join(format!("ocean-drive-files-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("versions.json");
+        fs::write(&path, "{}").unwrap();
+        assert!(read_json::<HashMap<String, String>>(path.clone())
+            .unwrap()
+            .is_empty());
+
+        // Sparse, so nothing is actually written
+        let file = fs::File::create(&path).unwrap();
+        file.set_len((DEFAULT_MAX_READ_MB + 1) * 1024 * 1024)
+            .unwrap();
+        for e in [
+            read_json::<HashMap<String, String>>(path.clone()).err(),
+            read_toml::<HashMap<String, String>>(path.clone()).err(),
+        ] {
+            let e = e.unwrap().to_string();
+            assert!(e.contains("is too big to be read (257 MB, the limit is 256 MB)"));
+        }
+        fs::remove_dir_all(&dir).ok();
+    }
+}
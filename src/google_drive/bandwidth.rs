@@ -0,0 +1,261 @@
+/*
+    Transfer speed limits by the time of the day, e.g. full speed at night and a limit or a pause
+    during the work hours. Windows are in local time and the first one that contains the current
+    time applies, transfers are not limited outside of them. Limit is shared by all the uploads and
+    downloads, and it's checked as the content goes, so it changes in the middle of a transfer.
+*/
+use anyhow::{bail, Context, Result};
+use chrono::{Local, NaiveTime};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, Read},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Most bytes that are read at once under a limit, so the transfer goes evenly
+const MAX_CHUNK: usize = 16 * 1024;
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct BandwidthWindow {
+    /// Start of the window ("HH:MM"), it can be later than `to` for a window over midnight
+    pub from: String,
+    /// End of the window ("HH:MM"), it's not a part of the window
+    pub to: String,
+    /// Uploads and downloads together take this many bytes per second at most
+    pub max_bytes_per_sec: Option<u64>,
+    /// Nothing is synced during the window
+    pub pause: Option<bool>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Limit {
+    Unlimited,
+    BytesPerSec(u64),
+    Paused,
+}
+
+struct Window {
+    /// As it's written in the config, e.g. "09:00-18:00"
+    name: String,
+    from: NaiveTime,
+    to: NaiveTime,
+    limit: Limit,
+}
+
+impl Window {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from < self.to {
+            self.from <= time && time < self.to
+        } else {
+            self.from <= time || time < self.to
+        }
+    }
+}
+
+pub struct Bandwidth {
+    windows: Vec<Window>,
+    /// Time when the bytes that are already let through are transferred at the current limit
+    free_at: Mutex<Instant>,
+}
+
+impl Bandwidth {
+    /// None when there are no windows
+    pub fn parse(windows: &[BandwidthWindow]) -> Result<Option<Self>> {
+        if windows.is_empty() {
+            return Ok(None);
+        }
+
+        let mut parsed = vec![];
+        for w in windows {
+            let name = format!("{}-{}", w.from, w.to);
+            let time = |t: &str| {
+                NaiveTime::parse_from_str(t, "%H:%M")
+                    .with_context(|| format!("Invalid time {:?} in bandwidth window {}", t, name))
+            };
+            let (from, to) = (time(&w.from)?, time(&w.to)?);
+            if from == to {
+                bail!("Bandwidth window {} is empty", name);
+            }
+
+            let limit = match (w.max_bytes_per_sec, w.pause.unwrap_or(false)) {
+                (None, true) => Limit::Paused,
+                (Some(0), false) => {
+                    bail!("`max_bytes_per_sec` of bandwidth window {} must be at least 1, use `pause = true` to stop syncing", name)
+                }
+                (Some(max), false) => Limit::BytesPerSec(max),
+                (None, false) => {
+                    bail!(
+                        "Bandwidth window {} needs either `max_bytes_per_sec` or `pause = true`",
+                        name
+                    )
+                }
+                (Some(_), true) => {
+                    bail!("Bandwidth window {} can't have both `max_bytes_per_sec` and `pause = true`", name)
+                }
+            };
+            parsed.push(Window {
+                name,
+                from,
+                to,
+                limit,
+            });
+        }
+
+        Ok(Some(Self {
+            windows: parsed,
+            free_at: Mutex::new(Instant::now()),
+        }))
+    }
+
+    /// Limit of the first window that contains the time
+    pub fn limit_at(&self, time: NaiveTime) -> Limit {
+        self.windows
+            .iter()
+            .find(|w| w.contains(time))
+            .map_or(Limit::Unlimited, |w| w.limit)
+    }
+
+    pub fn current(&self) -> Limit {
+        self.limit_at(Local::now().time())
+    }
+
+    /// Name of the window that pauses syncing now
+    pub fn paused_by(&self) -> Option<&str> {
+        let now = Local::now().time();
+        self.windows
+            .iter()
+            .find(|w| w.contains(now))
+            .filter(|w| w.limit == Limit::Paused)
+            .map(|w| w.name.as_str())
+    }
+
+    /// Lowest speed limit of the windows, None if none of them limits the speed
+    pub fn slowest(&self) -> Option<u64> {
+        self.windows
+            .iter()
+            .filter_map(|w| match w.limit {
+                Limit::BytesPerSec(max) => Some(max),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Waits until `bytes` more can be transferred under the current limit
+    fn take(&self, bytes: u64) {
+        let max = match self.current() {
+            Limit::BytesPerSec(max) => max,
+            // Reading fails during a pause, see `Throttled`
+            Limit::Unlimited | Limit::Paused => return,
+        };
+
+        let mut free_at = self.free_at.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        // Time that wasn't used is not saved up, so there's no burst after an idle while
+        let start = (*free_at).max(now);
+        *free_at = start + Duration::from_secs_f64(bytes as f64 / max as f64);
+        let wait = free_at.saturating_duration_since(now);
+        drop(free_at);
+
+        thread::sleep(wait);
+    }
+}
+
+/// Reader that goes at most as fast as the current limit allows, it's not limited for None
+pub struct Throttled<R> {
+    inner: R,
+    bandwidth: Option<Arc<Bandwidth>>,
+}
+
+impl<R> Throttled<R> {
+    pub fn new(inner: R, bandwidth: Option<Arc<Bandwidth>>) -> Self {
+        Self { inner, bandwidth }
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bandwidth = match &self.bandwidth {
+            Some(b) => b,
+            None => return self.inner.read(buf),
+        };
+
+        let len = match bandwidth.current() {
+            Limit::BytesPerSec(max) => buf.len().min(MAX_CHUNK).min(max as usize),
+            Limit::Unlimited => buf.len(),
+            // Running transfers are stopped too. Downloads go on from the partial file and
+            // uploads are queued until the window ends
+            Limit::Paused => {
+                return Err(io::Error::other("Transfer is paused by a bandwidth window"))
+            }
+        };
+        let read = self.inner.read(&mut buf[..len])?;
+        bandwidth.take(read as u64);
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(from: &str, to: &str, max: Option<u64>, pause: Option<bool>) -> BandwidthWindow {
+        BandwidthWindow {
+            from: from.to_string(),
+            to: to.to_string(),
+            max_bytes_per_sec: max,
+            pause,
+        }
+    }
+
+    fn at(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn limit_switches_at_window_boundaries() {
+        let bandwidth = Bandwidth::parse(&[
+            window("09:00", "18:00", Some(1000), None),
+            window("22:00", "06:00", None, Some(true)),
+        ])
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(bandwidth.limit_at(at("08:59:59")), Limit::Unlimited);
+        assert_eq!(bandwidth.limit_at(at("09:00:00")), Limit::BytesPerSec(1000));
+        assert_eq!(bandwidth.limit_at(at("17:59:59")), Limit::BytesPerSec(1000));
+        assert_eq!(bandwidth.limit_at(at("18:00:00")), Limit::Unlimited);
+        // Window over midnight
+        assert_eq!(bandwidth.limit_at(at("21:59:59")), Limit::Unlimited);
+        assert_eq!(bandwidth.limit_at(at("22:00:00")), Limit::Paused);
+        assert_eq!(bandwidth.limit_at(at("00:00:00")), Limit::Paused);
+        assert_eq!(bandwidth.limit_at(at("05:59:59")), Limit::Paused);
+        assert_eq!(bandwidth.limit_at(at("06:00:00")), Limit::Unlimited);
+    }
+
+    #[test]
+    fn first_matching_window_applies() {
+        let bandwidth = Bandwidth::parse(&[
+            window("12:00", "13:00", None, Some(true)),
+            window("09:00", "18:00", Some(1000), None),
+        ])
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(bandwidth.limit_at(at("12:30:00")), Limit::Paused);
+        assert_eq!(bandwidth.limit_at(at("13:00:00")), Limit::BytesPerSec(1000));
+        assert_eq!(bandwidth.slowest(), Some(1000));
+    }
+
+    #[test]
+    fn invalid_windows_are_rejected() {
+        assert!(Bandwidth::parse(&[]).unwrap().is_none());
+        assert!(Bandwidth::parse(&[window("9:00", "25:00", Some(1), None)]).is_err());
+        assert!(Bandwidth::parse(&[window("09:00", "09:00", Some(1), None)]).is_err());
+        assert!(Bandwidth::parse(&[window("09:00", "10:00", Some(0), None)]).is_err());
+        assert!(Bandwidth::parse(&[window("09:00", "10:00", None, None)]).is_err());
+        assert!(Bandwidth::parse(&[window("09:00", "10:00", Some(1), Some(true))]).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+/*
+    Daily limits for the API calls and the transferred bytes. Usage is saved to a file, so restarts
+    don't reset it. Counters are reset at local midnight.
+*/
+use super::errors::DriveError;
+use crate::files;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::PathBuf, sync::Mutex};
+
+#[derive(Serialize, Deserialize, Default)]
+struct Usage {
+    /// Day the counters belong to (YYYY-MM-DD)
+    day: String,
+    api_calls: u64,
+    bytes: u64,
+}
+
+pub struct Budget {
+    path: PathBuf,
+    max_api_calls: Option<u64>,
+    max_bytes: Option<u64>,
+    usage: Mutex<Usage>,
+}
+
+impl Budget {
+    /// Loads today's usage from the file. Missing or malformed file means nothing is used yet
+    pub fn load(path: PathBuf, max_api_calls: Option<u64>, max_bytes: Option<u64>) -> Self {
+        let usage = files::read_json(path.clone()).unwrap_or_default();
+
+        Self {
+            path,
+            max_api_calls,
+            max_bytes,
+            usage: Mutex::new(usage),
+        }
+    }
+
+    /// Counts a call, it fails if the calls or the bytes of today are used up
+    pub fn spend_call(&self) -> Result<()> {
+        let mut usage = self.today();
+        if is_over(usage.api_calls, self.max_api_calls) || is_over(usage.bytes, self.max_bytes) {
+            bail!(DriveError::BudgetExhausted);
+        }
+
+        usage.api_calls += 1;
+        self.save(&usage);
+        Ok(())
+    }
+
+    /// Counts transferred bytes. Transfer that is already started is not stopped, so the bytes are
+    /// counted even if they go over the limit
+    pub fn spend_bytes(&self, bytes: u64) {
+        let mut usage = self.today();
+        usage.bytes += bytes;
+        self.save(&usage);
+    }
+
+    #[cfg(feature = "tray")]
+    pub fn is_exhausted(&self) -> bool {
+        let usage = self.today();
+        is_over(usage.api_calls, self.max_api_calls) || is_over(usage.bytes, self.max_bytes)
+    }
+
+    /// API calls and bytes used today
+    #[cfg(feature = "monitor")]
+    pub fn used(&self) -> (u64, u64) {
+        let usage = self.today();
+        (usage.api_calls, usage.bytes)
+    }
+
+    /// Usage of today, counters of the previous days are dropped
+    fn today(&self) -> std::sync::MutexGuard<'_, Usage> {
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        let today = Local::now().format("%F").to_string();
+        if usage.day != today {
+            *usage = Usage {
+                day: today,
+                ..Usage::default()
+            };
+        }
+
+        usage
+    }
+
+    fn save(&self, usage: &Usage) {
+        let res = serde_json::to_string(usage)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(fs::write(&self.path, content)?));
+
+        if let Err(e) = res {
+            warn!("Unable to save the API usage.\nDetails: {}", e);
+        }
+    }
+}
+
+/// Time when the counters are reset
+pub fn next_reset() -> DateTime<Local> {
+    let tomorrow = (Local::now() + Duration::days(1)).date();
+
+    tomorrow
+        .and_hms_opt(0, 0, 0)
+        .unwrap_or_else(|| Local::now() + Duration::hours(1))
+}
+
+fn is_over(used: u64, max: Option<u64>) -> bool {
+    matches!(max, Some(max) if used >= max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::google_drive::{transport::Fake, Client};
+
+    #[test]
+    fn exceeding_the_byte_budget_pauses_transfers() {
+        let dir = std::env::temp_dir().join(format!("ocean-drive-budget-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("usage.json");
+        fs::remove_file(&path).ok();
+
+        let mut client = Client::with_transport(Fake(Box::new(|_| {
+            Ok((200, vec![], b"twenty bytes of data".to_vec()))
+        })));
+        client.set_budget(Budget::load(path.clone(), None, Some(10)));
+
+        // Download that is started is finished, even if it goes over the limit
+        assert_eq!(client.download_file("a").unwrap(), b"twenty bytes of data");
+        let e = client.download_file("b").unwrap_err();
+        assert!(DriveError::is_over_budget(&e));
+        assert!(DriveError::is_over_budget(
+            &client.list_files(None, None).unwrap_err()
+        ));
+
+        // Usage is kept across restarts
+        let budget = Budget::load(path.clone(), None, Some(10));
+        assert!(budget.spend_call().is_err());
+        let budget = Budget::load(path, None, Some(100));
+        assert!(budget.spend_call().is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -6,4 +6,33 @@ pub enum DriveError {
     Unauthorized,
     #[error("Request to the API failed with status 404")]
     NotFound,
+    #[error("Unable to connect to the API")]
+    Offline,
+    #[error("File is flagged by Google Drive as malware or spam, so it can't be downloaded")]
+    AbusiveFile,
+    /// Google Docs files have no content to download, big ones can't be exported either
+    #[error("File can't be downloaded or exported from Google Drive")]
+    NotDownloadable,
+    #[error("Daily budget of the API calls or transferred bytes is used up")]
+    BudgetExhausted,
+}
+
+impl DriveError {
+    pub fn is_over_budget(e: &anyhow::Error) -> bool {
+        e.chain()
+            .any(|cause| matches!(cause.downcast_ref(), Some(DriveError::BudgetExhausted)))
+    }
+
+    /// Checks if the error was caused by the API being unreachable (no network and etc.)
+    pub fn is_offline(e: &anyhow::Error) -> bool {
+        e.chain().any(|cause| {
+            if let Some(DriveError::Offline) = cause.downcast_ref::<DriveError>() {
+                return true;
+            }
+            if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+                return err.is_connect() || err.is_timeout();
+            }
+            false
+        })
+    }
 }
@@ -1,20 +1,123 @@
+pub mod bandwidth;
+pub mod budget;
 pub mod errors;
+pub mod transport;
 pub mod types;
-use anyhow::{bail, Result};
+use crate::logger;
+use anyhow::{bail, Context, Result};
+use bandwidth::{Bandwidth, BandwidthWindow, Throttled};
+use budget::Budget;
 use errors::DriveError;
-use reqwest::blocking::Client as HttpClient;
+use reqwest::{
+    blocking::{Body, Client as HttpClient, ClientBuilder, RequestBuilder, Response},
+    header::{HeaderValue, USER_AGENT},
+    Certificate,
+};
 use serde::{Deserialize, Serialize};
-use types::{File, FileList, FileUploadBody};
+use std::{
+    fs,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use transport::{Network, Recorder, Replayer, Transport};
+use types::{
+    About, AppProperties, File, FileList, FileUploadBody, Revision, RevisionList, SharedDrive,
+    TokenInfo,
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Session {
     pub access_token: String,
     pub refresh_token: Option<String>,
+    /// Email of the authorized account, saved to show it without requests to the API
+    pub email: Option<String>,
 }
 
+/// Connection pool of the process. Clients of all the profiles go through it, so they reuse the
+/// connections to the API instead of opening their own
+static HTTP: OnceLock<HttpClient> = OnceLock::new();
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+/// Applies to every read of the response, so a stuck download fails instead of hanging. Requests
+/// with a body must be sent and answered within it too, uploads get more time, see `upload_timeout`
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// Deadline of an upload grows with its size at this speed, so a big file on a slow link is not
+/// cut off, while a stuck upload still fails
+const MIN_UPLOAD_SPEED: u64 = 8 * 1024;
+/// Sent with every request, so the app can be told apart in the logs of Google and proxies
+pub const DEFAULT_USER_AGENT: &str = concat!(
+    env!("CARGO_PKG_NAME"),
+    "/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/uwumouse/ocean-drive)"
+);
+/// API key and quota project are sent only to the Drive API, not to the OAuth endpoints
+const API_HOST: &str = "www.googleapis.com";
+
+/// Fields of the file that are always requested from the API, since sync logic relies on them
+pub const MANDATORY_FIELDS: [&str; 11] = [
+    "id",
+    "name",
+    "mimeType",
+    "version",
+    "md5Checksum",
+    "trashed",
+    "parents",
+    "appProperties",
+    "modifiedTime",
+    "description",
+    "shortcutDetails",
+];
+
+/// Most files the API returns on one page of a listing
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
+/// Alias of the hidden app-private folder, it's used as the id of the folder
+pub const APP_DATA_FOLDER: &str = "appDataFolder";
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Config {
     pub dir: String,
+    /// Custom selector for the file fields requested from the API (e.g. "id, owners(emailAddress)")
+    /// Mandatory fields are always merged in
+    pub fields: Option<String>,
+    /// Id of the Shared Drive to sync instead of My Drive. `dir` is looked up in it, empty `dir`
+    /// means the whole Shared Drive
+    pub shared_drive_id: Option<String>,
+    /// Keep the synced files in the hidden app-private folder, so they are not visible in Drive.
+    /// `dir` is created in it when missing, empty `dir` means the folder itself. Requires the
+    /// `https://www.googleapis.com/auth/drive.appdata` scope
+    pub app_data: Option<bool>,
+    /// How many files are requested on one page of a listing (1000 by default, which is also the
+    /// maximum). Listings always follow all the pages
+    pub page_size: Option<u32>,
+    /// Requests to the API per day at most. Syncing is paused when they are used up, until the
+    /// counters are reset at midnight
+    pub max_api_calls_per_day: Option<u64>,
+    /// Bytes uploaded and downloaded per day at most, it works the same way as the calls limit
+    pub max_bytes_per_day: Option<u64>,
+    /// Speed limits or pauses by the time of the day, see `BandwidthWindow`
+    pub bandwidth_windows: Option<Vec<BandwidthWindow>>,
+    /// Sync into a subfolder of `dir` named after this machine (created when missing), so several
+    /// machines can back up into the same directory
+    pub namespace_by_hostname: Option<bool>,
+    /// Name of the subfolder for `namespace_by_hostname` (hostname of the machine by default)
+    pub hostname: Option<String>,
+    /// PEM file with the certificates that are trusted in addition to the system ones, e.g. the
+    /// root certificate of a corporate proxy that intercepts TLS
+    pub ca_bundle: Option<String>,
+    /// DANGEROUS, for testing only: accept any certificate, so anyone on the network can read
+    /// and change the traffic, including the access tokens
+    pub danger_accept_invalid_certs: Option<bool>,
+    /// Replaces the `User-Agent` header of the requests (`ocean-drive/<version>` by default)
+    pub user_agent: Option<String>,
+    /// API key of the Google Cloud project, for the projects that require one besides OAuth
+    pub api_key: Option<String>,
+    /// Google Cloud project that is billed for the requests and whose quota they use, instead of
+    /// the project of the OAuth client
+    pub quota_project: Option<String>,
 }
 
 #[derive(Clone)]
@@ -24,18 +127,181 @@ pub struct Client {
     redirect_uri: String,
     auth: Option<Session>,
     http: HttpClient,
+    /// Sends the requests built with `http`
+    transport: Arc<dyn Transport>,
+    fields: String,
+    shared_drive_id: Option<String>,
+    /// Listings search in the app-private folder instead of My Drive
+    app_data: bool,
+    page_size: u32,
+    /// Daily limits, nothing is limited for None
+    budget: Option<Arc<Budget>>,
+    /// Speed limits of the transfers by the time of the day, nothing is limited for None
+    bandwidth: Option<Arc<Bandwidth>>,
+    user_agent: String,
+    api_key: Option<String>,
+    quota_project: Option<String>,
+}
+
+/// Pages of a file listing, see `Client::file_pages`. Iteration stops after the first error
+pub struct FilePages<'a> {
+    client: &'a Client,
+    query: String,
+    fields: String,
+    next_page_token: Option<String>,
+    done: bool,
+}
+
+impl FilePages<'_> {
+    /// Whether all the pages were requested already
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+impl Iterator for FilePages<'_> {
+    type Item = Result<Vec<File>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let page =
+            self.client
+                .list_page(&self.query, &self.fields, self.next_page_token.as_deref());
+        match page {
+            Ok(list) => {
+                self.next_page_token = list.next_page_token;
+                self.done = self.next_page_token.is_none();
+                Some(Ok(list.files))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
 }
 
 // TODO: Cover all error cases with cases in errors enum
 impl Client {
-    pub fn new(client_id: String, client_secret: String, redirect_uri: String) -> Self {
-        Self {
+    pub fn new(client_id: String, client_secret: String, redirect_uri: String) -> Result<Self> {
+        let http = match HTTP.get() {
+            Some(http) => http.clone(),
+            None => {
+                let http = http_builder()
+                    .build()
+                    .context("Unable to set up the HTTP client")?;
+                HTTP.get_or_init(|| http).clone()
+            }
+        };
+
+        Ok(Self {
             client_id,
             client_secret,
             redirect_uri,
             auth: None,
-            http: HttpClient::new(),
+            transport: Arc::new(Network(http.clone())),
+            http,
+            fields: merge_fields("webViewLink, size"),
+            shared_drive_id: None,
+            app_data: false,
+            page_size: MAX_PAGE_SIZE,
+            budget: None,
+            bandwidth: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            api_key: None,
+            quota_project: None,
+        })
+    }
+
+    /// Sets custom fields selector for requested files, mandatory fields are added if missing
+    pub fn set_fields(&mut self, fields: &str) {
+        self.fields = merge_fields(fields);
+    }
+
+    /// Makes file listings search in the Shared Drive instead of My Drive
+    pub fn set_shared_drive(&mut self, id: &str) {
+        self.shared_drive_id = Some(id.to_string());
+    }
+
+    /// Makes file listings search in the app-private folder instead of My Drive. Files are created
+    /// there when their parent is `APP_DATA_FOLDER` or a folder in it
+    pub fn set_app_data_space(&mut self) {
+        self.app_data = true;
+    }
+
+    /// Number of files requested on one page of a listing, it's kept within 1..=MAX_PAGE_SIZE
+    pub fn set_page_size(&mut self, size: u32) {
+        self.page_size = size.clamp(1, MAX_PAGE_SIZE);
+    }
+
+    /// Makes every request and transfer count towards the daily limits of the budget
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.budget = Some(Arc::new(budget));
+    }
+
+    pub fn set_user_agent(&mut self, user_agent: &str) {
+        self.user_agent = user_agent.to_string();
+    }
+
+    /// Sends the API key with every request to the Drive API, in addition to the access token
+    pub fn set_api_key(&mut self, key: &str) {
+        self.api_key = Some(key.to_string());
+    }
+
+    /// Makes the requests to the Drive API use the quota of another Google Cloud project
+    pub fn set_quota_project(&mut self, project: &str) {
+        self.quota_project = Some(project.to_string());
+    }
+
+    #[cfg(feature = "tray")]
+    pub fn budget(&self) -> Option<Arc<Budget>> {
+        self.budget.clone()
+    }
+
+    pub fn set_bandwidth(&mut self, bandwidth: Bandwidth) {
+        self.bandwidth = Some(Arc::new(bandwidth));
+    }
+
+    /// Content read through this goes at most as fast as the current bandwidth limit allows
+    pub fn throttle<R: Read>(&self, content: R) -> Throttled<R> {
+        Throttled::new(content, self.bandwidth.clone())
+    }
+
+    /// Trusts the certificates of `ca_bundle` in addition to the system ones. Client gets its own
+    /// connection pool then. It must be called before the recording or replaying is set up, since
+    /// they keep the previous HTTP client
+    pub fn set_tls(&mut self, ca_bundle: Option<&Path>, accept_invalid_certs: bool) -> Result<()> {
+        let mut builder = http_builder();
+        if let Some(path) = ca_bundle {
+            for cert in read_certificates(path)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if accept_invalid_certs {
+            warn!("Certificate validation is disabled by `danger_accept_invalid_certs`. Connection to Google Drive is NOT secure, anyone on the network can read and change it");
+            builder = builder.danger_accept_invalid_certs(true);
         }
+
+        self.http = builder
+            .build()
+            .context("Unable to set up the HTTP client with the TLS config")?;
+        self.transport = Arc::new(Network(self.http.clone()));
+        Ok(())
+    }
+
+    /// Saves every response of the API to `dir`, so the session can be replayed later
+    pub fn record_to(&mut self, dir: PathBuf) -> Result<()> {
+        self.transport = Arc::new(Recorder::new(Network(self.http.clone()), dir)?);
+        Ok(())
+    }
+
+    /// Answers requests with the responses recorded to `dir` instead of sending them to the API
+    pub fn replay_from(&mut self, dir: PathBuf) -> Result<()> {
+        self.transport = Arc::new(Replayer::load(dir)?);
+        Ok(())
     }
 
     pub fn get_user_authorization_url(&self, scope: &str, redirect_uri: &str) -> String {
@@ -51,23 +317,102 @@ impl Client {
         self.auth = Some(s);
     }
 
+    /// Email of the account saved in the session
+    pub fn session_email(&self) -> Option<String> {
+        self.auth.as_ref().and_then(|s| s.email.clone())
+    }
+
+    /// Every request goes through here, so the common headers are added in one place
+    fn send(&self, req: RequestBuilder) -> Result<Response> {
+        if let Some(budget) = &self.budget {
+            budget.spend_call()?;
+        }
+
+        let mut req = req.header(USER_AGENT, &self.user_agent).build()?;
+        if req.url().host_str() == Some(API_HOST) {
+            let headers = req.headers_mut();
+            if let Some(key) = &self.api_key {
+                headers.insert("X-Goog-Api-Key", HeaderValue::from_str(key)?);
+            }
+            if let Some(project) = &self.quota_project {
+                headers.insert("X-Goog-User-Project", HeaderValue::from_str(project)?);
+            }
+        }
+
+        if !logger::verbose_api() {
+            return self.transport.execute(req);
+        }
+        let request = transport::log_request(&req);
+        transport::log_response(&request, self.transport.execute(req)?)
+    }
+
+    /// Body of an upload, it's streamed under the bandwidth limit when there's one
+    fn upload_body(&self, contents: Vec<u8>) -> Body {
+        match &self.bandwidth {
+            Some(_) => {
+                let size = contents.len() as u64;
+                Body::sized(self.throttle(Cursor::new(contents)), size)
+            }
+            None => Body::from(contents),
+        }
+    }
+
+    /// Time to send `size` bytes and get the response. Timeout of the client covers the whole
+    /// request with its body, so it would fail every upload that takes longer than a minute
+    fn upload_timeout(&self, size: u64) -> Duration {
+        let slowest = self.bandwidth.as_ref().and_then(|b| b.slowest());
+        upload_timeout(size, slowest)
+    }
+
+    /// Name of the bandwidth window that pauses the transfers now
+    pub fn transfers_paused_by(&self) -> Option<&str> {
+        self.bandwidth.as_ref().and_then(|b| b.paused_by())
+    }
+
+    fn spend_bytes(&self, bytes: u64) {
+        if let Some(budget) = &self.budget {
+            budget.spend_bytes(bytes);
+        }
+    }
+
     fn get(&self, url: String, query: &[(&str, &str)]) -> Result<reqwest::blocking::Response> {
+        self.get_range(url, query, None)
+    }
+
+    /// GET request for the part of the content starting at `from` byte (whole content for None)
+    fn get_range(
+        &self,
+        url: String,
+        query: &[(&str, &str)],
+        from: Option<u64>,
+    ) -> Result<reqwest::blocking::Response> {
         if let Some(auth) = &self.auth {
-            match self
+            let mut req = self
                 .http
                 .get(&url)
                 .bearer_auth(auth.access_token.clone())
                 .header("Content-Type", "application/json")
-                .query(query)
-                .send()
-            {
+                .query(query);
+            if let Some(from) = from {
+                req = req.header("Range", format!("bytes={}-", from));
+            }
+
+            match self.send(req) {
                 Ok(resp) => {
                     if resp.status() == 401 {
                         bail!(DriveError::Unauthorized);
                     }
                     return Ok(resp);
                 }
-                Err(_) => bail!("Request failed (GET {})", url),
+                Err(e) => {
+                    if DriveError::is_offline(&e) {
+                        bail!(DriveError::Offline);
+                    }
+                    if DriveError::is_over_budget(&e) {
+                        return Err(e);
+                    }
+                    bail!("Request failed (GET {})\nDetails: {}", url, e)
+                }
             }
         }
 
@@ -114,12 +459,11 @@ impl Client {
             params.push(("grant_type", String::from("authorization_code")));
         }
 
-        match self
-            .http
-            .post("https://oauth2.googleapis.com/token")
-            .form(&params)
-            .send()
-        {
+        match self.send(
+            self.http
+                .post("https://oauth2.googleapis.com/token")
+                .form(&params),
+        ) {
             Ok(resp) => {
                 if resp.status() == 401 {
                     bail!(DriveError::Unauthorized);
@@ -133,11 +477,38 @@ impl Client {
                 }
             }
             Err(e) => {
+                if DriveError::is_over_budget(&e) {
+                    return Err(e);
+                }
                 bail!("Failed to refresh access token.\nDetails: {}", e);
             }
         }
     }
 
+    /// Asks Google what the current access token grants. Token is sent in the body, so it doesn't
+    /// end up in the URL (and in the recordings)
+    pub fn get_token_info(&self) -> Result<TokenInfo> {
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => bail!(DriveError::Unauthorized),
+        };
+
+        let resp = self.send(
+            self.http
+                .post("https://oauth2.googleapis.com/tokeninfo")
+                .form(&[("access_token", &auth.access_token)]),
+        )?;
+        // Expired and revoked tokens are rejected with 400
+        if resp.status() == 400 || resp.status() == 401 {
+            bail!(DriveError::Unauthorized);
+        }
+        if !resp.status().is_success() {
+            bail!("Token info request failed with status {}", resp.status());
+        }
+
+        Ok(resp.json::<TokenInfo>()?)
+    }
+
     pub fn authorize_with_code(&mut self, code: String) -> Result<Session> {
         let session = self.get_token(false, Some(code.clone()), None)?;
         self.set_session(session.clone());
@@ -151,6 +522,7 @@ impl Client {
                 let mut new_session = self.get_token(true, None, Some(refresh_token.clone()))?;
 
                 new_session.refresh_token = Some(String::from(refresh_token));
+                new_session.email = auth.email.clone();
                 self.set_session(new_session.clone());
 
                 return Ok(new_session);
@@ -162,26 +534,72 @@ impl Client {
 
     /// Performs a GET Request to /files route, listing all files that meet `query` parameter
     /// - query is empty by default
-    /// - fields are the list of all fields that are present in `File` struct
+    /// - fields are the client fields selector (mandatory fields and the custom ones) by default
+    ///
+    /// All the pages are requested, so the list is complete
     pub fn list_files(&self, query: Option<&str>, fields: Option<&str>) -> Result<FileList> {
+        let mut files = vec![];
+        for page in self.file_pages(query.unwrap_or(""), fields) {
+            files.append(&mut page?);
+        }
+
+        Ok(FileList {
+            files,
+            next_page_token: None,
+        })
+    }
+
+    /// Same listing as `list_files`, but the pages are requested one at a time while they are
+    /// iterated, so the whole listing doesn't have to be kept in memory
+    pub fn file_pages(&self, query: &str, fields: Option<&str>) -> FilePages<'_> {
+        let default_fields = format!("files({})", self.fields);
+        let fields = fields.unwrap_or(&default_fields);
+        // Token of the next page is needed to follow the pages
+        let fields = if fields.contains("nextPageToken") {
+            fields.to_string()
+        } else {
+            format!("nextPageToken, {}", fields)
+        };
+
+        FilePages {
+            client: self,
+            query: query.to_string(),
+            fields,
+            next_page_token: None,
+            done: false,
+        }
+    }
+
+    fn list_page(&self, query: &str, fields: &str, page_token: Option<&str>) -> Result<FileList> {
+        let page_size = self.page_size.to_string();
+        let mut params = vec![
+            ("q", query),
+            ("fields", fields),
+            ("pageSize", &page_size),
+            ("supportsAllDrives", "true"),
+        ];
+        if let Some(token) = page_token {
+            params.push(("pageToken", token));
+        }
+        if let Some(drive_id) = &self.shared_drive_id {
+            params.push(("corpora", "drive"));
+            params.push(("driveId", drive_id));
+            params.push(("includeItemsFromAllDrives", "true"));
+        }
+        if self.app_data {
+            params.push(("spaces", APP_DATA_FOLDER));
+        }
+
         self.get_json::<FileList>(
             "https://www.googleapis.com/drive/v3/files".to_string(),
-            &[
-                ("q", query.unwrap_or("")),
-                (
-                    "fields",
-                    fields.unwrap_or(
-                        "files(id, md5Checksum, name, trashed, mimeType, parents, version)",
-                    ),
-                ),
-            ],
+            &params,
         )
     }
 
     pub fn get_file(&self, id: &str) -> Result<Option<File>> {
         match self.get_json(
             format!("https://www.googleapis.com/drive/v3/files/{}", id),
-            &[("fields", "id, name, mimeType, parents, version")],
+            &[("fields", &self.fields), ("supportsAllDrives", "true")],
         ) {
             Ok(f) => Ok(Some(f)),
             Err(e) => {
@@ -214,33 +632,152 @@ impl Client {
         Ok(Some(list.files[0].clone()))
     }
 
+    /// Also makes sure the user has access to the drive
+    pub fn get_shared_drive(&self, id: &str) -> Result<SharedDrive> {
+        self.get_json::<SharedDrive>(
+            format!("https://www.googleapis.com/drive/v3/drives/{}", id),
+            &[("fields", "name")],
+        )
+    }
+
+    /// Email of the account the client is authorized for
+    pub fn get_user_email(&self) -> Result<String> {
+        let about = self.get_json::<About>(
+            "https://www.googleapis.com/drive/v3/about".to_string(),
+            &[("fields", "user")],
+        )?;
+
+        match about.user.and_then(|u| u.email_address) {
+            Some(email) => Ok(email),
+            None => bail!("Drive returned no email for the authorized user"),
+        }
+    }
+
     pub fn download_file(&self, id: &str) -> Result<Vec<u8>> {
         match self.get(
             format!("https://www.googleapis.com/drive/v3/files/{}", id),
-            &[("alt", "media")],
+            &[("alt", "media"), ("supportsAllDrives", "true")],
         ) {
-            Ok(resp) => Ok(resp.bytes().unwrap().into_iter().collect::<Vec<u8>>()),
+            // Body is read in parts, so the timeout applies to each of them and not to the whole
+            Ok(mut resp) => {
+                let mut content = vec![];
+                resp.read_to_end(&mut content)?;
+                self.spend_bytes(content.len() as u64);
+                Ok(content)
+            }
             Err(e) => Err(e),
         }
     }
 
+    /// Lists the revisions of the file, oldest first. All the pages are requested
+    pub fn list_revisions(&self, id: &str) -> Result<Vec<Revision>> {
+        let url = format!("https://www.googleapis.com/drive/v3/files/{}/revisions", id);
+        let fields = "nextPageToken, revisions(id, modifiedTime, size, md5Checksum, keepForever)";
+
+        let mut revisions = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut params = vec![("fields", fields)];
+            if let Some(token) = &page_token {
+                params.push(("pageToken", token));
+            }
+
+            let mut page = self.get_json::<RevisionList>(url.clone(), &params)?;
+            revisions.append(&mut page.revisions);
+            match page.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => return Ok(revisions),
+            }
+        }
+    }
+
+    /// Content of the file as it was in the revision
+    pub fn download_revision(&self, id: &str, revision_id: &str) -> Result<Vec<u8>> {
+        let resp = self.get(
+            format!(
+                "https://www.googleapis.com/drive/v3/files/{}/revisions/{}",
+                id, revision_id
+            ),
+            &[("alt", "media")],
+        )?;
+        if resp.status() == 404 {
+            bail!(DriveError::NotFound);
+        }
+        if !resp.status().is_success() {
+            bail!(
+                "Drive responded with status {} to the download of revision {}",
+                resp.status(),
+                revision_id
+            );
+        }
+
+        let content = resp.bytes()?.to_vec();
+        self.spend_bytes(content.len() as u64);
+        Ok(content)
+    }
+
+    /// Requests file contents starting at `from` byte, so the body can be streamed
+    /// Server may ignore the range and respond with the whole file (status 200 instead of 206)
+    /// - acknowledge_abuse allows downloading files that Drive flagged as malware or spam
+    pub fn download_file_from(
+        &self,
+        id: &str,
+        from: u64,
+        acknowledge_abuse: bool,
+    ) -> Result<reqwest::blocking::Response> {
+        let resp = self.get_range(
+            format!("https://www.googleapis.com/drive/v3/files/{}", id),
+            &[
+                ("alt", "media"),
+                ("supportsAllDrives", "true"),
+                ("acknowledgeAbuse", &acknowledge_abuse.to_string()),
+            ],
+            Some(from),
+        )?;
+        // Body is streamed by the caller, so the size is taken from the headers
+        self.spend_bytes(resp.content_length().unwrap_or(0));
+
+        if resp.status() == 404 {
+            bail!(DriveError::NotFound);
+        }
+        if resp.status() == 403 {
+            let details = resp.text().unwrap_or_default();
+            if details.contains("cannotDownloadAbusiveFile") {
+                bail!(DriveError::AbusiveFile);
+            }
+            if details.contains("fileNotDownloadable")
+                || details.contains("exportSizeLimitExceeded")
+            {
+                bail!(DriveError::NotDownloadable);
+            }
+            bail!(
+                "Drive denied the download with status 403.\nDetails: {}",
+                details
+            );
+        }
+
+        Ok(resp)
+    }
+
     pub fn create_dir(&self, name: &str, parent_id: String) -> Result<File> {
         if let Some(auth) = &self.auth {
             let body = FileUploadBody {
                 name: name.to_string(),
                 parents: vec![parent_id],
                 mime_type: Some("application/vnd.google-apps.folder".to_string()),
+                description: None,
+                app_properties: None,
             };
 
             // Initialize uploading with sending first request in the sequence
-            let res = self
-                .http
-                .post("https://www.googleapis.com/drive/v3/files")
-                .query(&[("fields", "*")])
-                .bearer_auth(auth.access_token.clone())
-                .header("Content-Type", "application/json")
-                .body(serde_json::to_string(&body).unwrap())
-                .send()?;
+            let res = self.send(
+                self.http
+                    .post("https://www.googleapis.com/drive/v3/files")
+                    .query(&[("fields", "*"), ("supportsAllDrives", "true")])
+                    .bearer_auth(auth.access_token.clone())
+                    .header("Content-Type", "application/json")
+                    .body(serde_json::to_string(&body).unwrap()),
+            )?;
 
             if res.status() == 401 {
                 bail!(DriveError::Unauthorized);
@@ -252,66 +789,163 @@ impl Client {
         bail!(DriveError::Unauthorized);
     }
 
-    pub fn upload_file(&self, name: &str, parent_id: String, contents: Vec<u8>) -> Result<File> {
+    /// Uploads a new file, `description` is shown in its details in Drive. Properties are set
+    /// in the same request, so the file never exists without them
+    pub fn upload_file(
+        &self,
+        name: &str,
+        parent_id: String,
+        contents: Vec<u8>,
+        description: Option<String>,
+        app_properties: Option<AppProperties>,
+    ) -> Result<File> {
         if let Some(auth) = &self.auth {
             let body = FileUploadBody {
                 name: name.to_string(),
                 parents: vec![parent_id],
                 mime_type: None,
+                description,
+                app_properties,
             };
 
             // Initialize uploading with sending first request in the sequence
-            let res = self
-                .http
-                .post("https://www.googleapis.com/upload/drive/v3/files")
-                .bearer_auth(auth.access_token.clone())
-                .header("Content-Type", "application/json")
-                .query(&[("uploadType", "resumable"), ("fields", "*")])
-                .body(serde_json::to_string(&body).unwrap())
-                .send()?;
+            let res = self.send(
+                self.http
+                    .post("https://www.googleapis.com/upload/drive/v3/files")
+                    .bearer_auth(auth.access_token.clone())
+                    .header("Content-Type", "application/json")
+                    .query(&[
+                        ("uploadType", "resumable"),
+                        ("fields", "*"),
+                        ("supportsAllDrives", "true"),
+                    ])
+                    .body(serde_json::to_string(&body).unwrap()),
+            )?;
 
-            if res.status() == 401 {
-                bail!(DriveError::Unauthorized);
-            }
+            return self.upload_to_session(res, contents, &auth.access_token);
+        }
+
+        bail!(DriveError::Unauthorized);
+    }
 
-            let upload_location = res.headers().get("Location");
+    /// Replaces the content of the file. Properties are changed in the same request, so the
+    /// content and its properties are never out of step
+    pub fn update_file(
+        &self,
+        id: String,
+        contents: Vec<u8>,
+        app_properties: Option<AppProperties>,
+    ) -> Result<File> {
+        if let Some(auth) = &self.auth {
+            let url = format!("https://www.googleapis.com/upload/drive/v3/files/{}", id);
 
-            if upload_location.is_none() {
-                bail!("Unable to create resumable session to upload file to the cloud");
+            if let Some(props) = app_properties {
+                let body = serde_json::json!({ "appProperties": props });
+                let res = self.send(
+                    self.http
+                        .patch(url)
+                        .bearer_auth(auth.access_token.clone())
+                        .header("Content-Type", "application/json")
+                        .query(&[
+                            ("uploadType", "resumable"),
+                            ("fields", "*"),
+                            ("supportsAllDrives", "true"),
+                        ])
+                        .body(body.to_string()),
+                )?;
+
+                return self.upload_to_session(res, contents, &auth.access_token);
             }
-            let upload_location = upload_location.unwrap();
 
-            // Upload at once, at the end we should get all file data
-            let created = self
-                .http
-                .put(upload_location.to_str().unwrap())
-                .bearer_auth(auth.access_token.clone())
-                .body(contents)
-                .send()?;
+            let size = contents.len() as u64;
+            let res = self.send(
+                self.http
+                    .patch(url)
+                    .bearer_auth(auth.access_token.clone())
+                    .query(&[
+                        ("uploadType", "media"),
+                        ("fields", "*"),
+                        ("supportsAllDrives", "true"),
+                    ])
+                    .timeout(self.upload_timeout(size))
+                    .body(self.upload_body(contents)),
+            )?;
+            self.spend_bytes(size);
 
-            if created.status() != 200 && created.status() != 201 {
-                bail!("File wasn't uploaded successfully");
+            if res.status() == 401 {
+                bail!(DriveError::Unauthorized);
             }
 
-            // TODO: Handle errors with JSON Deserialization
-            return Ok(created.json::<File>()?);
+            return Ok(res.json::<File>()?);
         }
 
         bail!(DriveError::Unauthorized);
     }
 
-    pub fn update_file(&self, id: String, contents: Vec<u8>) -> Result<File> {
+    /// Sends the content to the resumable upload session that `res` has started
+    fn upload_to_session(&self, res: Response, contents: Vec<u8>, token: &str) -> Result<File> {
+        if res.status() == 401 {
+            bail!(DriveError::Unauthorized);
+        }
+
+        let upload_location = res.headers().get("Location");
+
+        if upload_location.is_none() {
+            bail!("Unable to create resumable session to upload file to the cloud");
+        }
+        let upload_location = upload_location.unwrap();
+
+        // Upload at once, at the end we should get all file data
+        let size = contents.len() as u64;
+        let created = self.send(
+            self.http
+                .put(upload_location.to_str().unwrap())
+                .bearer_auth(token)
+                .timeout(self.upload_timeout(size))
+                .body(self.upload_body(contents)),
+        )?;
+        self.spend_bytes(size);
+
+        if created.status() != 200 && created.status() != 201 {
+            bail!("File wasn't uploaded successfully");
+        }
+
+        // TODO: Handle errors with JSON Deserialization
+        Ok(created.json::<File>()?)
+    }
+
+    /// Rename also could mean moving of the file, so the parent should be specified
+    /// - old_parent_id is removed from the file parents if it's different from the new one
+    pub fn rename_file(
+        &self,
+        id: String,
+        new_name: &str,
+        parent_id: String,
+        old_parent_id: Option<String>,
+    ) -> Result<File> {
+        let mut body = std::collections::HashMap::new();
+        body.insert("name", new_name);
+
+        let mut query = vec![
+            ("fields", "*"),
+            ("addParents", &parent_id),
+            ("supportsAllDrives", "true"),
+        ];
+        if let Some(old_parent_id) = &old_parent_id {
+            if old_parent_id != &parent_id {
+                query.push(("removeParents", old_parent_id));
+            }
+        }
+
         if let Some(auth) = &self.auth {
-            let res = self
-                .http
-                .patch(format!(
-                    "https://www.googleapis.com/upload/drive/v3/files/{}",
-                    id
-                ))
-                .bearer_auth(auth.access_token.clone())
-                .query(&[("uploadType", "media")])
-                .body(contents)
-                .send()?;
+            let res = self.send(
+                self.http
+                    .patch(format!("https://www.googleapis.com/drive/v3/files/{}", id))
+                    .header("Content-Type", "application/json")
+                    .bearer_auth(auth.access_token.clone())
+                    .query(&query)
+                    .body(serde_json::to_string(&body).unwrap()),
+            )?;
 
             if res.status() == 401 {
                 bail!(DriveError::Unauthorized);
@@ -323,20 +957,34 @@ impl Client {
         bail!(DriveError::Unauthorized);
     }
 
-    /// Rename also could mean moving of the file, so the parent should be specified
-    pub fn rename_file(&self, id: String, new_name: &str, parent_id: String) -> Result<File> {
-        let mut body = std::collections::HashMap::new();
-        body.insert("name", new_name);
+    /// Copies the file on the drive, so its content doesn't have to be uploaded again
+    pub fn copy_file(
+        &self,
+        id: &str,
+        name: &str,
+        parent_id: String,
+        description: Option<String>,
+    ) -> Result<File> {
+        let body = FileUploadBody {
+            name: name.to_string(),
+            parents: vec![parent_id],
+            mime_type: None,
+            description,
+            app_properties: None,
+        };
 
         if let Some(auth) = &self.auth {
-            let res = self
-                .http
-                .patch(format!("https://www.googleapis.com/drive/v3/files/{}", id))
-                .header("Content-Type", "application/json")
-                .bearer_auth(auth.access_token.clone())
-                .query(&[("fields", "*"), ("addParents", &parent_id)])
-                .body(serde_json::to_string(&body).unwrap())
-                .send()?;
+            let res = self.send(
+                self.http
+                    .post(format!(
+                        "https://www.googleapis.com/drive/v3/files/{}/copy",
+                        id
+                    ))
+                    .header("Content-Type", "application/json")
+                    .bearer_auth(auth.access_token.clone())
+                    .query(&[("fields", "*"), ("supportsAllDrives", "true")])
+                    .body(serde_json::to_string(&body).unwrap()),
+            )?;
 
             if res.status() == 401 {
                 bail!(DriveError::Unauthorized);
@@ -350,15 +998,20 @@ impl Client {
 
     pub fn detele_file(&self, id: String) -> Result<()> {
         if let Some(auth) = &self.auth {
-            let res = self
-                .http
-                .delete(format!("https://www.googleapis.com/drive/v3/files/{}", id))
-                .bearer_auth(auth.access_token.clone())
-                .send()?;
+            let res = self.send(
+                self.http
+                    .delete(format!("https://www.googleapis.com/drive/v3/files/{}", id))
+                    .bearer_auth(auth.access_token.clone())
+                    .query(&[("supportsAllDrives", "true")]),
+            )?;
 
             if res.status() == 401 {
                 bail!(DriveError::Unauthorized);
             }
+            // File that is already gone is what the deletion is for
+            if res.status() == 404 {
+                return Ok(());
+            }
 
             return Ok(());
         }
@@ -366,3 +1019,308 @@ impl Client {
         bail!(DriveError::Unauthorized);
     }
 }
+
+/// Merges mandatory fields into the custom fields selector
+/// Selector is split only by top-level commas, so nested selectors like `owners(emailAddress)`
+/// are kept as is
+fn merge_fields(custom: &str) -> String {
+    let mut fields: Vec<String> = MANDATORY_FIELDS.iter().map(|f| f.to_string()).collect();
+    let mut depth = 0;
+    let mut current = String::new();
+
+    for c in custom.chars().chain(std::iter::once(',')) {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                let field = current.trim().to_string();
+                if !field.is_empty() && !fields.contains(&field) {
+                    fields.push(field);
+                }
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+
+    fields.join(", ")
+}
+
+/// Throttled upload can't go faster than the slowest bandwidth window, which is also shared with
+/// the downloads, so it gets time for half of that speed
+fn upload_timeout(size: u64, slowest_limit: Option<u64>) -> Duration {
+    let speed = match slowest_limit {
+        Some(max) => MIN_UPLOAD_SPEED.min(max / 2).max(1),
+        None => MIN_UPLOAD_SPEED,
+    };
+    READ_TIMEOUT + Duration::from_secs(size / speed)
+}
+
+fn http_builder() -> ClientBuilder {
+    HttpClient::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(READ_TIMEOUT)
+}
+
+/// Reads every certificate of the PEM file, a bundle usually has more than one
+fn read_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    const END: &str = "-----END CERTIFICATE-----";
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read the CA bundle {:?}", path))?;
+
+    let mut certs = vec![];
+    for block in content.split_inclusive(END).filter(|b| b.contains(END)) {
+        let cert = Certificate::from_pem(block.trim().as_bytes())
+            .with_context(|| format!("Invalid certificate in the CA bundle {:?}", path))?;
+        certs.push(cert);
+    }
+
+    if certs.is_empty() {
+        bail!("No certificates found in the CA bundle {:?}", path);
+    }
+    Ok(certs)
+}
+
+#[cfg(test)]
+impl Client {
+    /// Authorized client whose requests are answered by `transport`
+    pub fn with_transport(transport: impl Transport + 'static) -> Self {
+        let mut client = Self::new(String::new(), String::new(), String::new()).unwrap();
+        client.set_session(Session {
+            access_token: "token".to_string(),
+            refresh_token: Some("refresh".to_string()),
+            email: None,
+        });
+        client.transport = Arc::new(transport);
+        client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn mandatory_fields_are_added_to_a_minimal_selector() {
+        let merged = merge_fields("size");
+        for field in MANDATORY_FIELDS.iter().chain(&["size"]) {
+            assert!(
+                merged.split(", ").any(|f| f == *field),
+                "{} is missing",
+                field
+            );
+        }
+
+        // Nested selectors are kept whole and requested fields are not repeated
+        let merged = merge_fields("owners(emailAddress, displayName), id");
+        assert!(merged.ends_with(", owners(emailAddress, displayName)"));
+        assert_eq!(merged.matches("id, ").count(), 1);
+
+        let requested = Arc::new(Mutex::new(vec![]));
+        let sent = Arc::clone(&requested);
+        let mut client = Client::with_transport(transport::Fake(Box::new(move |req| {
+            sent.lock().unwrap().push(req.url().to_string());
+            Ok((200, vec![], br#"{"files": []}"#.to_vec()))
+        })));
+        client.set_fields("size");
+        client.list_files(Some("'root' in parents"), None).unwrap();
+
+        let url = reqwest::Url::parse(&requested.lock().unwrap()[0]).unwrap();
+        let fields = url
+            .query_pairs()
+            .find(|(k, _)| k == "fields")
+            .map(|(_, v)| v.into_owned())
+            .unwrap();
+        assert_eq!(
+            fields,
+            format!("nextPageToken, files({})", merge_fields("size"))
+        );
+    }
+
+    #[test]
+    fn upload_timeout_fits_the_slowest_window() {
+        assert_eq!(upload_timeout(0, None), READ_TIMEOUT);
+        assert_eq!(
+            upload_timeout(MIN_UPLOAD_SPEED * 100, None),
+            READ_TIMEOUT + Duration::from_secs(100)
+        );
+        // Half of the 1000 B/s limit
+        assert_eq!(
+            upload_timeout(50_000, Some(1000)),
+            READ_TIMEOUT + Duration::from_secs(100)
+        );
+        assert_eq!(
+            upload_timeout(50_000, Some(1)),
+            READ_TIMEOUT + Duration::from_secs(50_000)
+        );
+    }
+
+    #[test]
+    fn user_email_is_parsed_from_about() {
+        let about = br#"{
+            "user": {
+                "kind": "drive#user",
+                "displayName": "Me",
+                "emailAddress": "me@example.com"
+            }
+        }"#;
+        let client = Client::with_transport(transport::Fake(Box::new(move |req| {
+            assert!(req.url().path().ends_with("/about"));
+            Ok((200, vec![], about.to_vec()))
+        })));
+        assert_eq!(client.get_user_email().unwrap(), "me@example.com");
+
+        let client = Client::with_transport(transport::Fake(Box::new(|_| {
+            Ok((200, vec![], br#"{"user": {"displayName": "Me"}}"#.to_vec()))
+        })));
+        assert!(client.get_user_email().is_err());
+    }
+
+    #[test]
+    fn listing_is_scoped_to_the_shared_drive() {
+        let requested = Arc::new(Mutex::new(vec![]));
+        let sent = Arc::clone(&requested);
+        let mut client = Client::with_transport(transport::Fake(Box::new(move |req| {
+            let url = req.url().clone();
+            sent.lock().unwrap().push(url);
+            Ok((200, vec![], br#"{"files": []}"#.to_vec()))
+        })));
+        let param = |url: &reqwest::Url, key: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.into_owned())
+        };
+
+        client.list_files(Some("'root' in parents"), None).unwrap();
+        client.set_shared_drive("team");
+        client.list_files(Some("'team' in parents"), None).unwrap();
+
+        let requested = requested.lock().unwrap();
+        assert_eq!(param(&requested[0], "corpora"), None);
+        assert_eq!(param(&requested[0], "driveId"), None);
+        assert_eq!(param(&requested[1], "corpora").as_deref(), Some("drive"));
+        assert_eq!(param(&requested[1], "driveId").as_deref(), Some("team"));
+        assert_eq!(
+            param(&requested[1], "includeItemsFromAllDrives").as_deref(),
+            Some("true")
+        );
+    }
+
+    #[test]
+    fn listing_uses_the_app_data_space() {
+        let requested = Arc::new(Mutex::new(vec![]));
+        let sent = Arc::clone(&requested);
+        let mut client = Client::with_transport(transport::Fake(Box::new(move |req| {
+            sent.lock().unwrap().push(req.url().clone());
+            Ok((200, vec![], br#"{"files": []}"#.to_vec()))
+        })));
+        let spaces = |url: &reqwest::Url| {
+            url.query_pairs()
+                .find(|(k, _)| k == "spaces")
+                .map(|(_, v)| v.into_owned())
+        };
+
+        client.list_files(Some("'root' in parents"), None).unwrap();
+        client.set_app_data_space();
+        client
+            .list_files(Some("'appDataFolder' in parents"), None)
+            .unwrap();
+
+        let requested = requested.lock().unwrap();
+        assert_eq!(spaces(&requested[0]), None);
+        assert_eq!(spaces(&requested[1]).as_deref(), Some(APP_DATA_FOLDER));
+    }
+
+    #[test]
+    fn listing_follows_the_next_page_token() {
+        let requested = Arc::new(Mutex::new(vec![]));
+        let sent = Arc::clone(&requested);
+        let mut client = Client::with_transport(transport::Fake(Box::new(move |req| {
+            let url = req.url().clone();
+            let token = url
+                .query_pairs()
+                .find(|(k, _)| k == "pageToken")
+                .map(|(_, v)| v.into_owned());
+            sent.lock().unwrap().push(url);
+            let body = match token.as_deref() {
+                None => r#"{"nextPageToken": "p2", "files": [{"id": "a"}, {"id": "b"}]}"#,
+                Some("p2") => r#"{"files": [{"id": "c"}]}"#,
+                Some(t) => panic!("unexpected page token {}", t),
+            };
+            Ok((200, vec![], body.as_bytes().to_vec()))
+        })));
+        client.set_page_size(2);
+
+        let list = client.list_files(Some("'root' in parents"), None).unwrap();
+        let ids: Vec<_> = list.files.iter().map(|f| f.id.clone().unwrap()).collect();
+        assert_eq!(ids, vec!["a", "b", "c"]);
+
+        let requested = requested.lock().unwrap();
+        assert_eq!(requested.len(), 2);
+        for url in requested.iter() {
+            assert!(url.query_pairs().any(|(k, v)| k == "pageSize" && v == "2"));
+        }
+    }
+
+    #[test]
+    fn requests_carry_the_user_agent() {
+        let agents = Arc::new(Mutex::new(vec![]));
+        let sent = Arc::clone(&agents);
+        let mut client = Client::with_transport(transport::Fake(Box::new(move |req| {
+            let header = |name| {
+                req.headers()
+                    .get(name)
+                    .map(|v: &HeaderValue| v.to_str().unwrap().to_string())
+            };
+            sent.lock()
+                .unwrap()
+                .push((header("user-agent"), header("x-goog-api-key")));
+            Ok((200, vec![], br#"{"files": []}"#.to_vec()))
+        })));
+
+        client.list_files(None, None).unwrap();
+        client.set_user_agent("backup-box/2.0");
+        client.set_api_key("key");
+        client.list_files(None, None).unwrap();
+
+        let agents = agents.lock().unwrap();
+        let version = format!("ocean-drive/{} ", env!("CARGO_PKG_VERSION"));
+        assert!(matches!(&agents[0], (Some(a), None) if a.starts_with(&version)));
+        assert_eq!(
+            agents[1],
+            (Some("backup-box/2.0".to_string()), Some("key".to_string()))
+        );
+    }
+
+    #[test]
+    fn revision_is_listed_and_downloaded() {
+        let client = Client::with_transport(transport::Fake(Box::new(|req| {
+            let body: &[u8] = match req.url().path() {
+                "/drive/v3/files/a/revisions" => {
+                    br#"{
+                        "revisions": [
+                            {"id": "1", "modifiedTime": "2021-10-25T10:00:00Z", "size": "3", "md5Checksum": "x"},
+                            {"id": "2", "modifiedTime": "2021-10-26T10:00:00Z", "keepForever": true}
+                        ]
+                    }"#
+                }
+                "/drive/v3/files/a/revisions/1" => b"old",
+                _ => return Ok((404, vec![], vec![])),
+            };
+            Ok((200, vec![], body.to_vec()))
+        })));
+
+        let revisions = client.list_revisions("a").unwrap();
+        assert_eq!(revisions.len(), 2);
+        assert_eq!(revisions[0].size.as_deref(), Some("3"));
+        assert_eq!(revisions[1].keep_forever, Some(true));
+        assert_eq!(
+            client.download_revision("a", &revisions[0].id).unwrap(),
+            b"old"
+        );
+        assert!(client.download_revision("a", "3").is_err());
+    }
+}
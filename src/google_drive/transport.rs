@@ -0,0 +1,352 @@
+/*
+    Sends requests of the client. Besides the network, requests can be recorded to a directory or
+    answered from the recorded responses, so a sync issue can be reproduced without the drive.
+    Recording keeps method, URL, status, headers and body of every response. Authorization headers
+    are never saved and tokens in the token responses are replaced.
+    With `--verbose-api` requests and responses are also logged, without the secrets.
+*/
+use anyhow::{bail, Context, Result};
+use reqwest::{
+    blocking::{Client as HttpClient, Request, Response},
+    header::CONTENT_TYPE,
+    Url,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+/// Fields of the token responses that are replaced in the recordings
+const SECRET_FIELDS: [&str; 3] = ["access_token", "refresh_token", "id_token"];
+const REDACTED: &str = "REDACTED";
+/// Values that are never logged: tokens, client secret and authorization code in the bodies, API
+/// key in the URLs
+const LOGGED_SECRETS: [&str; 6] = [
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "client_secret",
+    "code",
+    "key",
+];
+/// Longer bodies are cut in the logs
+const MAX_LOGGED_BODY: usize = 4096;
+
+/// Recorded responses with their bodies, in the order they were received
+type Recorded = VecDeque<(Exchange, Vec<u8>)>;
+
+pub trait Transport: Send + Sync {
+    fn execute(&self, req: Request) -> Result<Response>;
+}
+
+pub struct Network(pub HttpClient);
+
+impl Transport for Network {
+    fn execute(&self, req: Request) -> Result<Response> {
+        Ok(self.0.execute(req)?)
+    }
+}
+
+/// Status, headers and body of a response of the fake transport
+#[cfg(test)]
+pub type Answer = (u16, Vec<(String, String)>, Vec<u8>);
+
+/// Answers the request like the API would, or fails like the network does
+#[cfg(test)]
+pub type Respond = dyn Fn(&Request) -> Result<Answer> + Send + Sync;
+
+/// Answers the requests with the function instead of the network, for the tests
+#[cfg(test)]
+pub struct Fake(pub Box<Respond>);
+
+#[cfg(test)]
+impl Transport for Fake {
+    fn execute(&self, req: Request) -> Result<Response> {
+        let (status, headers, body) = (self.0)(&req)?;
+        let exchange = Exchange {
+            method: req.method().to_string(),
+            url: req.url().to_string(),
+            status,
+            headers,
+        };
+
+        build_response(&exchange, body)
+    }
+}
+
+/// Single recorded exchange, its body is saved to a separate file next to it
+#[derive(Serialize, Deserialize)]
+struct Exchange {
+    method: String,
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+/// Sends requests with the inner transport (the network) and saves every response to `dir`
+pub struct Recorder {
+    inner: Box<dyn Transport>,
+    dir: PathBuf,
+    count: Mutex<usize>,
+}
+
+impl Recorder {
+    pub fn new(inner: impl Transport + 'static, dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Unable to create recording directory {:?}", dir))?;
+
+        Ok(Self {
+            inner: Box::new(inner),
+            dir,
+            count: Mutex::new(0),
+        })
+    }
+}
+
+impl Transport for Recorder {
+    /// Response body is read at once to save it, so downloads are not streamed while recording
+    fn execute(&self, req: Request) -> Result<Response> {
+        let method = req.method().to_string();
+        let url = req.url().to_string();
+        let resp = self.inner.execute(req)?;
+
+        let status = resp.status().as_u16();
+        let headers = headers_of(&resp);
+        let body = resp.bytes()?.to_vec();
+
+        let exchange = Exchange {
+            method,
+            url,
+            status,
+            headers,
+        };
+        let saved_body = if exchange.url.starts_with("https://oauth2.googleapis.com/") {
+            redact(&body, &SECRET_FIELDS)
+        } else {
+            body.clone()
+        };
+
+        let n = {
+            let mut count = self.count.lock().unwrap_or_else(|e| e.into_inner());
+            *count += 1;
+            *count
+        };
+        fs::write(
+            self.dir.join(format!("{:05}.json", n)),
+            serde_json::to_string_pretty(&exchange)?,
+        )?;
+        fs::write(self.dir.join(format!("{:05}.body", n)), saved_body)?;
+
+        build_response(&exchange, body)
+    }
+}
+
+/// Answers requests with the responses recorded in `dir`. The same requests get the responses
+/// in the order they were recorded
+pub struct Replayer {
+    responses: Mutex<HashMap<String, Recorded>>,
+}
+
+impl Replayer {
+    pub fn load(dir: PathBuf) -> Result<Self> {
+        let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+            .with_context(|| format!("Unable to read recording directory {:?}", dir))?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| matches!(p.extension(), Some(e) if e == "json"))
+            .collect();
+        files.sort();
+
+        let mut responses: HashMap<String, Recorded> = HashMap::new();
+        for file in files {
+            let exchange: Exchange = serde_json::from_str(&fs::read_to_string(&file)?)
+                .with_context(|| format!("Invalid recording {:?}", file))?;
+            let body = fs::read(file.with_extension("body")).unwrap_or_default();
+
+            responses
+                .entry(key(&exchange.method, &exchange.url))
+                .or_default()
+                .push_back((exchange, body));
+        }
+
+        Ok(Self {
+            responses: Mutex::new(responses),
+        })
+    }
+}
+
+impl Transport for Replayer {
+    fn execute(&self, req: Request) -> Result<Response> {
+        let key = key(req.method().as_str(), req.url().as_str());
+        let mut responses = self.responses.lock().unwrap_or_else(|e| e.into_inner());
+
+        match responses.get_mut(&key).and_then(|r| r.pop_front()) {
+            Some((exchange, body)) => build_response(&exchange, body),
+            None => bail!("Recording has no more responses for {}", key),
+        }
+    }
+}
+
+/// Logs the request with the secrets redacted, Authorization header is not logged at all.
+/// Returns its method and URL to log the response with
+pub fn log_request(req: &Request) -> String {
+    let line = format!("{} {}", req.method(), redact_url(req.url()));
+    match req.body().and_then(|b| b.as_bytes()) {
+        Some(body) if !body.is_empty() => {
+            debug!("API request: {}\n{}", line, loggable_body(body))
+        }
+        _ => debug!("API request: {}", line),
+    }
+
+    line
+}
+
+/// Logs the status of the response to the `request`. Only JSON bodies are logged, other ones
+/// (e.g. file content) are not read, so downloads are still streamed
+pub fn log_response(request: &str, resp: Response) -> Result<Response> {
+    let is_json = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .is_some_and(|t| t.contains("json"));
+    if !is_json {
+        debug!("API response: {} to {}", resp.status(), request);
+        return Ok(resp);
+    }
+
+    let exchange = Exchange {
+        method: String::new(),
+        url: String::new(),
+        status: resp.status().as_u16(),
+        headers: headers_of(&resp),
+    };
+    let body = resp.bytes()?.to_vec();
+    debug!(
+        "API response: {} to {}\n{}",
+        exchange.status,
+        request,
+        loggable_body(&body)
+    );
+
+    build_response(&exchange, body)
+}
+
+fn key(method: &str, url: &str) -> String {
+    format!("{} {}", method, url)
+}
+
+fn build_response(exchange: &Exchange, body: Vec<u8>) -> Result<Response> {
+    let mut resp = http::Response::builder().status(exchange.status);
+    for (name, value) in &exchange.headers {
+        resp = resp.header(name.as_str(), value.as_str());
+    }
+
+    Ok(Response::from(resp.body(body)?))
+}
+
+/// Cookies are never kept
+fn headers_of(resp: &Response) -> Vec<(String, String)> {
+    resp.headers()
+        .iter()
+        .filter(|(name, _)| name.as_str() != "set-cookie")
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+/// Replaces the `secrets` fields in the JSON body, anything else is kept as is
+fn redact(body: &[u8], secrets: &[&str]) -> Vec<u8> {
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(mut fields)) => {
+            for field in secrets.iter() {
+                if let Some(v) = fields.get_mut(*field) {
+                    *v = serde_json::Value::String(REDACTED.to_string());
+                }
+            }
+            serde_json::to_vec(&fields).unwrap_or_default()
+        }
+        _ => body.to_vec(),
+    }
+}
+
+fn redact_url(url: &Url) -> String {
+    if url.query().is_none() {
+        return url.to_string();
+    }
+
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| {
+            let v = if LOGGED_SECRETS.contains(&k.as_ref()) {
+                REDACTED.into()
+            } else {
+                v
+            };
+            (k.into_owned(), v.into_owned())
+        })
+        .collect();
+    let mut url = url.clone();
+    url.query_pairs_mut().clear().extend_pairs(pairs);
+
+    url.to_string()
+}
+
+/// JSON and form bodies with the secrets redacted, binary ones are only measured
+fn loggable_body(body: &[u8]) -> String {
+    let text = match std::str::from_utf8(body) {
+        Ok(text) => text,
+        Err(_) => return format!("<{} bytes of binary data>", body.len()),
+    };
+
+    let text = if text.trim_start().starts_with('{') {
+        String::from_utf8_lossy(&redact(body, &LOGGED_SECRETS)).into_owned()
+    } else {
+        // Token requests are sent as forms
+        text.split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((k, _)) if LOGGED_SECRETS.contains(&k) => format!("{}={}", k, REDACTED),
+                _ => pair.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    };
+
+    match text.char_indices().nth(MAX_LOGGED_BODY) {
+        Some((cut, _)) => format!("{}... ({} bytes)", &text[..cut], body.len()),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logged_request_omits_the_secrets() {
+        let req = HttpClient::new()
+            .get("https://www.googleapis.com/drive/v3/files?key=api-key&q=trashed")
+            .bearer_auth("access-secret")
+            .build()
+            .unwrap();
+        let line = log_request(&req);
+        assert!(!line.contains("api-key"));
+        assert!(!line.contains("access-secret"));
+        assert!(line.starts_with("GET https://www.googleapis.com/drive/v3/files?"));
+        assert!(line.contains("key=REDACTED"));
+        assert!(line.contains("q=trashed"));
+
+        let form = loggable_body(
+            b"grant_type=refresh_token&refresh_token=refresh-secret&client_id=id\
+              &client_secret=client-secret",
+        );
+        assert_eq!(
+            form,
+            "grant_type=refresh_token&refresh_token=REDACTED&client_id=id&client_secret=REDACTED"
+        );
+
+        let json = loggable_body(br#"{"access_token":"access-secret","expires_in":3599}"#);
+        assert!(!json.contains("access-secret"));
+        assert!(json.contains("3599"));
+    }
+}
@@ -1,16 +1,31 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Md5 of no content. Drive may not give the checksum of an empty file, but local files are
+/// compared by it
+pub const EMPTY_MD5: &str = "d41d8cd98f00b204e9800998ecf8427e";
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct FileList {
     pub files: Vec<File>,
+    /// Set when there are more files than fit on one page
+    #[serde(rename = "nextPageToken")]
+    pub next_page_token: Option<String>,
 }
 
+/// Custom properties of a file that are set with its content. None removes the property
+pub type AppProperties = HashMap<String, Option<String>>;
+
 #[derive(Serialize, Debug, Clone)]
 pub struct FileUploadBody {
     pub name: String,
     pub parents: Vec<String>,
     #[serde(rename = "mimeType")]
-    pub mime_type: Option<String>
+    pub mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(rename = "appProperties", skip_serializing_if = "Option::is_none")]
+    pub app_properties: Option<AppProperties>,
     // TODO: Add createdAt field (will point to time when file was created LOCALLY)
 }
 
@@ -25,4 +40,112 @@ pub struct File {
     pub md5: Option<String>,
     pub version: Option<String>,
     pub parents: Option<Vec<String>>,
+    #[serde(rename = "webViewLink")]
+    pub web_view_link: Option<String>,
+    /// Size in bytes (API returns it as a string)
+    pub size: Option<String>,
+    /// Custom properties, they keep the md5 and size of the plain content of encrypted files
+    #[serde(rename = "appProperties")]
+    pub app_properties: Option<HashMap<String, String>>,
+    /// RFC 3339 time in UTC, so the times can be compared as strings
+    #[serde(rename = "modifiedTime")]
+    pub modified_time: Option<String>,
+    /// Text that users add in the details of the file
+    pub description: Option<String>,
+    /// Set only for shortcuts
+    #[serde(rename = "shortcutDetails")]
+    pub shortcut_details: Option<ShortcutDetails>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ShortcutDetails {
+    #[serde(rename = "targetId")]
+    pub target_id: Option<String>,
+}
+
+impl File {
+    /// Md5 of the file content. For encrypted files it's the md5 before encryption
+    pub fn content_md5(&self) -> Option<String> {
+        self.app_property("plainMd5")
+            .or_else(|| self.md5.clone())
+            .or_else(|| self.is_empty().then(|| EMPTY_MD5.to_string()))
+    }
+
+    /// Id of the file whose content is downloaded for this one: the target for a followed
+    /// shortcut, the file itself otherwise
+    pub fn content_id(&self) -> Option<&str> {
+        self.shortcut_details
+            .as_ref()
+            .and_then(|s| s.target_id.as_deref())
+            .or(self.id.as_deref())
+    }
+
+    /// File has no content at all (Google Docs have no size, so they are never empty)
+    pub fn is_empty(&self) -> bool {
+        self.content_size() == Some(0)
+    }
+
+    /// Content was encrypted by the app, the md5 of the plain one is kept in the properties
+    pub fn is_encrypted(&self) -> bool {
+        self.app_property("plainMd5").is_some()
+    }
+
+    /// Size of the file content. For encrypted files it's the size before encryption
+    pub fn content_size(&self) -> Option<u64> {
+        self.app_property("plainSize")
+            .or_else(|| self.size.clone())
+            .and_then(|s| s.parse::<u64>().ok())
+    }
+
+    fn app_property(&self, key: &str) -> Option<String> {
+        self.app_properties.as_ref()?.get(key).cloned()
+    }
+}
+
+/// Past content of a file that Drive keeps. Google Docs have revisions too, but they can't be
+/// downloaded as they are
+#[derive(Deserialize, Debug, Clone)]
+pub struct Revision {
+    pub id: String,
+    #[serde(rename = "modifiedTime")]
+    pub modified_time: Option<String>,
+    /// Size in bytes (API returns it as a string)
+    pub size: Option<String>,
+    #[serde(rename = "md5Checksum")]
+    pub md5: Option<String>,
+    /// Revisions are deleted after 30 days or 100 newer revisions, unless they are kept forever
+    #[serde(rename = "keepForever")]
+    pub keep_forever: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RevisionList {
+    pub revisions: Vec<Revision>,
+    #[serde(rename = "nextPageToken")]
+    pub next_page_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct About {
+    pub user: Option<User>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct User {
+    #[serde(rename = "emailAddress")]
+    pub email_address: Option<String>,
+}
+
+/// Details of the access token from the tokeninfo endpoint
+#[derive(Deserialize, Debug, Clone)]
+pub struct TokenInfo {
+    /// Granted scopes separated by spaces
+    pub scope: Option<String>,
+    /// Seconds until the token expires (API returns it as a string)
+    pub expires_in: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SharedDrive {
+    pub name: Option<String>,
 }
@@ -0,0 +1,152 @@
+/*
+    Console output for the daemons: log lines with colored levels and a progress line for downloads.
+    Progress is drawn only in terminal and log lines clear it first, so they are not mixed up.
+*/
+use std::{
+    fmt,
+    io::{self, IsTerminal, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+static VERBOSE_API: AtomicBool = AtomicBool::new(false);
+static PROGRESS: Mutex<Option<Progress>> = Mutex::new(None);
+
+const BAR_WIDTH: usize = 20;
+
+#[derive(Clone, Copy)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+struct Progress {
+    done: usize,
+    total: usize,
+    current: String,
+}
+
+/// Sets up colors by the `--color` value: "always", "never" or "auto" (only in terminal)
+pub fn init(color: Option<&str>) {
+    let enabled = match color {
+        Some("always") => true,
+        Some("never") => false,
+        _ => io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+    };
+
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+/// Logs every request to the Drive API and its response (`--verbose-api`)
+pub fn set_verbose_api(enabled: bool) {
+    VERBOSE_API.store(enabled, Ordering::Relaxed);
+}
+
+pub fn verbose_api() -> bool {
+    VERBOSE_API.load(Ordering::Relaxed)
+}
+
+pub fn log(level: Level, msg: fmt::Arguments) {
+    let (prefix, color) = match level {
+        Level::Debug => ("Debug:", "36"),
+        Level::Info => ("Info:", "32"),
+        Level::Warn => ("Warn:", "33"),
+        Level::Error => ("Error:", "31"),
+    };
+    let prefix = if COLOR.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", color, prefix)
+    } else {
+        prefix.to_string()
+    };
+
+    let progress = PROGRESS.lock().unwrap_or_else(|e| e.into_inner());
+    clear_progress(&progress);
+    match level {
+        Level::Info => println!("{} {}", prefix, msg),
+        _ => eprintln!("{} {}", prefix, msg),
+    }
+    draw_progress(&progress);
+}
+
+/// Starts showing progress of `total` transfers
+pub fn progress_start(total: usize) {
+    let mut progress = PROGRESS.lock().unwrap_or_else(|e| e.into_inner());
+    *progress = Some(Progress {
+        done: 0,
+        total,
+        current: String::new(),
+    });
+    draw_progress(&progress);
+}
+
+/// Marks a transfer as finished, `name` is shown as the current one
+pub fn progress_inc(name: &str) {
+    let mut progress = PROGRESS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(p) = progress.as_mut() {
+        p.done += 1;
+        p.current = name.to_string();
+    }
+    draw_progress(&progress);
+}
+
+pub fn progress_finish() {
+    let mut progress = PROGRESS.lock().unwrap_or_else(|e| e.into_inner());
+    clear_progress(&progress);
+    *progress = None;
+}
+
+fn clear_progress(progress: &Option<Progress>) {
+    if progress.is_some() && io::stderr().is_terminal() {
+        eprint!("\r\x1b[2K");
+    }
+}
+
+fn draw_progress(progress: &Option<Progress>) {
+    let p = match progress {
+        Some(p) if io::stderr().is_terminal() => p,
+        _ => return,
+    };
+
+    let filled = (BAR_WIDTH * p.done)
+        .checked_div(p.total)
+        .unwrap_or(BAR_WIDTH)
+        .min(BAR_WIDTH);
+    eprint!(
+        "\r\x1b[2K[{}{}] {}/{} {}",
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled),
+        p.done,
+        p.total,
+        p.current
+    );
+    io::stderr().flush().ok();
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Level::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Level::Error, format_args!($($arg)*))
+    };
+}
@@ -1,16 +1,23 @@
+// Must be the first, so its macros are available in the other modules
+#[macro_use]
+mod logger;
 mod auth;
 mod files;
 mod google_drive;
 mod parse_url;
 mod readline;
 mod redirect_listener;
+mod service;
 mod setup;
 mod sync;
+#[cfg(feature = "tray")]
 mod tray;
+#[cfg(feature = "self-update")]
+mod update;
 mod user;
 extern crate clap;
-use anyhow::{Result, bail};
-use clap::{App, SubCommand};
+use anyhow::{bail, Result};
+use clap::{App, Arg};
 
 // TODO:
 //  - Create dir in Drive if needed
@@ -18,27 +25,101 @@ use clap::{App, SubCommand};
 //  + Sync dirs
 //  + Update remote if local is changed
 //  - vice versa
-//  - Setup for systemctl
+//  + Setup for systemctl
 //  - Add icon to tray (idk what would be there, but do it)
 //  + Add functionality to get out of some errors (like with not existing authorization and etc.)
 //  - Synced folder can be either the whole drive or folder in the root of the drive
 
 fn main() -> Result<()> {
-    let cmd = App::new("Ocean Drive")
+    let app = App::new("Ocean Drive")
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(
+            Arg::with_name("color")
+                .long("color")
+                .global(true)
+                .takes_value(true)
+                .possible_values(&["auto", "always", "never"])
+                .help("When to use colors in the output (auto by default)"),
+        )
+        .arg(
+            Arg::with_name("verbose-api")
+                .long("verbose-api")
+                .global(true)
+                .help("Log every request to the Drive API and its response, without the secrets"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .global(true)
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Use the config and the sync state of the profile from ~/.config/ocean-drive/profiles"),
+        )
         .subcommand(setup::root_subcommand())
-        .subcommand(SubCommand::with_name("run").about("[DEFAULT] Start synchronization."))
-        .get_matches();
+        .subcommand(sync::run_subcommand())
+        .subcommand(sync::init_subcommand())
+        .subcommand(sync::link_subcommand())
+        .subcommand(sync::pull_subcommand())
+        .subcommand(sync::prune_subcommand())
+        .subcommand(sync::relocate_subcommand())
+        .subcommand(sync::diff_subcommand())
+        .subcommand(sync::usage_subcommand())
+        .subcommand(sync::revisions_subcommand())
+        .subcommand(sync::restore_subcommand())
+        .subcommand(sync::migrate_subcommand())
+        .subcommand(sync::profiles_subcommand())
+        .subcommand(service::subcommand());
+    #[cfg(feature = "monitor")]
+    let app = app.subcommand(sync::monitor_subcommand());
+    #[cfg(feature = "self-update")]
+    let app = app.subcommand(update::subcommand());
+    let cmd = app.get_matches();
 
     // let c = files::read_toml::<config::Config>("./config.toml");
     // TODO: Add check for config file in the ~/.config folder. Create if does not exist. Or use the provided one from cli args
     let subcmd = cmd.subcommand_name().unwrap_or("run");
+    // Global option is available only in the subcommand matches when it's set after it
+    logger::init(
+        cmd.subcommand()
+            .1
+            .and_then(|m| m.value_of("color"))
+            .or_else(|| cmd.value_of("color")),
+    );
+    logger::set_verbose_api(
+        cmd.is_present("verbose-api")
+            || matches!(cmd.subcommand().1, Some(m) if m.is_present("verbose-api")),
+    );
+
+    if let Some(name) = cmd
+        .subcommand()
+        .1
+        .and_then(|m| m.value_of("profile"))
+        .or_else(|| cmd.value_of("profile"))
+    {
+        sync::set_profile(name)?;
+    }
 
     match subcmd {
         "setup" => setup::run(cmd.subcommand().1.unwrap()),
-        "run" => sync::run(),
+        "run" => sync::run(cmd.subcommand_matches("run")),
+        "init" => sync::init(),
+        "link" => sync::link(cmd.subcommand_matches("link").unwrap()),
+        "pull" => sync::pull(cmd.subcommand_matches("pull").unwrap()),
+        "prune" => sync::prune(cmd.subcommand_matches("prune").unwrap()),
+        "relocate" => sync::relocate(cmd.subcommand_matches("relocate").unwrap()),
+        "diff" => sync::diff(cmd.subcommand_matches("diff").unwrap()),
+        "usage" => sync::usage(cmd.subcommand_matches("usage").unwrap()),
+        "revisions" => sync::revisions(cmd.subcommand_matches("revisions").unwrap()),
+        "restore" => sync::restore(cmd.subcommand_matches("restore").unwrap()),
+        "migrate" => sync::migrate(cmd.subcommand_matches("migrate").unwrap()),
+        "profiles" => sync::profiles(cmd.subcommand_matches("profiles").unwrap()),
+        "install-service" => service::run(cmd.subcommand_matches("install-service").unwrap()),
+        #[cfg(feature = "monitor")]
+        "monitor" => sync::monitor(cmd.subcommand_matches("monitor").unwrap()),
+        #[cfg(feature = "self-update")]
+        "update" => update::run(cmd.subcommand_matches("update").unwrap()),
         _ => {
             bail!("Unknown subcommand. Try 'ocean-drive --help'");
         }
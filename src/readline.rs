@@ -1,43 +1,166 @@
 /* Some functions to get user input conveniently */
 
+use anyhow::{bail, Result};
 use std::io;
 use std::io::prelude::*;
+use std::process::{Command, Stdio};
 
 /* This function returns None only if empty line was supplied (user just hit Enter key) */
 pub fn prompt(prompt: &str) -> Option<String> {
+    read_answer(prompt).ok().flatten()
+}
+
+pub fn prompt_default(text: &str, default: &str) -> String {
+    if let Some(ans) = prompt(&format!("{} (Default: {:?})", text, default)) {
+        return ans;
+    }
+
+    return default.to_string();
+}
+
+#[deprecated(note = "use `prompt_default`")]
+#[allow(dead_code)]
+pub fn promt_default(text: &str, default: &str) -> String {
+    prompt_default(text, default)
+}
+
+/// Asks again until `validate` accepts the answer, the error it returns is shown to the user.
+/// Empty answer is validated as the default, if there's one. Fails only when the input is closed
+pub fn prompt_valid<T, F>(text: &str, default: Option<&str>, validate: F) -> Result<T>
+where
+    F: Fn(&str) -> std::result::Result<T, String>,
+{
+    valid_answer(&mut io::stdin().lock(), text, default, validate)
+}
+
+fn valid_answer<T, F>(
+    input: &mut impl BufRead,
+    text: &str,
+    default: Option<&str>,
+    validate: F,
+) -> Result<T>
+where
+    F: Fn(&str) -> std::result::Result<T, String>,
+{
+    let text = match default {
+        Some(default) => format!("{} (Default: {:?})", text, default),
+        None => text.to_string(),
+    };
+
+    loop {
+        let ans = match read_answer_from(input, &text) {
+            Ok(ans) => ans,
+            Err(_) => bail!("No answer was given, input is closed"),
+        };
+
+        match validate(ans.as_deref().or(default).unwrap_or("")) {
+            Ok(value) => return Ok(value),
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+/// Same as `prompt`, but the typed text is not shown, e.g. for passwords. It's shown anyway when
+/// the input is not a terminal. Fails when the input is closed
+pub fn prompt_secret(prompt: &str) -> Result<Option<String>> {
+    let hidden = set_echo(false);
+    let ans = read_answer(prompt);
+    if hidden {
+        set_echo(true);
+        // Enter was not echoed either
+        println!();
+    }
+
+    match ans {
+        Ok(ans) => Ok(ans),
+        Err(_) => bail!("No answer was given, input is closed"),
+    }
+}
+
+/// Asks a yes/no question until it's answered. Empty answer (or closed input) means `default`
+pub fn confirm(text: &str, default: bool) -> bool {
+    confirm_from(&mut io::stdin().lock(), text, default)
+}
+
+fn confirm_from(input: &mut impl BufRead, text: &str, default: bool) -> bool {
+    let hint = if default { "[Y/n]" } else { "[y/N]" };
+
+    loop {
+        let ans = match read_answer_from(input, &format!("{} {}", text, hint)) {
+            Ok(Some(ans)) => ans.to_lowercase(),
+            _ => return default,
+        };
+
+        match ans.as_str() {
+            "y" | "yes" => return true,
+            "n" | "no" => return false,
+            _ => println!("Please, answer y or n"),
+        }
+    }
+}
+
+/// Reads a trimmed line, None for an empty one. Closed input is an error, so the callers that
+/// ask again don't ask forever
+fn read_answer(prompt: &str) -> io::Result<Option<String>> {
+    read_answer_from(&mut io::stdin().lock(), prompt)
+}
+
+fn read_answer_from(input: &mut impl BufRead, prompt: &str) -> io::Result<Option<String>> {
     print!("{}: ", prompt);
-    io::stdout().flush().unwrap();
+    io::stdout().flush()?;
     let mut ans = String::new();
 
-    if let Err(_) = io::stdin().read_line(&mut ans) {
-        return None;
+    if input.read_line(&mut ans)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
     }
 
     if ans.trim().is_empty() {
-        return None;
+        return Ok(None);
     }
 
-    Some(ans.trim().to_string())
+    Ok(Some(ans.trim().to_string()))
 }
 
-pub fn promt_default(text: &str, default: &str) -> String {
-    if let Some(ans) = prompt(&format!("{} (Default: {:?})", text, default)) {
-        return ans;
+/// Turns the echo of the terminal on or off with `stty`, returns whether it was done
+fn set_echo(on: bool) -> bool {
+    Command::new("stty")
+        .arg(if on { "echo" } else { "-echo" })
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(ans: &str) -> std::result::Result<u16, String> {
+        ans.parse().map_err(|_| format!("{:?} is not a port", ans))
     }
 
-    return default.to_string();
-}
+    #[test]
+    fn invalid_answer_is_asked_again() {
+        let mut input = &b"http\n\n70000\n8080\n"[..];
+        assert_eq!(valid_answer(&mut input, "Port", None, port).unwrap(), 8080);
 
-pub fn binary_prompt(text: &str) -> bool {
-    loop {
-        if let Some(ans) = prompt(&format!("{} (Y/N))", text)) {
-            match ans.to_lowercase().as_str() {
-                "y" => true,
-                "n" => false,
-                _ => continue,
-            };
-        }
+        // Empty answer is the default
+        let mut input = &b"\n"[..];
+        assert_eq!(
+            valid_answer(&mut input, "Port", Some("80"), port).unwrap(),
+            80
+        );
+
+        let mut input = &b"http\n"[..];
+        assert!(valid_answer(&mut input, "Port", None, port).is_err());
+    }
 
-        return true;
+    #[test]
+    fn yes_or_no_is_asked_until_answered() {
+        assert!(confirm_from(&mut &b"maybe\nYes\n"[..], "Continue?", false));
+        assert!(!confirm_from(&mut &b"n\n"[..], "Continue?", true));
+        assert!(confirm_from(&mut &b"\n"[..], "Continue?", true));
+        // Closed input is the default too
+        assert!(!confirm_from(&mut &b""[..], "Continue?", false));
     }
 }
@@ -1,19 +1,35 @@
 use anyhow::{bail, Result};
 use std::{
-    io::prelude::*,
+    io::{self, prelude::*},
     net::{TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
 };
 
+/// Browsers open connections in advance and may never send anything on them
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Returns the path of the request, if it's the OAuth callback. Other requests of the browser
+/// (e.g. favicon) are answered with 404, so they don't take the place of the callback
 fn handle_request(mut stream: TcpStream) -> Option<String> {
+    stream.set_nonblocking(false).ok()?;
+    stream.set_read_timeout(Some(READ_TIMEOUT)).ok()?;
+
     let mut buffer = [0; 1000];
-    let _ = stream.read(&mut buffer).unwrap();
+    let size = stream.read(&mut buffer).ok()?;
 
     // convert buffer into string and 'parse' the URL
-    match String::from_utf8(buffer.to_vec()) {
+    match String::from_utf8(buffer[..size].to_vec()) {
         Ok(request) => {
             let split: Vec<&str> = request.split_whitespace().collect();
 
             if split.len() > 1 {
+                if !is_callback(split[1]) {
+                    not_found_res(stream);
+                    return None;
+                }
+
                 success_res(stream);
                 return Some(split[1].to_string());
             }
@@ -28,29 +44,57 @@ fn handle_request(mut stream: TcpStream) -> Option<String> {
     None
 }
 
-pub fn get_callback() -> Result<String> {
-    let listener = TcpListener::bind("127.0.0.1:8080");
-
-    match listener {
-        Ok(listener) => {
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        if let Some(url) = handle_request(stream) {
-                            return Ok(url);
-                        }
+/// Google redirects with either the code or the error in the query
+fn is_callback(path: &str) -> bool {
+    match path.split_once('?') {
+        Some((_, query)) => query
+            .split('&')
+            .any(|pair| pair.starts_with("code=") || pair.starts_with("error=")),
+        None => false,
+    }
+}
+
+/// Listens for the OAuth callback on the redirect port. Port stays bound between the waits, so
+/// the browser can come back while the user is asked whether to keep waiting
+pub struct Listener(TcpListener);
+
+impl Listener {
+    pub fn bind() -> Result<Self> {
+        Self::bind_to("127.0.0.1:8080")
+    }
+
+    fn bind_to(addr: &str) -> Result<Self> {
+        let listener = match TcpListener::bind(addr) {
+            Ok(listener) => listener,
+            Err(e) => bail!(
+                "Unable to setup listener on port 8080 for getting authorization code.\nError info: {}",
+                e
+            ),
+        };
+        listener.set_nonblocking(true)?;
+
+        Ok(Self(listener))
+    }
+
+    /// Waits for the callback for up to `timeout`. Returns None if the browser didn't come back
+    /// in time
+    pub fn get_callback(&self, timeout: Duration) -> Result<Option<String>> {
+        let deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline {
+            match self.0.accept() {
+                Ok((stream, _)) => {
+                    if let Some(url) = handle_request(stream) {
+                        return Ok(Some(url));
                     }
-                    Err(e) => eprintln!("Error: {}", e),
-                };
-            }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => eprintln!("Error: {}", e),
+            };
         }
-        Err(e) => bail!(
-            "Unable to setup listener on port 8080 for getting authorization code.\nError info: {}",
-            e
-        ),
-    }
 
-    bail!("App was unable to get authorization code from Google API");
+        Ok(None)
+    }
 }
 
 fn success_res(mut stream: TcpStream) {
@@ -61,6 +105,11 @@ fn success_res(mut stream: TcpStream) {
     stream.flush().unwrap();
 }
 
+fn not_found_res(mut stream: TcpStream) {
+    stream.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").ok();
+    stream.flush().ok();
+}
+
 fn error_res(error_message: String, mut stream: TcpStream) {
     println!("Error: {}", error_message);
     let response = format!(
@@ -71,3 +120,38 @@ fn error_res(error_message: String, mut stream: TcpStream) {
     stream.write_all(response.as_bytes()).unwrap();
     stream.flush().unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(port: u16, path: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn other_requests_dont_end_the_wait() {
+        let listener = Listener::bind_to("127.0.0.1:0").unwrap();
+        let port = listener.0.local_addr().unwrap().port();
+
+        let browser = thread::spawn(move || {
+            let favicon = get(port, "/favicon.ico");
+            let callback = get(port, "/?state=x&code=abc");
+            (favicon, callback)
+        });
+
+        let url = listener.get_callback(Duration::from_secs(30)).unwrap();
+        assert_eq!(url.as_deref(), Some("/?state=x&code=abc"));
+        let (favicon, callback) = browser.join().unwrap();
+        assert!(favicon.starts_with("HTTP/1.1 404"));
+        assert!(callback.starts_with("HTTP/1.1 200"));
+
+        assert!(is_callback("/?error=access_denied"));
+        assert!(!is_callback("/?codec=1"));
+        assert!(!is_callback("/code=1"));
+    }
+}
@@ -0,0 +1,194 @@
+/*
+    Running as a user-level systemd service. `install-service` writes the unit that runs
+    `ocean-drive run`, and the daemon reports to systemd that it's started and still syncing, so a
+    stuck daemon is restarted by the systemd watchdog. Reports do nothing outside of systemd.
+*/
+use anyhow::{Context, Result};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::{env, thread, time::Duration};
+
+const UNIT_NAME: &str = "ocean-drive.service";
+/// Sync cycle is aborted after 30 minutes, so a daemon that wasn't heard of for an hour is stuck
+const WATCHDOG_SECS: u64 = 60 * 60;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("install-service")
+        .about("Installs a systemd user service that runs the sync in the background.")
+        .arg(
+            Arg::with_name("enable")
+                .long("enable")
+                .help("Also enable the service and start it now"),
+        )
+}
+
+#[cfg(target_os = "linux")]
+pub fn run(m: &ArgMatches) -> Result<()> {
+    use crate::user;
+    use std::{fs, process::Command};
+
+    let exe = env::current_exe().context("Unable to find the installed binary")?;
+    let units_dir = user::get_home()?.join(".config/systemd/user");
+    fs::create_dir_all(&units_dir)
+        .with_context(|| format!("Unable to create directory {:?}", units_dir))?;
+
+    let unit_path = units_dir.join(UNIT_NAME);
+    fs::write(&unit_path, unit_file(&exe.to_string_lossy()))
+        .with_context(|| format!("Unable to write {:?}", unit_path))?;
+    info!("Service is saved to {:?}", unit_path);
+
+    let mut commands = vec![vec!["daemon-reload"]];
+    if m.is_present("enable") {
+        commands.push(vec!["enable", "--now", UNIT_NAME]);
+    }
+    for args in commands {
+        let status = Command::new("systemctl").arg("--user").args(&args).status();
+        if !matches!(status, Ok(s) if s.success()) {
+            warn!(
+                "`systemctl --user {}` failed, run it yourself to apply the service",
+                args.join(" ")
+            );
+            return Ok(());
+        }
+    }
+
+    if !m.is_present("enable") {
+        info!(
+            "Run `systemctl --user enable --now {}` to start syncing in the background",
+            UNIT_NAME
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn run(_m: &ArgMatches) -> Result<()> {
+    info!("Services are installed only on Linux with systemd. Nothing was changed");
+    Ok(())
+}
+
+/// Tray is not available to services, so it's disabled
+fn unit_file(exe: &str) -> String {
+    // Paths with spaces must be quoted for systemd
+    let exe = if exe.contains(char::is_whitespace) {
+        format!("\"{}\"", exe)
+    } else {
+        exe.to_string()
+    };
+
+    format!(
+        "[Unit]
+Description=Ocean Drive, Google Drive sync
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+ExecStart={} run --no-tray
+Restart=on-failure
+RestartSec=30
+WatchdogSec={}
+
+[Install]
+WantedBy=default.target
+",
+        exe, WATCHDOG_SECS
+    )
+}
+
+/// Tells systemd that the daemons are started
+pub fn notify_ready() {
+    notify("READY=1");
+}
+
+/// Tells systemd that syncing is still going, it's called after every healthy sync cycle
+pub fn notify_alive() {
+    if watchdog_interval().is_some() {
+        notify("WATCHDOG=1");
+    }
+}
+
+/// Status that `systemctl status` shows, e.g. the problem of the last sync cycle
+pub fn notify_status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+/// Sleeps, telling systemd that the daemon is fine meanwhile (e.g. while syncing is paused), so
+/// long pauses don't look like a stuck daemon
+pub fn sleep(duration: Duration) {
+    let step = match watchdog_interval() {
+        Some(interval) => interval / 2,
+        None => return thread::sleep(duration),
+    };
+
+    let mut left = duration;
+    while !left.is_zero() {
+        let nap = left.min(step);
+        thread::sleep(nap);
+        notify_alive();
+        left -= nap;
+    }
+}
+
+/// Watchdog of the service, None when it's not enabled for this process
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid != std::process::id().to_string() {
+            return None;
+        }
+    }
+
+    env::var("WATCHDOG_USEC")
+        .ok()?
+        .parse::<u64>()
+        .ok()
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+#[cfg(target_os = "linux")]
+fn notify(state: &str) {
+    use std::os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    };
+
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+
+    let res = UnixDatagram::unbound().and_then(|socket| {
+        // Socket in the abstract namespace starts with '@'
+        match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr)),
+            None => socket.send_to(state.as_bytes(), &path),
+        }
+    });
+    if let Err(e) = res {
+        warn!("Unable to notify systemd: {}", e);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify(_state: &str) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_file_runs_the_installed_binary() {
+        let unit = unit_file("/usr/local/bin/ocean-drive");
+        assert!(unit.contains("\nExecStart=/usr/local/bin/ocean-drive run --no-tray\n"));
+        assert!(unit.contains("\nType=notify\n"));
+        assert!(unit.contains(&format!("\nWatchdogSec={}\n", WATCHDOG_SECS)));
+        assert!(unit.contains("\nWantedBy=default.target\n"));
+
+        let unit = unit_file("/home/me/My Apps/ocean-drive");
+        assert!(unit.contains("\nExecStart=\"/home/me/My Apps/ocean-drive\" run --no-tray\n"));
+    }
+}
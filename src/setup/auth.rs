@@ -1,56 +1,206 @@
 use crate::{
     auth::Creds,
     files,
-    google_drive::Client,
+    google_drive::{errors::DriveError, types::TokenInfo, Client, Session},
     parse_url,
-    readline::{binary_prompt, prompt},
+    readline::{confirm, prompt_secret, prompt_valid},
     redirect_listener, user,
 };
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use std::{path::Path, time::Duration};
 use webbrowser;
 
-pub fn run() -> Result<()> {
-    let creds = get_client_creds();
+/// How long to wait for the browser before asking whether to keep waiting
+pub const DEFAULT_AUTH_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Returns the new session
+/// - timeout is how long to wait for the browser before asking whether to keep waiting
+pub fn run(timeout: Duration) -> Result<Session> {
+    let home = user::get_home()?;
+    let creds_file = home.join(".config/ocean-drive/creds.toml");
+    // Keep scopes that are configured in the existing creds file
+    let scopes = files::read_toml::<Creds>(creds_file)
+        .ok()
+        .and_then(|c| c.scopes);
+
+    let creds = get_client_creds()?;
     let redirect_uri = "http://localhost:8080";
-    let mut drive_client = Client::new(creds.0.clone(), creds.1.clone(), redirect_uri.to_string());
+    let mut drive_client = Client::new(creds.0.clone(), creds.1.clone(), redirect_uri.to_string())?;
+
+    let creds = Creds {
+        client_id: creds.0,
+        client_secret: creds.1,
+        scopes,
+    };
+
+    creds.validate()?;
+    if !creds.has_full_access() {
+        println!("Warn: App won't have access to the files that were not uploaded by Ocean Drive with the configured scopes.");
+    }
 
-    let user_consent_url = drive_client
-        .get_user_authorization_url("https://www.googleapis.com/auth/drive", redirect_uri);
+    let user_consent_url = drive_client.get_user_authorization_url(&creds.scope(), redirect_uri);
 
-    let auth_code = get_auth_code(user_consent_url);
+    let auth_code = get_auth_code(user_consent_url, timeout);
 
     if let Ok(code) = auth_code {
-        let session = drive_client.authorize_with_code(code.to_string())?;
+        let mut session = drive_client.authorize_with_code(code.to_string())?;
+
+        match drive_client.get_user_email() {
+            Ok(email) => session.email = Some(email),
+            Err(e) => eprintln!("Warn: Unable to get the account email.\nDetails: {}", e),
+        }
 
         println!("App is authorized. Saving user credentials and session files.");
 
-        let creds = Creds {
-            client_id: creds.0,
-            client_secret: creds.1,
-        };
+        let config_dir = home.join(".config/ocean-drive");
+
+        let creds_file = config_dir.join("creds.toml");
+        let session_file = config_dir.join("session.toml");
 
-        let home = user::get_home();
-        if let Ok(home) = home {
-            let config_dir = home.join(".config/ocean-drive");
+        files::write_toml(session.clone(), session_file)?;
+        files::write_toml(creds, creds_file)?;
+
+        return Ok(session);
+    }
+
+    bail!("Failed to get authroization code from the Google API");
+}
 
-            let creds_file = config_dir.join("creds.toml");
-            let session_file = config_dir.join("session.toml");
+/// Prints what the saved session gives access to, without the token values
+pub fn show() -> Result<()> {
+    let config_dir = user::get_home()?.join(".config/ocean-drive");
+    let session = files::read_toml::<Session>(config_dir.join("session.toml"))
+        .context("No saved session.\nTip: Run `ocean-drive setup auth` to authorize the app")?;
+    let creds = files::read_toml::<Creds>(config_dir.join("creds.toml"))?;
 
-            files::write_toml(session, session_file)?;
-            files::write_toml(creds, creds_file)?;
+    let mut client = Client::new(
+        creds.client_id.clone(),
+        creds.client_secret.clone(),
+        "http://localhost:8080".to_string(),
+    )?;
+    client.set_session(session.clone());
 
-            return Ok(());
+    for line in describe(&session, client.get_token_info(), &creds.scope()) {
+        println!("{}", line);
+    }
+
+    Ok(())
+}
+
+/// Lines that `show` prints for the session and its token info. Token values are never shown
+fn describe(session: &Session, info: Result<TokenInfo>, requested_scope: &str) -> Vec<String> {
+    let mut lines = vec![
+        format!("Account: {}", session.email.as_deref().unwrap_or("unknown")),
+        "Access token: saved (hidden)".to_string(),
+        format!(
+            "Refresh token: {}",
+            if session.refresh_token.is_some() {
+                "saved (hidden)"
+            } else {
+                "missing, the app has to be authorized again when the access token expires"
+            }
+        ),
+    ];
+
+    match info {
+        Ok(info) => {
+            lines.push(format!(
+                "Scopes: {}",
+                info.scope.unwrap_or_default().replace(' ', "\n  ")
+            ));
+            lines.push(match info.expires_in.and_then(|s| s.parse::<i64>().ok()) {
+                Some(secs) => {
+                    let at = chrono::Local::now() + chrono::Duration::seconds(secs);
+                    format!("Expires: in {} min ({})", secs / 60, at.format("%F %T"))
+                }
+                None => "Expires: unknown".to_string(),
+            });
+        }
+        Err(e) => {
+            // Scopes that were requested are still useful when the token can't be checked
+            lines.push(format!(
+                "Scopes (requested): {}",
+                requested_scope.replace(' ', "\n  ")
+            ));
+            lines.push(match e.downcast_ref::<DriveError>() {
+                Some(DriveError::Unauthorized) => {
+                    "Expires: access token is expired or revoked, it's refreshed on the next sync"
+                        .to_string()
+                }
+                _ => format!(
+                    "Expires: unknown, token info is unavailable.\nDetails: {}",
+                    e
+                ),
+            });
         }
+    }
 
-        bail!("Failed to save user credentials.");
+    lines
+}
+
+/// Refreshes the access token of the saved session once and saves the new session
+pub fn refresh() -> Result<()> {
+    let config_dir = user::get_home()?.join(".config/ocean-drive");
+    let client = refresh_session(&config_dir, |creds| {
+        Client::new(
+            creds.client_id,
+            creds.client_secret,
+            "http://localhost:8080".to_string(),
+        )
+    })?;
+    println!("Access token is refreshed and saved");
+
+    // New expiry is not in the token response, it's asked separately
+    match client
+        .get_token_info()
+        .map(|info| info.expires_in.and_then(|s| s.parse::<i64>().ok()))
+    {
+        Ok(Some(secs)) => {
+            let at = chrono::Local::now() + chrono::Duration::seconds(secs);
+            println!("Expires: in {} min ({})", secs / 60, at.format("%F %T"));
+        }
+        Ok(None) => println!("Expires: unknown"),
+        Err(e) => println!(
+            "Expires: unknown, token info is unavailable.\nDetails: {}",
+            e
+        ),
     }
 
-    bail!("Failed to get authroization code from the Google API");
+    Ok(())
 }
 
-fn get_auth_code(user_consent_url: String) -> Result<String> {
-    let auto_open =
-        binary_prompt("Do you want to automatically open authorization url in your browser?");
+/// Refreshes the session saved in `config_dir` with the client that `new_client` makes for the
+/// saved creds. Returns the client with the new session
+fn refresh_session(
+    config_dir: &Path,
+    new_client: impl FnOnce(Creds) -> Result<Client>,
+) -> Result<Client> {
+    let session_file = config_dir.join("session.toml");
+    let session = files::read_toml::<Session>(session_file.clone())
+        .context("No saved session.\nTip: Run `ocean-drive setup auth` to authorize the app")?;
+    if session.refresh_token.is_none() {
+        bail!("Saved session has no refresh token.\nTip: Run `ocean-drive setup auth` to authorize the app again");
+    }
+    let creds = files::read_toml::<Creds>(config_dir.join("creds.toml"))?;
+
+    let mut client = new_client(creds)?;
+    client.set_session(session);
+
+    let session = client.refresh_token().context(
+        "Unable to refresh the access token.\nTip: Run `ocean-drive setup auth` to authorize the app again",
+    )?;
+    files::write_toml(session, session_file)?;
+
+    Ok(client)
+}
+
+fn get_auth_code(user_consent_url: String, timeout: Duration) -> Result<String> {
+    // Bound before the URL is opened, so the browser can't come back before it's listened to
+    let listener = redirect_listener::Listener::bind()?;
+    let auto_open = confirm(
+        "Do you want to automatically open authorization url in your browser?",
+        true,
+    );
     let mut successfully_opened = false;
 
     if auto_open {
@@ -68,7 +218,20 @@ fn get_auth_code(user_consent_url: String) -> Result<String> {
         );
     }
 
-    let url = redirect_listener::get_callback()?;
+    let url = loop {
+        if let Some(url) = listener.get_callback(timeout)? {
+            break url;
+        }
+
+        println!("\nNo response from the browser for {}s.", timeout.as_secs());
+        if !confirm("Keep waiting for the authorization?", true) {
+            bail!("Authorization was not completed in the browser");
+        }
+        println!(
+            "\nPlease, authorize application via this link:\n  {}\n",
+            user_consent_url
+        );
+    };
     let query = parse_url::get_query(url)?;
     let code = query.get("code");
 
@@ -79,11 +242,105 @@ fn get_auth_code(user_consent_url: String) -> Result<String> {
     bail!("There was no authorization code in Google API Callback");
 }
 
-fn get_client_creds() -> (String, String) {
-    let empty = String::from("");
+fn get_client_creds() -> Result<(String, String)> {
+    let client_id = prompt_valid("Google OAuth client id", None, |ans| match ans {
+        "" => Err("Client id can't be empty".to_string()),
+        id => Ok(id.to_string()),
+    })?;
+    let client_secret = loop {
+        match prompt_secret("Google OAuth client secret (it's not shown)")? {
+            Some(secret) => break secret,
+            None => println!("Client secret can't be empty"),
+        }
+    };
+
+    Ok((client_id, client_secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::google_drive::transport::Fake;
+
+    fn session() -> Session {
+        Session {
+            access_token: "secret-access".to_string(),
+            refresh_token: Some("secret-refresh".to_string()),
+            email: Some("me@example.com".to_string()),
+        }
+    }
+
+    #[test]
+    fn token_info_is_shown_without_the_tokens() {
+        let info = br#"{
+            "azp": "client.apps.googleusercontent.com",
+            "scope": "https://www.googleapis.com/auth/drive openid",
+            "expires_in": "3599",
+            "email": "me@example.com"
+        }"#;
+        let client = Client::with_transport(Fake(Box::new(move |req| {
+            assert_eq!(
+                req.url().as_str(),
+                "https://oauth2.googleapis.com/tokeninfo"
+            );
+            Ok((200, vec![], info.to_vec()))
+        })));
+
+        let lines = describe(&session(), client.get_token_info(), "requested");
+        assert_eq!(lines[0], "Account: me@example.com");
+        assert_eq!(lines[1], "Access token: saved (hidden)");
+        assert_eq!(lines[2], "Refresh token: saved (hidden)");
+        assert_eq!(
+            lines[3],
+            "Scopes: https://www.googleapis.com/auth/drive\n  openid"
+        );
+        assert!(lines[4].starts_with("Expires: in 59 min ("));
+        assert!(lines.iter().all(|l| !l.contains("secret")));
+
+        // Expired token is rejected, the requested scopes are shown instead
+        let client = Client::with_transport(Fake(Box::new(|_| {
+            Ok((400, vec![], br#"{"error": "invalid_token"}"#.to_vec()))
+        })));
+        let lines = describe(&session(), client.get_token_info(), "drive openid");
+        assert_eq!(lines[3], "Scopes (requested): drive\n  openid");
+        assert!(lines[4].contains("expired or revoked"));
+    }
 
-    let client_id = prompt("Google OAuth client id").unwrap_or(empty.clone());
-    let client_secret = prompt("Google OAuth client secret").unwrap_or(empty.clone());
+    #[test]
+    fn refreshed_session_is_saved() {
+        let dir = std::env::temp_dir().join(format!("ocean-drive-auth-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        files::write_toml(session(), dir.join("session.toml")).unwrap();
+        let creds = Creds {
+            client_id: "client".to_string(),
+            client_secret: "secret-client".to_string(),
+            scopes: None,
+        };
+        files::write_toml(creds, dir.join("creds.toml")).unwrap();
 
-    return (client_id, client_secret);
+        refresh_session(&dir, |creds| {
+            assert_eq!(creds.client_id, "client");
+            Ok(Client::with_transport(Fake(Box::new(|req| {
+                assert_eq!(req.url().as_str(), "https://oauth2.googleapis.com/token");
+                let body = String::from_utf8(req.body().unwrap().as_bytes().unwrap().to_vec());
+                assert!(body.unwrap().contains("refresh_token=secret-refresh"));
+                let token = br#"{"access_token": "new-access", "expires_in": 3599}"#;
+                Ok((200, vec![], token.to_vec()))
+            }))))
+        })
+        .unwrap();
+
+        let saved = files::read_toml::<Session>(dir.join("session.toml")).unwrap();
+        assert_eq!(saved.access_token, "new-access");
+        assert_eq!(saved.refresh_token.as_deref(), Some("secret-refresh"));
+        assert_eq!(saved.email.as_deref(), Some("me@example.com"));
+
+        // Without a refresh token nothing is requested
+        let mut expired = session();
+        expired.refresh_token = None;
+        files::write_toml(expired, dir.join("session.toml")).unwrap();
+        let res = refresh_session(&dir, |_| panic!("client is not needed"));
+        assert!(format!("{:#}", res.err().unwrap()).contains("no refresh token"));
+    }
 }
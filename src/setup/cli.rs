@@ -1,14 +1,34 @@
-use clap::{App, SubCommand};
+use clap::{App, Arg, SubCommand};
 
 pub fn root_subcommand() -> App<'static, 'static> {
     SubCommand::with_name("setup")
         .about("Setups everything needed for app to work.")
         .usage("ocean-drive setup [SUBCOMMAND (if needed)]")
         .after_help("You can use this subcommand without subcommands to setup everthing")
+        .arg(auth_timeout_arg())
         .subcommand(auth_subcommand())
 }
 
 fn auth_subcommand() -> App<'static, 'static> {
     SubCommand::with_name("auth")
         .about("Only will update access token for the client. Usefull when is unable to automatically recover new access token (Usually app uses refresh token)")
+        .arg(auth_timeout_arg())
+        .arg(
+            Arg::with_name("show")
+                .long("show")
+                .help("Show the account, scopes and expiry of the saved session instead of authorizing again. Tokens are never printed"),
+        )
+        .arg(
+            Arg::with_name("refresh")
+                .long("refresh")
+                .conflicts_with("show")
+                .help("Get a new access token with the saved refresh token and save it, without authorizing again"),
+        )
+}
+
+fn auth_timeout_arg() -> Arg<'static, 'static> {
+    Arg::with_name("auth-timeout")
+        .long("auth-timeout")
+        .value_name("SECS")
+        .help("How long to wait for the authorization in the browser before asking whether to keep waiting (300 by default)")
 }
@@ -1,11 +1,18 @@
 /* Setup program to be ready to start */
 
-use crate::{files, google_drive::Config as DriveConfig, readline, user};
-use anyhow::Result;
+use crate::{
+    files,
+    google_drive::{Config as DriveConfig, Session},
+    readline,
+    sync::{ConflictPolicy, DownloadOrder, NameNormalization, ShortcutMode},
+    user,
+};
+use anyhow::{Context, Result};
 use clap::ArgMatches;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 mod auth;
 mod cli;
 
@@ -18,33 +25,231 @@ pub use self::cli::*;
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Config {
+    /// Can be left out when `mappings` are set
+    #[serde(default)]
     pub local_dir: String,
     pub drive: DriveConfig,
+    /// How deep remote directories are synced (0 means only files in the root directory)
+    pub max_depth: Option<usize>,
+    /// How many files are downloaded at once during the first sync
+    pub initial_sync_concurrency: Option<usize>,
+    /// Which files are downloaded first: "newest", "oldest", "smallest", "largest" or "name"
+    /// (by the path). Files are downloaded in the order of the directories by default
+    pub download_order: Option<DownloadOrder>,
+    /// Patterns (gitignore syntax) for files that are excluded from sync. Merged with rules from
+    /// `.oceanignore` files
+    pub ignore: Option<Vec<String>>,
+    /// Which file to keep when it was changed both locally and on the drive: "both" (default),
+    /// "local" or "remote"
+    pub conflicts: Option<ConflictPolicy>,
+    /// Name of the local copy when both files are kept, "[{timestamp}] {name}" by default. Tokens:
+    /// {name}, {stem}, {ext}, {timestamp}, {hostname} and {side}. It must contain {timestamp} and
+    /// {name} or {stem}
+    pub conflict_copy_pattern: Option<String>,
+    /// Ask what to do on every conflict when running in terminal
+    pub interactive_conflicts: Option<bool>,
+    /// Custom icon for the tray (the bundled one is used by default)
+    pub tray_icon_path: Option<String>,
+    /// Sync only starred files and the directories that contain them
+    pub only_starred: Option<bool>,
+    /// Save `.url` shortcuts for the files that can't be downloaded (e.g. Google Forms)
+    pub unsupported_shortcuts: Option<bool>,
+    /// What is done with the shortcuts on the drive: "skip" (default), "link" or "follow"
+    pub shortcuts: Option<ShortcutMode>,
+    /// Files are uploaded only when they weren't modified for this number of seconds, so
+    /// files that are still being written are not uploaded half-written
+    pub upload_stability_secs: Option<u64>,
+    /// Sync with the drive is aborted and retried later if it takes longer than this (30 minutes
+    /// by default)
+    pub sync_timeout_secs: Option<u64>,
+    /// Encrypt file contents before uploading, so the drive stores only ciphertext. File and
+    /// folder names are not encrypted
+    pub encrypt: Option<bool>,
+    /// File with the encryption passphrase (OCEAN_DRIVE_PASSPHRASE variable is used otherwise)
+    pub passphrase_file: Option<String>,
+    /// Unicode form of the local file names: "nfc", "nfd" or "none" (default, names are kept as
+    /// they are on the drive)
+    pub name_normalization: Option<NameNormalization>,
+    /// Download files that Google Drive flagged as malware or spam instead of skipping them
+    pub acknowledge_abuse: Option<bool>,
+    /// When a file on the drive got bigger, download only the new end of it and append it to the
+    /// local file (e.g. for logs). Whole file is downloaded if the result doesn't match the drive
+    pub append_downloads: Option<bool>,
+    /// Shell command or webhook URL that is run after a sync that changed local files
+    pub on_sync_complete: Option<String>,
+    /// Shell command or webhook URL that is run when a sync fails
+    pub on_error: Option<String>,
+    /// Sync files and directories whose names start with a dot (true by default). When it's false,
+    /// they are excluded in both directions, even if `.oceanignore` rules include them
+    pub sync_hidden: Option<bool>,
+    /// Sync that would remove more local files than this is stopped until it's confirmed (no
+    /// limit by default). Directory counts with everything in it. With `deletion_grace_secs`
+    /// deletions are counted when their grace period is over
+    pub max_deletes_per_cycle: Option<usize>,
+    /// Same as `max_deletes_per_cycle`, but in percents of all synced files and directories
+    pub max_deletes_percent: Option<u8>,
+    /// Local copies of the files trashed on the drive are removed only after this many seconds
+    /// (e.g. 86400 for a day) if they are still in the trash. Restoring a file on the drive
+    /// within this time cancels the deletion
+    pub deletion_grace_secs: Option<u64>,
+    /// Find local changes by scanning the directory instead of watching it. It's used anyway when
+    /// the directory can't be watched (e.g. the inotify watch limit is reached)
+    pub local_scan: Option<bool>,
+    /// Shortest time between the scans, used right after a change (5 seconds by default)
+    pub scan_interval_min_secs: Option<u64>,
+    /// Longest time between the scans, reached when nothing changes for a while (5 minutes by
+    /// default)
+    pub scan_interval_max_secs: Option<u64>,
+    /// How many times local file operations are tried when they fail with an error that may be
+    /// temporary, e.g. file locked by an antivirus (3 by default, 1 disables retries)
+    pub fs_attempts: Option<u32>,
+    /// Download only the files whose MIME type starts with one of these (e.g. "image/").
+    /// Directories are still synced to reach them, other files are not tracked
+    pub include_mime_prefixes: Option<Vec<String>>,
+    /// Same as `include_mime_prefixes`, but by the file extension (e.g. "jpg"). File is included
+    /// if it matches either of them
+    pub include_extensions: Option<Vec<String>>,
+    /// Several local directories synced with subfolders of the remote directory by `run`, instead
+    /// of `local_dir` alone. Other commands work with `local_dir` only
+    pub mappings: Option<Vec<Mapping>>,
+    /// Pause syncing while the laptop runs on battery with less charge than this (percents)
+    pub pause_on_battery_below: Option<u8>,
+    /// Pause syncing while the 1-minute load average per CPU is above this (e.g. 1.5)
+    pub pause_on_load_above: Option<f32>,
+    /// When a new file has the same content as a synced file in the same directory, copy that
+    /// file on the drive instead of uploading the content again. It saves the upload, but Drive
+    /// still stores both files
+    pub dedup_uploads: Option<bool>,
+    /// Download all the files right into `local_dir`, without the folders of the drive. Files
+    /// with the same name get a number (e.g. "a (2).txt"). New local files are uploaded to
+    /// `drive.dir`
+    pub flatten: Option<bool>,
+    /// On the first sync into a non-empty `local_dir`, mark the files that are the same as on the
+    /// drive as synced (as `init` does) instead of asking for confirmation
+    pub adopt_local_files: Option<bool>,
+    /// Largest config or state file (e.g. versions) that is read, in megabytes (256 by default).
+    /// Bigger files are rejected as broken instead of being read into memory
+    pub max_state_file_mb: Option<u64>,
+    /// Cron expression of the times to sync at, in local time (e.g. "0 2 * * *" for every night
+    /// at 2am). Local changes are queued until the next of them. Syncs every 10 seconds by default
+    pub schedule: Option<String>,
+    /// After every upload, check that the md5 of the file on the drive matches the uploaded
+    /// content, and upload it again if it doesn't. Costs an extra request per upload
+    pub verify_uploads: Option<bool>,
+    /// Local files and directories that can't be read (e.g. owned by root) are skipped with a
+    /// warning instead of failing the upload of their directory (true by default)
+    pub skip_unreadable: Option<bool>,
+    /// List only the files on the drive that were modified since the last fully successful sync.
+    /// Faster on big drives, but renames, moves and description changes don't change the
+    /// modification time, so such files are synced only when their content is changed
+    pub remote_only_changes: Option<bool>,
 }
 
-pub fn auth() -> Result<()> {
-    auth::run()
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Mapping {
+    pub local: String,
+    /// Path of the subfolder relative to `drive.dir` (e.g. "Photos/2021"), it's created when
+    /// missing. Empty path means `drive.dir` itself
+    pub remote_subpath: Option<String>,
+}
+
+/// Written after the whole setup succeeded, so scripts can check it without parsing the output
+#[derive(Serialize)]
+struct SetupComplete {
+    completed_at: String,
+    config_path: String,
+    account_email: String,
+}
+
+pub fn auth(m: &ArgMatches) -> Result<()> {
+    auth::run(auth_timeout(m)?).map(|_| ())
+}
+
+fn auth_timeout(m: &ArgMatches) -> Result<Duration> {
+    let secs = match m.value_of("auth-timeout") {
+        Some(secs) => secs
+            .parse::<u64>()
+            .with_context(|| format!("Invalid value for --auth-timeout: {:?}", secs))?,
+        None => return Ok(auth::DEFAULT_AUTH_TIMEOUT),
+    };
+
+    Ok(Duration::from_secs(secs))
 }
 
 pub fn run(m: &ArgMatches) -> Result<()> {
     // Run only authorization subcommand if provided
-    if let Some(sub) = m.subcommand_name() {
-        if sub == "auth" {
-            return auth();
+    if let Some(sub) = m.subcommand_matches("auth") {
+        if sub.is_present("show") {
+            return auth::show();
         }
+        if sub.is_present("refresh") {
+            return auth::refresh();
+        }
+        return auth(sub);
     }
 
+    let conf_dir = user::get_home()?.join(".config/ocean-drive");
+    let timeout = auth_timeout(m)?;
+
     println!("Ocean Drive Setup");
     println!("Starting Authorization process\n");
 
-    auth()?;
+    let email = complete(
+        conf_dir.join("setup_complete.json"),
+        conf_dir.join("config.toml"),
+        || auth::run(timeout),
+        || {
+            println!("\nAuthoziation complete. Making sure configuration directory exists\n");
+            create_configuration_dir()?;
+
+            println!("Now answer some questions to configure the app. \n");
+            // Todo: some validation for user fields
+            gather_configurations()
+        },
+    )?;
+
+    println!(
+        "\nSetup complete. Ocean Drive will sync the drive of {}",
+        email
+    );
+
+    Ok(())
+}
+
+/// Authorizes and configures the app, the completion marker is written only when both succeed.
+/// Returns the email of the authorized account
+fn complete(
+    marker_file: PathBuf,
+    config_path: PathBuf,
+    authorize: impl FnOnce() -> Result<Session>,
+    configure: impl FnOnce() -> Result<()>,
+) -> Result<String> {
+    // Marker from the previous setup must not tell that this one succeeded
+    if marker_file.exists() {
+        fs::remove_file(&marker_file)?;
+    }
 
-    println!("\nAuthoziation complete. Making sure configuration directory exists\n");
-    create_configuration_dir()?;
+    let session = authorize()?;
+    configure()?;
 
-    println!("Now answer some questions to configure the app. \n");
-    // Todo: some validation for user fields
-    gather_configurations()?;
+    // Email is fetched with the new session, so it also makes sure that the session really works
+    let email = session
+        .email
+        .context("Unable to get the account info from the drive")?;
+    write_completion_marker(marker_file, config_path, email.clone())?;
+
+    Ok(email)
+}
+
+fn write_completion_marker(path: PathBuf, config_path: PathBuf, email: String) -> Result<()> {
+    let marker = SetupComplete {
+        completed_at: chrono::Local::now().to_rfc3339(),
+        config_path: config_path.display().to_string(),
+        account_email: email,
+    };
+
+    fs::write(&path, serde_json::to_string_pretty(&marker)?)
+        .with_context(|| format!("Unable to write file {:?}", path.display()))?;
 
     Ok(())
 }
@@ -66,12 +271,26 @@ fn gather_configurations() -> Result<()> {
     let default_local_dir = &home.join("ocean");
 
     let local_dir_prompt = "Which directory will be used as local root for your drive?";
-    let local_dir = readline::promt_default(local_dir_prompt, default_local_dir.to_str().unwrap());
+    let local_dir = readline::prompt_valid(
+        local_dir_prompt,
+        Some(&default_local_dir.to_string_lossy()),
+        |ans| match Path::new(ans) {
+            p if p.is_file() => Err(format!("{:?} is a file, enter a directory", ans)),
+            _ => Ok(ans.to_string()),
+        },
+    )?;
 
-    let remote_dir = readline::promt_default(
+    let remote_dir = readline::prompt_valid(
         "Enter a name for directory in your drive that will be synced with local directory (Only in the root of yyour drive)",
-        "ocean",
-    );
+        Some("ocean"),
+        |ans| match ans {
+            "" => Err("Name of the directory can't be empty".to_string()),
+            name if name.contains('/') => {
+                Err("Directory must be in the root of the drive, enter a name without '/'".to_string())
+            }
+            name => Ok(name.to_string()),
+        },
+    )?;
     println!(
         "\nSaving configuration:\nDirectory '{}' will be up to date with '{}'",
         local_dir, remote_dir
@@ -79,10 +298,116 @@ fn gather_configurations() -> Result<()> {
 
     let config = Config {
         local_dir,
-        drive: DriveConfig { dir: remote_dir },
+        drive: DriveConfig {
+            dir: remote_dir,
+            fields: None,
+            shared_drive_id: None,
+            app_data: None,
+            page_size: None,
+            max_api_calls_per_day: None,
+            max_bytes_per_day: None,
+            bandwidth_windows: None,
+            namespace_by_hostname: None,
+            hostname: None,
+            ca_bundle: None,
+            danger_accept_invalid_certs: None,
+            user_agent: None,
+            api_key: None,
+            quota_project: None,
+        },
+        max_depth: None,
+        initial_sync_concurrency: None,
+        download_order: None,
+        ignore: None,
+        conflicts: None,
+        interactive_conflicts: None,
+        conflict_copy_pattern: None,
+        tray_icon_path: None,
+        only_starred: None,
+        unsupported_shortcuts: None,
+        shortcuts: None,
+        upload_stability_secs: None,
+        sync_timeout_secs: None,
+        encrypt: None,
+        passphrase_file: None,
+        name_normalization: None,
+        acknowledge_abuse: None,
+        append_downloads: None,
+        on_sync_complete: None,
+        on_error: None,
+        sync_hidden: None,
+        max_deletes_per_cycle: None,
+        max_deletes_percent: None,
+        deletion_grace_secs: None,
+        local_scan: None,
+        scan_interval_min_secs: None,
+        scan_interval_max_secs: None,
+        fs_attempts: None,
+        include_mime_prefixes: None,
+        include_extensions: None,
+        mappings: None,
+        pause_on_battery_below: None,
+        pause_on_load_above: None,
+        dedup_uploads: None,
+        flatten: None,
+        adopt_local_files: None,
+        max_state_file_mb: None,
+        schedule: None,
+        verify_uploads: None,
+        skip_unreadable: None,
+        remote_only_changes: None,
     };
 
     files::write_toml::<Config>(config, home.join(".config/ocean-drive/config.toml"))?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    fn session() -> Session {
+        Session {
+            access_token: "token".to_string(),
+            refresh_token: None,
+            email: Some("me@example.com".to_string()),
+        }
+    }
+
+    #[test]
+    fn marker_is_written_only_when_auth_and_config_succeed() {
+        let dir = std::env::temp_dir().join(format!("ocean-drive-setup-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let marker = dir.join("setup_complete.json");
+        let config = dir.join("config.toml");
+
+        let res = complete(
+            marker.clone(),
+            config.clone(),
+            || Err(anyhow!("denied")),
+            || Ok(()),
+        );
+        assert!(res.is_err());
+        assert!(!marker.exists());
+
+        // Marker of the previous setup is removed, even if this one fails
+        fs::write(&marker, "{}").unwrap();
+        let res = complete(
+            marker.clone(),
+            config.clone(),
+            || Ok(session()),
+            || Err(anyhow!("cancelled")),
+        );
+        assert!(res.is_err());
+        assert!(!marker.exists());
+
+        let email = complete(marker.clone(), config.clone(), || Ok(session()), || Ok(())).unwrap();
+        assert_eq!(email, "me@example.com");
+        let written: serde_json::Value = files::read_json(marker).unwrap();
+        assert_eq!(written["account_email"], "me@example.com");
+        assert_eq!(written["config_path"], config.display().to_string());
+    }
+}
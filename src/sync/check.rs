@@ -0,0 +1,139 @@
+/*
+    Offline check of the config for `run --config-check`, e.g. in a deployment pipeline. Config
+    and credentials are parsed and validated the same way the daemons do it, but the drive is not
+    contacted and nothing is synced. All the problems are reported at once.
+*/
+use crate::{
+    auth::Creds,
+    files,
+    google_drive::{bandwidth::Bandwidth, MAX_PAGE_SIZE},
+    setup::Config as AppConfig,
+    sync::{conflicts, crypto::Cipher, ignores, mappings, read_config, schedule::Schedule},
+};
+use anyhow::{bail, Result};
+use std::path::Path;
+
+pub fn run(conf_dir: &Path, inline: Option<&str>) -> Result<()> {
+    let mut problems = vec![];
+
+    match files::read_toml::<Creds>(conf_dir.join("creds.toml")) {
+        Ok(creds) => {
+            if creds.client_id.trim().is_empty() || creds.client_secret.trim().is_empty() {
+                problems
+                    .push("`client_id` and `client_secret` in creds.toml can't be empty".into());
+            }
+        }
+        Err(e) => problems.push(format!("{:#}", e)),
+    }
+    match read_config(conf_dir, inline) {
+        Ok(config) => problems.extend(check(&config)),
+        Err(e) => problems.push(format!("{:#}", e)),
+    }
+
+    if problems.is_empty() {
+        info!("Config is valid");
+        return Ok(());
+    }
+
+    for problem in &problems {
+        error!("{}", problem);
+    }
+    bail!("Config has {} problem(s)", problems.len());
+}
+
+/// Problems of the parsed config
+fn check(config: &AppConfig) -> Vec<String> {
+    let mut problems = vec![];
+    // Validations that the daemons do on start
+    let mut checks = vec![Cipher::load(config).map(|_| ())];
+
+    if config.local_dir.trim().is_empty() {
+        problems.push("`local_dir` can't be empty".to_string());
+    } else {
+        let root = Path::new(&config.local_dir);
+        let patterns = config.ignore.as_deref().unwrap_or(&[]);
+        checks.push(ignores::check_local_root(root));
+        checks.push(ignores::Ignores::load(root, patterns, true).map(|_| ()));
+    }
+    if let Some(pattern) = &config.conflict_copy_pattern {
+        checks.push(conflicts::validate_pattern(pattern));
+    }
+    if let Some(m) = &config.mappings {
+        checks.push(mappings::validate(m));
+    }
+    if let Some(expr) = &config.schedule {
+        checks.push(Schedule::parse(expr).map(|_| ()));
+    }
+    if let Some(windows) = &config.drive.bandwidth_windows {
+        checks.push(Bandwidth::parse(windows).map(|_| ()));
+    }
+    problems.extend(
+        checks
+            .into_iter()
+            .filter_map(|c| c.err().map(|e| format!("{:#}", e))),
+    );
+
+    let drive = &config.drive;
+    if drive.app_data.unwrap_or(false) && drive.shared_drive_id.is_some() {
+        problems.push("Only one of `app_data` and `shared_drive_id` can be set".to_string());
+    }
+    if let Some(path) = &drive.ca_bundle {
+        if !Path::new(path).is_file() {
+            problems.push(format!("`ca_bundle` file {:?} doesn't exist", path));
+        }
+    }
+    if matches!(drive.page_size, Some(s) if s == 0 || s > MAX_PAGE_SIZE) {
+        problems.push(format!("`page_size` must be from 1 to {}", MAX_PAGE_SIZE));
+    }
+    if config.initial_sync_concurrency == Some(0) {
+        problems.push("`initial_sync_concurrency` must be at least 1".to_string());
+    }
+    if config.sync_timeout_secs == Some(0) {
+        problems.push("`sync_timeout_secs` must be at least 1".to_string());
+    }
+    if config.max_state_file_mb == Some(0) {
+        problems.push("`max_state_file_mb` must be at least 1".to_string());
+    }
+    if matches!(config.max_deletes_percent, Some(p) if p > 100) {
+        problems.push("`max_deletes_percent` must be from 0 to 100".to_string());
+    }
+    if matches!(config.pause_on_battery_below, Some(p) if p > 100) {
+        problems.push("`pause_on_battery_below` must be from 0 to 100".to_string());
+    }
+    if matches!(config.pause_on_load_above, Some(l) if l.is_nan() || l <= 0.0) {
+        problems.push("`pause_on_load_above` must be above 0".to_string());
+    }
+    if matches!(
+        (config.scan_interval_min_secs, config.scan_interval_max_secs),
+        (Some(min), Some(max)) if min > max
+    ) {
+        problems.push(
+            "`scan_interval_min_secs` can't be longer than `scan_interval_max_secs`".to_string(),
+        );
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing;
+
+    #[test]
+    fn bad_glob_and_interval_are_reported() {
+        let dir = testing::temp_dir("check-problems");
+        let config = testing::config(&dir, "ignore = [\"*.log\"]");
+        assert!(check(&config).is_empty());
+
+        let extra = "ignore = [\"logs/[\"]\nsync_timeout_secs = 0\n\
+                     scan_interval_min_secs = 60\nscan_interval_max_secs = 10";
+        let problems = check(&testing::config(&dir, extra));
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems.iter().any(|p| p.contains("logs/[")));
+        assert!(problems.iter().any(|p| p.contains("`sync_timeout_secs`")));
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("`scan_interval_min_secs`")));
+    }
+}
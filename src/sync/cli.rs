@@ -0,0 +1,255 @@
+use clap::{App, AppSettings, Arg, SubCommand};
+
+pub fn run_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("run")
+        .about("[DEFAULT] Start synchronization.")
+        .arg(
+            Arg::with_name("max-depth")
+                .long("max-depth")
+                .takes_value(true)
+                .value_name("DEPTH")
+                .help("Limits how deep remote directories are synced. 0 means only files in the root directory"),
+        )
+        .arg(
+            Arg::with_name("fields")
+                .long("fields")
+                .takes_value(true)
+                .value_name("FIELDS")
+                .help("Custom selector for the file fields requested from Google Drive API. Fields id, name, mimeType, version, md5Checksum, trashed, parents, appProperties, modifiedTime, description and shortcutDetails are always requested"),
+        )
+        .arg(
+            Arg::with_name("no-tray")
+                .long("no-tray")
+                .help("Don't show the tray icon. It's also disabled when there's no display"),
+        )
+        .arg(
+            Arg::with_name("interactive")
+                .long("interactive")
+                .help("Ask what to do when a file was changed both locally and on the drive"),
+        )
+        .arg(
+            Arg::with_name("once")
+                .long("once")
+                .help("Download changes from the drive once and exit"),
+        )
+        .arg(
+            Arg::with_name("confirm-deletes")
+                .long("confirm-deletes")
+                .help("Allow syncs of this run to delete any number of local files, ignoring the configured deletion limits"),
+        )
+        .arg(
+            Arg::with_name("since")
+                .long("since")
+                .takes_value(true)
+                .value_name("TIME")
+                .requires("once")
+                .help("Only download files modified after this time: a duration (30m, 12h, 7d) or a date (2021-10-25)"),
+        )
+        .arg(
+            Arg::with_name("config-inline")
+                .long("config-inline")
+                .takes_value(true)
+                .value_name("CONFIG")
+                .help("Whole config as a JSON or TOML string, used instead of config.toml. Can be also set with OCEAN_DRIVE_CONFIG environment variable"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .takes_value(true)
+                .value_name("DIR")
+                .conflicts_with("replay")
+                .help("Save responses of Google Drive API to the directory to reproduce an issue later. Tokens are not saved"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Answer requests with the responses saved with --record instead of using Google Drive API"),
+        )
+        .arg(
+            Arg::with_name("all-profiles")
+                .long("all-profiles")
+                .conflicts_with_all(&["once", "config-inline", "record", "replay"])
+                .help("Sync every profile from ~/.config/ocean-drive/profiles in this process. Profiles share one tray and the connections to Google Drive"),
+        )
+        .arg(
+            Arg::with_name("config-check")
+                .long("config-check")
+                .conflicts_with("all-profiles")
+                .help("Only validate the config and the credentials, without contacting the drive, then exit. Exits with an error if there are problems"),
+        )
+        .arg(
+            Arg::with_name("adopt")
+                .long("adopt")
+                .conflicts_with("all-profiles")
+                .help("On the first sync into a non-empty local directory, mark the files that are the same as on the drive as synced instead of asking for confirmation"),
+        )
+        .arg(
+            Arg::with_name("remote-only-changes")
+                .long("remote-only-changes")
+                .conflicts_with_all(&["all-profiles", "since"])
+                .help("Only list the files on the drive that were modified since the last successful sync, which is faster on big drives. Files that were only renamed or moved on the drive are not synced until their content is changed. Same as `remote_only_changes = true` in the config"),
+        )
+}
+
+pub fn init_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("init")
+        .about("Marks files that are already in the local directory and the same as on the drive as synced. Usefull when switching from another client.")
+}
+
+pub fn link_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("link")
+        .about("Prints a link to the Google Drive page of the synced file.")
+        .arg(
+            Arg::with_name("path")
+                .required(true)
+                .value_name("LOCAL_PATH")
+                .help("Path to the file in the local directory"),
+        )
+        .arg(
+            Arg::with_name("open")
+                .long("open")
+                .help("Open the link in the browser instead of printing it"),
+        )
+}
+
+pub fn pull_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("pull")
+        .about("Downloads a file or a directory from the drive again, even if it wasn't changed.")
+        .arg(
+            Arg::with_name("path")
+                .required(true)
+                .value_name("REMOTE_PATH")
+                .help("Path to the file relative to the synced drive directory. Use / to pull everything"),
+        )
+}
+
+pub fn prune_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("prune")
+        .about(
+            "Deletes local files that were removed from the drive, but are still synced locally.",
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Only list the files that would be deleted"),
+        )
+        .arg(
+            Arg::with_name("yes")
+                .long("yes")
+                .short("y")
+                .conflicts_with("dry-run")
+                .help("Delete the files without asking for confirmation"),
+        )
+}
+
+pub fn relocate_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("relocate")
+        .about("Moves the local directory to another place, keeping it synced.")
+        .arg(
+            Arg::with_name("path")
+                .required(true)
+                .value_name("NEW_PATH")
+                .help("New path of the local directory, it must not exist yet"),
+        )
+}
+
+pub fn diff_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("diff")
+        .about(
+            "Lists the differences between the local directory and the drive without syncing them.",
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print the differences as JSON"),
+        )
+}
+
+pub fn usage_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("usage")
+        .about("Shows how much space the synced files take locally and on the drive, by the top-level folders.")
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print the usage as JSON"),
+        )
+}
+
+pub fn revisions_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("revisions")
+        .about("Lists the past revisions of a file that Google Drive keeps.")
+        .arg(
+            Arg::with_name("path")
+                .required(true)
+                .value_name("REMOTE_PATH")
+                .help("Path to the file relative to the synced drive directory"),
+        )
+}
+
+pub fn restore_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("restore")
+        .about("Downloads a past revision of a file as a copy next to it (see `revisions`).")
+        .arg(
+            Arg::with_name("path")
+                .required(true)
+                .value_name("REMOTE_PATH")
+                .help("Path to the file relative to the synced drive directory"),
+        )
+        .arg(
+            Arg::with_name("revision")
+                .long("revision")
+                .required(true)
+                .takes_value(true)
+                .value_name("ID")
+                .help("Id of the revision, as printed by `revisions`"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Where to save the revision instead of the copy next to the file"),
+        )
+}
+
+#[cfg(feature = "monitor")]
+pub fn monitor_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("monitor")
+        .about("Shows the state of the daemon in the terminal and keeps it updated.")
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .takes_value(true)
+                .value_name("SECS")
+                .help("How often the screen is updated (2 seconds by default)"),
+        )
+}
+
+pub fn migrate_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("migrate")
+        .about("Moves the config files to a newer layout.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("profiles")
+                .about("Moves the config, session and sync state into a profile directory, so it can be synced with `run --all-profiles`.")
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .takes_value(true)
+                        .value_name("PROFILE")
+                        .help("Name of the profile (default by default)"),
+                ),
+        )
+}
+
+pub fn profiles_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("profiles")
+        .about("Manages the profiles that are synced with `run --all-profiles`.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Lists the profiles with their directories and the time of the last sync."),
+        )
+}
@@ -0,0 +1,153 @@
+/*
+    Decides what to do when a file was changed both locally and on the drive.
+    In interactive mode user is asked about every conflict (only if stdin is a terminal), otherwise
+    the configured policy is applied.
+*/
+use crate::{readline, user};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::Cell,
+    io::{self, IsTerminal},
+    path::Path,
+};
+
+/// Name of the local copy that is made when both files are kept. Tokens: {name}, {stem} (name
+/// without the extension), {ext} (extension with the dot), {timestamp}, {hostname} and {side}
+pub const DEFAULT_COPY_PATTERN: &str = "[{timestamp}] {name}";
+const TIMESTAMP_FORMAT: &str = "%d.%m.%y %H:%M:%S";
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Local file is renamed to a timestamped copy and both files are synced
+    Both,
+    Local,
+    Remote,
+}
+
+pub struct Conflicts {
+    policy: ConflictPolicy,
+    interactive: bool,
+    /// Choice that user asked to apply to all the conflicts in this session
+    remembered: Cell<Option<ConflictPolicy>>,
+    copy_pattern: String,
+}
+
+impl Conflicts {
+    pub fn new(
+        policy: Option<ConflictPolicy>,
+        interactive: bool,
+        copy_pattern: Option<&str>,
+    ) -> Result<Self> {
+        let copy_pattern = copy_pattern.unwrap_or(DEFAULT_COPY_PATTERN);
+        validate_pattern(copy_pattern)?;
+
+        Ok(Self {
+            policy: policy.unwrap_or(ConflictPolicy::Both),
+            interactive: interactive && io::stdin().is_terminal(),
+            remembered: Cell::new(None),
+            copy_pattern: copy_pattern.to_string(),
+        })
+    }
+
+    /// Name for the local copy of the file `name`. Copies after the first one (`n` > 1) get the
+    /// number too
+    pub fn copy_name(&self, name: &str, n: u32) -> String {
+        let (stem, ext) = split_extension(name);
+        let hostname = if self.copy_pattern.contains("{hostname}") {
+            user::get_hostname().unwrap_or_else(|_| "unknown".to_string())
+        } else {
+            String::new()
+        };
+
+        let copy = self
+            .copy_pattern
+            .replace("{name}", name)
+            .replace("{stem}", stem)
+            .replace("{ext}", &ext)
+            .replace(
+                "{timestamp}",
+                &chrono::Local::now().format(TIMESTAMP_FORMAT).to_string(),
+            )
+            .replace("{hostname}", &hostname)
+            // Copies are made only of the local files
+            .replace("{side}", "local");
+        if n < 2 {
+            return copy;
+        }
+
+        let (stem, ext) = split_extension(&copy);
+        format!("{} ({}){}", stem, n, ext)
+    }
+
+    pub fn resolve(&self, f: &Path) -> ConflictPolicy {
+        if let Some(choice) = self.remembered.get() {
+            return choice;
+        }
+
+        if !self.interactive {
+            return self.policy;
+        }
+
+        println!(
+            "\nConflict: {:?} was changed both locally and on the drive.",
+            f.display()
+        );
+
+        loop {
+            let ans = readline::prompt(
+                "Keep [l]ocal, [r]emote or [b]oth? Add '!' to apply to all conflicts (e.g. 'b!')",
+            );
+            // Just hitting Enter means the configured policy
+            let ans = match ans {
+                Some(ans) => ans.to_lowercase(),
+                None => return self.policy,
+            };
+
+            let choice = match ans.trim_end_matches('!') {
+                "l" => ConflictPolicy::Local,
+                "r" => ConflictPolicy::Remote,
+                "b" => ConflictPolicy::Both,
+                _ => continue,
+            };
+
+            if ans.ends_with('!') {
+                self.remembered.set(Some(choice));
+            }
+
+            return choice;
+        }
+    }
+}
+
+/// Splits the name into the stem and the extension with the dot ("" if there's none)
+pub fn split_extension(name: &str) -> (&str, String) {
+    let path = Path::new(name);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| format!(".{}", e))
+        .unwrap_or_default();
+
+    (stem, ext)
+}
+
+/// Copy must differ from the original and from the other copies of it, so the pattern needs the
+/// name and the time
+pub fn validate_pattern(pattern: &str) -> Result<()> {
+    if pattern.contains('/') {
+        bail!("Conflict copy pattern {:?} can't contain '/'", pattern);
+    }
+    if !pattern.contains("{timestamp}")
+        || !(pattern.contains("{name}") || pattern.contains("{stem}"))
+    {
+        bail!(
+            "Conflict copy pattern {:?} must contain {{timestamp}} and either {{name}} or {{stem}}, otherwise copies could overwrite each other",
+            pattern
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,205 @@
+/*
+    Client-side encryption of the file contents, so the drive never gets plaintext of synced files.
+    Encrypted content is MAGIC | salt | nonce | AES-256-GCM ciphertext. Key is derived from the
+    passphrase and the salt, so files that were encrypted by other runs or devices are readable.
+    Drive files with encrypted content have the md5 of the plain one in their properties.
+*/
+use crate::setup::Config;
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::Read,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+const MAGIC: &[u8; 8] = b"OCEANEN1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + SALT_LEN + NONCE_LEN;
+/// Key derivation is slow on purpose, so the passphrase can't be brute forced
+const KDF_ROUNDS: u32 = 200_000;
+
+/// Passphrase can be passed with this variable instead of a file
+pub const PASSPHRASE_ENV: &str = "OCEAN_DRIVE_PASSPHRASE";
+
+pub struct Cipher {
+    passphrase: String,
+    /// New content is encrypted only when it's enabled, otherwise encrypted files are just read
+    encrypt: bool,
+    /// Salt for the content encrypted by this run
+    salt: [u8; SALT_LEN],
+    keys: Mutex<HashMap<[u8; SALT_LEN], Key<Aes256Gcm>>>,
+}
+
+impl Cipher {
+    /// Returns None if no passphrase is configured, so encrypted files can't be read
+    pub fn load(config: &Config) -> Result<Option<Arc<Self>>> {
+        let encrypt = config.encrypt.unwrap_or(false);
+        let passphrase = match &config.passphrase_file {
+            Some(path) => Some(
+                fs::read_to_string(path)
+                    .with_context(|| format!("Unable to read passphrase file {:?}", path))?,
+            ),
+            None => env::var(PASSPHRASE_ENV).ok(),
+        };
+
+        let passphrase = match passphrase.map(|p| p.trim().to_string()) {
+            Some(p) if !p.is_empty() => p,
+            _ if encrypt => bail!(
+                "Encryption is enabled, but no passphrase is set.\nTip: Set `passphrase_file` in the config or {} environment variable",
+                PASSPHRASE_ENV
+            ),
+            _ => return Ok(None),
+        };
+
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+
+        Ok(Some(Arc::new(Self {
+            passphrase,
+            encrypt,
+            salt,
+            keys: Mutex::new(HashMap::new()),
+        })))
+    }
+
+    pub fn is_encrypting(&self) -> bool {
+        self.encrypt
+    }
+
+    /// Encrypts the content if encryption is enabled
+    pub fn seal(&self, plain: Vec<u8>) -> Result<Vec<u8>> {
+        if !self.encrypt {
+            return Ok(plain);
+        }
+
+        let cipher = Aes256Gcm::new(&self.key(&self.salt));
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let encrypted = cipher
+            .encrypt(&nonce, plain.as_ref())
+            .map_err(|_| anyhow!("Unable to encrypt file content"))?;
+
+        let mut content = Vec::with_capacity(HEADER_LEN + encrypted.len());
+        content.extend_from_slice(MAGIC);
+        content.extend_from_slice(&self.salt);
+        content.extend_from_slice(&nonce);
+        content.extend_from_slice(&encrypted);
+
+        Ok(content)
+    }
+
+    fn decrypt(&self, content: &[u8]) -> Result<Vec<u8>> {
+        if content.len() < HEADER_LEN {
+            bail!("Encrypted content is too short");
+        }
+
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&content[MAGIC.len()..MAGIC.len() + SALT_LEN]);
+        let nonce = Nonce::from_slice(&content[MAGIC.len() + SALT_LEN..HEADER_LEN]);
+
+        Aes256Gcm::new(&self.key(&salt))
+            .decrypt(nonce, &content[HEADER_LEN..])
+            .map_err(|_| {
+                anyhow!("Unable to decrypt file content. Perhaps, the passphrase is wrong")
+            })
+    }
+
+    fn key(&self, salt: &[u8; SALT_LEN]) -> Key<Aes256Gcm> {
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+
+        *keys.entry(*salt).or_insert_with(|| {
+            let mut key = [0u8; 32];
+            pbkdf2::pbkdf2_hmac::<Sha256>(self.passphrase.as_bytes(), salt, KDF_ROUNDS, &mut key);
+            key.into()
+        })
+    }
+}
+
+pub fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(MAGIC)
+}
+
+pub fn is_encrypted_file(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; MAGIC.len()];
+    let read = fs::File::open(path)?.read(&mut magic)?;
+
+    Ok(read == MAGIC.len() && is_encrypted(&magic))
+}
+
+/// Decrypts the content if it's encrypted, plain content is returned as is. See `unseal`
+pub fn open(cipher: Option<&Cipher>, content: Vec<u8>, marked: bool) -> Result<Vec<u8>> {
+    Ok(unseal(cipher, &content, marked)?.unwrap_or(content))
+}
+
+/// Plain content of the encrypted one, None if the content is plain. `marked` tells that the file
+/// on the drive has the properties of the encrypted one. A plain file can start with the magic
+/// too, so the content of an unmarked file is plain when it can't be decrypted
+pub fn unseal(cipher: Option<&Cipher>, content: &[u8], marked: bool) -> Result<Option<Vec<u8>>> {
+    if !is_encrypted(content) {
+        return Ok(None);
+    }
+
+    match cipher {
+        Some(c) if marked => c.decrypt(content).map(Some),
+        Some(c) => Ok(c.decrypt(content).ok()),
+        None if marked => bail!(
+            "File is encrypted, but no passphrase is set.\nTip: Set `passphrase_file` in the config or {} environment variable",
+            PASSPHRASE_ENV
+        ),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher(encrypt: bool) -> Cipher {
+        Cipher {
+            passphrase: "passphrase".to_string(),
+            encrypt,
+            salt: [7; SALT_LEN],
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn sealed_content_is_opened() {
+        let c = cipher(true);
+        let sealed = c.seal(b"plain content".to_vec()).unwrap();
+
+        assert!(is_encrypted(&sealed));
+        assert_ne!(&sealed[HEADER_LEN..], b"plain content");
+        assert_eq!(
+            open(Some(&c), sealed.clone(), true).unwrap(),
+            b"plain content"
+        );
+        // Another run derives the same key from the salt in the content
+        let reader = cipher(false);
+        assert_eq!(
+            open(Some(&reader), sealed.clone(), false).unwrap(),
+            b"plain content"
+        );
+        assert!(open(None, sealed, true).is_err());
+    }
+
+    #[test]
+    fn plain_content_with_magic_is_kept() {
+        let plain = b"OCEANEN1 is how this text starts, it's long enough for a header".to_vec();
+
+        assert_eq!(open(None, plain.clone(), false).unwrap(), plain);
+        assert_eq!(
+            open(Some(&cipher(true)), plain.clone(), false).unwrap(),
+            plain
+        );
+        assert!(open(Some(&cipher(true)), plain, true).is_err());
+        assert_eq!(cipher(false).seal(b"plain".to_vec()).unwrap(), b"plain");
+    }
+}
@@ -0,0 +1,93 @@
+/*
+    Deletions of the files trashed on the drive that wait for the grace period, so a file trashed
+    by mistake can be restored before its local copy is gone. They are saved to a file, so the
+    wait survives restarts.
+*/
+use crate::{files, sync::versions::Version};
+use anyhow::{bail, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::TryFrom, fs, path::PathBuf, time::Duration};
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Deferred {
+    version: Version,
+    /// When the file was found in the trash (seconds since epoch)
+    trashed_at: i64,
+    /// Number of the synced files and directories that are deleted with it
+    entries: Option<usize>,
+}
+
+pub struct DeferredDeletions {
+    path: PathBuf,
+    /// Deletions by the file id
+    items: HashMap<String, Deferred>,
+}
+
+impl DeferredDeletions {
+    /// Loads the deletions from the file. Missing or malformed file means there are none
+    pub fn load(path: PathBuf) -> Self {
+        let items = files::read_json(path.clone()).unwrap_or_default();
+
+        Self { path, items }
+    }
+
+    /// Starts the grace period of the file, unless it was started already. `entries` is the
+    /// number of the synced files and directories that the deletion removes
+    pub fn defer(&mut self, id: &str, version: Version, entries: usize) -> Result<()> {
+        if self.items.contains_key(id) {
+            return Ok(());
+        }
+
+        self.items.insert(
+            id.to_string(),
+            Deferred {
+                version,
+                trashed_at: Utc::now().timestamp(),
+                entries: Some(entries),
+            },
+        );
+        self.save()
+    }
+
+    /// Forgets the deletion of the file, returns whether there was one
+    pub fn cancel(&mut self, id: &str) -> Result<bool> {
+        if self.items.remove(id).is_none() {
+            return Ok(false);
+        }
+
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Version of the local file that waits for the deletion
+    pub fn version(&self, id: &str) -> Option<&Version> {
+        self.items.get(id).map(|d| &d.version)
+    }
+
+    /// Deletions whose grace period is over, with the number of entries they remove
+    pub fn due(&self, grace: Duration) -> Vec<(String, Version, usize)> {
+        let now = Utc::now().timestamp();
+        let grace = i64::try_from(grace.as_secs()).unwrap_or(i64::MAX);
+
+        self.items
+            .iter()
+            .filter(|(_, d)| now.saturating_sub(d.trashed_at) >= grace)
+            .map(|(id, d)| (id.clone(), d.version.clone(), d.entries.unwrap_or(1)))
+            .collect()
+    }
+
+    fn save(&self) -> Result<()> {
+        let content = serde_json::to_string(&self.items)?;
+
+        if let Err(e) = fs::write(&self.path, content) {
+            bail!(
+                "Failed to save deferred deletions to file {:?}.\nDetails: {}",
+                self.path.display(),
+                e
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,378 @@
+/*
+    Compares the local directory with the drive without changing anything. Versions saved by the
+    last sync are the third side of the comparison: they tell whether a file is new or was changed
+    (or removed) locally or on the drive.
+*/
+use crate::{
+    files,
+    google_drive::Client,
+    sync::{
+        conf_dir, connect,
+        ignores::Ignores,
+        read_config, util,
+        versions::{Version, Versions, VersionsList},
+        NameNormalization,
+    },
+};
+use anyhow::Result;
+use clap::ArgMatches;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Serialize, Default)]
+struct Diff {
+    only_remote: Vec<Entry>,
+    only_local: Vec<Entry>,
+    differing: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    /// Path relative to the local root
+    path: String,
+    /// What happened to the file since the last sync
+    state: &'static str,
+}
+
+struct RemoteItem {
+    id: String,
+    is_folder: bool,
+    md5: Option<String>,
+    version: Option<String>,
+}
+
+pub fn run(m: &ArgMatches) -> Result<()> {
+    let conf_dir = conf_dir()?;
+    let config = read_config(&conf_dir, None)?;
+    let root = PathBuf::from(&config.local_dir);
+
+    let (client, remote_dir_id) = connect(&conf_dir, &config)?;
+    let client = util::lock_ref_when_free(&client);
+    let v_list = Versions::new(conf_dir.join("versions.json"), &root)?.list()?;
+    let ignores = Ignores::load(
+        &root,
+        config.ignore.as_deref().unwrap_or(&[]),
+        config.sync_hidden.unwrap_or(true),
+    )?;
+    let normalization = config.name_normalization.unwrap_or(NameNormalization::None);
+
+    let mut remote = BTreeMap::new();
+    list_remote(
+        &client,
+        &remote_dir_id,
+        &root,
+        normalization,
+        &ignores,
+        &mut remote,
+    )?;
+    let mut local = BTreeMap::new();
+    list_local(&root, &ignores, &mut local)?;
+
+    let diff = compare(&root, &remote, &local, &v_list)?;
+
+    if m.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+        return Ok(());
+    }
+
+    print_entries("Only on the drive", &diff.only_remote);
+    print_entries("Only in the local directory", &diff.only_local);
+    print_entries("Different", &diff.differing);
+    if diff.only_remote.is_empty() && diff.only_local.is_empty() && diff.differing.is_empty() {
+        info!("Local directory is the same as the drive.");
+    }
+
+    Ok(())
+}
+
+fn list_remote(
+    client: &Client,
+    id: &str,
+    dir: &Path,
+    normalization: NameNormalization,
+    ignores: &Ignores,
+    items: &mut BTreeMap<PathBuf, RemoteItem>,
+) -> Result<()> {
+    let list = client.list_files(
+        Some(&format!("'{}' in parents and trashed = false", id)),
+        None,
+    )?;
+
+    for file in list.files {
+        let (file_id, name) = match (file.id.clone(), &file.name) {
+            (Some(id), Some(name)) => (id, normalization.apply(name)),
+            _ => continue,
+        };
+        let is_folder = file.mime_type.as_deref() == Some("application/vnd.google-apps.folder");
+        let path = dir.join(&name);
+        if ignores.is_ignored(&path, is_folder) {
+            continue;
+        }
+
+        if is_folder {
+            list_remote(client, &file_id, &path, normalization, ignores, items)?;
+        }
+        items.insert(
+            path,
+            RemoteItem {
+                id: file_id,
+                is_folder,
+                md5: file.content_md5(),
+                version: file.version.clone(),
+            },
+        );
+    }
+
+    Ok(())
+}
+
+/// Lists local files with whether they are directories
+fn list_local(dir: &Path, ignores: &Ignores, items: &mut BTreeMap<PathBuf, bool>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_dir = path.is_dir();
+        if ignores.is_ignored(&path, is_dir) {
+            continue;
+        }
+
+        if is_dir {
+            list_local(&path, ignores, items)?;
+        }
+        items.insert(path, is_dir);
+    }
+
+    Ok(())
+}
+
+fn compare(
+    root: &Path,
+    remote: &BTreeMap<PathBuf, RemoteItem>,
+    local: &BTreeMap<PathBuf, bool>,
+    v_list: &VersionsList,
+) -> Result<Diff> {
+    let by_path: HashMap<&str, &Version> = v_list.values().map(|v| (v.path.as_str(), v)).collect();
+    let mut diff = Diff::default();
+
+    // Content of a directory that exists on one side only is not listed, the directory is enough
+    let mut reported: Vec<&Path> = vec![];
+    for (path, item) in remote {
+        if local.contains_key(path) || reported.iter().any(|p| path.starts_with(p)) {
+            continue;
+        }
+
+        let state = if v_list.contains_key(&item.id) {
+            "removed locally"
+        } else {
+            "new on the drive"
+        };
+        diff.only_remote.push(entry(root, path, state));
+        reported.push(path);
+    }
+
+    for path in local.keys() {
+        if remote.contains_key(path) || reported.iter().any(|p| path.starts_with(p)) {
+            continue;
+        }
+
+        let state = if by_path.contains_key(path.display().to_string().as_str()) {
+            "removed from the drive"
+        } else {
+            "new locally"
+        };
+        diff.only_local.push(entry(root, path, state));
+        reported.push(path);
+    }
+
+    for (path, item) in remote {
+        let is_dir = match local.get(path) {
+            Some(is_dir) => *is_dir,
+            None => continue,
+        };
+        let synced = v_list.get(&item.id);
+
+        if is_dir != item.is_folder {
+            diff.differing.push(entry(
+                root,
+                path,
+                "file on one side, directory on the other",
+            ));
+            continue;
+        }
+        if item.is_folder {
+            continue;
+        }
+
+        let remote_changed = matches!(synced, Some(v) if Some(&v.version) != item.version.as_ref());
+        // Google Docs have no content to compare, only their versions
+        let remote_md5 = match &item.md5 {
+            Some(md5) => md5,
+            None => {
+                if remote_changed {
+                    diff.differing
+                        .push(entry(root, path, "changed on the drive"));
+                }
+                continue;
+            }
+        };
+
+        let local_md5 = local_md5(path, synced)?;
+        if &local_md5 == remote_md5 {
+            continue;
+        }
+
+        let state = match synced {
+            None => "not synced yet",
+            Some(v) => {
+                let local_changed = v.md5.as_ref() != Some(&local_md5);
+                let remote_changed = remote_changed || v.md5.as_ref() != Some(remote_md5);
+                match (local_changed, remote_changed) {
+                    (true, true) => "changed on both sides",
+                    (true, false) => "changed locally",
+                    (false, true) => "changed on the drive",
+                    (false, false) => "differs from the last sync",
+                }
+            }
+        };
+        diff.differing.push(entry(root, path, state));
+    }
+
+    Ok(diff)
+}
+
+/// Md5 of the local file. Files that weren't modified since the last sync are not read
+fn local_md5(path: &Path, synced: Option<&Version>) -> Result<String> {
+    if let Some(v) = synced {
+        let stat = util::file_stat(path);
+        if let (Some(md5), Some((size, mtime))) = (&v.md5, stat) {
+            if v.size == Some(size) && v.mtime == Some(mtime) {
+                return Ok(md5.clone());
+            }
+        }
+    }
+
+    let content = files::read_bytes(path.to_path_buf())?;
+    Ok(format!("{:x}", md5::compute(&content)))
+}
+
+fn entry(root: &Path, path: &Path, state: &'static str) -> Entry {
+    Entry {
+        path: path
+            .strip_prefix(root)
+            .unwrap_or(path)
+            .display()
+            .to_string(),
+        state,
+    }
+}
+
+fn print_entries(title: &str, entries: &[Entry]) {
+    if entries.is_empty() {
+        return;
+    }
+
+    println!("{}:", title);
+    for e in entries {
+        println!("  {} ({})", e.path, e.state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing::{self, FakeDrive, ROOT_ID};
+
+    #[test]
+    fn every_kind_of_difference_is_reported() {
+        let dir = testing::temp_dir("diff");
+        let root = dir.join("local");
+        fs::create_dir(&root).unwrap();
+        let drive = FakeDrive::new();
+        let synced = [
+            "same.txt",
+            "removed_local.txt",
+            "removed_drive.txt",
+            "changed_local.txt",
+            "changed_drive.txt",
+            "both.txt",
+            "flip",
+        ];
+        for name in &synced {
+            drive.add_file(name, ROOT_ID, name, b"synced");
+        }
+        assert!(
+            testing::remote_daemon(&drive, testing::config(&root, ""), &dir)
+                .sync()
+                .unwrap()
+        );
+
+        drive.add_file("new_remote.txt", ROOT_ID, "new_remote.txt", b"new");
+        drive.add_file("unsynced.txt", ROOT_ID, "unsynced.txt", b"remote");
+        drive.remove("removed_drive.txt");
+        drive.change("changed_drive.txt", |f| f.content = b"remote".to_vec());
+        drive.change("both.txt", |f| f.content = b"remote".to_vec());
+        fs::remove_file(root.join("removed_local.txt")).unwrap();
+        fs::write(root.join("new_local.txt"), b"new").unwrap();
+        fs::write(root.join("unsynced.txt"), b"local").unwrap();
+        fs::write(root.join("changed_local.txt"), b"local").unwrap();
+        fs::write(root.join("both.txt"), b"local").unwrap();
+        fs::remove_file(root.join("flip")).unwrap();
+        fs::create_dir(root.join("flip")).unwrap();
+
+        let client = drive.client();
+        let client = client.lock().unwrap();
+        let v_list = testing::versions(&dir, &root)
+            .lock()
+            .unwrap()
+            .list()
+            .unwrap();
+        let ignores = Ignores::load(&root, &[], true).unwrap();
+        let mut remote = BTreeMap::new();
+        list_remote(
+            &client,
+            ROOT_ID,
+            &root,
+            NameNormalization::None,
+            &ignores,
+            &mut remote,
+        )
+        .unwrap();
+        let mut local = BTreeMap::new();
+        list_local(&root, &ignores, &mut local).unwrap();
+        let diff = compare(&root, &remote, &local, &v_list).unwrap();
+
+        let entries = |entries: &[Entry]| -> Vec<(String, &str)> {
+            entries.iter().map(|e| (e.path.clone(), e.state)).collect()
+        };
+        let expected = |entries: &[(&str, &'static str)]| -> Vec<(String, &str)> {
+            entries.iter().map(|(p, s)| (p.to_string(), *s)).collect()
+        };
+        assert_eq!(
+            entries(&diff.only_remote),
+            expected(&[
+                ("new_remote.txt", "new on the drive"),
+                ("removed_local.txt", "removed locally"),
+            ])
+        );
+        assert_eq!(
+            entries(&diff.only_local),
+            expected(&[
+                ("new_local.txt", "new locally"),
+                ("removed_drive.txt", "removed from the drive"),
+            ])
+        );
+        assert_eq!(
+            entries(&diff.differing),
+            expected(&[
+                ("both.txt", "changed on both sides"),
+                ("changed_drive.txt", "changed on the drive"),
+                ("changed_local.txt", "changed locally"),
+                ("flip", "file on one side, directory on the other"),
+                ("unsynced.txt", "not synced yet"),
+            ])
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::{io, path::PathBuf};
+use thiserror::Error;
+
+/// Errors of syncing that callers may need to tell apart, others are plain messages
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error("No file with name '{0}' found in your drive")]
+    RemoteRootNotFound(String),
+    #[error("Please, make sure that file '{0}' on your drive is really a directory")]
+    RemoteRootNotADir(String),
+    /// Session is missing or can't be refreshed, so the app must be authorized again
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("No space left on the device to save {0:?}")]
+    DiskFull(PathBuf),
+    /// File was changed both locally and on the drive, and both versions can't be kept
+    #[error("Unable to keep both versions of {0:?}, it was changed both locally and on the drive")]
+    Conflict(PathBuf, #[source] io::Error),
+    /// Sync cycle took longer than `sync_timeout_secs`, the rest is synced by the next one
+    #[error("Sync cycle timed out after {0}s. The rest will be synced on the next cycle")]
+    CycleTimedOut(u64),
+}
+
+impl SyncError {
+    pub fn is_timed_out(e: &anyhow::Error) -> bool {
+        e.chain()
+            .any(|cause| matches!(cause.downcast_ref(), Some(SyncError::CycleTimedOut(_))))
+    }
+
+    /// Checks if restarting the daemon won't help, since the user has to fix something first
+    pub fn is_fatal(e: &anyhow::Error) -> bool {
+        e.chain().any(|cause| {
+            matches!(
+                cause.downcast_ref(),
+                Some(SyncError::RemoteRootNotFound(_))
+                    | Some(SyncError::RemoteRootNotADir(_))
+                    | Some(SyncError::Unauthorized(_))
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::Creds,
+        files,
+        google_drive::Config as DriveConfig,
+        sync::{setup_client, testing, Recording},
+    };
+    use anyhow::{anyhow, Context};
+    use std::fs;
+
+    #[test]
+    fn timed_out_cycle_is_found_under_context() {
+        let e = Err::<(), _>(anyhow!(SyncError::CycleTimedOut(60)))
+            .context("Sync failed")
+            .unwrap_err();
+        assert!(SyncError::is_timed_out(&e));
+        assert!(!SyncError::is_fatal(&e));
+        assert!(!SyncError::is_timed_out(&anyhow!("Sync cycle timed out")));
+    }
+
+    #[test]
+    fn missing_session_is_matched_as_unauthorized() {
+        let dir = testing::temp_dir("errors-session");
+        let creds = Creds {
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            scopes: None,
+        };
+        files::write_toml(creds, dir.join("creds.toml")).unwrap();
+        let drive: DriveConfig = toml::from_str("dir = \"Docs\"").unwrap();
+
+        let e = setup_client(&dir, &drive, &Recording::Off).err().unwrap();
+        match e.downcast_ref::<SyncError>() {
+            Some(SyncError::Unauthorized(message)) => assert!(message.contains("ocean-drive auth")),
+            _ => panic!("Unexpected error: {:#}", e),
+        }
+        assert!(SyncError::is_fatal(&e));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+/*
+    Contents of the synced folders by the folder id, built from the versions for a sync pass. So
+    when a folder is renamed, moved or removed, the files in it are found without going through
+    all the versions.
+*/
+use crate::sync::{
+    util,
+    versions::{Version, VersionsList},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+pub struct FolderIndex {
+    /// Ids of the files and folders by the id of their parent. Files in a followed directory
+    /// shortcut are the children of the shortcut
+    children: HashMap<String, Vec<String>>,
+    /// Shortcut ids by the ids of their followed targets
+    shortcuts: HashMap<String, String>,
+    /// Folders with followed shortcuts inside, on any level
+    with_shortcuts: HashSet<String>,
+}
+
+impl FolderIndex {
+    pub fn build(versions: &VersionsList) -> Self {
+        // Files in a followed directory have its target as the parent
+        let shortcuts: HashMap<String, String> = versions
+            .iter()
+            .filter_map(|(id, v)| Some((v.shortcut_target.clone()?, id.clone())))
+            .collect();
+        let parent_of = |id: &str| -> Option<String> {
+            let parent = &versions.get(id)?.parent_id;
+            Some(shortcuts.get(parent).unwrap_or(parent).clone())
+        };
+
+        let mut children: HashMap<String, Vec<String>> = HashMap::new();
+        for id in versions.keys() {
+            if let Some(parent) = parent_of(id) {
+                children.entry(parent).or_default().push(id.clone());
+            }
+        }
+
+        let mut with_shortcuts = HashSet::new();
+        for id in shortcuts.values() {
+            let mut parent = parent_of(id);
+            while let Some(p) = parent {
+                // Rest of the way up is marked already
+                if !with_shortcuts.insert(p.clone()) {
+                    break;
+                }
+                parent = parent_of(&p);
+            }
+        }
+
+        Self {
+            children,
+            shortcuts,
+            with_shortcuts,
+        }
+    }
+
+    /// Changes of the shortcut targets don't change the folders of the shortcuts, so such
+    /// folders are synced even when they are the same as on the last sync
+    pub fn has_shortcuts(&self, id: &str) -> bool {
+        self.with_shortcuts.contains(id)
+    }
+
+    /// Ids of everything inside the folder, on all levels
+    pub fn descendants(&self, id: &str) -> Vec<String> {
+        let mut found = vec![];
+        let mut queue = vec![id];
+        while let Some(parent) = queue.pop() {
+            for child in self.children.get(parent).into_iter().flatten() {
+                found.push(child.clone());
+                queue.push(child);
+            }
+        }
+
+        found
+    }
+
+    /// Updates the paths of the folder contents after the folder was moved from `old` to `new`.
+    /// Files that are not under `old` anymore were moved in this pass already, they are kept
+    pub fn move_contents(&self, id: &str, old: &Path, new: &Path, versions: &mut VersionsList) {
+        for child in self.descendants(id) {
+            if let Some(v) = versions.get_mut(&child) {
+                if let Ok(rest) = Path::new(&v.path).strip_prefix(old) {
+                    v.path = util::path_string(&new.join(rest));
+                }
+            }
+        }
+    }
+
+    /// Forgets the versions of the folder contents that are still under `path`
+    pub fn remove_contents(&self, id: &str, path: &Path, versions: &mut VersionsList) {
+        for child in self.descendants(id) {
+            if matches!(versions.get(&child), Some(v) if Path::new(&v.path).starts_with(path)) {
+                versions.remove(&child);
+            }
+        }
+    }
+
+    /// Forgets the versions of the folder contents wherever their local files are (e.g. when
+    /// the files are flattened), and returns them. Files that were moved out of the folder in this
+    /// pass are kept
+    pub fn take_contents(&self, id: &str, versions: &mut VersionsList) -> Vec<(String, Version)> {
+        let mut taken = vec![];
+        let mut queue = vec![id];
+        while let Some(parent) = queue.pop() {
+            for child in self.children.get(parent).into_iter().flatten() {
+                let moved = match versions.get(child) {
+                    Some(v) => self.shortcuts.get(&v.parent_id).unwrap_or(&v.parent_id) != parent,
+                    None => continue,
+                };
+                if !moved {
+                    taken.extend(versions.remove_entry(child));
+                    queue.push(child);
+                }
+            }
+        }
+
+        taken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::versions::Version;
+
+    fn version(parent_id: &str, path: &str, is_folder: bool) -> Version {
+        Version {
+            is_folder,
+            parent_id: parent_id.to_string(),
+            version: "1".to_string(),
+            path: path.to_string(),
+            md5: None,
+            children_hash: None,
+            web_link: None,
+            size: None,
+            mtime: None,
+            unsupported: None,
+            description: None,
+            shortcut_target: None,
+            modified_time: None,
+        }
+    }
+
+    #[test]
+    fn paths_follow_a_renamed_parent() {
+        let mut versions: VersionsList = vec![
+            ("docs", version("root", "/sync/docs", true)),
+            ("work", version("docs", "/sync/docs/work", true)),
+            ("plan", version("work", "/sync/docs/work/plan.txt", false)),
+            ("moved", version("docs", "/sync/other/moved.txt", false)),
+            ("notes", version("root", "/sync/notes.txt", false)),
+        ]
+        .into_iter()
+        .map(|(id, v)| (id.to_string(), v))
+        .collect();
+
+        let index = FolderIndex::build(&versions);
+        let mut found = index.descendants("docs");
+        found.sort();
+        assert_eq!(found, vec!["moved", "plan", "work"]);
+
+        index.move_contents(
+            "docs",
+            Path::new("/sync/docs"),
+            Path::new("/sync/papers"),
+            &mut versions,
+        );
+        assert_eq!(versions["work"].path, "/sync/papers/work");
+        assert_eq!(versions["plan"].path, "/sync/papers/work/plan.txt");
+        // Already moved out of the folder in this pass
+        assert_eq!(versions["moved"].path, "/sync/other/moved.txt");
+        assert_eq!(versions["notes"].path, "/sync/notes.txt");
+
+        index.remove_contents("docs", Path::new("/sync/papers"), &mut versions);
+        let mut left: Vec<_> = versions.keys().cloned().collect();
+        left.sort();
+        assert_eq!(left, vec!["docs", "moved", "notes"]);
+    }
+}
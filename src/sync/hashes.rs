@@ -0,0 +1,93 @@
+/*
+    Md5 of the local files, shared by the daemons. Hash is kept with the size and modification time
+    of the file it was computed for, so the file is hashed again only after it's changed, no matter
+    how many checks need it (change detection, dedup, upload verification). Downloaded files are
+    remembered with the md5 that Drive has checked already.
+*/
+use crate::sync::util;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Size and modification time of the file, and its md5
+type Entry = (u64, i64, String);
+
+#[derive(Clone, Default)]
+pub struct Hashes(Arc<Mutex<HashMap<PathBuf, Entry>>>);
+
+impl Hashes {
+    /// Md5 of the file. It's read only if it was changed since it was last hashed
+    pub fn of(&self, f: &Path) -> io::Result<String> {
+        self.of_with(f, || {
+            let mut hasher = md5::Context::new();
+            io::copy(&mut fs::File::open(f)?, &mut hasher)?;
+            Ok(format!("{:x}", hasher.compute()))
+        })
+    }
+
+    /// Same as `of`, but the file is hashed by `hash`
+    fn of_with(&self, f: &Path, hash: impl FnOnce() -> io::Result<String>) -> io::Result<String> {
+        // Stat is taken before the file is read, so a change in the meantime is hashed next time
+        let (size, mtime) = match util::file_stat(f) {
+            Some(stat) => stat,
+            None => return hash(),
+        };
+        if let Some((s, m, md5)) = self.lock().get(f) {
+            if *s == size && *m == mtime {
+                return Ok(md5.clone());
+            }
+        }
+
+        // Lock is not held while hashing, big files take a while
+        let md5 = hash()?;
+        self.lock()
+            .insert(f.to_path_buf(), (size, mtime, md5.clone()));
+        Ok(md5)
+    }
+
+    /// Saves the known md5 of the file as it's now, e.g. right after it's downloaded
+    pub fn remember(&self, f: &Path, md5: &str) {
+        if let Some((size, mtime)) = util::file_stat(f) {
+            self.lock()
+                .insert(f.to_path_buf(), (size, mtime, md5.to_string()));
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<PathBuf, Entry>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, env};
+
+    #[test]
+    fn file_is_hashed_once_until_changed() {
+        let f = env::temp_dir().join(format!("ocean-drive-hashes-{}", std::process::id()));
+        fs::write(&f, "content").unwrap();
+        let hashes = Hashes::default();
+        let calls = Cell::new(0);
+        let hash = || {
+            calls.set(calls.get() + 1);
+            Ok(calls.get().to_string())
+        };
+
+        assert_eq!(hashes.of_with(&f, hash).unwrap(), "1");
+        assert_eq!(hashes.of_with(&f, hash).unwrap(), "1");
+        assert_eq!(hashes.clone().of_with(&f, hash).unwrap(), "1");
+        assert_eq!(calls.get(), 1);
+
+        // Size is different, so it's hashed again whatever the modification time is
+        fs::write(&f, "changed content").unwrap();
+        assert_eq!(hashes.of_with(&f, hash).unwrap(), "2");
+
+        hashes.remember(&f, "known");
+        assert_eq!(hashes.of(&f).unwrap(), "known");
+        fs::remove_file(&f).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+/*
+    User hooks that are run after a sync with changes and after a failed sync. Hook is either a
+    shell command, which gets the summary in OCEAN_DRIVE_* environment variables, or a webhook URL,
+    which gets the summary as JSON in a POST request. Failed hooks are only reported.
+*/
+use crate::setup::Config;
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::{
+    path::PathBuf,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Hooks that run longer are killed
+const HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Changes made to the local directory by a single sync
+#[derive(Serialize, Default, Debug)]
+pub struct SyncReport {
+    pub downloaded: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub failed: usize,
+}
+
+impl SyncReport {
+    pub fn is_empty(&self) -> bool {
+        self.downloaded.is_empty() && self.removed.is_empty() && self.failed == 0
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: &'a str,
+    #[serde(flatten)]
+    report: Option<&'a SyncReport>,
+    error: Option<String>,
+}
+
+/// Runs `on_sync_complete` hook, if the sync changed anything
+pub fn sync_complete(config: &Config, report: &SyncReport) {
+    if let Some(hook) = &config.on_sync_complete {
+        if !report.is_empty() {
+            run_hook(
+                hook,
+                Payload {
+                    event: "sync_complete",
+                    report: Some(report),
+                    error: None,
+                },
+            );
+        }
+    }
+}
+
+pub fn sync_failed(config: &Config, e: &anyhow::Error) {
+    if let Some(hook) = &config.on_error {
+        run_hook(
+            hook,
+            Payload {
+                event: "error",
+                report: None,
+                error: Some(e.to_string()),
+            },
+        );
+    }
+}
+
+fn run_hook(hook: &str, payload: Payload) {
+    let res = if hook.starts_with("http://") || hook.starts_with("https://") {
+        post_webhook(hook, &payload)
+    } else {
+        run_command(hook, &payload)
+    };
+
+    if let Err(e) = res {
+        warn!(
+            "Hook {:?} for {} failed.\nDetails: {}",
+            hook, payload.event, e
+        );
+    }
+}
+
+fn post_webhook(url: &str, payload: &Payload) -> Result<()> {
+    let resp = reqwest::blocking::Client::builder()
+        .timeout(HOOK_TIMEOUT)
+        .build()?
+        .post(url)
+        .json(payload)
+        .send()?;
+
+    if !resp.status().is_success() {
+        bail!("Webhook responded with status {}", resp.status());
+    }
+
+    Ok(())
+}
+
+fn run_command(command: &str, payload: &Payload) -> Result<()> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .env("OCEAN_DRIVE_EVENT", payload.event);
+
+    if let Some(report) = payload.report {
+        let changed: Vec<String> = report
+            .downloaded
+            .iter()
+            .chain(report.removed.iter())
+            .map(|p| p.display().to_string())
+            .collect();
+
+        cmd.env(
+            "OCEAN_DRIVE_DOWNLOADED",
+            report.downloaded.len().to_string(),
+        )
+        .env("OCEAN_DRIVE_REMOVED", report.removed.len().to_string())
+        .env("OCEAN_DRIVE_FAILED", report.failed.to_string())
+        .env("OCEAN_DRIVE_CHANGED_PATHS", changed.join("\n"));
+    }
+    if let Some(error) = &payload.error {
+        cmd.env("OCEAN_DRIVE_ERROR", error);
+    }
+
+    let mut child = cmd.spawn()?;
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                bail!("Command exited with {}", status);
+            }
+            return Ok(());
+        }
+
+        if started.elapsed() >= HOOK_TIMEOUT {
+            child.kill().ok();
+            child.wait().ok();
+            bail!("Command was killed after {}s", HOOK_TIMEOUT.as_secs());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sync::testing::{self, FakeDrive, ROOT_ID};
+    use std::fs;
+
+    #[test]
+    fn hook_gets_the_summary_after_a_sync() {
+        let dir = testing::temp_dir("hooks");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_file("a", ROOT_ID, "a.txt", b"a");
+        let env_file = dir.join("hook.env");
+        let hook = format!(
+            "on_sync_complete = {:?}",
+            format!("env | grep ^OCEAN_DRIVE_ | sort > '{}'", env_file.display())
+        );
+        let daemon = testing::remote_daemon(&drive, testing::config(&local, &hook), &dir);
+        assert!(daemon.sync().unwrap());
+
+        let env = fs::read_to_string(&env_file).unwrap();
+        let lines: Vec<&str> = env.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                format!(
+                    "OCEAN_DRIVE_CHANGED_PATHS={}",
+                    local.join("a.txt").display()
+                ),
+                "OCEAN_DRIVE_DOWNLOADED=1".to_string(),
+                "OCEAN_DRIVE_EVENT=sync_complete".to_string(),
+                "OCEAN_DRIVE_FAILED=0".to_string(),
+                "OCEAN_DRIVE_REMOVED=0".to_string(),
+            ]
+        );
+
+        // Nothing is changed, so the hook isn't run
+        fs::remove_file(&env_file).unwrap();
+        assert!(daemon.sync().unwrap());
+        assert!(!env_file.exists());
+    }
+}
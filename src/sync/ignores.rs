@@ -0,0 +1,223 @@
+/*
+    Decides which files are excluded from sync (in both directions).
+    Rules are taken from the `ignore` list in config and from `.oceanignore` files (gitignore syntax)
+    in the local root and its subdirectories. Rules from deeper directories take precedence.
+    When hidden files are not synced, every path with a `.`-prefixed component is excluded before
+    any rules are checked, so they can't be included back with `!` rules.
+    Directories with the state of the app are always excluded, whatever the rules are, since
+    syncing them would change them again and again.
+*/
+use crate::user;
+use anyhow::{bail, Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+pub const IGNORE_FILE: &str = ".oceanignore";
+
+pub struct Ignores {
+    root: PathBuf,
+    config: Gitignore,
+    sync_hidden: bool,
+    /// Config and cache directories of the app, see `app_dirs`
+    app_dirs: Vec<PathBuf>,
+    /// Rules from `.oceanignore` files, keyed by the directory. Files are read once per `Ignores`
+    dirs: RefCell<HashMap<PathBuf, Option<Gitignore>>>,
+}
+
+impl Ignores {
+    pub fn load(root: &Path, patterns: &[String], sync_hidden: bool) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new(root);
+
+        for p in patterns {
+            builder
+                .add_line(None, p)
+                .with_context(|| format!("Invalid ignore pattern in config: {:?}", p))?;
+        }
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            config: builder.build()?,
+            sync_hidden,
+            app_dirs: app_dirs(),
+            dirs: RefCell::new(HashMap::new()),
+        })
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        // Ignore files are local only
+        if path.file_name() == Some(OsStr::new(IGNORE_FILE)) {
+            return true;
+        }
+
+        if path == self.root || !path.starts_with(&self.root) {
+            return false;
+        }
+
+        if self.app_dirs.iter().any(|dir| path.starts_with(dir)) {
+            return true;
+        }
+
+        if !self.sync_hidden && is_hidden(path.strip_prefix(&self.root).unwrap()) {
+            return true;
+        }
+
+        // Go from the closest directory to the root, the first rule that matched wins
+        for dir in path.ancestors().skip(1) {
+            if let Some(ignored) = self.match_dir_rules(dir, path, is_dir) {
+                return ignored;
+            }
+            if dir == self.root {
+                break;
+            }
+        }
+
+        self.config
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+    }
+
+    /// Matches path against `.oceanignore` in the `dir`
+    /// Returns `None` when there's no such file or no rule matched the path
+    fn match_dir_rules(&self, dir: &Path, path: &Path, is_dir: bool) -> Option<bool> {
+        let mut dirs = self.dirs.borrow_mut();
+        let rules = dirs
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| read_ignore_file(dir));
+
+        let m = rules.as_ref()?.matched_path_or_any_parents(path, is_dir);
+
+        if m.is_none() {
+            return None;
+        }
+
+        Some(m.is_ignore())
+    }
+}
+
+/// Fails when the local root is inside a directory of the app, its state files would be synced
+/// then. Root that contains one is fine, the directory is just skipped
+pub fn check_local_root(root: &Path) -> Result<()> {
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+
+    for dir in app_dirs() {
+        if root.starts_with(&dir) {
+            bail!(
+                "Local directory {:?} is inside {:?}, where Ocean Drive keeps its state. Choose another local directory",
+                root,
+                dir
+            );
+        }
+        if dir.starts_with(&root) {
+            warn!(
+                "Local directory {:?} contains {:?}, where Ocean Drive keeps its state. It's never synced",
+                root, dir
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Directories where the app keeps versions, pending changes, credentials and downloads that are
+/// not finished yet. Both the plain and the resolved paths are returned, so symlinks match too
+fn app_dirs() -> Vec<PathBuf> {
+    let home = match user::get_home() {
+        Ok(home) => home,
+        Err(_) => return vec![],
+    };
+
+    let mut dirs = vec![];
+    for dir in &[".config/ocean-drive", ".cache/ocean-drive"] {
+        let dir = home.join(dir);
+        if let Ok(resolved) = dir.canonicalize() {
+            if resolved != dir {
+                dirs.push(resolved);
+            }
+        }
+        dirs.push(dir);
+    }
+
+    dirs
+}
+
+fn read_ignore_file(dir: &Path) -> Option<Gitignore> {
+    let file = dir.join(IGNORE_FILE);
+
+    if !file.is_file() {
+        return None;
+    }
+
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(e) = builder.add(&file) {
+        warn!("Some rules in {:?} are invalid: {}", file.display(), e);
+    }
+
+    match builder.build() {
+        Ok(rules) => Some(rules),
+        Err(e) => {
+            warn!("Unable to use {:?}: {}", file.display(), e);
+            None
+        }
+    }
+}
+
+/// Checks if any component of the path starts with a dot
+fn is_hidden(path: &Path) -> bool {
+    path.components()
+        .any(|c| c.as_os_str().to_string_lossy().starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing;
+    use std::fs;
+
+    #[test]
+    fn negations_and_directory_rules() {
+        let root = testing::temp_dir("ignores-rules");
+        fs::create_dir_all(root.join("build/keep")).unwrap();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join(IGNORE_FILE), "*.log\n!important.log\nbuild/\n").unwrap();
+        // Deeper rules win over the ones of the root
+        fs::write(root.join("docs").join(IGNORE_FILE), "!*.log\ndrafts/\n").unwrap();
+        let ignores = Ignores::load(&root, &["*.tmp".to_string()], true).unwrap();
+
+        assert!(ignores.is_ignored(&root.join("debug.log"), false));
+        assert!(!ignores.is_ignored(&root.join("important.log"), false));
+        assert!(ignores.is_ignored(&root.join("build"), true));
+        assert!(ignores.is_ignored(&root.join("build/keep/a.txt"), false));
+        assert!(!ignores.is_ignored(&root.join("docs/debug.log"), false));
+        assert!(ignores.is_ignored(&root.join("docs/drafts"), true));
+        // Directory rule doesn't match a file with the same name
+        assert!(!ignores.is_ignored(&root.join("docs/drafts"), false));
+        assert!(!ignores.is_ignored(&root.join("drafts"), true));
+        assert!(ignores.is_ignored(&root.join("docs/a.tmp"), false));
+        assert!(ignores.is_ignored(&root.join("docs").join(IGNORE_FILE), false));
+        assert!(!ignores.is_ignored(&root.join("docs/a.txt"), false));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn state_files_in_the_synced_tree_are_always_ignored() {
+        // Home is synced as a whole and every file is included back by the rules
+        let home = user::get_home().unwrap();
+        let ignores = Ignores::load(&home, &["!*".to_string()], true).unwrap();
+        for state in [
+            ".config/ocean-drive/versions.json",
+            ".config/ocean-drive/profiles/work/session.toml",
+            ".cache/ocean-drive/downloads/a.part",
+        ] {
+            assert!(ignores.is_ignored(&home.join(state), false), "{}", state);
+        }
+        assert!(!ignores.is_ignored(&home.join(".config/other/config.toml"), false));
+
+        assert!(check_local_root(&home).is_ok());
+        assert!(check_local_root(&home.join(".config/ocean-drive/sync")).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+/*
+    Paths that one of the daemons is working on right now. Remote daemon claims the files it
+    downloads and local daemon claims the files it uploads, so they never act on the same file at
+    the same time (e.g. upload a half-downloaded file).
+*/
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<Mutex<HashSet<PathBuf>>>);
+
+/// Path is released when the claim is dropped
+pub struct Claim {
+    in_flight: InFlight,
+    path: PathBuf,
+}
+
+impl InFlight {
+    /// Returns None if the path is already claimed by another daemon
+    pub fn claim(&self, path: &Path) -> Option<Claim> {
+        let mut paths = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if !paths.insert(path.to_path_buf()) {
+            return None;
+        }
+
+        Some(Claim {
+            in_flight: self.clone(),
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn is_busy(&self, path: &Path) -> bool {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(path)
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let mut paths = self.in_flight.0.lock().unwrap_or_else(|e| e.into_inner());
+        paths.remove(&self.path);
+    }
+}
@@ -0,0 +1,228 @@
+/*
+    Adopts an existing local directory (e.g. downloaded by another client), so files that are
+    already the same as on the drive are not downloaded again by `run`.
+*/
+use crate::{
+    files,
+    google_drive::Client,
+    sync::{
+        conf_dir, connect,
+        ignores::Ignores,
+        lock::RunLock,
+        read_config, util,
+        versions::{Version, Versions, VersionsList},
+    },
+};
+use anyhow::Result;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub fn run() -> Result<()> {
+    let conf_dir = conf_dir()?;
+    let config = read_config(&conf_dir, None)?;
+
+    let _lock = RunLock::acquire(conf_dir.join("run.lock"))?;
+    let (client, remote_dir_id) = connect(&conf_dir, &config)?;
+
+    let mut versions = Versions::new(conf_dir.join("versions.json"), Path::new(&config.local_dir))?;
+    let mut v_list = versions.list()?;
+    let mut mismatches = vec![];
+
+    let ignores = Ignores::load(
+        Path::new(&config.local_dir),
+        config.ignore.as_deref().unwrap_or(&[]),
+        config.sync_hidden.unwrap_or(true),
+    )?;
+
+    let client = util::lock_ref_when_free(&client);
+    let adopted = adopt_dir(
+        &remote_dir_id,
+        Path::new(&config.local_dir),
+        &client,
+        &mut v_list,
+        &ignores,
+        &mut mismatches,
+    )?;
+
+    versions.save(v_list)?;
+
+    info!("{} files and directories are marked as synced.", adopted);
+
+    if !mismatches.is_empty() {
+        println!("\nThese files are different from the drive and need your attention:");
+        for (path, reason) in mismatches {
+            println!("  {:?}: {}", path.display(), reason);
+        }
+    }
+
+    Ok(())
+}
+
+/// Matches local files to the remote ones by path and md5 and saves versions for the same ones
+/// Folders are saved without the drive version, so `run` still lists them and downloads the files
+/// that are missing locally
+/// Returns the number of adopted files
+pub fn adopt_dir(
+    id: &str,
+    dir: &Path,
+    client: &Client,
+    v_list: &mut VersionsList,
+    ignores: &Ignores,
+    mismatches: &mut Vec<(PathBuf, String)>,
+) -> Result<usize> {
+    let remote = client.list_files(Some(&format!("'{}' in parents", id)), None)?;
+    let mut adopted = 0;
+    let mut remote_names = HashSet::new();
+
+    for file in remote.files {
+        if file.trashed.unwrap_or(false) {
+            continue;
+        }
+
+        let (file_id, name) = match (&file.id, &file.name) {
+            (Some(id), Some(name)) => (id.clone(), name.clone()),
+            _ => continue,
+        };
+        let path = dir.join(&name);
+        let is_folder = file.mime_type.as_deref() == Some("application/vnd.google-apps.folder");
+        remote_names.insert(name);
+
+        // Missing files are downloaded by `run`, since their folders are listed again
+        if !path.exists() {
+            continue;
+        }
+
+        if is_folder != path.is_dir() {
+            let reason = if is_folder {
+                "it's a directory on the drive, but a file locally"
+            } else {
+                "it's a file on the drive, but a directory locally"
+            };
+            mismatches.push((path, reason.to_string()));
+            continue;
+        }
+
+        if !is_folder {
+            let remote_md5 = match file.content_md5() {
+                Some(md5) => md5,
+                None => {
+                    mismatches.push((path, "unable to compare with Google Docs file".to_string()));
+                    continue;
+                }
+            };
+
+            let content = files::read_bytes(path.clone())?;
+            if format!("{:x}", md5::compute(&content)) != remote_md5 {
+                mismatches.push((path, "content is different".to_string()));
+                continue;
+            }
+        }
+
+        let stat = util::file_stat(&path);
+        let v = Version {
+            is_folder,
+            parent_id: id.to_string(),
+            version: if is_folder {
+                String::new()
+            } else {
+                file.version.clone().unwrap_or(String::from("1"))
+            },
+            path: path.display().to_string(),
+            md5: file.content_md5(),
+            children_hash: None,
+            web_link: file.web_view_link.clone(),
+            size: if is_folder { None } else { stat.map(|s| s.0) },
+            mtime: if is_folder { None } else { stat.map(|s| s.1) },
+            unsupported: None,
+            description: file.description.clone(),
+            modified_time: file.modified_time.clone(),
+            shortcut_target: None,
+        };
+        v_list.insert(file_id.clone(), v);
+        adopted += 1;
+
+        if is_folder {
+            adopted += adopt_dir(&file_id, &path, client, v_list, ignores, mismatches)?;
+        }
+    }
+
+    // Files that exist only locally are uploaded only when changed, so it's better to know them
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string());
+
+        if let Some(name) = name {
+            if !remote_names.contains(&name) && !ignores.is_ignored(&path, path.is_dir()) {
+                mismatches.push((path, "exists only locally".to_string()));
+            }
+        }
+    }
+
+    Ok(adopted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing::{self, FakeDrive, ROOT_ID};
+
+    #[test]
+    fn pre_populated_tree_is_adopted() {
+        let dir = testing::temp_dir("init-adopt");
+        let local = dir.join("local");
+        fs::create_dir_all(local.join("dir")).unwrap();
+        fs::write(local.join("a.txt"), b"a").unwrap();
+        fs::write(local.join("dir/b.txt"), b"b").unwrap();
+        fs::write(local.join("changed.txt"), b"local").unwrap();
+        fs::write(local.join("new.txt"), b"new").unwrap();
+
+        let drive = FakeDrive::new();
+        drive.add_file("a", ROOT_ID, "a.txt", b"a");
+        drive.add_folder("dir", ROOT_ID, "dir");
+        drive.add_file("b", "dir", "b.txt", b"b");
+        drive.add_file("changed", ROOT_ID, "changed.txt", b"remote");
+        drive.add_file("missing", ROOT_ID, "missing.txt", b"missing");
+
+        let client = drive.client();
+        let client = client.lock().unwrap();
+        let ignores = Ignores::load(&local, &[], true).unwrap();
+        let mut v_list = VersionsList::new();
+        let mut mismatches = vec![];
+        let adopted = adopt_dir(
+            ROOT_ID,
+            &local,
+            &client,
+            &mut v_list,
+            &ignores,
+            &mut mismatches,
+        )
+        .unwrap();
+
+        assert_eq!(adopted, 3);
+        assert_eq!(v_list["a"].path, local.join("a.txt").display().to_string());
+        assert_eq!(v_list["b"].parent_id, "dir");
+        assert_eq!(v_list["b"].md5, Some(format!("{:x}", md5::compute(b"b"))));
+        // Folders are listed again by `run`, so the missing files are downloaded
+        assert!(v_list["dir"].is_folder);
+        assert_eq!(v_list["dir"].version, "");
+        assert!(!v_list.contains_key("changed"));
+        assert!(!v_list.contains_key("missing"));
+
+        mismatches.sort();
+        assert_eq!(
+            mismatches,
+            vec![
+                (
+                    local.join("changed.txt"),
+                    "content is different".to_string()
+                ),
+                (local.join("new.txt"), "exists only locally".to_string()),
+            ]
+        );
+        // Only the metadata is read, nothing is downloaded
+        assert!(drive.requests().iter().all(|r| !r.contains("alt=media")));
+    }
+}
@@ -0,0 +1,91 @@
+/* Resolves local files to their pages in Google Drive */
+use crate::sync::{
+    conf_dir, read_config,
+    versions::{Versions, VersionsList},
+};
+use anyhow::{bail, Result};
+use clap::ArgMatches;
+use std::{env, fs, path::Path};
+
+pub fn run(m: &ArgMatches) -> Result<()> {
+    let conf_dir = conf_dir()?;
+    let config = read_config(&conf_dir, None)?;
+    let mut versions = Versions::new(conf_dir.join("versions.json"), Path::new(&config.local_dir))?;
+    let v_list = versions.list()?;
+
+    let path = env::current_dir()?.join(m.value_of("path").unwrap());
+    let link = match link_of(&path, &v_list) {
+        Some(link) => link,
+        None => bail!(
+            "File {:?} is not synced with your drive (yet)",
+            path.display()
+        ),
+    };
+
+    if m.is_present("open") {
+        if let Err(e) = webbrowser::open(&link) {
+            bail!("Unable to open link in browser: {}", e);
+        }
+        return Ok(());
+    }
+
+    println!("{}", link);
+    Ok(())
+}
+
+/// Link to the Drive page of the synced file at `path`
+fn link_of(path: &Path, v_list: &VersionsList) -> Option<String> {
+    // Path could be passed with symlinks or `..` in it, so try the canonical one as well
+    let (id, v) = Versions::find_item_by_path(path.to_path_buf(), v_list).or_else(|| {
+        fs::canonicalize(path)
+            .ok()
+            .and_then(|p| Versions::find_item_by_path(p, v_list))
+    })?;
+
+    // Older versions files have no links, but file id is enough to build one
+    Some(
+        v.web_link
+            .unwrap_or(format!("https://drive.google.com/open?id={}", id)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing::{self, FakeDrive, ROOT_ID};
+    use serde_json::json;
+
+    #[test]
+    fn local_path_is_resolved_to_its_link() {
+        let dir = testing::temp_dir("link");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_file("a", ROOT_ID, "a.txt", b"a");
+        drive.change("a", |f| {
+            f.fields.insert(
+                "webViewLink".to_string(),
+                json!("https://drive.google.com/file/d/a/view"),
+            );
+        });
+        drive.add_file("b", ROOT_ID, "b.txt", b"b");
+        let daemon = testing::remote_daemon(&drive, testing::config(&local, ""), &dir);
+        assert!(daemon.sync().unwrap());
+
+        let v_list = testing::versions(&dir, &local)
+            .lock()
+            .unwrap()
+            .list()
+            .unwrap();
+        assert_eq!(
+            link_of(&local.join("a.txt"), &v_list).as_deref(),
+            Some("https://drive.google.com/file/d/a/view")
+        );
+        assert_eq!(
+            link_of(&local.join("b.txt"), &v_list).as_deref(),
+            Some("https://drive.google.com/open?id=b")
+        );
+        assert_eq!(link_of(&local.join("c.txt"), &v_list), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+/* Makes sure that only one process works with the same config at a time, since they would
+    overwrite each other's versions file
+*/
+use anyhow::{bail, Context, Result};
+use std::{
+    fs::{self, File, TryLockError},
+    io::Write,
+    path::PathBuf,
+    process,
+};
+
+/// Lock is held while the value is alive and released by the OS even if the process was killed
+pub struct RunLock {
+    _file: File,
+}
+
+impl RunLock {
+    pub fn acquire(path: PathBuf) -> Result<Self> {
+        // File must not be truncated before the lock is taken, since it holds PID of the owner
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Unable to open lock file {:?}", path.display()))?;
+
+        match file.try_lock() {
+            Ok(_) => {}
+            Err(TryLockError::WouldBlock) => {
+                let pid = fs::read_to_string(&path).unwrap_or_default();
+                bail!(
+                    "Ocean Drive is already running (PID {}).\nTip: Stop it before running this command",
+                    pid.trim()
+                );
+            }
+            Err(TryLockError::Error(e)) => {
+                bail!("Unable to lock file {:?}: {}", path.display(), e)
+            }
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", process::id())?;
+
+        Ok(Self { _file: file })
+    }
+
+    /// PID of the process that holds the lock, if it's held. Lock is not taken by the check
+    #[cfg(feature = "monitor")]
+    pub fn holder(path: PathBuf) -> Option<String> {
+        let file = File::open(&path).ok()?;
+
+        match file.try_lock_shared() {
+            Err(TryLockError::WouldBlock) => Some(
+                fs::read_to_string(&path)
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+            ),
+            // Released when the file is closed
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing;
+
+    #[test]
+    fn second_run_fails_while_the_lock_is_held() {
+        let path = testing::temp_dir("lock").join("run.lock");
+        let lock = RunLock::acquire(path.clone()).unwrap();
+
+        let e = RunLock::acquire(path.clone()).err().unwrap();
+        assert!(e
+            .to_string()
+            .contains(&format!("already running (PID {})", process::id())));
+
+        drop(lock);
+        assert!(RunLock::acquire(path).is_ok());
+    }
+}
@@ -0,0 +1,187 @@
+/*
+    Syncs several local directories with subfolders of the remote directory in one process. The
+    client is shared, but every mapping has its own versions and pending changes in
+    `mappings/<name>` of the config dir, since their files must not be mixed up.
+*/
+use crate::{
+    google_drive::Client,
+    service,
+    setup::{Config as AppConfig, Mapping},
+    sync::{find_or_create_dir, open_versions, profiles, remote::RemoteDaemon, session, watchdog},
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{mpsc::channel, Arc, Mutex},
+};
+
+pub fn run(
+    conf_dir: &Path,
+    config: &AppConfig,
+    client: Arc<Mutex<Client>>,
+    remote_dir_id: &str,
+    once: bool,
+) -> Result<()> {
+    let mappings = config.mappings.as_deref().unwrap_or_default();
+    validate(mappings)?;
+
+    let recovering: watchdog::Recovering = Arc::new(Mutex::new(vec![]));
+    let (tx, rx) = channel::<(String, Result<()>)>();
+
+    for mapping in mappings {
+        let name = state_name(&mapping.local);
+        let state_dir = conf_dir.join("mappings").join(&name);
+        fs::create_dir_all(&state_dir)
+            .with_context(|| format!("Unable to create directory {:?}", state_dir))?;
+
+        let subpath = remote_subpath(mapping);
+        let id = resolve_remote(&client, remote_dir_id, &subpath)?;
+        let mut c = config.clone();
+        c.local_dir = mapping.local.clone();
+        c.mappings = None;
+        info!("Syncing {:?} with '{}'", mapping.local, subpath);
+
+        if once {
+            let versions = open_versions(&state_dir, &c, &client, &id)?;
+            RemoteDaemon::new(c, Arc::clone(&client), versions, id)?.sync_once()?;
+            continue;
+        }
+
+        profiles::spawn_daemons(
+            &name,
+            c,
+            Arc::clone(&client),
+            id,
+            &state_dir,
+            &recovering,
+            &tx,
+        )?;
+    }
+    drop(tx);
+    service::notify_ready();
+
+    if once {
+        return Ok(());
+    }
+    while let Some((name, res)) = session::next_stopped(&rx) {
+        if let Err(e) = res {
+            bail!("Fatal error in a thread {:?}.\nDetails: {}", name, e);
+        }
+    }
+    session::print_summary();
+
+    Ok(())
+}
+
+/// Each file must belong to one mapping only, otherwise the mappings would download each other's
+/// files. So neither the local directories nor the remote subfolders may be nested
+pub fn validate(mappings: &[Mapping]) -> Result<()> {
+    for (i, a) in mappings.iter().enumerate() {
+        for b in &mappings[i + 1..] {
+            let (a_local, b_local) = (Path::new(&a.local), Path::new(&b.local));
+            if a_local.starts_with(b_local) || b_local.starts_with(a_local) {
+                bail!(
+                    "Local directories {:?} and {:?} of the mappings overlap",
+                    a.local,
+                    b.local
+                );
+            }
+
+            let (a_remote, b_remote) = (remote_subpath(a), remote_subpath(b));
+            let (a_path, b_path) = (Path::new(&a_remote), Path::new(&b_remote));
+            if a_path.starts_with(b_path) || b_path.starts_with(a_path) {
+                bail!(
+                    "Remote subfolders '{}' and '{}' of the mappings overlap, each mapping needs its own",
+                    a_remote,
+                    b_remote
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn remote_subpath(mapping: &Mapping) -> String {
+    mapping
+        .remote_subpath
+        .as_deref()
+        .unwrap_or_default()
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Finds the subfolder by its path, the missing folders are created
+fn resolve_remote(client: &Arc<Mutex<Client>>, root_id: &str, subpath: &str) -> Result<String> {
+    let mut id = root_id.to_string();
+    for name in subpath.split('/').filter(|part| !part.is_empty()) {
+        let dir = find_or_create_dir(name, &id, client)
+            .with_context(|| format!("Unable to get the remote subfolder '{}'", subpath))?;
+        id = dir
+            .id
+            .with_context(|| format!("Drive returned no id for the subfolder '{}'", name))?;
+    }
+
+    Ok(id)
+}
+
+/// Name of the state directory, it's derived from the local path so reordering the mappings
+/// doesn't mix up their versions
+fn state_name(local: &str) -> String {
+    let name = PathBuf::from(local)
+        .components()
+        .filter_map(|c| c.as_os_str().to_str())
+        .filter(|c| *c != "/")
+        .collect::<Vec<_>>()
+        .join("_");
+
+    if name.is_empty() {
+        "root".to_string()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing::{self, FakeDrive, ROOT_ID};
+
+    #[test]
+    fn each_mapping_syncs_its_own_subfolder() {
+        let dir = testing::temp_dir("mappings");
+        let (photos, docs) = (dir.join("photos"), dir.join("docs"));
+        fs::create_dir(&photos).unwrap();
+        fs::create_dir(&docs).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("photos", ROOT_ID, "Photos");
+        drive.add_file("p", "photos", "p.jpg", b"p");
+        drive.add_folder("work", ROOT_ID, "Work");
+        drive.add_folder("docs", "work", "Docs");
+        drive.add_file("d", "docs", "d.txt", b"d");
+        drive.add_file("other", ROOT_ID, "other.txt", b"other");
+
+        let mappings = format!(
+            "mappings = [{{ local = {:?}, remote_subpath = \"Photos\" }}, {{ local = {:?}, remote_subpath = \"/Work/Docs/\" }}]",
+            photos.display().to_string(),
+            docs.display().to_string()
+        );
+        let config = testing::config(Path::new(""), &mappings);
+        run(&dir, &config, drive.client(), ROOT_ID, true).unwrap();
+
+        assert_eq!(fs::read(photos.join("p.jpg")).unwrap(), b"p");
+        assert_eq!(fs::read(docs.join("d.txt")).unwrap(), b"d");
+        assert!(!photos.join("other.txt").exists());
+        assert!(!docs.join("other.txt").exists());
+        assert!(!photos.join("d.txt").exists());
+        for local in [&photos, &docs] {
+            let state = dir
+                .join("mappings")
+                .join(state_name(&local.to_string_lossy()));
+            assert!(state.join("versions.json").is_file());
+        }
+    }
+}
@@ -0,0 +1,146 @@
+/*
+    Moves the files of the flat config layout (everything right in the config dir) into a profile
+    directory, so the setup can be run with `run --all-profiles` next to other profiles. Originals
+    are copied to a backup directory first. Running it again after it's done changes nothing.
+*/
+use crate::{
+    sync::{lock::RunLock, profiles::check_name},
+    user,
+};
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use std::{fs, path::Path};
+
+/// Files of the flat layout that belong to a profile
+const PROFILE_FILES: [&str; 7] = [
+    "config.toml",
+    "creds.toml",
+    "session.toml",
+    "versions.json",
+    "pending.json",
+    "remote_root.toml",
+    "budget.json",
+];
+
+pub fn run(m: &ArgMatches) -> Result<()> {
+    match m.subcommand_matches("profiles") {
+        Some(m) => profiles(
+            &user::get_home()?.join(".config/ocean-drive"),
+            m.value_of("name").unwrap_or("default"),
+        ),
+        None => bail!("Nothing to migrate. Try 'ocean-drive migrate --help'"),
+    }
+}
+
+fn profiles(conf_dir: &Path, name: &str) -> Result<()> {
+    check_name(name)?;
+
+    let profile_dir = conf_dir.join("profiles").join(name);
+    let flat: Vec<&str> = PROFILE_FILES
+        .iter()
+        .copied()
+        .filter(|f| conf_dir.join(f).is_file())
+        .collect();
+
+    if flat.is_empty() {
+        if profile_dir.join("config.toml").is_file() {
+            info!(
+                "Config is already in profile {:?}, nothing to migrate.",
+                name
+            );
+            return Ok(());
+        }
+        bail!("No config found in {:?}, nothing to migrate", conf_dir);
+    }
+
+    // Daemon of the flat layout must not write the files while they are moved
+    let _lock = RunLock::acquire(conf_dir.join("run.lock"))?;
+
+    // Files that are already in the profile are left from an interrupted migration only if the
+    // flat ones are gone, otherwise it's not clear which of them to keep
+    for f in &flat {
+        if profile_dir.join(f).exists() {
+            bail!(
+                "Profile {:?} already has {}, so it's not clear which one to keep. Move one of them by hand or choose another profile name with --name",
+                name,
+                f
+            );
+        }
+    }
+
+    let backup_dir = conf_dir.join(format!(
+        "backup-{}",
+        chrono::Local::now().format("%Y%m%d-%H%M%S")
+    ));
+    fs::create_dir_all(&backup_dir)
+        .with_context(|| format!("Unable to create backup directory {:?}", backup_dir))?;
+    for f in &flat {
+        fs::copy(conf_dir.join(f), backup_dir.join(f))
+            .with_context(|| format!("Unable to back up {}", f))?;
+    }
+
+    fs::create_dir_all(&profile_dir)?;
+    for f in &flat {
+        move_file(&conf_dir.join(f), &profile_dir.join(f))?;
+    }
+
+    info!(
+        "Moved {} to profile {:?}. Originals are backed up to {:?}.\nTip: Run `ocean-drive run --all-profiles` to sync it",
+        flat.join(", "),
+        name,
+        backup_dir
+    );
+
+    Ok(())
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    fs::rename(from, to).with_context(|| {
+        format!(
+            "Unable to move {:?} to {:?}. Run the migration again to continue",
+            from, to
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing;
+
+    #[test]
+    fn flat_layout_becomes_a_single_profile() {
+        let conf_dir = testing::temp_dir("migrate-profiles");
+        let flat = ["config.toml", "creds.toml", "session.toml", "versions.json"];
+        for f in &flat {
+            fs::write(conf_dir.join(f), f.as_bytes()).unwrap();
+        }
+
+        profiles(&conf_dir, "default").unwrap();
+        let profile_dir = conf_dir.join("profiles/default");
+        for f in &flat {
+            assert!(!conf_dir.join(f).exists());
+            assert_eq!(fs::read(profile_dir.join(f)).unwrap(), f.as_bytes());
+        }
+        let backups: Vec<_> = fs::read_dir(&conf_dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| {
+                p.file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .starts_with("backup-")
+            })
+            .collect();
+        assert_eq!(backups.len(), 1);
+        for f in &flat {
+            assert_eq!(fs::read(backups[0].join(f)).unwrap(), f.as_bytes());
+        }
+
+        // Nothing is left to move, so the second run changes nothing
+        profiles(&conf_dir, "default").unwrap();
+        for f in &flat {
+            assert_eq!(fs::read(profile_dir.join(f)).unwrap(), f.as_bytes());
+        }
+    }
+}
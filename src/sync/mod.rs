@@ -1,20 +1,172 @@
+mod check;
+mod cli;
+mod conflicts;
+mod crypto;
+mod deferred;
+mod diff;
+pub mod errors;
+mod folders;
+mod hashes;
+mod hooks;
+mod ignores;
+mod inflight;
+mod init;
+mod link;
 mod local;
+mod lock;
+mod mappings;
+mod migrate;
+#[cfg(feature = "monitor")]
+mod monitor;
+mod names;
+mod pending;
+pub mod power;
+mod profiles;
+mod prune;
+mod pull;
+mod relocate;
+mod revisions;
 pub mod remote;
+mod scanner;
+mod schedule;
+mod session;
+#[cfg(test)]
+mod testing;
+mod usage;
 mod util;
 mod versions;
-use crate::tray::Tray;
+mod watchdog;
+#[cfg(feature = "tray")]
+use crate::tray::{self, Tray};
 use crate::{
     auth::{util::update_for_shared_client, Creds},
     files,
-    google_drive::{errors::DriveError, types::File, Client, Session},
+    google_drive::{
+        bandwidth::Bandwidth, budget::Budget, errors::DriveError, types::File, Client,
+        Config as DriveConfig, Session, APP_DATA_FOLDER,
+    },
+    readline, service,
     setup::Config as AppConfig,
     user,
 };
-use anyhow::{bail, Result};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use errors::SyncError;
+use ignores::Ignores;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc::channel, Arc, Mutex, OnceLock};
 use std::thread;
 use versions::Versions;
+
+pub use self::cli::*;
+pub use self::conflicts::ConflictPolicy;
+pub use self::names::NameNormalization;
+pub use self::remote::{DownloadOrder, ShortcutMode};
+
+/// Environment variable with the whole config, used in containers instead of config.toml
+const CONFIG_ENV: &str = "OCEAN_DRIVE_CONFIG";
+
+/// Profile picked with `--profile`, commands use its state instead of the one in the config dir
+static PROFILE: OnceLock<String> = OnceLock::new();
+
+/// Debug mode of the client: API responses are saved to a directory or answered from it
+enum Recording {
+    Off,
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+/// Directory that is synced, as it's shown in the tray
+#[cfg_attr(not(feature = "tray"), allow(dead_code))]
+pub struct Synced {
+    /// Name of the profile, None when the process syncs only one directory
+    pub profile: Option<String>,
+    pub remote: remote::RemoteDaemon,
+    pub remote_dir_id: String,
+    pub local_path: String,
+    pub account: Option<String>,
+}
+
+/// Resolved remote root directory, saved so it's not looked up by name on every start
+#[derive(Serialize, Deserialize)]
+struct RemoteRoot {
+    dir: String,
+    shared_drive_id: Option<String>,
+    app_data: Option<bool>,
+    id: String,
+    /// Name of the directory on the drive, it's different from `dir` after a rename on the drive
+    name: Option<String>,
+}
+
+/// Makes the commands use the state of the profile (`--profile`) instead of the config dir
+pub fn set_profile(name: &str) -> Result<()> {
+    profiles::check_name(name)?;
+    PROFILE.set(name.to_string()).ok();
+    Ok(())
+}
+
+/// Directory with the config and the sync state: the one of the profile picked with `--profile`
+/// or the config dir itself
+pub fn conf_dir() -> Result<PathBuf> {
+    let base = user::get_home()?.join(".config/ocean-drive");
+    profiles::state_dir(&base, PROFILE.get().map(String::as_str))
+}
+
+pub fn link(m: &ArgMatches) -> Result<()> {
+    link::run(m)
+}
+
+pub fn init() -> Result<()> {
+    init::run()
+}
+
+pub fn pull(m: &ArgMatches) -> Result<()> {
+    pull::run(m)
+}
+
+pub fn prune(m: &ArgMatches) -> Result<()> {
+    prune::run(m)
+}
+
+pub fn relocate(m: &ArgMatches) -> Result<()> {
+    relocate::run(m)
+}
+
+pub fn diff(m: &ArgMatches) -> Result<()> {
+    diff::run(m)
+}
+
+pub fn usage(m: &ArgMatches) -> Result<()> {
+    usage::run(m)
+}
+
+pub fn migrate(m: &ArgMatches) -> Result<()> {
+    migrate::run(m)
+}
+
+pub fn profiles(m: &ArgMatches) -> Result<()> {
+    match m.subcommand_name() {
+        Some("list") => profiles::list(),
+        _ => bail!("Unknown subcommand. Try 'ocean-drive profiles --help'"),
+    }
+}
+
+pub fn revisions(m: &ArgMatches) -> Result<()> {
+    revisions::list(m)
+}
+
+pub fn restore(m: &ArgMatches) -> Result<()> {
+    revisions::restore(m)
+}
+
+#[cfg(feature = "monitor")]
+pub fn monitor(m: &ArgMatches) -> Result<()> {
+    monitor::run(m)
+}
 /*
     Setups two daemons for updates: local and remote.
     Each of them is responsible for either downloading files from the remote, or uploading local files to the remote
@@ -22,70 +174,458 @@ use versions::Versions;
     Threads will share a mutable referce to drive client, this will allow to keep the same authroziation
     while app is running.
 */
-pub fn run() -> Result<()> {
-    let conf_dir = user::get_home()?.join(".config/ocean-drive");
-    let conf_file = conf_dir.join("config.toml");
-    let config = files::read_toml::<AppConfig>(conf_file)?;
-
-    let mut client = Arc::new(Mutex::new(setup_client(&conf_dir)?));
-    // Get info about root dir in the drive (We do this here because daemons will need the same
-    // info)
-    let remote_dir = get_remote_dir(&config.drive.dir, &mut client)?;
-    let versions = Arc::new(Mutex::new(Versions::new(conf_dir.join("versions.json"))?));
-
-    let mut threads = vec![];
-    // Start 2 threads for remote and local daemons
-    for i in 1..=3 {
+pub fn run(m: Option<&ArgMatches>) -> Result<()> {
+    if matches!(m, Some(m) if m.is_present("all-profiles")) {
+        if let Some(name) = PROFILE.get() {
+            bail!(
+                "--profile {:?} can't be used together with --all-profiles",
+                name
+            );
+        }
+        return profiles::run(m.filter(|m| m.is_present("no-tray")).is_none());
+    }
+
+    let conf_dir = conf_dir()?;
+    if let Some(m) = m.filter(|m| m.is_present("config-check")) {
+        return check::run(&conf_dir, m.value_of("config-inline"));
+    }
+    let mut config = read_config(&conf_dir, m.and_then(|m| m.value_of("config-inline")))?;
+    let with_tray = with_tray(m);
+
+    // Command line options take precedence over the config file
+    if let Some(m) = m {
+        if let Some(depth) = m.value_of("max-depth") {
+            config.max_depth = Some(
+                depth
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid value for --max-depth: {:?}", depth))?,
+            );
+        }
+        if let Some(fields) = m.value_of("fields") {
+            config.drive.fields = Some(fields.to_string());
+        }
+        if m.is_present("interactive") {
+            config.interactive_conflicts = Some(true);
+        }
+        if m.is_present("confirm-deletes") {
+            config.max_deletes_per_cycle = None;
+            config.max_deletes_percent = None;
+        }
+        if m.is_present("adopt") {
+            config.adopt_local_files = Some(true);
+        }
+        if m.is_present("remote-only-changes") {
+            config.remote_only_changes = Some(true);
+        }
+    }
+
+    let recording = match (
+        m.and_then(|m| m.value_of("record")),
+        m.and_then(|m| m.value_of("replay")),
+    ) {
+        (Some(dir), _) => Recording::Record(PathBuf::from(dir)),
+        (_, Some(dir)) => Recording::Replay(PathBuf::from(dir)),
+        _ => Recording::Off,
+    };
+
+    let _lock = lock::RunLock::acquire(conf_dir.join("run.lock"))?;
+    let (client, remote_dir_id) = connect_with(&conf_dir, &config, &recording)?;
+    if matches!(&config.mappings, Some(mappings) if !mappings.is_empty()) {
+        let once = matches!(m, Some(m) if m.is_present("once"));
+        return mappings::run(&conf_dir, &config, client, &remote_dir_id, once);
+    }
+    let versions = open_versions(&conf_dir, &config, &client, &remote_dir_id)?;
+
+    if matches!(m, Some(m) if m.is_present("once")) {
+        let mut d = remote::RemoteDaemon::new(config, client, versions, remote_dir_id)?;
+        if let Some(since) = m.and_then(|m| m.value_of("since")) {
+            d.set_since(util::parse_since(since)?);
+        }
+
+        return d.sync_once();
+    }
+
+    let account = util::lock_ref_when_free(&client).session_email();
+    if let Some(account) = &account {
+        info!("Syncing the drive of {}", account);
+    }
+
+    let recovering: watchdog::Recovering = Arc::new(Mutex::new(vec![]));
+    // Daemons report here when they stop, so the first fatal error stops the whole app
+    let (tx, rx) = channel::<(String, Result<()>)>();
+
+    // Daemons never write the same file at the same time
+    let in_flight = inflight::InFlight::default();
+    // Files are hashed once until they change, whichever daemon needs it
+    let hashes = hashes::Hashes::default();
+
+    for name in daemon_names(with_tray) {
         let cl = Arc::clone(&client);
         let v = Arc::clone(&versions);
         let c = config.clone();
-        let rdir_id = remote_dir.id.clone().unwrap();
+        let rdir_id = remote_dir_id.clone();
+        let rec = Arc::clone(&recovering);
+        let account = account.clone();
+        let conf_dir = conf_dir.clone();
+        let tx = tx.clone();
+        let in_flight = in_flight.clone();
+        let hashes = hashes.clone();
+
+        let name = name.to_string();
+
+        thread::Builder::new().name(name.clone()).spawn(move || {
+            let res = if name == "remote" {
+                watchdog::supervise(&name, rec, move || -> Result<()> {
+                    let mut d = remote::RemoteDaemon::new(
+                        c.clone(),
+                        cl.clone(),
+                        v.clone(),
+                        rdir_id.clone(),
+                    )?;
+                    d.set_in_flight(in_flight.clone());
+                    d.set_hashes(hashes.clone());
+
+                    d.start_sync_loop()
+                })
+            } else if name == "local" {
+                let pending_file = conf_dir.join("pending.json");
+                watchdog::supervise(&name, rec, move || -> Result<()> {
+                    let mut d = local::LocalDaemon::new(
+                        c.clone(),
+                        cl.clone(),
+                        v.clone(),
+                        rdir_id.clone(),
+                        pending_file.clone(),
+                    )?;
+                    d.set_in_flight(in_flight.clone());
+                    d.set_hashes(hashes.clone());
+
+                    d.start()
+                })
+            } else {
+                remote::RemoteDaemon::new(c.clone(), cl.clone(), v, rdir_id.clone()).and_then(
+                    |mut d| {
+                        d.set_in_flight(in_flight);
+                        d.set_hashes(hashes);
+                        let synced = Synced {
+                            profile: None,
+                            remote: d,
+                            remote_dir_id: rdir_id,
+                            local_path: c.local_dir,
+                            account,
+                        };
+                        start_tray(c.tray_icon_path.as_deref(), vec![synced], rec)
+                    },
+                )
+            };
+
+            tx.send((name, res)).ok();
+        })?;
+    }
+    drop(tx);
+    service::notify_ready();
+
+    while let Some((name, res)) = session::next_stopped(&rx) {
+        if let Err(e) = res {
+            // App is still able to sync without tray
+            if name == "tray" {
+                warn!("Tray is not available.\nDetails: {}", e);
+                continue;
+            }
+            bail!("Fatal error in a thread {:?}.\nDetails: {}", name, e);
+        }
+    }
+    session::print_summary();
+
+    Ok(())
+}
 
-        let name = match i {
-            1 => "remote",
-            2 => "local",
-            _ => "tray",
+/// Reads config passed inline (with the flag or the env variable) or the one from config dir
+/// Inline config can be either JSON or TOML
+fn read_config(conf_dir: &Path, inline: Option<&str>) -> Result<AppConfig> {
+    let inline = inline
+        .map(String::from)
+        .or_else(|| env::var(CONFIG_ENV).ok().filter(|c| !c.trim().is_empty()));
+
+    let config: AppConfig = match inline {
+        Some(c) if c.trim_start().starts_with('{') => {
+            serde_json::from_str(&c).context("Unable to parse inline JSON config")?
         }
-        .to_string();
+        Some(c) => toml::from_str(&c).context("Unable to parse inline TOML config")?,
+        None => files::read_toml::<AppConfig>(conf_dir.join("config.toml"))?,
+    };
+    // State files are read after the config
+    if let Some(mb) = config.max_state_file_mb {
+        files::set_max_read_mb(mb);
+    }
+
+    Ok(config)
+}
+
+/// Creates a shared drive client and finds id of the remote root directory
+fn connect(conf_dir: &PathBuf, config: &AppConfig) -> Result<(Arc<Mutex<Client>>, String)> {
+    connect_with(conf_dir, config, &Recording::Off)
+}
+
+fn connect_with(
+    conf_dir: &PathBuf,
+    config: &AppConfig,
+    recording: &Recording,
+) -> Result<(Arc<Mutex<Client>>, String)> {
+    let mut client = setup_client(conf_dir, &config.drive, recording)?;
+    if let Some(fields) = &config.drive.fields {
+        client.set_fields(fields);
+    }
+    if let Some(size) = config.drive.page_size {
+        client.set_page_size(size);
+    }
+    if config.drive.max_api_calls_per_day.is_some() || config.drive.max_bytes_per_day.is_some() {
+        client.set_budget(Budget::load(
+            conf_dir.join("budget.json"),
+            config.drive.max_api_calls_per_day,
+            config.drive.max_bytes_per_day,
+        ));
+    }
+    if let Some(bandwidth) =
+        Bandwidth::parse(config.drive.bandwidth_windows.as_deref().unwrap_or(&[]))?
+    {
+        client.set_bandwidth(bandwidth);
+    }
+    let app_data = config.drive.app_data.unwrap_or(false);
+    if app_data && config.drive.shared_drive_id.is_some() {
+        bail!("App data folder can't be used together with a Shared Drive, set only one of `app_data` and `shared_drive_id`");
+    }
+    if app_data {
+        client.set_app_data_space();
+    }
+    if let Some(drive_id) = &config.drive.shared_drive_id {
+        let drive = client.get_shared_drive(drive_id).with_context(|| {
+            format!(
+                "Unable to access Shared Drive '{}'. Make sure your account is a member of it",
+                drive_id
+            )
+        })?;
+        info!("Syncing Shared Drive '{}'", drive.name.unwrap_or_default());
+        client.set_shared_drive(drive_id);
+    }
+    let mut client = Arc::new(Mutex::new(client));
 
-        let daemon = thread::Builder::new()
-            .name(name)
-            .spawn(move || -> Result<()> {
-                if i == 1 {
-                    let mut d =
-                        remote::RemoteDaemon::new(c.clone(), cl.clone(), v, rdir_id.clone())?;
+    let remote_dir_id = match &config.drive.shared_drive_id {
+        // Root folder of the Shared Drive has the same id as the drive
+        Some(drive_id) if config.drive.dir.is_empty() => drive_id.clone(),
+        None if app_data && config.drive.dir.is_empty() => APP_DATA_FOLDER.to_string(),
+        // Get info about root dir in the drive (We do this here because daemons will need the
+        // same info)
+        _ => get_remote_dir_id(conf_dir, &config.drive, &mut client)?,
+    };
 
-                    d.start_sync_loop()?;
-                } else if i == 2 {
-                    let d = local::LocalDaemon::new(c, cl.clone(), v, rdir_id.clone())?;
+    if config.drive.namespace_by_hostname.unwrap_or(false) {
+        let id = get_host_dir_id(&config.drive, &remote_dir_id, &client)?;
+        return Ok((client, id));
+    }
 
-                    d.start()?;
-                } else {
-                    let d = remote::RemoteDaemon::new(c.clone(), cl.clone(), v, rdir_id.clone())?;
+    Ok((client, remote_dir_id))
+}
 
-                    // TODO: Make certain path for the trayicon (e.g. in /opt)
-                    let tray = Tray::setup("./trayicon.png", d, rdir_id, c.local_dir)?;
-                    tray.start();
-                }
+/// Opens the versions file. If it was corrupted, local files that are the same as on the drive are
+/// adopted again, so they are not downloaded from scratch. The same is done on the first sync into
+/// a non-empty directory with `adopt_local_files`, otherwise such sync has to be confirmed
+fn open_versions(
+    conf_dir: &Path,
+    config: &AppConfig,
+    client: &Arc<Mutex<Client>>,
+    remote_dir_id: &str,
+) -> Result<Arc<Mutex<Versions>>> {
+    open_versions_asking(conf_dir, config, client, remote_dir_id, |question| {
+        io::stdin().is_terminal() && readline::confirm(question, false)
+    })
+}
+
+/// `open_versions` that asks the yes/no `question` with `ask`
+fn open_versions_asking(
+    conf_dir: &Path,
+    config: &AppConfig,
+    client: &Arc<Mutex<Client>>,
+    remote_dir_id: &str,
+    ask: impl FnOnce(&str) -> bool,
+) -> Result<Arc<Mutex<Versions>>> {
+    let local_dir = Path::new(&config.local_dir);
+    let mut versions = Versions::new(conf_dir.join("versions.json"), local_dir)?;
 
-                Ok(())
-            })?;
+    let adopt = config.adopt_local_files.unwrap_or(false);
+    let first_sync = !versions.was_reset() && versions.list()?.is_empty() && has_files(local_dir);
+    if first_sync && !adopt {
+        confirm_first_sync(local_dir, ask)?;
+    }
+
+    if versions.was_reset() || (first_sync && adopt) {
+        let mut v_list = versions.list()?;
+        let ignores = Ignores::load(
+            local_dir,
+            config.ignore.as_deref().unwrap_or(&[]),
+            config.sync_hidden.unwrap_or(true),
+        )?;
+        let adopted = init::adopt_dir(
+            remote_dir_id,
+            local_dir,
+            &util::lock_ref_when_free(client),
+            &mut v_list,
+            &ignores,
+            &mut vec![],
+        )?;
+        versions.save(v_list)?;
 
-        threads.push(daemon);
+        info!(
+            "{} files and directories that are the same as on the drive are marked as synced.",
+            adopted
+        );
     }
 
-    for t in threads {
-        // TODO: I hate Result<Result<...>> for t.join()
-        let name = t.thread().name().unwrap_or("no_name").to_string();
-        let started = t.join();
-        if let Err(e) = started {
-            bail!("Fatal error in a thread {:?}.\nDetails: {:#?}", name, e);
+    Ok(Arc::new(Mutex::new(versions)))
+}
+
+fn has_files(dir: &Path) -> bool {
+    fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some())
+}
+
+/// Files that are already in the directory would be mixed with the downloaded ones, so the sync is
+/// done only when it's confirmed in the terminal
+fn confirm_first_sync(local_dir: &Path, ask: impl FnOnce(&str) -> bool) -> Result<()> {
+    warn!(
+        "{:?} is not empty, but nothing was synced to it yet. Files of the drive are downloaded next to the files in it: the ones on the same paths are replaced with the drive ones, the rest are left untouched and not uploaded until they are changed",
+        local_dir
+    );
+    if ask("Sync into this directory anyway?") {
+        return Ok(());
+    }
+
+    bail!(
+        "Sync into the non-empty directory {:?} wasn't confirmed.\nTip: Run `ocean-drive run --adopt` (or `ocean-drive init` before `run`) to mark the files that are the same as on the drive as synced, or choose an empty directory",
+        local_dir
+    );
+}
+
+/// Returns id of the cached remote root directory if it still exists, otherwise the directory is
+/// looked up by name and cached
+fn get_remote_dir_id(
+    conf_dir: &Path,
+    drive: &DriveConfig,
+    client: &mut Arc<Mutex<Client>>,
+) -> Result<String> {
+    let cache_file = conf_dir.join("remote_root.toml");
+
+    // Cached id is valid only for the same configured directory
+    if let Ok(cached) = files::read_toml::<RemoteRoot>(cache_file.clone()) {
+        if cached.dir == drive.dir
+            && cached.shared_drive_id == drive.shared_drive_id
+            && cached.app_data.unwrap_or(false) == drive.app_data.unwrap_or(false)
+        {
+            let file = util::lock_ref_when_free(client).get_file(&cached.id);
+
+            if let Ok(Some(f)) = file {
+                if !f.trashed.unwrap_or(false)
+                    && f.mime_type.as_deref() == Some("application/vnd.google-apps.folder")
+                {
+                    // Directory is found by the id, so it's still synced after it's renamed
+                    let known = cached.name.clone().unwrap_or_else(|| drive.dir.clone());
+                    match f.name {
+                        Some(name) if !drive.dir.is_empty() && name != known => {
+                            info!(
+                                "Remote directory '{}' was renamed to '{}' on the drive, it's still synced. Set `drive.dir` to the new name to keep it that way if the cache in {:?} is removed",
+                                known, name, cache_file
+                            );
+                            let id = cached.id.clone();
+                            let renamed = RemoteRoot {
+                                name: Some(name),
+                                ..cached
+                            };
+                            save_remote_root(renamed, cache_file);
+                            return Ok(id);
+                        }
+                        _ => return Ok(cached.id),
+                    }
+                }
+            }
         }
     }
 
+    let remote_dir = if drive.app_data.unwrap_or(false) {
+        get_app_data_dir(&drive.dir, client)?
+    } else {
+        get_remote_dir(&drive.dir, client)?
+    };
+    let id = remote_dir.id.with_context(|| {
+        format!(
+            "Drive returned no id for the remote root directory '{}'",
+            &drive.dir
+        )
+    })?;
+
+    let cached = RemoteRoot {
+        dir: drive.dir.clone(),
+        shared_drive_id: drive.shared_drive_id.clone(),
+        app_data: drive.app_data,
+        id: id.clone(),
+        name: remote_dir.name,
+    };
+    save_remote_root(cached, cache_file);
+
+    Ok(id)
+}
+
+fn save_remote_root(root: RemoteRoot, cache_file: PathBuf) {
+    if let Err(e) = files::write_toml(root, cache_file) {
+        warn!(
+            "Unable to save id of the remote root directory.\nDetails: {}",
+            e
+        );
+    }
+}
+
+/// Tray is skipped with --no-tray or when it can't be shown
+fn with_tray(m: Option<&ArgMatches>) -> bool {
+    let no_tray = matches!(m, Some(m) if m.is_present("no-tray"));
+    cfg!(feature = "tray") && !no_tray && has_display()
+}
+
+/// Threads started by `run`: remote and local daemons and the tray if needed
+fn daemon_names(with_tray: bool) -> Vec<&'static str> {
+    let mut names = vec!["remote", "local"];
+    if with_tray {
+        names.push("tray");
+    }
+    names
+}
+
+/// Tray can't be shown without a graphical session (e.g. on servers)
+fn has_display() -> bool {
+    ["DISPLAY", "WAYLAND_DISPLAY"]
+        .iter()
+        .any(|var| matches!(std::env::var_os(var), Some(v) if !v.is_empty()))
+}
+
+#[cfg(feature = "tray")]
+fn start_tray(
+    icon_path: Option<&str>,
+    synced: Vec<Synced>,
+    recovering: watchdog::Recovering,
+) -> Result<()> {
+    let icon = tray::icon_path(icon_path);
+    let tray = Tray::setup(&icon, synced, recovering)?;
+    tray.start();
+
     Ok(())
 }
 
+#[cfg(not(feature = "tray"))]
+fn start_tray(
+    _icon_path: Option<&str>,
+    _synced: Vec<Synced>,
+    _recovering: watchdog::Recovering,
+) -> Result<()> {
+    bail!("Ocean Drive is built without the tray feature")
+}
+
 fn get_remote_dir(name: &String, drive_ref: &mut Arc<Mutex<Client>>) -> Result<File> {
     let mut drive;
 
@@ -98,20 +638,21 @@ fn get_remote_dir(name: &String, drive_ref: &mut Arc<Mutex<Client>>) -> Result<F
 
     match drive.list_files(
         Some(&format!("name = '{}'", &name)),
-        Some("files(id, mimeType)"),
+        Some("files(id, name, mimeType)"),
     ) {
         Ok(list) => {
             if list.files.len() == 0 {
-                bail!("No file with name '{}' found in your drive", name);
+                bail!(SyncError::RemoteRootNotFound(name.to_string()));
             }
 
             let root = &list.files[0];
 
-            if root.mime_type.as_ref().unwrap().as_str() != "application/vnd.google-apps.folder" {
-                bail!(
-                    "Please, make sure that file '{}' on your drive is really a directory",
-                    name
-                );
+            if root.id.is_none() {
+                bail!("Drive returned no id for the file '{}'", name);
+            }
+
+            if root.mime_type.as_deref() != Some("application/vnd.google-apps.folder") {
+                bail!(SyncError::RemoteRootNotADir(name.to_string()));
             }
 
             Ok(root.clone())
@@ -119,14 +660,12 @@ fn get_remote_dir(name: &String, drive_ref: &mut Arc<Mutex<Client>>) -> Result<F
         Err(e) => {
             if let Some(err) = e.downcast_ref::<DriveError>() {
                 match err {
-                    DriveError::Unauthorized => {
-                        match update_for_shared_client(&mut drive) {
-                            Ok(_) => {
-                                println!("Info: Client authorization was updated since it was out of date.")
-                            }
-                            Err(e) => bail!(e),
+                    DriveError::Unauthorized => match update_for_shared_client(&mut drive) {
+                        Ok(_) => {
+                            info!("Client authorization was updated since it was out of date.")
                         }
-                    }
+                        Err(e) => bail!(e),
+                    },
                     _ => {}
                 }
             }
@@ -139,40 +678,389 @@ fn get_remote_dir(name: &String, drive_ref: &mut Arc<Mutex<Client>>) -> Result<F
     }
 }
 
-fn setup_client(conf_dir: &PathBuf) -> Result<Client> {
+/// Subfolder of this machine for `namespace_by_hostname`, it's created when missing
+fn get_host_dir_id(
+    config: &DriveConfig,
+    parent_id: &str,
+    client: &Arc<Mutex<Client>>,
+) -> Result<String> {
+    let hostname = match &config.hostname {
+        Some(name) => name.clone(),
+        None => user::get_hostname()?,
+    };
+    if hostname.is_empty() || hostname.contains('/') || hostname.contains('\'') {
+        bail!("Invalid hostname {:?} for the remote subfolder", hostname);
+    }
+
+    let host_dir = find_or_create_dir(&hostname, parent_id, client)
+        .with_context(|| format!("Unable to get the subfolder '{}' of this machine", hostname))?;
+    host_dir
+        .id
+        .with_context(|| format!("Drive returned no id for the subfolder '{}'", hostname))
+}
+
+/// Finds the directory in the app-private folder. Nobody can create it there by hand, so it's
+/// created when missing
+fn get_app_data_dir(name: &str, client: &Arc<Mutex<Client>>) -> Result<File> {
+    find_or_create_dir(name, APP_DATA_FOLDER, client)
+        .context("Unable to get the directory in the app data folder")
+}
+
+fn find_or_create_dir(name: &str, parent_id: &str, client: &Arc<Mutex<Client>>) -> Result<File> {
+    let client = util::lock_ref_when_free(client);
+    let list = client
+        .list_files(
+            Some(&format!(
+                "name = '{}' and '{}' in parents and mimeType = 'application/vnd.google-apps.folder' and trashed = false",
+                name, parent_id
+            )),
+            Some("files(id, name, mimeType)"),
+        )
+        .context("Unable to obtain information about the directory")?;
+
+    if let Some(dir) = list.files.into_iter().next() {
+        return Ok(dir);
+    }
+
+    info!("Creating remote directory '{}'", name);
+    client
+        .create_dir(name, parent_id.to_string())
+        .context("Unable to create the directory")
+}
+
+fn setup_client(conf_dir: &PathBuf, drive: &DriveConfig, recording: &Recording) -> Result<Client> {
     let session_file = conf_dir.join("session.toml");
     let creds_file = conf_dir.join("creds.toml");
 
     let session;
     let creds = files::read_toml::<Creds>(creds_file)?;
 
+    creds.validate()?;
+    if !creds.has_full_access() {
+        warn!("Configured OAuth scopes don't give access to the whole drive. Files that were not uploaded by Ocean Drive won't be synced.");
+    }
+
     match files::read_toml::<Session>(session_file.clone()) {
         Ok(s) => {
             session = s;
         }
-        Err(_) => bail!("Unable to read access authorization data.\nTip: Try to run `ocean-drive auth` to update authorization data"),
+        Err(_) => bail!(SyncError::Unauthorized("Unable to read access authorization data.\nTip: Try to run `ocean-drive auth` to update authorization data".to_string())),
     };
 
     let mut client = Client::new(
         creds.client_id.clone(),
         creds.client_secret.clone(),
         "https://localhost:8080".to_string(),
-    );
+    )?;
 
     client.set_session(session.clone());
+    if let Some(user_agent) = &drive.user_agent {
+        client.set_user_agent(user_agent);
+    }
+    if let Some(key) = &drive.api_key {
+        client.set_api_key(key);
+    }
+    if let Some(project) = &drive.quota_project {
+        client.set_quota_project(project);
+    }
+    if drive.ca_bundle.is_some() || drive.danger_accept_invalid_certs.unwrap_or(false) {
+        client.set_tls(
+            drive.ca_bundle.as_deref().map(Path::new),
+            drive.danger_accept_invalid_certs.unwrap_or(false),
+        )?;
+    }
+    // Set before the token is refreshed, so the refresh is recorded or replayed too
+    match recording {
+        Recording::Off => {}
+        Recording::Record(dir) => {
+            client.record_to(dir.clone())?;
+            info!("Responses of Google Drive API are recorded to {:?}", dir);
+        }
+        Recording::Replay(dir) => {
+            client.replay_from(dir.clone())?;
+            info!(
+                "Google Drive API is replaced with the responses recorded to {:?}",
+                dir
+            );
+        }
+    }
 
     if session.refresh_token.is_some() {
         match client.refresh_token() {
-            Ok(new_session) => {
+            Ok(mut new_session) => {
+                // Sessions saved by older versions have no email
+                if new_session.email.is_none() {
+                    new_session.email = client.get_user_email().ok();
+                    client.set_session(new_session.clone());
+                }
                 files::write_toml(new_session, session_file)?;
 
-                println!("Info: Authorization for client is updated.");
+                info!("Authorization for client is updated.");
             }
-            Err(_) => eprintln!("Warn: App was unable to update Google API Access Token.\nTip: Try to manually authorize using `ocean-drive auth`."),
+            Err(_) => warn!("App was unable to update Google API Access Token.\nTip: Try to manually authorize using `ocean-drive auth`."),
         };
     } else {
-        println!("Warn: No refresh token for client is provided!\nPerhaps, it's good to run `ocean-drive auth` to updates your tokens.");
+        warn!("No refresh token for client is provided!\nPerhaps, it's good to run `ocean-drive auth` to updates your tokens.");
     }
 
     Ok(client)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use testing::{FakeDrive, ROOT_ID};
+
+    #[test]
+    fn remote_root_without_id_is_an_error() {
+        let drive = FakeDrive::new();
+        drive.add_folder("docs", ROOT_ID, "Docs");
+        drive.change("docs", |f| {
+            f.fields.insert("id".to_string(), Value::Null);
+        });
+        drive.add_file("notes", ROOT_ID, "Notes", b"");
+        let mut client = drive.client();
+
+        let e = get_remote_dir(&"Docs".to_string(), &mut client).unwrap_err();
+        assert!(e.to_string().contains("no id"));
+        let e = get_remote_dir(&"Missing".to_string(), &mut client).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<SyncError>(),
+            Some(SyncError::RemoteRootNotFound(_))
+        ));
+        let e = get_remote_dir(&"Notes".to_string(), &mut client).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<SyncError>(),
+            Some(SyncError::RemoteRootNotADir(_))
+        ));
+    }
+
+    #[test]
+    fn only_daemons_are_started_without_tray() {
+        let m = cli::run_subcommand().get_matches_from(vec!["run", "--no-tray"]);
+        assert!(!with_tray(Some(&m)));
+        assert_eq!(daemon_names(with_tray(Some(&m))), vec!["remote", "local"]);
+        assert_eq!(daemon_names(true), vec!["remote", "local", "tray"]);
+    }
+
+    /// Built only by `cargo test --no-default-features`, so the daemon-only configuration is
+    /// compiled and checked without CI
+    #[cfg(not(feature = "tray"))]
+    #[test]
+    fn run_starts_only_daemons_without_the_tray_feature() {
+        let m = cli::run_subcommand().get_matches_from(vec!["run"]);
+        assert!(!with_tray(Some(&m)));
+        assert_eq!(daemon_names(with_tray(Some(&m))), vec!["remote", "local"]);
+        assert!(start_tray(None, vec![], Arc::new(Mutex::new(vec![]))).is_err());
+    }
+
+    #[test]
+    fn inline_config_is_used_over_the_file() {
+        let dir = testing::temp_dir("inline-config");
+        fs::write(
+            dir.join("config.toml"),
+            "local_dir = \"/from/file\"\n[drive]\ndir = \"File\"\n",
+        )
+        .unwrap();
+
+        let toml = "local_dir = \"/from/toml\"\n[drive]\ndir = \"Toml\"\n";
+        let config = read_config(&dir, Some(toml)).unwrap();
+        assert_eq!(
+            (config.local_dir.as_str(), config.drive.dir.as_str()),
+            ("/from/toml", "Toml")
+        );
+
+        let json = r#"{"local_dir": "/from/json", "drive": {"dir": "Json"}}"#;
+        let config = read_config(&dir, Some(json)).unwrap();
+        assert_eq!(
+            (config.local_dir.as_str(), config.drive.dir.as_str()),
+            ("/from/json", "Json")
+        );
+
+        assert!(read_config(&dir, Some("{ not json")).is_err());
+        if env::var(CONFIG_ENV).is_err() {
+            assert_eq!(read_config(&dir, None).unwrap().local_dir, "/from/file");
+        }
+    }
+
+    #[test]
+    fn cached_remote_root_is_used_without_a_name_lookup() {
+        let dir = testing::temp_dir("remote-root-cache");
+        let drive = FakeDrive::new();
+        drive.add_folder("docs", ROOT_ID, "Docs");
+        let mut client = drive.client();
+        let config: DriveConfig = toml::from_str("dir = \"Docs\"").unwrap();
+
+        assert_eq!(
+            get_remote_dir_id(&dir, &config, &mut client).unwrap(),
+            "docs"
+        );
+        assert!(dir.join("remote_root.toml").exists());
+
+        drive.clear_requests();
+        assert_eq!(
+            get_remote_dir_id(&dir, &config, &mut client).unwrap(),
+            "docs"
+        );
+        let requests = drive.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("GET /drive/v3/files/docs?"));
+
+        // Directory is looked up by the name again when the cached one no longer exists
+        drive.remove("docs");
+        drive.add_folder("new-docs", ROOT_ID, "Docs");
+        assert_eq!(
+            get_remote_dir_id(&dir, &config, &mut client).unwrap(),
+            "new-docs"
+        );
+    }
+
+    #[test]
+    fn hostname_subfolder_is_the_effective_root() {
+        let drive = FakeDrive::new();
+        drive.add_folder("backups", ROOT_ID, "Backups");
+        let client = drive.client();
+        let config: DriveConfig = toml::from_str(
+            "dir = \"Backups\"\nnamespace_by_hostname = true\nhostname = \"laptop\"",
+        )
+        .unwrap();
+
+        let id = get_host_dir_id(&config, "backups", &client).unwrap();
+        let host_dir = drive.file(&id).unwrap();
+        assert_eq!(host_dir.name, "laptop");
+        assert_eq!(host_dir.parent, "backups");
+        assert!(host_dir.folder);
+
+        // Subfolder is found on the next start, not created again
+        assert_eq!(get_host_dir_id(&config, "backups", &client).unwrap(), id);
+        let host_dirs = drive
+            .files()
+            .into_iter()
+            .filter(|(_, f)| f.name == "laptop");
+        assert_eq!(host_dirs.count(), 1);
+    }
+
+    #[test]
+    fn ca_bundle_from_the_config_is_used_by_the_client() {
+        let dir = testing::temp_dir("ca-bundle");
+        fs::write(
+            dir.join("creds.toml"),
+            "client_id = \"id\"\nclient_secret = \"secret\"",
+        )
+        .unwrap();
+        fs::write(dir.join("session.toml"), "access_token = \"token\"").unwrap();
+        let bundle = dir.join("ca.pem");
+        let config: DriveConfig =
+            toml::from_str(&format!("dir = \"\"\nca_bundle = {:?}", bundle)).unwrap();
+
+        fs::write(&bundle, include_str!("test_ca.pem")).unwrap();
+        assert!(setup_client(&dir, &config, &Recording::Off).is_ok());
+
+        // Bundle that can't be used fails the setup instead of being ignored
+        fs::write(&bundle, "not a certificate").unwrap();
+        let error = || {
+            format!(
+                "{:#}",
+                setup_client(&dir, &config, &Recording::Off).err().unwrap()
+            )
+        };
+        assert!(error().contains("No certificates found in the CA bundle"));
+        fs::remove_file(&bundle).unwrap();
+        assert!(error().contains("Unable to read the CA bundle"));
+    }
+
+    #[test]
+    fn corrupt_versions_are_set_aside_and_files_adopted() {
+        let dir = testing::temp_dir("corrupt-versions");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        fs::write(local.join("a.txt"), b"a").unwrap();
+        fs::write(dir.join("versions.json"), "{\"a\": {\"is_folder\": tru").unwrap();
+        let drive = FakeDrive::new();
+        drive.add_file("a", ROOT_ID, "a.txt", b"a");
+
+        let config = testing::config(&local, "");
+        let versions = open_versions(&dir, &config, &drive.client(), ROOT_ID).unwrap();
+        let mut versions = versions.lock().unwrap();
+        assert!(versions.was_reset());
+        assert_eq!(
+            fs::read_to_string(dir.join("versions.json.corrupt")).unwrap(),
+            "{\"a\": {\"is_folder\": tru"
+        );
+        // Local file is the same as on the drive, so it's not downloaded again
+        let v_list = versions.list().unwrap();
+        assert_eq!(v_list.len(), 1);
+        assert_eq!(v_list["a"].path, local.join("a.txt").display().to_string());
+    }
+
+    #[test]
+    fn first_sync_into_a_non_empty_dir_is_confirmed() {
+        let dir = testing::temp_dir("first-sync");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_file("a", ROOT_ID, "a.txt", b"a");
+        let open = |config: &AppConfig, answer: bool| {
+            let mut asked = false;
+            let res = open_versions_asking(&dir, config, &drive.client(), ROOT_ID, |_| {
+                asked = true;
+                answer
+            });
+            (res.map(|v| v.lock().unwrap().list().unwrap()), asked)
+        };
+
+        // Nothing to mix the drive files with
+        let config = testing::config(&local, "");
+        let (v_list, asked) = open(&config, false);
+        assert!(v_list.unwrap().is_empty());
+        assert!(!asked);
+
+        fs::write(local.join("a.txt"), b"a").unwrap();
+        let (res, asked) = open(&config, false);
+        assert!(asked);
+        assert!(format!("{:#}", res.err().unwrap()).contains("wasn't confirmed"));
+
+        let (v_list, asked) = open(&config, true);
+        assert!(asked);
+        assert!(v_list.unwrap().is_empty());
+
+        // Files that are the same as on the drive are adopted without asking
+        let adopt = testing::config(&local, "adopt_local_files = true");
+        let (v_list, asked) = open(&adopt, false);
+        assert!(!asked);
+        assert_eq!(
+            v_list.unwrap()["a"].path,
+            util::path_string(&local.join("a.txt"))
+        );
+    }
+
+    #[test]
+    fn cached_root_id_resolves_after_a_rename() {
+        let dir = testing::temp_dir("renamed-root");
+        let drive = FakeDrive::new();
+        drive.add_folder("docs", ROOT_ID, "Documents");
+        let mut client = drive.client();
+        let config: DriveConfig = toml::from_str("dir = \"Documents\"").unwrap();
+        assert_eq!(
+            get_remote_dir_id(&dir, &config, &mut client).unwrap(),
+            "docs"
+        );
+
+        drive.change("docs", |f| f.name = "Papers".to_string());
+        drive.clear_requests();
+        assert_eq!(
+            get_remote_dir_id(&dir, &config, &mut client).unwrap(),
+            "docs"
+        );
+        // Found by the cached id, not looked up by the name
+        assert_eq!(drive.requests().len(), 1, "{:?}", drive.requests());
+        let cached = files::read_toml::<RemoteRoot>(dir.join("remote_root.toml")).unwrap();
+        assert_eq!(cached.dir, "Documents");
+        assert_eq!(cached.name.as_deref(), Some("Papers"));
+        assert_eq!(
+            get_remote_dir_id(&dir, &config, &mut client).unwrap(),
+            "docs"
+        );
+    }
+}
@@ -0,0 +1,135 @@
+/*
+    Terminal dashboard for the machines without the tray. Daemon doesn't share its state with other
+    processes, so only what it saves to the config dir is shown: whether it's running, changes
+    waiting for upload, usage of the daily budget and expiry of the access token.
+*/
+use crate::{
+    auth::Creds,
+    files,
+    google_drive::{budget::Budget, Client, Session},
+    sync::{conf_dir, lock::RunLock, pending::PendingOps, read_config},
+};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use clap::ArgMatches;
+use std::{
+    io::{self, BufRead},
+    path::Path,
+    sync::mpsc::{channel, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_REFRESH: Duration = Duration::from_secs(2);
+/// Token is checked with a request to Google, so it's done less often than the redraws
+const TOKEN_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const MIB: u64 = 1024 * 1024;
+
+pub fn run(m: &ArgMatches) -> Result<()> {
+    let conf_dir = conf_dir()?;
+    let refresh = match m.value_of("interval") {
+        Some(secs) => Duration::from_secs(
+            secs.parse::<u64>()
+                .with_context(|| format!("Invalid value for --interval: {:?}", secs))?,
+        ),
+        None => DEFAULT_REFRESH,
+    };
+
+    // Terminal is not switched to the raw mode, so `q` is read once Enter is pressed
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(l) if l.trim() == "q" => break,
+                Ok(_) => continue,
+                Err(_) => break,
+            }
+        }
+        tx.send(()).ok();
+    });
+
+    let mut token_expiry = None;
+    let mut token_checked: Option<Instant> = None;
+    loop {
+        if !matches!(token_checked, Some(at) if at.elapsed() < TOKEN_CHECK_INTERVAL) {
+            token_expiry = Some(check_token(&conf_dir));
+            token_checked = Some(Instant::now());
+        }
+
+        draw(&conf_dir, token_expiry.as_deref().unwrap_or("unknown"));
+
+        match rx.recv_timeout(refresh) {
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => return Ok(()),
+        }
+    }
+}
+
+fn draw(conf_dir: &Path, token_expiry: &str) {
+    // Clear the screen and move the cursor to the top left corner
+    print!("\x1b[2J\x1b[H");
+    println!("Ocean Drive monitor ({})\n", Local::now().format("%T"));
+
+    match RunLock::holder(conf_dir.join("run.lock")) {
+        Some(pid) => println!("Daemon: running (PID {})", pid),
+        None => println!("Daemon: not running"),
+    }
+
+    let pending = PendingOps::load(conf_dir.join("pending.json"));
+    println!("Changes waiting for upload: {}", pending.len());
+
+    let config = read_config(conf_dir, None).ok();
+    let (max_calls, max_bytes) = config.as_ref().map_or((None, None), |c| {
+        (c.drive.max_api_calls_per_day, c.drive.max_bytes_per_day)
+    });
+    let (calls, bytes) = Budget::load(conf_dir.join("budget.json"), max_calls, max_bytes).used();
+    println!("API calls today: {}", with_limit(calls, max_calls));
+    println!(
+        "Transferred today: {} MiB",
+        with_limit(bytes / MIB, max_bytes.map(|b| b / MIB))
+    );
+
+    println!("Access token: {}", token_expiry);
+    println!("\nType q and press Enter to exit");
+}
+
+fn with_limit(used: u64, max: Option<u64>) -> String {
+    match max {
+        Some(max) => format!("{} of {}", used, max),
+        None => used.to_string(),
+    }
+}
+
+/// Describes when the saved access token expires. Daemon refreshes it, so the new one is read
+/// every time
+fn check_token(conf_dir: &Path) -> String {
+    let session = match files::read_toml::<Session>(conf_dir.join("session.toml")) {
+        Ok(s) => s,
+        Err(_) => return "not authorized".to_string(),
+    };
+    let creds = match files::read_toml::<Creds>(conf_dir.join("creds.toml")) {
+        Ok(c) => c,
+        Err(_) => return "unknown (no credentials)".to_string(),
+    };
+
+    let mut client = match Client::new(
+        creds.client_id.clone(),
+        creds.client_secret.clone(),
+        "http://localhost:8080".to_string(),
+    ) {
+        Ok(c) => c,
+        Err(_) => return "unknown (no HTTP client)".to_string(),
+    };
+    client.set_session(session);
+
+    match client.get_token_info() {
+        Ok(info) => match info.expires_in.and_then(|s| s.parse::<i64>().ok()) {
+            Some(secs) => {
+                let at: DateTime<Local> = Local::now() + chrono::Duration::seconds(secs);
+                format!("expires at {}", at.format("%T"))
+            }
+            None => "valid".to_string(),
+        },
+        Err(_) => "expired or revoked, the daemon will refresh it".to_string(),
+    }
+}
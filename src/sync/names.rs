@@ -0,0 +1,28 @@
+/*
+    Unicode normalization of the file names. The same name can be written with composed or
+    decomposed accents (e.g. by macOS), so names are brought to one form before they become
+    local paths, otherwise such files are downloaded twice.
+*/
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum NameNormalization {
+    /// Composed form ("é" is a single character), the one that Linux tools and Drive usually use
+    Nfc,
+    /// Decomposed form ("é" is "e" followed by a combining accent), the one that macOS uses
+    Nfd,
+    /// Names are kept as they are on the drive
+    None,
+}
+
+impl NameNormalization {
+    pub fn apply(self, name: &str) -> String {
+        match self {
+            NameNormalization::Nfc => name.nfc().collect(),
+            NameNormalization::Nfd => name.nfd().collect(),
+            NameNormalization::None => name.to_string(),
+        }
+    }
+}
@@ -0,0 +1,68 @@
+/*
+    Queue of local changes that weren't uploaded because Drive was unreachable.
+    It's saved to a file, so changes are not lost if the app is restarted while offline.
+*/
+use crate::files;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, fs, path::PathBuf};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Operation {
+    /// File or directory was created or modified
+    Write(PathBuf),
+    Rename(PathBuf, PathBuf),
+    Delete(PathBuf),
+}
+
+pub struct PendingOps {
+    path: PathBuf,
+    ops: VecDeque<Operation>,
+}
+
+impl PendingOps {
+    /// Loads the queue from the file. Missing or malformed file means there's nothing to do
+    pub fn load(path: PathBuf) -> Self {
+        let ops = files::read_json(path.clone()).unwrap_or_default();
+
+        Self { path, ops }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    #[cfg(feature = "monitor")]
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn front(&self) -> Option<&Operation> {
+        self.ops.front()
+    }
+
+    pub fn push(&mut self, op: Operation) -> Result<()> {
+        self.ops.push_back(op);
+        self.save()
+    }
+
+    /// Removes the first operation when it's done
+    pub fn pop(&mut self) -> Result<()> {
+        self.ops.pop_front();
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let content = serde_json::to_string(&self.ops)?;
+
+        if let Err(e) = fs::write(&self.path, content) {
+            bail!(
+                "Failed to save pending changes to file {:?}.\nDetails: {}",
+                self.path.display(),
+                e
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+/*
+    Pauses syncing when the laptop runs on a low battery or the system is busy. The state is read
+    from /sys and /proc, so on other systems syncing is never paused. Syncing is also paused during
+    the bandwidth windows with `pause = true`.
+*/
+use crate::{google_drive::bandwidth::Bandwidth, setup::Config};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+const POWER_SUPPLIES: &str = "/sys/class/power_supply";
+
+#[derive(Clone, Default)]
+pub struct PowerLimits {
+    /// Pause when running on battery with less charge than this (percents)
+    battery_below: Option<u8>,
+    /// Pause when the 1-minute load average per CPU is above this
+    load_above: Option<f32>,
+    bandwidth: Option<Arc<Bandwidth>>,
+    /// Where the batteries are looked up
+    power_supplies: PathBuf,
+}
+
+impl PowerLimits {
+    pub fn from_config(config: &Config) -> Self {
+        let windows = config.drive.bandwidth_windows.as_deref().unwrap_or(&[]);
+        Self {
+            battery_below: config.pause_on_battery_below,
+            load_above: config.pause_on_load_above,
+            // Windows are validated when the client is set up
+            bandwidth: Bandwidth::parse(windows).ok().flatten().map(Arc::new),
+            power_supplies: PathBuf::from(POWER_SUPPLIES),
+        }
+    }
+
+    /// Why syncing should wait now, None if it doesn't have to
+    pub fn pause_reason(&self) -> Option<String> {
+        if let Some(window) = self.bandwidth.as_ref().and_then(|b| b.paused_by()) {
+            return Some(format!("bandwidth window {} pauses syncing", window));
+        }
+
+        if let Some(min) = self.battery_below {
+            if let Some(charge) = discharging_battery(&self.power_supplies) {
+                if charge < min {
+                    return Some(format!("on battery, {}% left", charge));
+                }
+            }
+        }
+
+        if let Some(max) = self.load_above {
+            if let Some(load) = load_per_cpu() {
+                if load > max {
+                    return Some(format!("system is busy, load is {:.1} per CPU", load));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+impl PowerLimits {
+    /// Limits of the config that read the batteries from `power_supplies` instead of /sys
+    pub fn with_power_supplies(config: &Config, power_supplies: PathBuf) -> Self {
+        Self {
+            power_supplies,
+            ..Self::from_config(config)
+        }
+    }
+}
+
+/// Charge of the battery in percents, if the machine runs on it
+fn discharging_battery(power_supplies: &Path) -> Option<u8> {
+    let supplies = fs::read_dir(power_supplies).ok()?;
+
+    supplies.flatten().find_map(|supply| {
+        let dir = supply.path();
+        if read_value(&dir, "type")? != "Battery" || read_value(&dir, "status")? != "Discharging" {
+            return None;
+        }
+
+        read_value(&dir, "capacity")?.parse().ok()
+    })
+}
+
+fn load_per_cpu() -> Option<f32> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    let load: f32 = loadavg.split_whitespace().next()?.parse().ok()?;
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+
+    Some(load / cpus as f32)
+}
+
+fn read_value(dir: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(dir.join(name))
+        .ok()
+        .map(|v| v.trim().to_string())
+}
@@ -0,0 +1,435 @@
+/*
+    Runs several profiles in one process. Profile is a directory in `profiles` of the config dir
+    with its own config.toml, creds.toml and session.toml. Each profile has its own client and
+    versions, so when one of them fails the others keep syncing. Profiles share the connections to
+    the API and one tray. Other commands use a single profile with `--profile NAME`.
+*/
+use crate::{
+    google_drive::Client,
+    service,
+    setup::Config,
+    sync::{
+        connect, has_display, hashes::Hashes, inflight::InFlight, local::LocalDaemon,
+        lock::RunLock, open_versions, read_config, remote::RemoteDaemon, session, start_tray, util,
+        watchdog, Synced,
+    },
+    user,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+pub fn run(show_tray: bool) -> Result<()> {
+    let profiles_dir = user::get_home()?.join(".config/ocean-drive/profiles");
+    let dirs = profile_dirs(&profiles_dir)?;
+
+    if dirs.is_empty() {
+        bail!(
+            "No profiles found in {:?}.\nTip: Profile is a directory with its own config.toml, creds.toml and session.toml",
+            profiles_dir
+        );
+    }
+
+    let recovering: watchdog::Recovering = Arc::new(Mutex::new(vec![]));
+    let (tx, rx) = channel::<(String, Result<()>)>();
+    // Locks are held until all the profiles are stopped
+    let mut locks = vec![];
+    let mut synced = vec![];
+    // Tray is shared, so it has the icon of the first profile that sets one
+    let mut icon_path = None;
+
+    for dir in dirs {
+        let name = dir.file_name().unwrap().to_string_lossy().to_string();
+
+        match start(&name, &dir, &recovering, &tx) {
+            Ok((lock, s, icon)) => {
+                info!("Profile {:?} is started", name);
+                locks.push(lock);
+                synced.push(s);
+                icon_path = icon_path.or(icon);
+            }
+            Err(e) => error!("Unable to start profile {:?}.\nDetails: {}", name, e),
+        }
+    }
+
+    if locks.is_empty() {
+        bail!("None of the profiles could be started");
+    }
+    if show_tray && cfg!(feature = "tray") && has_display() {
+        let rec = Arc::clone(&recovering);
+        let tx = tx.clone();
+        thread::Builder::new()
+            .name("tray".to_string())
+            .spawn(move || {
+                let res = start_tray(icon_path.as_deref(), synced, rec);
+                tx.send(("tray".to_string(), res)).ok();
+            })?;
+    }
+    drop(tx);
+    service::notify_ready();
+
+    // Daemon of a profile stops only when the watchdog gave up on it, other profiles still work
+    while let Some((name, res)) = session::next_stopped(&rx) {
+        if let Err(e) = res {
+            if name == "tray" {
+                warn!("Tray is not available.\nDetails: {}", e);
+                continue;
+            }
+            error!("Daemon {:?} stopped.\nDetails: {}", name, e);
+        }
+    }
+    session::print_summary();
+
+    Ok(())
+}
+
+/// Prints the profiles with a valid config, their directories and the time of the last sync
+pub fn list() -> Result<()> {
+    let profiles_dir = user::get_home()?.join(".config/ocean-drive/profiles");
+    let lines = describe(&profiles_dir)?;
+
+    if lines.is_empty() {
+        println!(
+            "No profiles found in {:?}.\nTip: Run `ocean-drive migrate profiles` to make a profile of the current config",
+            profiles_dir
+        );
+    }
+    for line in lines {
+        println!("{}", line);
+    }
+
+    Ok(())
+}
+
+/// Lines that `list` prints for the profiles in `profiles_dir`, none when there are no profiles
+fn describe(profiles_dir: &Path) -> Result<Vec<String>> {
+    let dirs = if profiles_dir.is_dir() {
+        profile_dirs(profiles_dir)?
+    } else {
+        vec![]
+    };
+
+    let mut lines = vec![];
+    for dir in dirs {
+        let name = dir.file_name().unwrap().to_string_lossy().to_string();
+        let config = match read_config(&dir, None) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!(
+                    "Profile {:?} is skipped, its config is invalid.\nDetails: {:#}",
+                    name, e
+                );
+                continue;
+            }
+        };
+
+        lines.push(name);
+        lines.push(format!("  Local: {}", config.local_dir));
+        lines.push(format!("  Remote: {}", remote_dir(&config)));
+        lines.push(format!("  Last sync: {}", last_sync(&dir)));
+    }
+
+    Ok(lines)
+}
+
+/// Profile is a directory, so its name must not lead out of the profiles dir
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        bail!("Invalid profile name {:?}", name);
+    }
+
+    Ok(())
+}
+
+/// Directory with the config and the state of the profile in `conf_dir`, `conf_dir` itself for
+/// None
+pub fn state_dir(conf_dir: &Path, profile: Option<&str>) -> Result<PathBuf> {
+    let name = match profile {
+        Some(name) => name,
+        None => return Ok(conf_dir.to_path_buf()),
+    };
+    check_name(name)?;
+
+    let dir = conf_dir.join("profiles").join(name);
+    if !dir.join("config.toml").is_file() {
+        bail!(
+            "Profile {:?} is not found in {:?}.\nTip: Run `ocean-drive profiles list` to see the profiles",
+            name,
+            conf_dir.join("profiles")
+        );
+    }
+
+    Ok(dir)
+}
+
+/// Directories of the profiles, the ones with config.toml
+fn profile_dirs(profiles_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(profiles_dir)
+        .with_context(|| format!("Unable to read profiles from {:?}", profiles_dir))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.join("config.toml").is_file())
+        .collect();
+    dirs.sort();
+
+    Ok(dirs)
+}
+
+fn remote_dir(config: &Config) -> String {
+    let drive = &config.drive;
+    let place = match &drive.shared_drive_id {
+        Some(id) => format!("shared drive {}", id),
+        None if drive.app_data.unwrap_or(false) => "app data folder".to_string(),
+        None => "My Drive".to_string(),
+    };
+
+    format!("'{}' in {}", drive.dir, place)
+}
+
+/// Versions are saved after every sync, so their file was changed by the last one
+fn last_sync(state_dir: &Path) -> String {
+    match fs::metadata(state_dir.join("versions.json")) {
+        Ok(meta) if meta.len() > 0 => meta
+            .modified()
+            .map(|t| {
+                chrono::DateTime::<chrono::Local>::from(t)
+                    .format("%F %T")
+                    .to_string()
+            })
+            .unwrap_or_else(|_| "unknown".to_string()),
+        _ => "never".to_string(),
+    }
+}
+
+/// Starts remote and local daemons of the profile in `conf_dir`. Returns what the tray shows for
+/// it and the configured tray icon
+fn start(
+    name: &str,
+    conf_dir: &Path,
+    recovering: &watchdog::Recovering,
+    tx: &Sender<(String, Result<()>)>,
+) -> Result<(RunLock, Synced, Option<String>)> {
+    let config = read_config(conf_dir, None)?;
+    let lock = RunLock::acquire(conf_dir.join("run.lock"))?;
+    let (client, remote_dir_id) = connect(&conf_dir.to_path_buf(), &config)?;
+    let account = util::lock_ref_when_free(&client).session_email();
+    let icon_path = config.tray_icon_path.clone();
+    let local_path = config.local_dir.clone();
+    let remote = spawn_daemons(
+        name,
+        config,
+        client,
+        remote_dir_id.clone(),
+        conf_dir,
+        recovering,
+        tx,
+    )?;
+
+    let synced = Synced {
+        profile: Some(name.to_string()),
+        remote,
+        remote_dir_id,
+        local_path,
+        account,
+    };
+    Ok((lock, synced, icon_path))
+}
+
+/// Starts remote and local daemons that sync `config.local_dir` with the remote directory. Their
+/// versions and pending changes are kept in `state_dir`. Daemons report to `tx` when the watchdog
+/// gave up on them. Returned daemon shares their state, it syncs on demand (e.g. from the tray)
+pub fn spawn_daemons(
+    name: &str,
+    config: Config,
+    client: Arc<Mutex<Client>>,
+    remote_dir_id: String,
+    state_dir: &Path,
+    recovering: &watchdog::Recovering,
+    tx: &Sender<(String, Result<()>)>,
+) -> Result<RemoteDaemon> {
+    let versions = open_versions(state_dir, &config, &client, &remote_dir_id)?;
+    let in_flight = InFlight::default();
+    let hashes = Hashes::default();
+
+    for kind in &["remote", "local"] {
+        let daemon_name = format!("{}/{}", name, kind);
+        let is_remote = *kind == "remote";
+        let c = config.clone();
+        let cl = Arc::clone(&client);
+        let v = Arc::clone(&versions);
+        let rdir_id = remote_dir_id.clone();
+        let pending_file = state_dir.join("pending.json");
+        let rec = Arc::clone(recovering);
+        let tx = tx.clone();
+        let in_flight = in_flight.clone();
+        let hashes = hashes.clone();
+
+        thread::Builder::new()
+            .name(daemon_name.clone())
+            .spawn(move || {
+                let res = watchdog::supervise(&daemon_name, rec, move || -> Result<()> {
+                    if is_remote {
+                        let mut d =
+                            RemoteDaemon::new(c.clone(), cl.clone(), v.clone(), rdir_id.clone())?;
+                        d.set_in_flight(in_flight.clone());
+                        d.set_hashes(hashes.clone());
+                        d.start_sync_loop()
+                    } else {
+                        let mut d = LocalDaemon::new(
+                            c.clone(),
+                            cl.clone(),
+                            v.clone(),
+                            rdir_id.clone(),
+                            pending_file.clone(),
+                        )?;
+                        d.set_in_flight(in_flight.clone());
+                        d.set_hashes(hashes.clone());
+                        d.start()
+                    }
+                });
+
+                tx.send((daemon_name, res)).ok();
+            })?;
+    }
+
+    let mut remote = RemoteDaemon::new(config, client, versions, remote_dir_id)?;
+    remote.set_in_flight(in_flight);
+    remote.set_hashes(hashes);
+    Ok(remote)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing::{self, FakeDrive, ROOT_ID};
+    use std::{env, time::Duration};
+
+    #[test]
+    fn profiles_have_their_own_state_dirs() {
+        let conf_dir = env::temp_dir().join(format!("ocean-drive-profiles-{}", std::process::id()));
+        for name in &["home", "work"] {
+            fs::create_dir_all(conf_dir.join("profiles").join(name)).unwrap();
+            fs::write(conf_dir.join("profiles").join(name).join("config.toml"), "").unwrap();
+        }
+        // Not a profile without a config
+        fs::create_dir_all(conf_dir.join("profiles/backup")).unwrap();
+
+        let dirs = profile_dirs(&conf_dir.join("profiles")).unwrap();
+        assert_eq!(
+            dirs,
+            vec![
+                conf_dir.join("profiles/home"),
+                conf_dir.join("profiles/work")
+            ]
+        );
+        assert_eq!(state_dir(&conf_dir, None).unwrap(), conf_dir);
+        assert_eq!(
+            state_dir(&conf_dir, Some("work")).unwrap(),
+            conf_dir.join("profiles/work")
+        );
+        assert!(state_dir(&conf_dir, Some("backup")).is_err());
+        assert!(state_dir(&conf_dir, Some("../profiles/work")).is_err());
+        assert!(state_dir(&conf_dir, Some("..")).is_err());
+
+        fs::remove_dir_all(&conf_dir).unwrap();
+    }
+
+    #[test]
+    fn profiles_sync_independently() {
+        let dir = testing::temp_dir("profiles-sync");
+        let recovering: watchdog::Recovering = Arc::new(Mutex::new(vec![]));
+        let (tx, _rx) = channel();
+
+        let mut locals = vec![];
+        let mut drives = vec![];
+        for name in ["home", "work"] {
+            let state = dir.join(name);
+            let local = state.join("local");
+            fs::create_dir_all(&local).unwrap();
+            let drive = FakeDrive::new();
+            drive.add_file(name, ROOT_ID, &format!("{}.txt", name), name.as_bytes());
+            // Work drive is unreachable, it must not stop the home one
+            drive.set_offline(name == "work");
+
+            let config = testing::config(&local, "");
+            let client = drive.client();
+            spawn_daemons(
+                name,
+                config,
+                client,
+                ROOT_ID.to_string(),
+                &state,
+                &recovering,
+                &tx,
+            )
+            .unwrap();
+            locals.push(local);
+            drives.push(drive);
+        }
+
+        let synced = |path: &Path| {
+            (0..100).any(|_| {
+                thread::sleep(Duration::from_millis(100));
+                path.exists()
+            })
+        };
+        assert!(synced(&locals[0].join("home.txt")));
+        assert!(!locals[1].join("work.txt").exists());
+        assert!(!locals[0].join("work.txt").exists());
+
+        drives[1].set_offline(false);
+        assert!(synced(&locals[1].join("work.txt")));
+        assert!(!locals[1].join("home.txt").exists());
+    }
+
+    #[test]
+    fn two_profiles_are_listed() {
+        let dir = testing::temp_dir("profiles-list");
+        let profiles_dir = dir.join("profiles");
+        assert!(describe(&profiles_dir).unwrap().is_empty());
+
+        let home = profiles_dir.join("home");
+        let work = profiles_dir.join("work");
+        for (profile, drive) in [
+            (&home, "dir = \"Photos\""),
+            (&work, "dir = \"\"\nshared_drive_id = \"team\""),
+        ] {
+            fs::create_dir_all(profile).unwrap();
+            let config = format!(
+                "local_dir = {:?}\n[drive]\n{}\n",
+                profile.join("sync"),
+                drive
+            );
+            fs::write(profile.join("config.toml"), config).unwrap();
+        }
+        fs::write(work.join("versions.json"), "{}").unwrap();
+        // Broken profile is skipped
+        fs::create_dir_all(profiles_dir.join("old")).unwrap();
+        fs::write(profiles_dir.join("old/config.toml"), "local_dir =").unwrap();
+
+        let lines = describe(&profiles_dir).unwrap();
+        assert_eq!(lines.len(), 8, "{:?}", lines);
+        assert_eq!(
+            lines[..4],
+            [
+                "home".to_string(),
+                format!("  Local: {}", home.join("sync").display()),
+                "  Remote: 'Photos' in My Drive".to_string(),
+                "  Last sync: never".to_string(),
+            ]
+        );
+        assert_eq!(lines[4], "work");
+        assert_eq!(
+            lines[5],
+            format!("  Local: {}", work.join("sync").display())
+        );
+        assert_eq!(lines[6], "  Remote: '' in shared drive team");
+        assert!(lines[7].starts_with("  Last sync: 20"), "{}", lines[7]);
+    }
+}
@@ -0,0 +1,178 @@
+/*
+    Removes local files that are tracked, but don't exist on the drive anymore, e.g. when they were
+    deleted while the app wasn't running and the deletion was missed.
+*/
+use crate::{
+    google_drive::Client,
+    readline,
+    sync::{
+        conf_dir, connect,
+        lock::RunLock,
+        read_config, util,
+        versions::{Versions, VersionsList},
+    },
+};
+use anyhow::{Context, Result};
+use clap::ArgMatches;
+use std::{
+    fs,
+    io::{self, IsTerminal},
+    path::Path,
+};
+
+pub fn run(m: &ArgMatches) -> Result<()> {
+    let conf_dir = conf_dir()?;
+    let config = read_config(&conf_dir, None)?;
+
+    let _lock = RunLock::acquire(conf_dir.join("run.lock"))?;
+    let (client, _) = connect(&conf_dir, &config)?;
+    let client = util::lock_ref_when_free(&client);
+    let mut versions = Versions::new(conf_dir.join("versions.json"), Path::new(&config.local_dir))?;
+    let mut v_list = versions.list()?;
+
+    let orphans = find_orphans(&client, &v_list)?;
+    if orphans.is_empty() {
+        info!("All synced files are still on the drive, nothing to prune.");
+        versions.save(v_list)?;
+        return Ok(());
+    }
+
+    println!("Files that are no longer on the drive:");
+    for (_, path) in &orphans {
+        println!("  {}", path);
+    }
+
+    let confirmed = if m.is_present("dry-run") {
+        false
+    } else if m.is_present("yes") {
+        true
+    } else if io::stdin().is_terminal() {
+        readline::confirm(&format!("Delete {} local files?", orphans.len()), false)
+    } else {
+        warn!("Nothing was deleted, since there is no terminal to confirm it. Use --yes to delete without confirmation.");
+        false
+    };
+
+    if confirmed {
+        let mut kept = vec![];
+        // Content goes before its directory, so the directory is empty by the time it's removed
+        for (id, path) in orphans.iter().rev() {
+            let removed = remove_path(Path::new(path))
+                .with_context(|| format!("Unable to delete {:?}", path))?;
+            if !removed {
+                kept.push(path);
+            }
+            v_list.remove(id);
+        }
+        info!(
+            "{} files and directories were deleted.",
+            orphans.len() - kept.len()
+        );
+
+        if !kept.is_empty() {
+            println!("\nThese directories have files that are not synced, so they were kept:");
+            for path in kept.iter().rev() {
+                println!("  {}", path);
+            }
+        }
+    }
+
+    versions.save(v_list)
+}
+
+/// Ids and paths of the tracked files that are deleted or trashed on the drive. Parent
+/// directories go before their content, so they are removed in the reverse order
+fn find_orphans(client: &Client, v_list: &VersionsList) -> Result<Vec<(String, String)>> {
+    let mut orphans = vec![];
+    for (id, v) in v_list.iter() {
+        let file = client
+            .get_file(id)
+            .with_context(|| format!("Unable to check {:?} on the drive", v.path))?;
+
+        if !matches!(file, Some(f) if !f.trashed.unwrap_or(false)) {
+            orphans.push((id.clone(), v.path.clone()));
+        }
+    }
+    orphans.sort_by(|a, b| a.1.cmp(&b.1));
+
+    Ok(orphans)
+}
+
+/// Removes the file, or the directory if it's empty. Only the tracked files are removed, so a
+/// directory with other files in it is kept and false is returned for it
+fn remove_path(path: &Path) -> io::Result<bool> {
+    let res = if path.is_dir() {
+        fs::remove_dir(path)
+    } else {
+        fs::remove_file(path)
+    };
+
+    match res {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(_) if path.is_dir() && fs::read_dir(path)?.next().is_some() => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing::{self, FakeDrive, ROOT_ID};
+    use std::env;
+
+    #[test]
+    fn directory_with_untracked_files_is_kept() {
+        let dir = env::temp_dir().join(format!("ocean-drive-prune-{}", std::process::id()));
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        fs::write(dir.join("tracked"), "").unwrap();
+        fs::write(dir.join("untracked"), "").unwrap();
+
+        assert!(remove_path(&dir.join("tracked")).unwrap());
+        assert!(remove_path(&dir.join("empty")).unwrap());
+        assert!(remove_path(&dir.join("missing")).unwrap());
+        assert!(!remove_path(&dir).unwrap());
+        assert!(dir.join("untracked").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_gone_from_the_drive_is_an_orphan() {
+        let dir = testing::temp_dir("prune-orphans");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("docs", ROOT_ID, "docs");
+        drive.add_file("kept", "docs", "kept.txt", b"kept");
+        drive.add_file("deleted", "docs", "deleted.txt", b"deleted");
+        drive.add_file("trashed", ROOT_ID, "trashed.txt", b"trashed");
+        let daemon = testing::remote_daemon(&drive, testing::config(&local, ""), &dir);
+        assert!(daemon.sync().unwrap());
+
+        // Changes made while the app wasn't running
+        drive.remove("deleted");
+        drive.change("trashed", |f| f.trashed = true);
+
+        let v_list = testing::versions(&dir, &local)
+            .lock()
+            .unwrap()
+            .list()
+            .unwrap();
+        let client = drive.client();
+        let orphans = find_orphans(&client.lock().unwrap(), &v_list).unwrap();
+        assert_eq!(
+            orphans,
+            vec![
+                (
+                    "deleted".to_string(),
+                    local.join("docs/deleted.txt").display().to_string()
+                ),
+                (
+                    "trashed".to_string(),
+                    local.join("trashed.txt").display().to_string()
+                ),
+            ]
+        );
+    }
+}
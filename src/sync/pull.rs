@@ -0,0 +1,25 @@
+/* Forces download of a single file or directory, e.g. when the local copy was damaged */
+use crate::sync::{
+    conf_dir, connect, lock::RunLock, read_config, remote::RemoteDaemon, versions::Versions,
+};
+use anyhow::Result;
+use clap::ArgMatches;
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+pub fn run(m: &ArgMatches) -> Result<()> {
+    let conf_dir = conf_dir()?;
+    let config = read_config(&conf_dir, None)?;
+
+    let _lock = RunLock::acquire(conf_dir.join("run.lock"))?;
+    let (client, remote_dir_id) = connect(&conf_dir, &config)?;
+    let versions = Arc::new(Mutex::new(Versions::new(
+        conf_dir.join("versions.json"),
+        Path::new(&config.local_dir),
+    )?));
+
+    let daemon = RemoteDaemon::new(config, client, versions, remote_dir_id)?;
+    daemon.pull(m.value_of("path").unwrap())
+}
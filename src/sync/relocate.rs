@@ -0,0 +1,166 @@
+/*
+    Moves the local root directory to another place. Versions keep absolute paths, so they are
+    rewritten to the new root, otherwise everything would be synced from scratch.
+*/
+use crate::{
+    files,
+    setup::Config,
+    sync::{conf_dir, lock::RunLock, pending::PendingOps, read_config, versions::Versions},
+};
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Error code of rename between different file systems
+const CROSS_DEVICE_ERROR: i32 = 18;
+
+pub fn run(m: &ArgMatches) -> Result<()> {
+    let conf_dir = conf_dir()?;
+    let config = read_config(&conf_dir, None)?;
+    let _lock = RunLock::acquire(conf_dir.join("run.lock"))?;
+    let new_root = std::env::current_dir()?.join(m.value_of("path").unwrap());
+
+    relocate(&conf_dir, config, new_root)
+}
+
+/// Moves the local directory of `config` to `new_root` and rewrites the state in `conf_dir`
+fn relocate(conf_dir: &Path, mut config: Config, new_root: PathBuf) -> Result<()> {
+    if !PendingOps::load(conf_dir.join("pending.json")).is_empty() {
+        bail!("Some local changes are not uploaded yet.\nTip: Run `ocean-drive run` while online to upload them first");
+    }
+
+    let old_root = PathBuf::from(&config.local_dir);
+
+    if !old_root.is_dir() {
+        bail!("Local directory {:?} does not exist", old_root.display());
+    }
+    if new_root.exists() {
+        bail!(
+            "{:?} already exists. Choose a path that doesn't exist yet",
+            new_root.display()
+        );
+    }
+    if new_root.starts_with(&old_root) {
+        bail!("Local directory can't be moved into itself");
+    }
+
+    move_dir(&old_root, &new_root)
+        .with_context(|| format!("Unable to move local directory to {:?}", new_root.display()))?;
+    // State is rewritten only when all the files are in the new place
+    if old_root.exists() || !new_root.is_dir() {
+        bail!(
+            "Local directory was not moved completely, versions are left as they were. Check {:?} and {:?}",
+            old_root.display(),
+            new_root.display()
+        );
+    }
+
+    // Relative paths already point to the new root, only the absolute ones are rewritten
+    let mut versions = Versions::new(conf_dir.join("versions.json"), &new_root)?;
+    let mut v_list = versions.list()?;
+    let mut moved = 0;
+    for v in v_list.values_mut() {
+        if let Ok(relative) = Path::new(&v.path).strip_prefix(&old_root) {
+            v.path = new_root.join(relative).display().to_string();
+            moved += 1;
+        }
+    }
+    versions.save(v_list)?;
+
+    config.local_dir = new_root.display().to_string();
+    files::write_toml(config, conf_dir.join("config.toml"))?;
+
+    info!(
+        "Local directory is moved to {:?}, {} synced files and directories are updated.",
+        new_root.display(),
+        moved
+    );
+
+    Ok(())
+}
+
+/// Renames the directory, or copies it and removes the original when it's moved to another file
+/// system
+fn move_dir(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Ok(_) => return Ok(()),
+        Err(e) if e.raw_os_error() == Some(CROSS_DEVICE_ERROR) => {}
+        Err(e) => bail!(e),
+    }
+
+    if let Err(e) = copy_dir(from, to) {
+        // Half-copied directory is not needed, the original is still in place
+        fs::remove_dir_all(to).ok();
+        bail!(e);
+    }
+    fs::remove_dir_all(from)?;
+
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            let size = fs::copy(entry.path(), &target)?;
+            if size != entry.metadata()?.len() {
+                return Err(io::Error::other(format!(
+                    "{:?} was not copied completely",
+                    entry.path()
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing::{self, FakeDrive, ROOT_ID};
+
+    #[test]
+    fn version_paths_point_to_the_moved_files() {
+        let dir = testing::temp_dir("relocate");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("docs", ROOT_ID, "docs");
+        drive.add_file("a", "docs", "a.txt", b"a");
+        let config = testing::config(&local, "");
+        assert!(testing::remote_daemon(&drive, config.clone(), &dir)
+            .sync()
+            .unwrap());
+
+        let moved = dir.join("moved");
+        relocate(&dir, config, moved.clone()).unwrap();
+
+        assert!(!local.exists());
+        let v_list = testing::versions(&dir, &moved)
+            .lock()
+            .unwrap()
+            .list()
+            .unwrap();
+        assert_eq!(v_list.len(), 2);
+        for v in v_list.values() {
+            assert!(Path::new(&v.path).starts_with(&moved), "{}", v.path);
+            assert!(Path::new(&v.path).exists(), "{}", v.path);
+        }
+        assert_eq!(
+            v_list["a"].path,
+            moved.join("docs/a.txt").display().to_string()
+        );
+        let saved: Config = files::read_toml(dir.join("config.toml")).unwrap();
+        assert_eq!(saved.local_dir, moved.display().to_string());
+    }
+}
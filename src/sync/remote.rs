@@ -2,26 +2,171 @@
     from remote to local
 */
 use crate::auth;
-use crate::google_drive::{errors::DriveError, types::File, Client};
+use crate::files;
+use crate::google_drive::{budget, errors::DriveError, types::File, Client};
+use crate::logger;
+use crate::readline;
+use crate::service;
 use crate::setup::Config;
+use crate::sync::conflicts;
+use crate::sync::crypto::{self, Cipher};
+use crate::sync::deferred::DeferredDeletions;
+use crate::sync::errors::SyncError;
+use crate::sync::folders::FolderIndex;
+use crate::sync::hashes::Hashes;
+use crate::sync::hooks::{self, SyncReport};
+use crate::sync::ignores::Ignores;
+use crate::sync::inflight::InFlight;
+use crate::sync::names::NameNormalization;
+use crate::sync::power::PowerLimits;
+use crate::sync::schedule::Schedule;
+use crate::sync::session;
 use crate::sync::util;
-use crate::sync::versions::{Version, Versions};
-use anyhow::{bail, Result};
+use crate::sync::versions::{Version, Versions, VersionsList};
+use crate::user;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use reqwest::blocking::Response;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs,
-    io::Write,
+    io::{self, IsTerminal},
+    iter, mem,
     path::{Path, PathBuf},
     str::FromStr,
     sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
 };
 
+const DEFAULT_INITIAL_SYNC_CONCURRENCY: usize = 4;
+/// Sync cycle is aborted after this time, so a stuck request doesn't block the client forever
+const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// How often battery and load are checked while syncing is paused
+const POWER_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const SHORTCUT_TYPE: &str = "application/vnd.google-apps.shortcut";
+/// With `remote_only_changes` the files modified a bit before the last sync are listed again, in
+/// case the clock of the drive is off
+const CHANGES_OVERLAP_SECS: i64 = 60;
+/// State file with the start of the last fully successful sync
+const LAST_SYNC_FILE: &str = "last_sync.json";
+/// Most levels of the parents that are checked, in case a drive gives them in a circle
+const MAX_PARENTS_DEPTH: usize = 64;
+/// Google types that can be neither downloaded nor exported to a file
+const UNSUPPORTED_TYPES: [&str; 7] = [
+    "application/vnd.google-apps.form",
+    "application/vnd.google-apps.site",
+    "application/vnd.google-apps.map",
+    "application/vnd.google-apps.fusiontable",
+    "application/vnd.google-apps.jam",
+    SHORTCUT_TYPE,
+    "application/vnd.google-apps.drive-sdk",
+];
+
+/// State of a single sync, shared by all the synced directories
+struct Cycle {
+    ignores: Ignores,
+    /// Files that are queued for download after all directories are processed
+    downloads: Vec<(File, PathBuf)>,
+    /// Process files even if they have the same version as on the last sync
+    force: bool,
+    /// Time when the cycle is aborted (never for None)
+    deadline: Option<Instant>,
+    /// Local files of the trashed ones by their ids, they are removed when all directories are
+    /// processed and the deletion limits are checked
+    removals: Vec<(String, Version)>,
+    /// Trashed files wait here for the grace period before their local files are removed (None
+    /// when there's no grace period)
+    deferred: Option<DeferredDeletions>,
+    /// Paths that files were renamed or moved to, they are not removed with the trashed files
+    /// that had the same path
+    moved_in: HashSet<PathBuf>,
+    /// Contents of the folders as they were before the sync
+    folders: FolderIndex,
+    /// Folders with a file that waits for its path to be freed, they are listed again on the
+    /// next sync
+    relisted: Vec<String>,
+    /// Targets of the directory shortcuts that are being followed, a shortcut to one of them
+    /// would lead in a circle
+    following: HashSet<String>,
+    /// Only files modified after this time are listed, see `set_since` and `remote_only_changes`
+    since: Option<DateTime<Utc>>,
+    report: SyncReport,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LastSync {
+    /// Seconds since epoch
+    started_at: i64,
+}
+
+impl LastSync {
+    /// Files modified after this are listed on the next sync
+    fn listed_since(&self) -> Option<DateTime<Utc>> {
+        Utc.timestamp_opt(self.started_at - CHANGES_OVERLAP_SECS, 0)
+            .single()
+    }
+}
+
+/// Order of the downloads in a sync, e.g. to get the recent files first during the first sync
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadOrder {
+    Newest,
+    Oldest,
+    Smallest,
+    Largest,
+    Name,
+}
+
+impl DownloadOrder {
+    /// Sorts the queue, so the first download is at the end, where the downloads are taken from
+    fn sort(&self, downloads: &mut [(File, PathBuf)]) {
+        downloads.sort_by(|(a, a_path), (b, b_path)| {
+            let order = match self {
+                DownloadOrder::Newest => b.modified_time.cmp(&a.modified_time),
+                DownloadOrder::Oldest => a.modified_time.cmp(&b.modified_time),
+                DownloadOrder::Smallest => a.content_size().cmp(&b.content_size()),
+                DownloadOrder::Largest => b.content_size().cmp(&a.content_size()),
+                DownloadOrder::Name => a_path.cmp(b_path),
+            };
+            order.reverse()
+        });
+    }
+}
+
+/// What is done with the shortcuts on the drive
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ShortcutMode {
+    /// Skipped as the other files that can't be downloaded, see `unsupported_shortcuts`
+    Skip,
+    /// Saved as `.url` files that open the target in the browser
+    Link,
+    /// Target is synced in place of the shortcut. Shortcuts to the directories that are synced
+    /// already (or contain the synced one) are skipped, so nothing is synced twice
+    Follow,
+}
+
 #[derive(Clone)]
 pub struct RemoteDaemon {
     client_ref: Arc<Mutex<Client>>,
     config: Config,
     remote_dir_id: String,
     versions_ref: Arc<Mutex<Versions>>,
+    /// Only files modified after this time are synced
+    since: Option<DateTime<Utc>>,
+    /// Decrypts downloaded files, if the passphrase is set
+    cipher: Option<Arc<Cipher>>,
+    /// Files that are downloaded are claimed here, so the local daemon doesn't upload them
+    in_flight: InFlight,
+    /// Md5 of the downloaded files is shared with the local daemon, so it doesn't hash them again
+    hashes: Hashes,
+    /// Sync only at these times instead of polling all the time
+    schedule: Option<Schedule>,
+    /// Where the downloads are kept until they are complete
+    parts_dir: PathBuf,
 }
 
 impl RemoteDaemon {
@@ -34,35 +179,167 @@ impl RemoteDaemon {
         Ok(Self {
             versions_ref,
             client_ref,
+            cipher: Cipher::load(&config)?,
+            schedule: config
+                .schedule
+                .as_deref()
+                .map(Schedule::parse)
+                .transpose()?,
             config,
             remote_dir_id,
+            since: None,
+            in_flight: InFlight::default(),
+            hashes: Hashes::default(),
+            parts_dir: user::get_home()?.join(".cache/ocean-drive/downloads"),
         })
     }
 
+    /// Makes the daemon sync only files modified after `since`. Directories are still checked,
+    /// but they are not marked as synced, so skipped files are synced by the next full sync
+    pub fn set_since(&mut self, since: DateTime<Utc>) {
+        self.since = Some(since);
+    }
+
+    /// Shares the claimed paths with the local daemon
+    pub fn set_in_flight(&mut self, in_flight: InFlight) {
+        self.in_flight = in_flight;
+    }
+
+    pub fn set_hashes(&mut self, hashes: Hashes) {
+        self.hashes = hashes;
+    }
+
+    /// Keeps the parts of the downloads in `dir` instead of the cache
+    #[cfg(test)]
+    pub fn set_parts_dir(&mut self, dir: PathBuf) {
+        self.parts_dir = dir;
+    }
+
+    /// Daily limits of the client, if they are configured
+    #[cfg(feature = "tray")]
+    pub fn budget(&self) -> Option<Arc<budget::Budget>> {
+        util::lock_ref_when_free(&self.client_ref).budget()
+    }
+
+    /// Conditions when syncing is paused to save battery or CPU
+    pub fn power_limits(&self) -> PowerLimits {
+        PowerLimits::from_config(&self.config)
+    }
+
+    /// Syncs once, retrying if the client authorization had to be updated
+    pub fn sync_once(&self) -> Result<()> {
+        while !self.sync()? {}
+
+        Ok(())
+    }
+
     pub fn start_sync_loop(&mut self) -> Result<()> {
+        let power = self.power_limits();
+        let mut paused = false;
+        if let Some(schedule) = &self.schedule {
+            wait_for(schedule);
+        }
         loop {
+            if let Some(reason) = power.pause_reason() {
+                if !paused {
+                    info!("Syncing is paused: {}", reason);
+                    paused = true;
+                }
+                service::sleep(POWER_CHECK_INTERVAL);
+                continue;
+            } else if paused {
+                info!("Syncing is resumed");
+                paused = false;
+            }
+
             match self.sync() {
                 Ok(success) => {
-                    if !success { continue }
-                },
+                    if !success {
+                        continue;
+                    }
+                }
+                // Nothing can be synced until the counters are reset
+                Err(e) if DriveError::is_over_budget(&e) => {
+                    let reset = budget::next_reset();
+                    warn!("{}. Syncing is paused until {}", e, reset.format("%F %T"));
+                    let left = (reset - chrono::Local::now()).to_std().unwrap_or_default();
+                    service::sleep(left);
+                    continue;
+                }
+                // Daemon isn't restarted for it, the next cycle goes on from where this one stopped
+                Err(e) if SyncError::is_timed_out(&e) => {
+                    warn!("{}", e);
+                    service::notify_status(&e.to_string());
+                }
                 Err(e) => bail!(e),
             }
-            std::thread::sleep(std::time::Duration::from_secs(10));
+            service::notify_alive();
+            match &self.schedule {
+                Some(schedule) => wait_for(schedule),
+                None => service::sleep(std::time::Duration::from_secs(10)),
+            }
         }
     }
 
     /// Returns wether process was succseffull of there was some issues that was handled, but
     /// synchronization wasn't finished
+    /// Hooks are run after the shared references are released
     pub fn sync(&self) -> Result<bool> {
+        let mut report = SyncReport::default();
+        let started = Instant::now();
+        let res = self.sync_cycle(&mut report);
+        // Interrupted cycle is repeated right away, so it's counted once
+        if !matches!(res, Ok(false)) {
+            session::record_cycle(started.elapsed(), &report, &res);
+        }
+
+        match &res {
+            Ok(true) => hooks::sync_complete(&self.config, &report),
+            Ok(false) => {}
+            Err(e) => hooks::sync_failed(&self.config, e),
+        }
+
+        res
+    }
+
+    fn sync_cycle(&self, report: &mut SyncReport) -> Result<bool> {
         let mut client = util::lock_ref_when_free(&self.client_ref);
         let mut versions = util::lock_ref_when_free(&self.versions_ref);
         let mut versions_list = versions.list().unwrap();
+        let local_dir = PathBuf::from_str(&self.config.local_dir).unwrap();
+        // Ignore rules are loaded every time, so changes are applied without restart
+        let mut cycle = Cycle {
+            ignores: Ignores::load(
+                &local_dir,
+                self.config.ignore.as_deref().unwrap_or(&[]),
+                self.config.sync_hidden.unwrap_or(true),
+            )?,
+            downloads: vec![],
+            force: false,
+            deadline: Some(Instant::now() + self.sync_timeout()),
+            removals: vec![],
+            deferred: self.deferred_deletions(&versions),
+            moved_in: HashSet::new(),
+            folders: FolderIndex::build(&versions_list),
+            relisted: vec![],
+            following: HashSet::new(),
+            since: self
+                .since
+                .or_else(|| self.last_sync(&versions, &versions_list)),
+            report: SyncReport::default(),
+        };
+        let tracked = versions_list.len();
+        let started = Utc::now();
+
+        let concurrency = self.download_concurrency(&versions_list);
 
         match self.sync_dir(
             &self.remote_dir_id,
-            PathBuf::from_str(&self.config.local_dir).unwrap(),
+            local_dir,
+            0,
             &client,
             &mut versions_list,
+            &mut cycle,
         ) {
             Ok(_) => {}
             Err(e) => {
@@ -71,7 +348,7 @@ impl RemoteDaemon {
                         DriveError::Unauthorized => {
                             match auth::util::update_for_shared_client(&mut client) {
                                 Ok(_) => {
-                                    println!("Info: Client authorization was updated since it was out of date.");
+                                    info!("Client authorization was updated since it was out of date.");
                                     drop(client);
                                     drop(versions);
                                     return Ok(false);
@@ -82,12 +359,43 @@ impl RemoteDaemon {
                         _ => {}
                     }
                 }
+                // Callers tell these apart, so they aren't turned into a message
+                if DriveError::is_over_budget(&e) || SyncError::is_timed_out(&e) {
+                    return Err(e);
+                }
 
                 bail!("Unable to get updates from remote.\nDetails: {}", e);
             }
         }
 
+        self.apply_removals(&client, &mut cycle, tracked, &versions_list)?;
+        relist_folders(mem::take(&mut cycle.relisted), &mut versions_list);
+
+        let deadline = cycle.deadline;
+        let complete = self.download_files(
+            &client,
+            cycle.downloads,
+            concurrency,
+            deadline,
+            &mut versions_list,
+            &mut cycle.report,
+        );
+        *report = cycle.report;
+
         versions.save(versions_list).unwrap();
+        if is_past(deadline) {
+            bail!(SyncError::CycleTimedOut(self.sync_timeout().as_secs()));
+        }
+        // Files that failed are listed again only if the next sync starts from before them
+        if complete && report.failed == 0 && self.config.remote_only_changes.unwrap_or(false) {
+            let last = LastSync {
+                started_at: started.timestamp(),
+            };
+            let path = versions.state_file(LAST_SYNC_FILE);
+            fs::write(&path, serde_json::to_string(&last)?).with_context(|| {
+                format!("Failed to save the last sync time to {:?}", path.display())
+            })?;
+        }
         // Make shared references avaliable again
         drop(versions);
         drop(client);
@@ -95,113 +403,302 @@ impl RemoteDaemon {
         Ok(true)
     }
 
+    /// Empty versions means this is the first sync, so everything is downloaded faster
+    fn download_concurrency(&self, versions_list: &HashMap<String, Version>) -> usize {
+        if versions_list.is_empty() {
+            self.config
+                .initial_sync_concurrency
+                .unwrap_or(DEFAULT_INITIAL_SYNC_CONCURRENCY)
+        } else {
+            1
+        }
+    }
+
     fn sync_dir(
         &self,
         id: &String,
         dir_path: PathBuf,
+        depth: usize,
         client: &MutexGuard<Client>,
         local_versions: &mut HashMap<String, Version>,
-    ) -> Result<()> {
+        cycle: &mut Cycle,
+    ) -> Result<Option<String>> {
+        // Versions of the queued files are already updated, so the whole cycle is dropped
+        if is_past(cycle.deadline) {
+            bail!(SyncError::CycleTimedOut(self.sync_timeout().as_secs()));
+        }
+
         let dir_info = client.get_file(&id)?;
 
         if dir_info.is_none() {
-            println!(
-                "Warn: Unable to find directory with id '{}' in your drive. Skipping it",
+            warn!(
+                "Unable to find directory with id '{}' in your drive. Skipping it",
                 &id
             );
-            return Ok(());
+            return Ok(None);
         }
 
         let dir_info = dir_info.unwrap();
-        let local_dir_info = local_versions.get(id);
+        let local_dir_info = local_versions.get(id).cloned();
 
+        // Targets of the shortcuts in the dir may be changed, even if the dir wasn't
+        let skippable = !cycle.force && !cycle.folders.has_shortcuts(id);
         // if the dir wasnt updated, then there's no need to even check this dir
-        if local_dir_info.is_some() && local_dir_info.unwrap().version == dir_info.version.unwrap()
-        {
-            return Ok(());
+        if let Some(local_dir_info) = local_dir_info.as_ref().filter(|_| skippable) {
+            if local_dir_info.version == dir_info.version.unwrap() {
+                return Ok(local_dir_info.children_hash.clone());
+            }
         }
 
-        let dir = client.list_files(Some(&format!("'{}' in parents", &id)), None)?;
+        let query = self.children_query(id, cycle.since);
+        let mut pages = client.file_pages(&query, None);
+        let mut files = pages.next().unwrap_or_else(|| Ok(vec![]))?;
+        // Folders with many children are listed by ids and versions first, so the rest of their
+        // full pages is requested only when the children changed
+        let children_hash = if pages.is_done() {
+            hash_children(files.iter().map(child_key).collect())
+        } else {
+            let mut keys = vec![];
+            for page in client.file_pages(&query, Some("files(id, version)")) {
+                keys.extend(page?.iter().map(child_key));
+            }
+            hash_children(keys)
+        };
 
-        // Files is a haspmap with key of file id and value is file
-        let mut files: HashMap<String, File> = HashMap::new();
-        dir.files.iter().for_each(|f| {
-            files.insert(f.id.as_ref().unwrap().clone(), f.clone());
-        });
+        // Folder version is also bumped by changes that are not related to its children (e.g.
+        // properties update), so skip the children if they are the same as on the last sync
+        if let Some(local_dir_info) = local_dir_info.filter(|_| skippable) {
+            if local_dir_info.children_hash.as_ref() == Some(&children_hash) {
+                return Ok(Some(children_hash));
+            }
+        }
+
+        // First page is kept, the rest follows it. The whole listing is sorted, so the order holds
+        // across the pages
+        for page in pages {
+            files.extend(page?);
+        }
+        // Listing may change between the pages, so a file can be on two of them
+        let mut seen = HashSet::new();
+        files.retain(|f| seen.insert(f.id.clone()));
+        let mut files = processing_order(files);
+        // Sort is stable, so the order within every phase is kept
+        files.sort_by_key(|f| self.operation_phase(f, &dir_path, local_versions));
 
-        for (file_id, file) in files.clone() {
+        for file in files {
+            let file_id = file.id.clone().unwrap();
+            let file = self.follow_shortcut(client, file, cycle)?;
+            // Target of the followed shortcut
+            let shortcut_target = file
+                .shortcut_details
+                .as_ref()
+                .filter(|_| !is_unsupported(&file))
+                .and_then(|s| s.target_id.clone());
             let is_folder =
                 file.mime_type.as_ref().unwrap() == "application/vnd.google-apps.folder";
-            let v = local_versions.clone();
-            let local = v.get(&file_id);
+            // Only this file's version is copied, copying all of them for every file of a
+            // big folder would be too slow
+            let v = local_versions.get(&file_id).cloned();
+            let local = v.as_ref();
+            let name = self.local_name(file.name.as_ref().unwrap());
 
-            if local.is_some() {
+            // Folders are always traversed, the included files may be anywhere in them
+            if !is_folder && !self.is_included(&file, &name) {
+                continue;
+            }
+
+            // Flattened files are never in the directory they are listed from
+            if local.is_some() && !self.flatten() {
                 let local_path = Path::new(&local.unwrap().path);
 
                 if !local_path.starts_with(&dir_path) {
-                    let updated_path = dir_path.join(file.name.as_ref().unwrap());
+                    let updated_path = dir_path.join(&name);
                     let mut updated_version = local.unwrap().clone();
-                    updated_version.path = updated_path.into_os_string().into_string().unwrap();
+                    updated_version.path = util::path_string(&updated_path);
                     local_versions.remove(&file_id);
                     local_versions.insert(file_id.clone(), updated_version);
                 }
             }
 
             // This file is new or changed
-            if local.is_none() || &local.unwrap().version != file.version.as_ref().unwrap() {
-                let name = &file.name.as_ref().unwrap();
-                let f = dir_path.join(name).to_path_buf();
-                let file_path = f.to_str().unwrap();
+            if cycle.force
+                || local.is_none()
+                || &local.unwrap().version != file.version.as_ref().unwrap()
+            {
+                let f = if self.flatten() {
+                    // Folders are not created, so their paths are only used as the place of
+                    // their content in the tree
+                    let dir = if is_folder {
+                        dir_path.clone()
+                    } else {
+                        PathBuf::from(&self.config.local_dir)
+                    };
+                    flat_path(&dir, &name, &file_id, local_versions)
+                } else {
+                    dir_path.join(&name)
+                };
+                let file_path = &util::path_string(&f);
+
+                if cycle.ignores.is_ignored(&f, is_folder) {
+                    continue;
+                }
 
                 if file.trashed.unwrap() {
                     local_versions.remove(&file_id);
-                    self.remove_from_fs(&local)?;
+                    if let Some(local) = local {
+                        // Flattened files are removed one by one, there's no local folder
+                        if local.is_folder && self.flatten() {
+                            let contents = cycle.folders.take_contents(&file_id, local_versions);
+                            cycle
+                                .removals
+                                .extend(contents.into_iter().filter(|(_, v)| !v.is_folder));
+                            continue;
+                        }
+                        // Content of the directory goes with it, its versions would be stale
+                        if local.is_folder {
+                            cycle.folders.remove_contents(
+                                &file_id,
+                                Path::new(&local.path),
+                                local_versions,
+                            );
+                        }
+                        cycle.removals.push((file_id.clone(), local.clone()));
+                    }
+                    continue;
+                }
+
+                if let Some(deferred) = &mut cycle.deferred {
+                    // Folder that became a file (or the reverse) isn't the restored one
+                    let restored =
+                        deferred.version(&file_id).map(|v| v.is_folder) == Some(is_folder);
+                    if restored && deferred.cancel(&file_id)? {
+                        info!(
+                            "{:?} was restored on the drive, it won't be deleted",
+                            file_path
+                        );
+                    }
+                }
+
+                // Directory became a file or vice versa, so the old one is removed with
+                // everything in it and the new one is synced as a completely new file
+                let local = match local {
+                    Some(l) if l.is_folder && !is_folder && self.flatten() => {
+                        let contents = cycle.folders.take_contents(&file_id, local_versions);
+                        cycle
+                            .removals
+                            .extend(contents.into_iter().filter(|(_, v)| !v.is_folder));
+                        None
+                    }
+                    Some(l) if l.is_folder != is_folder => {
+                        cycle
+                            .folders
+                            .remove_contents(&file_id, Path::new(&l.path), local_versions);
+                        cycle.removals.push((file_id.clone(), l.clone()));
+                        // Removals are applied after the traversal, and later still with the
+                        // grace period, so the old one may keep the path until then
+                        if Path::new(&l.path) == f && (is_folder || cycle.deferred.is_some()) {
+                            local_versions.remove(&file_id);
+                            cycle.relisted.push(id.clone());
+                            continue;
+                        }
+                        None
+                    }
+                    _ => local,
+                };
+
+                // Old one waits for the end of its grace period
+                let waiting = cycle
+                    .deferred
+                    .as_ref()
+                    .and_then(|d| d.version(&file_id))
+                    .filter(|w| w.is_folder != is_folder && Path::new(&w.path) == f);
+                if waiting.is_some() {
+                    cycle.relisted.push(id.clone());
                     continue;
                 }
 
                 if name.contains("/") {
-                    return Ok(());
+                    return Ok(None);
                 }
 
+                if is_folder && matches!(self.config.max_depth, Some(max) if depth >= max) {
+                    info!(
+                        "Directory {:?} is deeper than the max depth. Skipping it",
+                        file_path
+                    );
+                    continue;
+                }
+
+                if is_unsupported(&file) {
+                    let v = self.skip_unsupported(&file, id, &f, local)?;
+                    local_versions.insert(file_id, v);
+                    continue;
+                }
+
+                let mut subdir_hash = None;
+
                 // If changed we need to update existing one. We need to remove existing for it
                 if is_folder {
                     // Check directory name was changed, then just rename in on the file system
-                    if let Some(local) = local {
+                    if let Some(local) = local.filter(|_| !self.flatten()) {
                         if &local.path != file_path {
-                            match fs::rename(&local.path, file_path) {
+                            let attempts = self.fs_attempts();
+                            match util::retry_fs(attempts, || fs::rename(&local.path, file_path)) {
                                 Err(e) => bail!(
                                     "Failed to rename file {:?} to {:?}: {}",
                                     local.path,
                                     file_path,
                                     e
                                 ),
-                                Ok(_) => {}
+                                Ok(_) => {
+                                    cycle.moved_in.insert(PathBuf::from(file_path));
+                                    // Files in it are moved too, even if the folder has the
+                                    // same children and won't be synced
+                                    cycle.folders.move_contents(
+                                        &file_id,
+                                        Path::new(&local.path),
+                                        Path::new(file_path),
+                                        local_versions,
+                                    );
+                                }
                             }
                         }
                     }
 
                     // Generate a path for a subdirectory
-                    let subdir = dir_path.join(name);
-                    if !subdir.exists() {
+                    let subdir = f.clone();
+                    if !subdir.exists() && !self.flatten() {
                         fs::create_dir(subdir.clone())?;
                     }
 
                     // We go recursively for every file in the subdir
-                    self.sync_dir(&file_id, subdir, client, local_versions)?;
+                    let listed_id = shortcut_target.clone().unwrap_or(file_id.clone());
+                    cycle.following.extend(shortcut_target.clone());
+                    subdir_hash = self.sync_dir(
+                        &listed_id,
+                        subdir,
+                        depth + 1,
+                        client,
+                        local_versions,
+                        cycle,
+                    )?;
+                    cycle.following.remove(&listed_id);
                 } else {
-                    // Check if it's a new file and download it
-                    // Also re-download if we the file data has changed
-                    if local.is_none() || local.unwrap().md5 != file.md5 {
-                        let filepath = dir_path.join(&name);
-                        self.save_file(client, &file, filepath)?;
-                    }
-
                     // If the file is present, we check if it's was renamed
                     if let Some(local) = local {
                         if &local.path != file_path {
-                            fs::rename(&local.path, &file_path)?;
+                            util::retry_fs(self.fs_attempts(), || {
+                                fs::rename(&local.path, &file_path)
+                            })?;
+                            cycle.moved_in.insert(PathBuf::from(file_path));
                         }
                     }
+
+                    // Check if it's a new file and queue it for download
+                    // Also re-download if we the file data has changed
+                    if cycle.force || local.is_none() || content_changed(local.unwrap(), &file) {
+                        cycle.downloads.push((file.clone(), f.clone()));
+                    }
                 }
 
                 // If local version is present, we need to remove it before updating
@@ -209,47 +706,679 @@ impl RemoteDaemon {
                     local_versions.remove(&file_id);
                 }
 
-                let latest = Version {
-                    is_folder,
-                    md5: file.md5,
-                    parent_id: id.clone(),
-                    path: dir_path.join(name).into_os_string().into_string().unwrap(),
-                    version: file.version.as_ref().unwrap().to_string(),
+                let mut latest = version_of(&file, id, &f, subdir_hash);
+                latest.shortcut_target = shortcut_target;
+                if is_folder && cycle.since.is_some() {
+                    // Hash of a partial listing can't be compared with the full one
+                    latest.children_hash = None;
+                    // Older files were skipped, not synced, so the folder isn't synced either.
+                    // With `remote_only_changes` they were synced by the earlier syncs
+                    if self.since.is_some() {
+                        latest.version = local.map(|l| l.version.clone()).unwrap_or_default();
+                    }
+                }
+                local_versions.insert(file_id, latest);
+            }
+        }
+        Ok(Some(children_hash))
+    }
+
+    /// With `shortcuts = "follow"` the shortcut is replaced with its target, but the id, name and
+    /// place are kept, so it's synced as a file of this directory. Shortcuts that can't be
+    /// followed are returned as they are
+    fn follow_shortcut(&self, client: &Client, file: File, cycle: &Cycle) -> Result<File> {
+        if self.config.shortcuts != Some(ShortcutMode::Follow) || file.trashed.unwrap_or(false) {
+            return Ok(file);
+        }
+        let target_id = match file
+            .shortcut_details
+            .as_ref()
+            .and_then(|s| s.target_id.clone())
+        {
+            Some(id) => id,
+            None => return Ok(file),
+        };
+        let name = file.name.clone().unwrap_or_default();
+
+        let target = match client.get_file(&target_id)? {
+            Some(t) if !t.trashed.unwrap_or(false) => t,
+            _ => {
+                warn!(
+                    "Target of the shortcut {:?} is not available. Skipping it",
+                    name
+                );
+                return Ok(file);
+            }
+        };
+        if target.mime_type.as_deref() == Some("application/vnd.google-apps.folder") {
+            if cycle.following.contains(&target_id) {
+                warn!(
+                    "Shortcut {:?} leads to a directory it's in, it's not followed",
+                    name
+                );
+                return Ok(file);
+            }
+            if self.is_inside(client, &target_id, &self.remote_dir_id)?
+                || self.is_inside(client, &self.remote_dir_id, &target_id)?
+            {
+                info!(
+                    "Shortcut {:?} leads to a directory that is synced already, it's not followed",
+                    name
+                );
+                return Ok(file);
+            }
+        }
+
+        Ok(File {
+            id: file.id,
+            name: file.name,
+            trashed: file.trashed,
+            parents: file.parents,
+            shortcut_details: file.shortcut_details,
+            ..target
+        })
+    }
+
+    /// Whether the file is `ancestor` or somewhere inside of it
+    fn is_inside(&self, client: &Client, id: &str, ancestor: &str) -> Result<bool> {
+        let mut id = id.to_string();
+        for _ in 0..MAX_PARENTS_DEPTH {
+            if id == ancestor {
+                return Ok(true);
+            }
+            id = match client
+                .get_file(&id)?
+                .and_then(|f| f.parents?.into_iter().next())
+            {
+                Some(parent) => parent,
+                None => return Ok(false),
+            };
+        }
+
+        Ok(false)
+    }
+
+    /// Query for the files in the directory. Directories are always listed when only starred
+    /// files are synced, otherwise starred files in unstarred directories can't be reached. Same
+    /// for the files modified before `since`, and the trashed ones are listed too since trashing
+    /// doesn't change the modification time
+    fn children_query(&self, id: &str, since: Option<DateTime<Utc>>) -> String {
+        let mut query = format!("'{}' in parents", id);
+        if self.config.only_starred.unwrap_or(false) {
+            query.push_str(
+                " and (starred = true or mimeType = 'application/vnd.google-apps.folder')",
+            );
+        }
+        if let Some(since) = since {
+            query.push_str(&modified_after(since));
+        }
+
+        query
+    }
+
+    /// Downloads a file or a whole directory by its path relative to the remote root, even if it
+    /// wasn't changed since the last sync. Empty path means the remote root itself
+    pub fn pull(&self, path: &str) -> Result<()> {
+        let client = util::lock_ref_when_free(&self.client_ref);
+        let mut versions = util::lock_ref_when_free(&self.versions_ref);
+        let mut versions_list = versions.list()?;
+        let local_dir = PathBuf::from_str(&self.config.local_dir).unwrap();
+        let mut cycle = Cycle {
+            ignores: Ignores::load(
+                &local_dir,
+                self.config.ignore.as_deref().unwrap_or(&[]),
+                self.config.sync_hidden.unwrap_or(true),
+            )?,
+            downloads: vec![],
+            force: true,
+            deadline: None,
+            removals: vec![],
+            deferred: self.deferred_deletions(&versions),
+            moved_in: HashSet::new(),
+            folders: FolderIndex::build(&versions_list),
+            relisted: vec![],
+            following: HashSet::new(),
+            since: None,
+            report: SyncReport::default(),
+        };
+        let tracked = versions_list.len();
+
+        // Find the requested file going down from the root one name at a time
+        let mut parent_id = self.remote_dir_id.clone();
+        let mut local_path = local_dir;
+        let mut target: Option<File> = None;
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            if let Some(prev) = &target {
+                parent_id = prev.id.clone().unwrap();
+            }
+
+            let file = match client.get_file_by_name(name, Some(parent_id.clone()))? {
+                Some(f) if !f.trashed.unwrap_or(false) => f,
+                _ => bail!("No file {:?} found in the drive directory", path),
+            };
+            local_path = local_path.join(self.local_name(file.name.as_ref().unwrap()));
+            target = Some(file);
+        }
+
+        match target {
+            None => {
+                self.sync_dir(
+                    &self.remote_dir_id,
+                    local_path,
+                    0,
+                    &client,
+                    &mut versions_list,
+                    &mut cycle,
+                )?;
+            }
+            Some(file) => {
+                let file_id = file.id.clone().unwrap();
+
+                let is_folder =
+                    file.mime_type.as_deref() == Some("application/vnd.google-apps.folder");
+                if self.flatten() && !is_folder {
+                    let root = Path::new(&self.config.local_dir);
+                    let name = self.local_name(file.name.as_ref().unwrap());
+                    local_path = flat_path(root, &name, &file_id, &versions_list);
+                }
+
+                let latest = if is_folder {
+                    if !self.flatten() {
+                        fs::create_dir_all(&local_path)?;
+                    }
+                    let children_hash = self.sync_dir(
+                        &file_id,
+                        local_path.clone(),
+                        0,
+                        &client,
+                        &mut versions_list,
+                        &mut cycle,
+                    )?;
+                    version_of(&file, &parent_id, &local_path, children_hash)
+                } else if is_unsupported(&file) {
+                    let local = versions_list.get(&file_id);
+                    self.skip_unsupported(&file, &parent_id, &local_path, local)?
+                } else {
+                    cycle.downloads.push((file.clone(), local_path.clone()));
+                    version_of(&file, &parent_id, &local_path, None)
                 };
-                local_versions.insert(file_id, latest.clone());
+
+                versions_list.insert(file_id, latest);
             }
         }
 
+        self.apply_removals(&client, &mut cycle, tracked, &versions_list)?;
+        relist_folders(mem::take(&mut cycle.relisted), &mut versions_list);
+        self.download_files(
+            &client,
+            cycle.downloads,
+            1,
+            None,
+            &mut versions_list,
+            &mut cycle.report,
+        );
+        versions.save(versions_list)?;
+
+        info!(
+            "{} files were downloaded from the drive.",
+            cycle.report.downloaded.len()
+        );
+
         Ok(())
     }
 
-    fn save_file(
+    /// Files like Google Forms can't be downloaded, so they are only saved to versions (to not
+    /// retry them on every sync) and replaced with a shortcut to their page if it's configured
+    fn skip_unsupported(
         &self,
-        client: &MutexGuard<Client>,
+        file: &File,
+        parent_id: &str,
+        path: &Path,
+        local: Option<&Version>,
+    ) -> Result<Version> {
+        if local.is_none() {
+            warn!(
+                "{:?} can't be downloaded from the drive. Skipping it",
+                path.display()
+            );
+        }
+
+        let mut path = path.to_path_buf();
+        let link = file.mime_type.as_deref() == Some(SHORTCUT_TYPE)
+            && self.config.shortcuts == Some(ShortcutMode::Link);
+        if link || self.config.unsupported_shortcuts.unwrap_or(false) {
+            path = PathBuf::from(format!("{}.url", path.display()));
+
+            // Shortcut of the renamed file
+            if let Some(local) = local {
+                if Path::new(&local.path) != path && Path::new(&local.path).is_file() {
+                    fs::remove_file(&local.path)?;
+                }
+            }
+
+            if let Some(link) = &file.web_view_link {
+                fs::write(&path, format!("[InternetShortcut]\nURL={}\n", link))?;
+            }
+        }
+
+        let mut v = version_of(file, parent_id, &path, None);
+        v.unsupported = Some(true);
+
+        Ok(v)
+    }
+
+    /// Downloads queued files using `concurrency` workers until the `deadline`
+    /// Versions of the files that failed to download are removed, so they're retried on the next sync
+    /// Returns whether none of them failed or was busy
+    fn download_files(
+        &self,
+        client: &Client,
+        mut downloads: Vec<(File, PathBuf)>,
+        concurrency: usize,
+        deadline: Option<Instant>,
+        local_versions: &mut HashMap<String, Version>,
+        report: &mut SyncReport,
+    ) -> bool {
+        if let Some(order) = self.config.download_order {
+            order.sort(&mut downloads);
+        }
+        if !downloads.is_empty() {
+            logger::progress_start(downloads.len());
+        }
+        let queue = Mutex::new(downloads);
+        let failed = Mutex::new(vec![]);
+        let flagged = Mutex::new(vec![]);
+        let not_downloadable = Mutex::new(vec![]);
+        let downloaded = Mutex::new(vec![]);
+        let busy = Mutex::new(vec![]);
+
+        thread::scope(|s| {
+            for _ in 0..concurrency.max(1) {
+                s.spawn(|| loop {
+                    if is_past(deadline) || client.transfers_paused_by().is_some() {
+                        break;
+                    }
+
+                    let next = queue.lock().unwrap().pop();
+                    let (file, path) = match next {
+                        Some(d) => d,
+                        None => break,
+                    };
+
+                    // File is being uploaded, so it's downloaded on the next sync
+                    let _claim = match self.in_flight.claim(&path) {
+                        Some(c) => c,
+                        None => {
+                            busy.lock().unwrap().push(file.id.clone().unwrap());
+                            continue;
+                        }
+                    };
+
+                    match self.save_file(client, &file, path.clone()) {
+                        Ok(_) => downloaded.lock().unwrap().push(path),
+                        Err(e) if is_abusive(&e) => {
+                            warn!("File {:?} is flagged by Google Drive as malware or spam, so it's skipped.\nTip: Set `acknowledge_abuse = true` in the config to download such files anyway", path.display());
+                            flagged.lock().unwrap().push(file.id.clone().unwrap());
+                        }
+                        Err(e) if is_not_downloadable(&e) => {
+                            not_downloadable.lock().unwrap().push((file.clone(), path.clone()));
+                        }
+                        // Partial file is kept, so it goes on from there after the window
+                        Err(_) if client.transfers_paused_by().is_some() => {
+                            busy.lock().unwrap().push(file.id.clone().unwrap());
+                        }
+                        Err(e) => {
+                            error!("Failed to download file {:?}\nCause: {}", path.display(), e);
+                            failed.lock().unwrap().push(file.id.clone().unwrap());
+                        }
+                    }
+                    logger::progress_inc(file.name.as_deref().unwrap_or_default());
+                });
+            }
+        });
+        logger::progress_finish();
+
+        // Files that were not downloaded in time are the same as the failed ones
+        let skipped = queue
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|(f, _)| f.id.unwrap());
+        let failed = failed.into_inner().unwrap();
+        report.failed += failed.len();
+        report.downloaded.extend(downloaded.into_inner().unwrap());
+        let busy = busy.into_inner().unwrap();
+        let complete = failed.is_empty() && busy.is_empty();
+        for id in failed.into_iter().chain(skipped).chain(busy) {
+            relist_parents(&id, local_versions);
+            local_versions.remove(&id);
+        }
+        // Flagged files are not downloaded again until they are changed on the drive
+        for id in flagged.into_inner().unwrap() {
+            if let Some(v) = local_versions.get_mut(&id) {
+                v.unsupported = Some(true);
+            }
+        }
+        // Same for the files that Drive refuses to give out (e.g. too big to export), they are
+        // handled as the unsupported ones: skipped or replaced with a shortcut
+        for (file, path) in not_downloadable.into_inner().unwrap() {
+            let id = file.id.clone().unwrap();
+            let parent_id = match local_versions.get(&id) {
+                Some(v) => v.parent_id.clone(),
+                None => continue,
+            };
+
+            match self.skip_unsupported(&file, &parent_id, &path, None) {
+                Ok(v) => {
+                    local_versions.insert(id, v);
+                }
+                Err(e) => {
+                    error!("Failed to skip file {:?}\nCause: {}", path.display(), e);
+                    local_versions.remove(&id);
+                }
+            }
+        }
+
+        complete
+    }
+
+    /// Whether the file passes the `include_mime_prefixes` and `include_extensions` filters
+    fn is_included(&self, file: &File, name: &str) -> bool {
+        let prefixes = self.config.include_mime_prefixes.as_deref();
+        let extensions = self.config.include_extensions.as_deref();
+        if prefixes.is_none() && extensions.is_none() {
+            return true;
+        }
+
+        let mime_type = file.mime_type.as_deref().unwrap_or_default();
+        let extension = Path::new(name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default();
+
+        prefixes
+            .unwrap_or_default()
+            .iter()
+            .any(|p| mime_type.starts_with(p.as_str()))
+            || extensions.unwrap_or_default().iter().any(|e| {
+                !extension.is_empty() && e.trim_start_matches('.').eq_ignore_ascii_case(extension)
+            })
+    }
+
+    /// Name of the local file for the file on the drive, in the configured Unicode form
+    fn local_name(&self, name: &str) -> String {
+        self.config
+            .name_normalization
+            .unwrap_or(NameNormalization::None)
+            .apply(name)
+    }
+
+    fn flatten(&self) -> bool {
+        self.config.flatten.unwrap_or(false)
+    }
+
+    fn sync_timeout(&self) -> Duration {
+        self.config
+            .sync_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_SYNC_TIMEOUT)
+    }
+
+    /// Downloads file to a `.part` file in the cache dir first, so the interrupted download is
+    /// continued from where it stopped. File is written to `file_path` only if its content is valid
+    fn save_file(&self, client: &Client, file: &File, file_path: PathBuf) -> Result<()> {
+        // Local file has this content already, e.g. it was copied there. Its md5 is usually known
+        // already, so it's not even read
+        if let Some(md5) = &file.md5 {
+            if file_path.is_file() && self.hashes.of(&file_path).ok().as_ref() == Some(md5) {
+                return Ok(());
+            }
+        }
+
+        let append = self.config.append_downloads.unwrap_or(false);
+        self.download_to(client, file, file_path, append)
+    }
+
+    /// Downloads the file content to a part in the cache first, the download is resumed from it
+    /// if it was interrupted. With `append` the local file may be taken as the part, see
+    /// `start_from_local`
+    fn download_to(
+        &self,
+        client: &Client,
         file: &File,
         file_path: PathBuf,
+        append: bool,
     ) -> Result<()> {
-        let contents = client.download_file(file.id.as_ref().unwrap()).unwrap();
+        let id = file.content_id().unwrap();
+        let size = file.size.as_ref().and_then(|s| s.parse::<u64>().ok());
+        // There's nothing to request, and a range request of no content fails
+        if size == Some(0) {
+            return util::retry_fs(self.fs_attempts(), || fs::write(&file_path, b""))
+                .with_context(|| format!("Unable to create file {:?}", file_path.display()));
+        }
+        fs::create_dir_all(&self.parts_dir)?;
+        let part_path = self.parts_dir.join(format!("{}.part", id));
 
-        match fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&file_path)
-        {
-            Ok(mut file) => {
-                if let Err(e) = file.write(&contents) {
-                    bail!("Error writing to file {:?}: {}", file_path.display(), e)
+        let mut offset = fs::metadata(&part_path).map(|m| m.len()).unwrap_or(0);
+        // Part can't be as big as the file, it's left from another version of it
+        if matches!(size, Some(s) if offset >= s) {
+            offset = 0;
+        }
+        let appending = append && offset == 0 && start_from_local(file, &file_path, &part_path)?;
+        if appending {
+            offset = fs::metadata(&part_path)?.len();
+        }
+
+        let mut resp = self.request_content(client, id, offset)?;
+        if offset > 0 && !continues_at(&resp, offset, size) {
+            // Server sent the whole file or the content doesn't match the part, so start over
+            offset = 0;
+            if resp.status() != 200 {
+                resp = self.request_content(client, id, 0)?;
+            }
+        }
+
+        if !resp.status().is_success() {
+            bail!(
+                "Drive responded with status {} to the download of {:?}",
+                resp.status(),
+                file_path.display()
+            );
+        }
+
+        let mut part = if offset == 0 {
+            fs::File::create(&part_path)?
+        } else {
+            fs::OpenOptions::new().append(true).open(&part_path)?
+        };
+        match io::copy(&mut client.throttle(&mut resp), &mut part) {
+            Err(e) if util::is_disk_full(&e) => bail!(SyncError::DiskFull(part_path)),
+            res => res.with_context(|| {
+                format!("Download of {:?} was interrupted", file_path.display())
+            })?,
+        };
+        drop(part);
+
+        if let Some(md5) = &file.md5 {
+            let mut hasher = md5::Context::new();
+            io::copy(&mut fs::File::open(&part_path)?, &mut hasher)?;
+
+            if &format!("{:x}", hasher.compute()) != md5 {
+                fs::remove_file(&part_path)?;
+                if appending {
+                    // File was changed not only at the end
+                    return self.download_to(client, file, file_path, false);
                 }
+                bail!(
+                    "Downloaded content of {:?} is corrupted, it will be downloaded again",
+                    file_path.display()
+                );
+            }
+        }
 
-                Ok(())
+        // Part is copied instead of moving, since cache can be on another file system
+        let plain = if crypto::is_encrypted_file(&part_path)? {
+            let sealed = fs::read(&part_path)?;
+            crypto::unseal(self.cipher.as_deref(), &sealed, file.is_encrypted())
+                .with_context(|| format!("Unable to decrypt {:?}", file_path.display()))?
+        } else {
+            None
+        };
+        let res = if let Some(content) = &plain {
+            util::retry_fs(self.fs_attempts(), || fs::write(&file_path, content))
+        } else {
+            util::retry_fs(self.fs_attempts(), || fs::copy(&part_path, &file_path)).map(|_| ())
+        };
+        match res {
+            Err(e) if util::is_disk_full(&e) => bail!(SyncError::DiskFull(file_path)),
+            res => {
+                res.with_context(|| format!("Unable to access file {:?}", file_path.display()))?
             }
-            Err(e) => bail!(
-                "Unable to access file {:?}: {}",
-                file_path.into_os_string().into_string().unwrap(),
-                e
-            ),
         }
+        fs::remove_file(&part_path)?;
+        // Content was checked against the md5 from Drive, so the local daemon doesn't hash it
+        if let (None, Some(md5)) = (&plain, &file.md5) {
+            self.hashes.remember(&file_path, md5);
+        }
+
+        Ok(())
+    }
+
+    /// Requests file content from `from` byte. Files flagged as abusive are requested again with
+    /// the acknowledgment only if it's allowed in the config
+    fn request_content(&self, client: &Client, id: &str, from: u64) -> Result<Response> {
+        match client.download_file_from(id, from, false) {
+            Err(e) if is_abusive(&e) && self.config.acknowledge_abuse.unwrap_or(false) => {
+                warn!("File with id '{}' is flagged by Google Drive as malware or spam, downloading it anyway", id);
+                client.download_file_from(id, from, true)
+            }
+            res => res,
+        }
+    }
+
+    /// Removes local files of the trashed ones, or defers it for the grace period. Nothing is
+    /// deleted if there are too many of them, but renames and moves of the cycle are kept
+    fn apply_removals(
+        &self,
+        client: &Client,
+        cycle: &mut Cycle,
+        tracked: usize,
+        versions_list: &VersionsList,
+    ) -> Result<()> {
+        let removals: Vec<_> = mem::take(&mut cycle.removals)
+            .into_iter()
+            // Another file took the name of the trashed one, so the local file is that one now
+            .filter(|(_, removed)| !cycle.moved_in.contains(Path::new(&removed.path)))
+            .map(|(id, removed)| {
+                let entries = removed_entries(&id, &removed, &cycle.folders, versions_list);
+                (id, removed, entries)
+            })
+            .collect();
+
+        // Deferred ones are checked when their grace period is over
+        if cycle.deferred.is_none() {
+            self.check_deletes(removals.iter().map(|(_, _, n)| n).sum(), tracked)?;
+        }
+        for (id, removed, entries) in removals {
+            if let Some(deferred) = &mut cycle.deferred {
+                deferred.defer(&id, removed, entries)?;
+                continue;
+            }
+            self.remove_from_fs(&Some(&removed))?;
+            cycle.report.removed.push(PathBuf::from(&removed.path));
+        }
+
+        self.apply_deferred(client, cycle, tracked, versions_list)
+    }
+
+    /// Removes the local files of the ones that stayed in the trash for the whole grace period
+    fn apply_deferred(
+        &self,
+        client: &Client,
+        cycle: &mut Cycle,
+        tracked: usize,
+        versions_list: &VersionsList,
+    ) -> Result<()> {
+        let (deferred, grace) = match (&mut cycle.deferred, self.config.deletion_grace_secs) {
+            (Some(d), Some(secs)) => (d, Duration::from_secs(secs)),
+            _ => return Ok(()),
+        };
+
+        let mut due = vec![];
+        for (id, removed, entries) in deferred.due(grace) {
+            let restored = matches!(
+                client.get_file(&id)?,
+                Some(f) if !f.trashed.unwrap_or(false)
+                    && (f.mime_type.as_deref() == Some("application/vnd.google-apps.folder"))
+                        == removed.is_folder
+            );
+            // Path could be taken by another synced file in the meantime
+            let taken = versions_list.values().any(|v| v.path == removed.path);
+            if restored || taken {
+                deferred.cancel(&id)?;
+            } else {
+                due.push((id, removed, entries));
+            }
+        }
+
+        // They stay deferred, so they are checked again on the next cycle
+        self.check_deletes(due.iter().map(|(_, _, n)| n).sum(), tracked)?;
+        for (id, removed, _) in due {
+            self.remove_from_fs(&Some(&removed))?;
+            cycle.report.removed.push(PathBuf::from(&removed.path));
+            deferred.cancel(&id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Start of the last fully successful sync with `remote_only_changes`, so only the files
+    /// changed after it are listed. Everything is listed on the first sync
+    fn last_sync(&self, versions: &Versions, list: &VersionsList) -> Option<DateTime<Utc>> {
+        if !self.config.remote_only_changes.unwrap_or(false) || list.is_empty() {
+            return None;
+        }
+        let last: LastSync = files::read_json(versions.state_file(LAST_SYNC_FILE)).ok()?;
+
+        last.listed_since()
+    }
+
+    /// Deletions waiting for the grace period, if it's configured
+    fn deferred_deletions(&self, versions: &Versions) -> Option<DeferredDeletions> {
+        self.config
+            .deletion_grace_secs
+            .map(|_| DeferredDeletions::load(versions.state_file("deletions.json")))
+    }
+
+    /// Fails when the cycle would remove more local files than the configured limits allow, unless
+    /// it's confirmed in the terminal. A mistake on the drive (e.g. emptied shared folder) must
+    /// not wipe the local directory
+    fn check_deletes(&self, count: usize, tracked: usize) -> Result<()> {
+        let limits = (
+            self.config.max_deletes_per_cycle,
+            self.config.max_deletes_percent,
+        );
+        if !too_many_deletes(count, tracked, limits) {
+            return Ok(());
+        }
+
+        if io::stdin().is_terminal()
+            && readline::confirm(
+                &format!(
+                    "Sync would delete {} of {} local files and directories. Continue?",
+                    count, tracked
+                ),
+                false,
+            )
+        {
+            return Ok(());
+        }
+
+        bail!(
+            "Sync would delete {} of {} local files and directories, which is more than the configured limit. Nothing was deleted, other changes were synced.\nTip: Check the trash of the drive, then run `ocean-drive run --once --confirm-deletes` to allow it",
+            count,
+            tracked
+        );
     }
 
     /* Removes a file from a local root, the opposite of save_file fn */
@@ -257,15 +1386,1392 @@ impl RemoteDaemon {
         if let Some(local) = local {
             let removed_path = Path::new(&local.path);
 
-            if removed_path.exists() {
+            let res = util::retry_fs(self.fs_attempts(), || {
                 if local.is_folder {
-                    fs::remove_dir_all(&removed_path)?;
+                    fs::remove_dir_all(&removed_path)
                 } else {
-                    fs::remove_file(&removed_path)?;
+                    fs::remove_file(&removed_path)
                 }
+            });
+            // It's fine if the file was removed locally too
+            match res {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                res => res?,
             }
         }
 
         Ok(())
     }
+
+    /// Files of a directory are processed in phases: renames and moves first, then the trashed
+    /// files, then the rest. So a file can take the name of a trashed or renamed one in the same
+    /// sync. Downloads are done after all directories anyway
+    fn operation_phase(
+        &self,
+        file: &File,
+        dir_path: &Path,
+        local_versions: &HashMap<String, Version>,
+    ) -> u8 {
+        let local = file.id.as_ref().and_then(|id| local_versions.get(id));
+        let path = file
+            .name
+            .as_ref()
+            .map(|n| dir_path.join(self.local_name(n)));
+
+        match local {
+            _ if file.trashed.unwrap_or(false) => 1,
+            Some(l) if path.as_deref() != Some(Path::new(&l.path)) => 0,
+            _ => 2,
+        }
+    }
+
+    fn fs_attempts(&self) -> u32 {
+        self.config
+            .fs_attempts
+            .unwrap_or(util::DEFAULT_FS_ATTEMPTS)
+            .max(1)
+    }
+}
+
+/// Sleeps until the next scheduled sync
+fn wait_for(schedule: &Schedule) {
+    let now = chrono::Local::now();
+    if let Some(next) = schedule.next_after(now) {
+        info!("Next sync is at {}", next.format("%F %H:%M"));
+        service::sleep((next - now).to_std().unwrap_or_default());
+    }
+}
+
+/// Builds a version entry for a file that is synced to the `path`
+fn version_of(file: &File, parent_id: &str, path: &Path, children_hash: Option<String>) -> Version {
+    let is_folder = file.mime_type.as_deref() == Some("application/vnd.google-apps.folder");
+
+    Version {
+        is_folder,
+        md5: file.content_md5(),
+        parent_id: parent_id.to_string(),
+        path: path.display().to_string(),
+        version: file.version.clone().unwrap_or(String::from("1")),
+        children_hash,
+        web_link: file.web_view_link.clone(),
+        size: file.content_size(),
+        // Local file is not written yet, time is saved by the local daemon
+        mtime: None,
+        unsupported: None,
+        description: file.description.clone(),
+        modified_time: file.modified_time.clone(),
+        shortcut_target: None,
+    }
+}
+
+/// Path in `dir` for a file of the flattened tree. Name gets a number (e.g. "a (2).txt") when
+/// another synced file has it already, and the numbered name the file has is kept
+fn flat_path(dir: &Path, name: &str, id: &str, versions: &VersionsList) -> PathBuf {
+    let taken: HashSet<&str> = versions
+        .iter()
+        .filter(|(other, _)| other.as_str() != id)
+        .map(|(_, v)| v.path.as_str())
+        .collect();
+    let (stem, ext) = conflicts::split_extension(name);
+    let is_numbered = |n: &str| {
+        n.strip_prefix(stem)
+            .and_then(|n| n.strip_suffix(ext.as_str()))
+            .and_then(|n| n.strip_prefix(" (")?.strip_suffix(')'))
+            .is_some_and(|n| n.parse::<u32>().is_ok())
+    };
+
+    if let Some(own) = versions.get(id).map(|v| Path::new(&v.path)) {
+        let own_name = own.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if own.parent() == Some(dir)
+            && (own_name == name || is_numbered(own_name))
+            && !taken.contains(own.to_string_lossy().as_ref())
+        {
+            return own.to_path_buf();
+        }
+    }
+
+    iter::once(name.to_string())
+        .chain((2..).map(|n| format!("{} ({}){}", stem, n, ext)))
+        .map(|n| dir.join(n))
+        .find(|p| !taken.contains(util::path_string(p).as_str()))
+        .unwrap()
+}
+
+/// Whether the content on the drive differs from the synced one. Files that Drive gives no md5
+/// for are compared by the modification time, or by the version when it's not known either
+fn content_changed(local: &Version, file: &File) -> bool {
+    let size_changed = matches!(
+        (local.size, file.content_size()),
+        (Some(a), Some(b)) if a != b
+    );
+
+    match (&local.md5, file.content_md5()) {
+        (Some(local_md5), Some(md5)) => *local_md5 != md5 || size_changed,
+        _ if size_changed => true,
+        _ => match (&local.modified_time, &file.modified_time) {
+            (Some(local_time), Some(time)) => local_time != time,
+            _ => file.version.as_ref() != Some(&local.version),
+        },
+    }
+}
+
+/// Log-like files usually only grow, so the local file is copied to the part and only the rest of
+/// the content is requested. Encrypted files can't be appended, and files without md5 can't be
+/// checked afterwards. Returns whether the part was started
+fn start_from_local(file: &File, file_path: &Path, part_path: &Path) -> Result<bool> {
+    let size = match file.size.as_ref().and_then(|s| s.parse::<u64>().ok()) {
+        Some(s) => s,
+        None => return Ok(false),
+    };
+    if file.md5.is_none() || file.content_md5() != file.md5 {
+        return Ok(false);
+    }
+
+    match fs::metadata(file_path) {
+        Ok(meta) if meta.is_file() && meta.len() > 0 && meta.len() < size => {
+            fs::copy(file_path, part_path)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Checks that the ranged response continues the part of `offset` bytes of the file of `size`
+/// bytes. Content-Range looks like "bytes 100-999/1000"
+fn continues_at(resp: &Response, offset: u64, size: Option<u64>) -> bool {
+    if resp.status() != 206 {
+        return false;
+    }
+
+    let range = match resp
+        .headers()
+        .get("Content-Range")
+        .and_then(|h| h.to_str().ok())
+    {
+        Some(r) => r.trim_start_matches("bytes "),
+        None => return false,
+    };
+    let (span, total) = match range.split_once('/') {
+        Some(r) => r,
+        None => return false,
+    };
+    let start = span.split('-').next().and_then(|s| s.parse::<u64>().ok());
+
+    start == Some(offset)
+        && match (size, total.parse::<u64>()) {
+            (Some(size), Ok(total)) => size == total,
+            _ => true,
+        }
+}
+
+/// Query condition for the files that are changed after `since`. Folders and trashed files are
+/// listed anyway, see `children_query`
+fn modified_after(since: DateTime<Utc>) -> String {
+    format!(
+        " and (modifiedTime > '{}' or mimeType = 'application/vnd.google-apps.folder' or trashed = true)",
+        since.to_rfc3339_opts(SecondsFormat::Secs, true)
+    )
+}
+
+/// Number of the synced files and directories that the removal deletes locally. Folder goes with
+/// everything that was in it, except for the files that were moved out of it in this cycle
+fn removed_entries(
+    id: &str,
+    removed: &Version,
+    folders: &FolderIndex,
+    versions_list: &VersionsList,
+) -> usize {
+    if !removed.is_folder {
+        return 1;
+    }
+
+    let contents = folders.descendants(id);
+    1 + contents
+        .iter()
+        .filter(|c| !versions_list.contains_key(*c))
+        .count()
+}
+
+/// Whether deleting `count` of the `tracked` files goes over the limits of the count and percent
+fn too_many_deletes(count: usize, tracked: usize, limits: (Option<usize>, Option<u8>)) -> bool {
+    let (max_count, max_percent) = limits;
+    let over_count = matches!(max_count, Some(max) if count > max);
+    let over_percent = matches!(
+        max_percent,
+        Some(max) if count > 0 && count * 100 > tracked * usize::from(max)
+    );
+
+    over_count || over_percent
+}
+
+/// Folders of the file are listed again on the next sync. Otherwise the ones that didn't change
+/// on the drive are skipped and the file is never retried
+fn relist_parents(id: &str, local_versions: &mut HashMap<String, Version>) {
+    let mut parent = local_versions.get(id).map(|v| v.parent_id.clone());
+    while let Some(parent_id) = parent {
+        parent = match local_versions.get_mut(&parent_id) {
+            // Parents of a cleared folder are cleared already
+            Some(folder) if !folder.version.is_empty() || folder.children_hash.is_some() => {
+                folder.version.clear();
+                folder.children_hash = None;
+                Some(folder.parent_id.clone())
+            }
+            _ => None,
+        };
+    }
+}
+
+/// Folders and their parents are listed again on the next sync. Their versions are saved after
+/// their contents are traversed, so they are cleared only when the traversal is over
+fn relist_folders(ids: Vec<String>, local_versions: &mut HashMap<String, Version>) {
+    for id in ids {
+        if let Some(folder) = local_versions.get_mut(&id) {
+            folder.version.clear();
+            folder.children_hash = None;
+        }
+        relist_parents(&id, local_versions);
+    }
+}
+
+fn is_past(deadline: Option<Instant>) -> bool {
+    matches!(deadline, Some(d) if Instant::now() >= d)
+}
+
+/// Folders go first, then files by name, so the logs are the same on every sync and directories
+/// exist before their contents. Files with the same id are listed once
+fn processing_order(mut files: Vec<File>) -> Vec<File> {
+    files.sort_by(|a, b| {
+        let a_folder = a.mime_type.as_deref() == Some("application/vnd.google-apps.folder");
+        let b_folder = b.mime_type.as_deref() == Some("application/vnd.google-apps.folder");
+
+        b_folder
+            .cmp(&a_folder)
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    files.dedup_by(|a, b| a.id == b.id);
+
+    files
+}
+
+fn is_abusive(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<DriveError>(),
+        Some(DriveError::AbusiveFile)
+    )
+}
+
+fn is_not_downloadable(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<DriveError>(),
+        Some(DriveError::NotDownloadable)
+    )
+}
+
+fn is_unsupported(file: &File) -> bool {
+    matches!(&file.mime_type, Some(t) if UNSUPPORTED_TYPES.contains(&t.as_str()))
+}
+
+/// Builds a hash of the directory children ids and versions, so it changes only when some child
+/// was added, removed or modified
+fn hash_children(mut children: Vec<String>) -> String {
+    children.sort();
+
+    format!("{:x}", md5::compute(children.join(",")))
+}
+
+/// Identifies the state of the child for `hash_children`
+fn child_key(f: &File) -> String {
+    format!(
+        "{}:{}",
+        f.id.as_ref().unwrap_or(&String::new()),
+        f.version.as_ref().unwrap_or(&String::new())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::google_drive::transport;
+    use crate::sync::testing::{self, FakeDrive, ROOT_ID};
+    use serde_json::{json, Value};
+
+    fn version(parent_id: &str, is_folder: bool) -> Version {
+        Version {
+            is_folder,
+            parent_id: parent_id.to_string(),
+            version: "7".to_string(),
+            path: String::new(),
+            md5: None,
+            children_hash: if is_folder {
+                Some("hash".to_string())
+            } else {
+                None
+            },
+            web_link: None,
+            size: None,
+            mtime: None,
+            unsupported: None,
+            description: None,
+            shortcut_target: None,
+            modified_time: None,
+        }
+    }
+
+    #[test]
+    fn unchanged_children_are_skipped_when_folder_version_changes() {
+        let dir = testing::temp_dir("remote-children");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("dir", ROOT_ID, "dir");
+        drive.add_folder("sub", "dir", "sub");
+        drive.add_file("a", "sub", "a.txt", b"a");
+        let daemon = testing::remote_daemon(&drive, testing::config(&local, ""), &dir);
+
+        assert!(daemon.sync().unwrap());
+        assert_eq!(fs::read(local.join("dir/sub/a.txt")).unwrap(), b"a");
+
+        // Only the metadata of the folders is changed, e.g. their properties
+        drive.change("dir", |_| {});
+        drive.change(ROOT_ID, |_| {});
+        drive.clear_requests();
+        assert!(daemon.sync().unwrap());
+
+        let requests = drive.requests();
+        assert!(requests.iter().any(|r| r.contains("'dir' in parents")));
+        assert!(!requests.iter().any(|r| r.contains("/files/sub")));
+        assert!(!requests.iter().any(|r| r.contains("'sub' in parents")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn folders_deeper_than_max_depth_are_not_synced() {
+        let dir = testing::temp_dir("remote-depth");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("dir", ROOT_ID, "dir");
+        drive.add_file("a", "dir", "a.txt", b"a");
+        drive.add_folder("sub", "dir", "sub");
+        drive.add_file("b", "sub", "b.txt", b"b");
+        let config = testing::config(&local, "max_depth = 1");
+        let daemon = testing::remote_daemon(&drive, config, &dir);
+
+        assert!(daemon.sync().unwrap());
+
+        assert!(local.join("dir/a.txt").is_file());
+        assert!(!local.join("dir/sub").exists());
+        assert!(!drive
+            .requests()
+            .iter()
+            .any(|r| r.contains("'sub' in parents")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn initial_concurrency_is_used_only_on_the_first_sync() {
+        let dir = testing::temp_dir("remote-concurrency");
+        let local = dir.join("local");
+        let drive = FakeDrive::new();
+        let config = testing::config(&local, "initial_sync_concurrency = 8");
+        let daemon = testing::remote_daemon(&drive, config, &dir);
+
+        let mut versions = HashMap::new();
+        assert_eq!(daemon.download_concurrency(&versions), 8);
+        versions.insert("a".to_string(), version(ROOT_ID, false));
+        assert_eq!(daemon.download_concurrency(&versions), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parents_of_a_failed_file_are_listed_again() {
+        let mut versions = HashMap::new();
+        versions.insert("root".to_string(), version("", true));
+        versions.insert("dir".to_string(), version("root", true));
+        versions.insert("file".to_string(), version("dir", false));
+        versions.insert("other".to_string(), version("", true));
+
+        relist_parents("file", &mut versions);
+
+        for id in ["root", "dir"] {
+            assert_eq!(versions[id].version, "");
+            assert_eq!(versions[id].children_hash, None);
+        }
+        assert_eq!(versions["file"].version, "7");
+        assert_eq!(versions["other"].version, "7");
+    }
+
+    #[test]
+    fn removed_folder_counts_its_contents() {
+        let mut before = HashMap::new();
+        before.insert("dir".to_string(), version("root", true));
+        before.insert("sub".to_string(), version("dir", true));
+        before.insert("a".to_string(), version("sub", false));
+        before.insert("moved".to_string(), version("dir", false));
+        let folders = FolderIndex::build(&before);
+
+        // Only the file that was moved out of the folder is still synced
+        let mut after = HashMap::new();
+        after.insert("moved".to_string(), version("root", false));
+
+        assert_eq!(removed_entries("dir", &before["dir"], &folders, &after), 3);
+        assert_eq!(removed_entries("a", &before["a"], &folders, &after), 1);
+    }
+
+    #[test]
+    fn deletes_are_limited_by_count_and_percent() {
+        assert!(!too_many_deletes(100, 100, (None, None)));
+        assert!(!too_many_deletes(10, 100, (Some(10), None)));
+        assert!(too_many_deletes(11, 100, (Some(10), None)));
+        assert!(!too_many_deletes(50, 100, (None, Some(50))));
+        assert!(too_many_deletes(51, 100, (None, Some(50))));
+        assert!(!too_many_deletes(0, 0, (None, Some(0))));
+        assert!(too_many_deletes(1, 1000, (Some(100), Some(0))));
+    }
+
+    #[test]
+    fn files_modified_before_the_last_sync_are_not_listed() {
+        // 2024-05-01T12:00:00Z
+        let started_at = Utc.timestamp_opt(1714564800, 0).unwrap();
+        let last = LastSync {
+            started_at: started_at.timestamp(),
+        };
+
+        // Changes made while the last sync was listing are listed again
+        let since = last.listed_since().unwrap();
+        assert_eq!(
+            since,
+            started_at - chrono::Duration::seconds(CHANGES_OVERLAP_SECS)
+        );
+        assert!(
+            modified_after(since).starts_with(" and (modifiedTime > '2024-05-01T11:59:00Z' or ")
+        );
+    }
+
+    #[test]
+    fn pulled_file_is_downloaded_even_if_its_version_is_the_same() {
+        let dir = testing::temp_dir("remote-pull");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("dir", ROOT_ID, "dir");
+        drive.add_file("a", "dir", "a.txt", b"a");
+        drive.add_file("b", "dir", "b.txt", b"b");
+        let daemon = testing::remote_daemon(&drive, testing::config(&local, ""), &dir);
+        assert!(daemon.sync().unwrap());
+
+        // The local copies are damaged, but the drive versions are unchanged
+        fs::write(local.join("dir/a.txt"), b"damaged").unwrap();
+        fs::write(local.join("dir/b.txt"), b"damaged").unwrap();
+        drive.clear_requests();
+
+        daemon.pull("dir/a.txt").unwrap();
+        assert_eq!(fs::read(local.join("dir/a.txt")).unwrap(), b"a");
+        assert_eq!(fs::read(local.join("dir/b.txt")).unwrap(), b"damaged");
+        let downloads: Vec<_> = drive
+            .requests()
+            .into_iter()
+            .filter(|r| r.contains("alt=media"))
+            .collect();
+        assert_eq!(downloads.len(), 1);
+        assert!(downloads[0].contains("/files/a"));
+    }
+
+    #[test]
+    fn only_starred_files_are_listed_when_enabled() {
+        let dir = testing::temp_dir("remote-starred");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("dir", ROOT_ID, "dir");
+        drive.add_file("a", "dir", "a.txt", b"a");
+        drive.add_file("b", "dir", "b.txt", b"b");
+        drive.change("a", |f| {
+            f.fields
+                .insert("starred".to_string(), serde_json::Value::Bool(true));
+        });
+
+        let plain = testing::remote_daemon(&drive, testing::config(&local, ""), &dir);
+        assert!(!plain.children_query("dir", None).contains("starred"));
+
+        let config = testing::config(&local, "only_starred = true");
+        let daemon = testing::remote_daemon(&drive, config, &dir);
+        assert!(daemon
+            .children_query("dir", None)
+            .contains("starred = true"));
+        assert!(daemon.sync().unwrap());
+        assert!(local.join("dir/a.txt").exists());
+        assert!(!local.join("dir/b.txt").exists());
+    }
+
+    #[test]
+    fn form_is_skipped_and_not_retried() {
+        let dir = testing::temp_dir("remote-form");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_file("form", ROOT_ID, "Survey", b"");
+        drive.change("form", |f| {
+            let fields = [
+                ("mimeType", "application/vnd.google-apps.form".into()),
+                ("md5Checksum", Value::Null),
+                ("size", Value::Null),
+                ("webViewLink", "https://docs.google.com/forms/d/form".into()),
+            ];
+            for (field, value) in fields {
+                f.fields.insert(field.to_string(), value);
+            }
+        });
+        let config = testing::config(&local, "unsupported_shortcuts = true");
+        let daemon = testing::remote_daemon(&drive, config, &dir);
+
+        assert!(daemon.sync().unwrap());
+        let downloads = |drive: &FakeDrive| {
+            drive
+                .requests()
+                .iter()
+                .filter(|r| r.contains("alt=media") || r.contains("/export"))
+                .count()
+        };
+        assert_eq!(downloads(&drive), 0);
+        assert_eq!(
+            fs::read_to_string(local.join("Survey.url")).unwrap(),
+            "[InternetShortcut]\nURL=https://docs.google.com/forms/d/form\n"
+        );
+        let v_list = testing::versions(&dir, &local)
+            .lock()
+            .unwrap()
+            .list()
+            .unwrap();
+        assert_eq!(v_list["form"].unsupported, Some(true));
+
+        // The form is changed, but it's still not downloaded
+        drive.change("form", |_| {});
+        assert!(daemon.sync().unwrap());
+        assert_eq!(downloads(&drive), 0);
+    }
+
+    #[test]
+    fn download_restarts_when_the_part_size_is_wrong() {
+        let dir = testing::temp_dir("remote-part");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let parts_dir = dir.join("downloads");
+        fs::create_dir_all(&parts_dir).unwrap();
+        let drive = FakeDrive::new();
+
+        // Part is left from a longer version of the file
+        drive.add_file("stale-part", ROOT_ID, "a.txt", b"short");
+        fs::write(parts_dir.join("stale-part.part"), b"longer content").unwrap();
+        // Part continues the file, but the drive reports another size than the listing
+        drive.add_file("wrong-total", ROOT_ID, "b.txt", b"abcdef");
+        drive.change("wrong-total", |f| {
+            f.fields.insert("size".to_string(), "7".into());
+        });
+        fs::write(parts_dir.join("wrong-total.part"), b"xyz").unwrap();
+
+        let daemon = testing::remote_daemon(&drive, testing::config(&local, ""), &dir);
+        assert!(daemon.sync().unwrap());
+        assert_eq!(fs::read(local.join("a.txt")).unwrap(), b"short");
+        assert_eq!(fs::read(local.join("b.txt")).unwrap(), b"abcdef");
+        assert!(!parts_dir.join("stale-part.part").exists());
+        assert!(!parts_dir.join("wrong-total.part").exists());
+    }
+
+    #[test]
+    fn files_modified_before_since_are_ignored() {
+        let dir = testing::temp_dir("remote-since");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("dir", ROOT_ID, "dir");
+        drive.add_file("old", "dir", "old.txt", b"old");
+        drive.add_file("new", "dir", "new.txt", b"new");
+        drive.change("new", |f| {
+            let time = "2024-06-01T00:00:00.000Z".into();
+            f.fields.insert("modifiedTime".to_string(), time);
+        });
+
+        let mut daemon = testing::remote_daemon(&drive, testing::config(&local, ""), &dir);
+        let since = util::parse_since("2024-03-01").unwrap();
+        assert!(daemon
+            .children_query("dir", Some(since))
+            .contains("modifiedTime > '2024-03-01T00:00:00Z'"));
+
+        daemon.set_since(since);
+        assert!(daemon.sync().unwrap());
+        assert!(local.join("dir/new.txt").exists());
+        assert!(!local.join("dir/old.txt").exists());
+    }
+
+    #[test]
+    fn folder_that_becomes_a_file_replaces_the_local_one() {
+        let dir = testing::temp_dir("remote-flip");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("x", ROOT_ID, "x");
+        drive.add_file("a", "x", "a.txt", b"a");
+        let daemon = testing::remote_daemon(&drive, testing::config(&local, ""), &dir);
+        assert!(daemon.sync().unwrap());
+        assert!(local.join("x/a.txt").is_file());
+
+        drive.remove("a");
+        drive.change("x", |f| {
+            f.folder = false;
+            f.content = b"file".to_vec();
+        });
+        assert!(daemon.sync().unwrap());
+        assert_eq!(fs::read(local.join("x")).unwrap(), b"file");
+        let v_list = testing::versions(&dir, &local)
+            .lock()
+            .unwrap()
+            .list()
+            .unwrap();
+        assert!(!v_list["x"].is_folder);
+
+        drive.change("x", |f| {
+            f.folder = true;
+            f.content = vec![];
+        });
+        drive.add_file("b", "x", "b.txt", b"b");
+        // Old file is removed after the traversal, so the folder is created on the next sync
+        assert!(daemon.sync().unwrap());
+        assert!(!local.join("x").exists());
+        assert!(daemon.sync().unwrap());
+        assert_eq!(fs::read(local.join("x/b.txt")).unwrap(), b"b");
+        let v_list = testing::versions(&dir, &local)
+            .lock()
+            .unwrap()
+            .list()
+            .unwrap();
+        assert!(v_list["x"].is_folder);
+        assert!(!v_list.contains_key("a"));
+    }
+
+    #[test]
+    fn decomposed_name_is_not_downloaded_twice() {
+        let dir = testing::temp_dir("remote-nfc");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let (composed, decomposed) = ("caf\u{e9}.txt", "cafe\u{301}.txt");
+        let drive = FakeDrive::new();
+        drive.add_file("a", ROOT_ID, decomposed, b"a");
+        let config = testing::config(&local, "name_normalization = \"nfc\"");
+        let daemon = testing::remote_daemon(&drive, config, &dir);
+        assert!(daemon.sync().unwrap());
+        assert_eq!(fs::read(local.join(composed)).unwrap(), b"a");
+
+        // Another client writes the name in the other form, it's still the same file
+        drive.change("a", |f| f.name = composed.to_string());
+        drive.clear_requests();
+        assert!(daemon.sync().unwrap());
+        assert!(!drive.requests().iter().any(|r| r.contains("alt=media")));
+        assert_eq!(fs::read_dir(&local).unwrap().count(), 1);
+        assert!(local.join(composed).is_file());
+    }
+
+    #[test]
+    fn replayed_sync_makes_the_same_decisions() {
+        use crate::google_drive::transport::{Recorder, Replayer};
+
+        let dir = testing::temp_dir("remote-replay");
+        let recording = dir.join("recording");
+        let drive = FakeDrive::new();
+        drive.add_folder("dir", ROOT_ID, "dir");
+        drive.add_file("a", "dir", "a.txt", b"a");
+        drive.add_file("b", ROOT_ID, "b.txt", b"b");
+        drive.add_file("ignored", ROOT_ID, "c.tmp", b"c");
+
+        let sync = |name: &str, client: Client| {
+            let state = dir.join(name);
+            let local = state.join("local");
+            fs::create_dir_all(&local).unwrap();
+            let config = testing::config(&local, "ignore = [\"*.tmp\"]");
+            let versions = testing::versions(&state, &local);
+            let client = Arc::new(Mutex::new(client));
+            let mut daemon =
+                RemoteDaemon::new(config, client, versions.clone(), ROOT_ID.to_string()).unwrap();
+            daemon.set_parts_dir(state.join("downloads"));
+            assert!(daemon.sync().unwrap());
+
+            let mut synced: Vec<_> = versions
+                .lock()
+                .unwrap()
+                .list()
+                .unwrap()
+                .into_iter()
+                .map(|(id, v)| {
+                    let path = Path::new(&v.path).strip_prefix(&local).unwrap().to_owned();
+                    (id, v.version, path)
+                })
+                .collect();
+            synced.sort();
+            (synced, fs::read(local.join("dir/a.txt")).unwrap())
+        };
+
+        let recorder = Recorder::new(drive.transport(), recording.clone()).unwrap();
+        let recorded = sync("recorded", Client::with_transport(recorder));
+        // Drive is changed after the recording, but the replay sees it as it was
+        drive.change("b", |f| f.content = b"changed".to_vec());
+        let replayer = Replayer::load(recording).unwrap();
+        let replayed = sync("replayed", Client::with_transport(replayer));
+
+        assert_eq!(recorded.0.len(), 3);
+        assert_eq!(recorded, replayed);
+    }
+
+    #[test]
+    fn flagged_file_is_downloaded_only_when_acknowledged() {
+        let dir = testing::temp_dir("remote-abuse");
+        let drive = FakeDrive::new();
+        drive.add_file("bad", ROOT_ID, "bad.exe", b"bad");
+        drive.change("bad", |f| {
+            let error = r#"{"error": {"errors": [{"reason": "cannotDownloadAbusiveFile"}]}}"#;
+            f.download_error = Some(error.to_string());
+        });
+        let media = |drive: &FakeDrive| {
+            drive
+                .requests()
+                .iter()
+                .filter(|r| r.contains("alt=media"))
+                .count()
+        };
+
+        let skipping = dir.join("skipping");
+        let local = skipping.join("local");
+        fs::create_dir_all(&local).unwrap();
+        let daemon = testing::remote_daemon(&drive, testing::config(&local, ""), &skipping);
+        daemon.sync().unwrap();
+        assert!(!local.join("bad.exe").exists());
+        let v_list = testing::versions(&skipping, &local)
+            .lock()
+            .unwrap()
+            .list()
+            .unwrap();
+        assert_eq!(v_list["bad"].unsupported, Some(true));
+        // The decision is kept, so the file isn't requested on every sync
+        drive.clear_requests();
+        daemon.sync().unwrap();
+        assert_eq!(media(&drive), 0);
+
+        let acknowledging = dir.join("acknowledging");
+        let local = acknowledging.join("local");
+        fs::create_dir_all(&local).unwrap();
+        let config = testing::config(&local, "acknowledge_abuse = true");
+        let daemon = testing::remote_daemon(&drive, config, &acknowledging);
+        assert!(daemon.sync().unwrap());
+        assert_eq!(fs::read(local.join("bad.exe")).unwrap(), b"bad");
+    }
+
+    #[test]
+    fn listing_is_processed_in_a_fixed_order() {
+        let folder = "application/vnd.google-apps.folder";
+        let listing: Vec<File> = serde_json::from_value(serde_json::json!([
+            {"id": "3", "name": "b.txt", "mimeType": "text/plain"},
+            {"id": "5", "name": "photos", "mimeType": folder},
+            {"id": "1", "name": "a.txt", "mimeType": "text/plain"},
+            {"id": "4", "name": "docs", "mimeType": folder},
+            {"id": "2", "name": "a.txt", "mimeType": "text/plain"},
+            // Listed on two pages at once
+            {"id": "1", "name": "a.txt", "mimeType": "text/plain"},
+        ]))
+        .unwrap();
+
+        let order = |files: Vec<File>| -> Vec<String> {
+            processing_order(files)
+                .into_iter()
+                .map(|f| f.id.unwrap())
+                .collect()
+        };
+        assert_eq!(order(listing.clone()), vec!["4", "5", "1", "2", "3"]);
+        let mut reversed = listing;
+        reversed.reverse();
+        assert_eq!(order(reversed), vec!["4", "5", "1", "2", "3"]);
+    }
+
+    #[test]
+    fn too_big_export_falls_back_to_the_configured_handling() {
+        let dir = testing::temp_dir("remote-export-limit");
+        let drive = FakeDrive::new();
+        drive.add_file("sheet", ROOT_ID, "Budget", b"");
+        drive.change("sheet", |f| {
+            let error = r#"{"error": {"errors": [{"reason": "exportSizeLimitExceeded"}]}}"#;
+            f.download_error = Some(error.to_string());
+            let fields = [
+                ("mimeType", "application/vnd.google-apps.spreadsheet".into()),
+                ("md5Checksum", Value::Null),
+                ("size", Value::Null),
+                (
+                    "webViewLink",
+                    "https://docs.google.com/spreadsheets/d/sheet".into(),
+                ),
+            ];
+            for (field, value) in fields {
+                f.fields.insert(field.to_string(), value);
+            }
+        });
+
+        let skipping = dir.join("skipping");
+        let local = skipping.join("local");
+        fs::create_dir_all(&local).unwrap();
+        let daemon = testing::remote_daemon(&drive, testing::config(&local, ""), &skipping);
+        assert!(daemon.sync().unwrap());
+        assert!(!local.join("Budget").exists());
+        assert!(!local.join("Budget.url").exists());
+        let v_list = testing::versions(&skipping, &local)
+            .lock()
+            .unwrap()
+            .list()
+            .unwrap();
+        assert_eq!(v_list["sheet"].unsupported, Some(true));
+        // The outcome is recorded, so the export isn't attempted again
+        drive.clear_requests();
+        assert!(daemon.sync().unwrap());
+        assert!(!drive.requests().iter().any(|r| r.contains("alt=media")));
+
+        let linking = dir.join("linking");
+        let local = linking.join("local");
+        fs::create_dir_all(&local).unwrap();
+        let config = testing::config(&local, "unsupported_shortcuts = true");
+        let daemon = testing::remote_daemon(&drive, config, &linking);
+        assert!(daemon.sync().unwrap());
+        assert!(!local.join("Budget").exists());
+        assert_eq!(
+            fs::read_to_string(local.join("Budget.url")).unwrap(),
+            "[InternetShortcut]\nURL=https://docs.google.com/spreadsheets/d/sheet\n"
+        );
+    }
+
+    #[test]
+    fn renamed_folder_makes_way_for_a_new_one() {
+        let dir = testing::temp_dir("remote-rename-order");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("old", ROOT_ID, "Photos");
+        drive.add_file("a", "old", "a.jpg", b"a");
+        let daemon = testing::remote_daemon(&drive, testing::config(&local, ""), &dir);
+        assert!(daemon.sync().unwrap());
+
+        // New folder takes the name of the renamed one and goes first by its name
+        drive.change("old", |f| f.name = "Pictures".to_string());
+        drive.add_folder("new", ROOT_ID, "Photos");
+        drive.add_file("b", "new", "b.jpg", b"b");
+        assert!(daemon.sync().unwrap());
+
+        assert_eq!(fs::read(local.join("Pictures/a.jpg")).unwrap(), b"a");
+        assert_eq!(fs::read(local.join("Photos/b.jpg")).unwrap(), b"b");
+        assert!(!local.join("Photos/a.jpg").exists());
+        assert!(!local.join("Pictures/b.jpg").exists());
+    }
+
+    #[test]
+    fn only_images_are_downloaded_from_a_mixed_folder() {
+        let dir = testing::temp_dir("remote-include-mime");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("trip", ROOT_ID, "Trip");
+        let files = [
+            ("photo", ROOT_ID, "photo.jpg", "image/jpeg"),
+            ("notes", ROOT_ID, "notes.txt", "text/plain"),
+            ("shot", "trip", "shot.png", "image/png"),
+            ("video", "trip", "clip.mp4", "video/mp4"),
+        ];
+        for (id, parent, name, mime_type) in files {
+            drive.add_file(id, parent, name, name.as_bytes());
+            drive.change(id, |f| {
+                f.fields.insert("mimeType".to_string(), mime_type.into());
+            });
+        }
+        let config = testing::config(&local, "include_mime_prefixes = [\"image/\"]");
+        let daemon = testing::remote_daemon(&drive, config, &dir);
+        assert!(daemon.sync().unwrap());
+
+        assert!(local.join("photo.jpg").is_file());
+        assert!(local.join("Trip/shot.png").is_file());
+        assert!(!local.join("notes.txt").exists());
+        assert!(!local.join("Trip/clip.mp4").exists());
+        let v_list = testing::versions(&dir, &local)
+            .lock()
+            .unwrap()
+            .list()
+            .unwrap();
+        assert!(!v_list.contains_key("notes"));
+        assert!(!v_list.contains_key("video"));
+    }
+
+    #[test]
+    fn file_deleted_on_both_sides_converges_without_errors() {
+        let dir = testing::temp_dir("remote-both-deleted");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_file("a", ROOT_ID, "a.txt", b"a");
+        drive.add_file("b", ROOT_ID, "b.txt", b"b");
+        let daemon = testing::remote_daemon(&drive, testing::config(&local, ""), &dir);
+        assert!(daemon.sync().unwrap());
+
+        // Local deletion of a.txt was handled already, so its version is gone too. Only the
+        // local file of b.txt is gone
+        let versions = testing::versions(&dir, &local);
+        let mut v_list = versions.lock().unwrap().list().unwrap();
+        v_list.remove("a");
+        versions.lock().unwrap().save(v_list).unwrap();
+        fs::remove_file(local.join("a.txt")).unwrap();
+        fs::remove_file(local.join("b.txt")).unwrap();
+        drive.change("a", |f| f.trashed = true);
+        drive.change("b", |f| f.trashed = true);
+
+        assert!(daemon.sync().unwrap());
+        assert!(!local.join("a.txt").exists());
+        assert!(!local.join("b.txt").exists());
+        let v_list = versions.lock().unwrap().list().unwrap();
+        assert!(!v_list.contains_key("a"));
+        assert!(!v_list.contains_key("b"));
+    }
+
+    #[test]
+    fn paged_listing_syncs_the_same_as_a_single_page() {
+        let dir = testing::temp_dir("remote-pages");
+        let drive = FakeDrive::new();
+        drive.add_folder("docs", ROOT_ID, "docs");
+        for i in 0..7 {
+            let name = format!("{}.txt", i);
+            drive.add_file(&format!("f{}", i), ROOT_ID, &name, name.as_bytes());
+            drive.add_file(&format!("d{}", i), "docs", &name, name.as_bytes());
+        }
+
+        let sync = |name: &str, page_size: Option<u32>| {
+            let state = dir.join(name);
+            let local = state.join("local");
+            fs::create_dir_all(&local).unwrap();
+            let client = drive.client();
+            if let Some(size) = page_size {
+                client.lock().unwrap().set_page_size(size);
+            }
+            let versions = testing::versions(&state, &local);
+            let config = testing::config(&local, "");
+            let mut daemon =
+                RemoteDaemon::new(config, client, Arc::clone(&versions), ROOT_ID.to_string())
+                    .unwrap();
+            daemon.set_parts_dir(state.join("downloads"));
+            daemon.sync_once().unwrap();
+
+            let v_list = versions.lock().unwrap().list().unwrap();
+            v_list
+                .into_iter()
+                .map(|(id, v)| {
+                    let path = Path::new(&v.path).strip_prefix(&local).unwrap().to_owned();
+                    assert_eq!(fs::read(local.join(&path)).is_ok(), !v.is_folder);
+                    (id, (path, v.version, v.md5, v.children_hash))
+                })
+                .collect::<std::collections::BTreeMap<_, _>>()
+        };
+
+        let whole = sync("whole", None);
+        drive.clear_requests();
+        let paged = sync("paged", Some(2));
+        let requests = drive.requests();
+        assert!(requests.iter().any(|r| r.contains("pageToken=")));
+        // First full page of each folder is requested once, then kept
+        let first_pages = requests.iter().filter(|r| {
+            r.contains("pageSize=2")
+                && !r.contains("pageToken=")
+                && !r.contains("files(id, version)")
+        });
+        assert_eq!(first_pages.count(), 2);
+        assert_eq!(whole.len(), 15);
+        assert_eq!(paged, whole);
+    }
+
+    #[test]
+    fn trashed_file_is_kept_for_the_grace_period() {
+        let dir = testing::temp_dir("remote-grace");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_file("a", ROOT_ID, "a.txt", b"a");
+        drive.add_file("b", ROOT_ID, "b.txt", b"b");
+        let config = testing::config(&local, "deletion_grace_secs = 3600");
+        let daemon = testing::remote_daemon(&drive, config, &dir);
+        assert!(daemon.sync().unwrap());
+
+        drive.change("a", |f| f.trashed = true);
+        drive.change("b", |f| f.trashed = true);
+        assert!(daemon.sync().unwrap());
+        assert!(local.join("a.txt").exists());
+        assert!(local.join("b.txt").exists());
+
+        // Restored file isn't deleted anymore
+        drive.change("a", |f| f.trashed = false);
+        assert!(daemon.sync().unwrap());
+        let deletions = dir.join("deletions.json");
+        let mut deferred: Value = serde_json::from_slice(&fs::read(&deletions).unwrap()).unwrap();
+        assert!(deferred.get("a").is_none());
+
+        // Grace period of the other one is over
+        let trashed_at = deferred["b"]["trashed_at"].as_i64().unwrap();
+        deferred["b"]["trashed_at"] = (trashed_at - 3600).into();
+        fs::write(&deletions, deferred.to_string()).unwrap();
+        assert!(daemon.sync().unwrap());
+        assert!(local.join("a.txt").exists());
+        assert!(!local.join("b.txt").exists());
+    }
+
+    #[test]
+    fn only_the_appended_tail_is_downloaded() {
+        let dir = testing::temp_dir("remote-append");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_file("log", ROOT_ID, "app.log", b"line 1\n");
+        drive.add_file("notes", ROOT_ID, "notes.txt", b"draft\n");
+        let ranges = Arc::new(Mutex::new(vec![]));
+        let seen = Arc::clone(&ranges);
+        let fake = drive.transport();
+        let client = Client::with_transport(transport::Fake(Box::new(move |req| {
+            if let Some(range) = req.headers().get("Range") {
+                let path = req.url().path().to_string();
+                let range = range.to_str().unwrap().to_string();
+                seen.lock().unwrap().push((path, range));
+            }
+            (fake.0)(req)
+        })));
+        let config = testing::config(&local, "append_downloads = true");
+        let versions = testing::versions(&dir, &local);
+        let mut daemon = RemoteDaemon::new(
+            config,
+            Arc::new(Mutex::new(client)),
+            versions,
+            ROOT_ID.to_string(),
+        )
+        .unwrap();
+        daemon.set_parts_dir(dir.join("downloads"));
+        assert!(daemon.sync().unwrap());
+
+        drive.change("log", |f| f.content = b"line 1\nline 2\n".to_vec());
+        // Not only the end is changed, so it's downloaded as a whole
+        drive.change("notes", |f| f.content = b"final version\n".to_vec());
+        ranges.lock().unwrap().clear();
+        assert!(daemon.sync().unwrap());
+
+        assert_eq!(
+            fs::read(local.join("app.log")).unwrap(),
+            b"line 1\nline 2\n"
+        );
+        assert_eq!(
+            fs::read(local.join("notes.txt")).unwrap(),
+            b"final version\n"
+        );
+        let ranges = ranges.lock().unwrap();
+        let range = |path: &str| {
+            ranges
+                .iter()
+                .filter(|(p, _)| p == path)
+                .map(|(_, r)| r.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(range("/drive/v3/files/log"), vec!["bytes=7-"]);
+        assert_eq!(range("/drive/v3/files/notes"), vec!["bytes=6-", "bytes=0-"]);
+    }
+
+    #[test]
+    fn downloads_follow_the_configured_order() {
+        let dir = testing::temp_dir("remote-download-order");
+        let drive = FakeDrive::new();
+        drive.add_folder("docs", ROOT_ID, "docs");
+        let files = [
+            ("old", ROOT_ID, "b.txt", "2020-01-01T00:00:00.000Z", "1234"),
+            ("new", "docs", "c.txt", "2023-01-01T00:00:00.000Z", "12"),
+            ("mid", ROOT_ID, "a.txt", "2021-01-01T00:00:00.000Z", "123"),
+        ];
+        for (id, parent, name, modified, content) in files {
+            drive.add_file(id, parent, name, content.as_bytes());
+            drive.change(id, |f| {
+                f.fields.insert("modifiedTime".to_string(), modified.into());
+            });
+        }
+
+        let order = |strategy: &str| {
+            let state = dir.join(strategy);
+            let local = state.join("local");
+            fs::create_dir_all(&local).unwrap();
+            let extra = format!(
+                "initial_sync_concurrency = 1\ndownload_order = {:?}",
+                strategy
+            );
+            let daemon = testing::remote_daemon(&drive, testing::config(&local, &extra), &state);
+            drive.clear_requests();
+            assert!(daemon.sync().unwrap());
+            drive
+                .requests()
+                .iter()
+                .filter(|r| r.contains("alt=media"))
+                .map(|r| r.split(['/', '?']).nth(4).unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(order("newest"), vec!["new", "mid", "old"]);
+        assert_eq!(order("oldest"), vec!["old", "mid", "new"]);
+        assert_eq!(order("smallest"), vec!["new", "mid", "old"]);
+        assert_eq!(order("largest"), vec!["old", "mid", "new"]);
+        assert_eq!(order("name"), vec!["mid", "old", "new"]);
+    }
+
+    #[test]
+    fn empty_file_is_created_once() {
+        let dir = testing::temp_dir("remote-empty");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_file("empty", ROOT_ID, "empty.txt", b"");
+        // Drive may not give the checksum of an empty file
+        drive.change("empty", |f| {
+            f.fields.insert("md5Checksum".to_string(), Value::Null);
+        });
+        let daemon = testing::remote_daemon(&drive, testing::config(&local, ""), &dir);
+        assert!(daemon.sync().unwrap());
+        assert_eq!(fs::read(local.join("empty.txt")).unwrap(), b"");
+        let downloads = |drive: &FakeDrive| {
+            drive
+                .requests()
+                .into_iter()
+                .filter(|r| r.contains("/empty?") && r.contains("alt=media"))
+                .count()
+        };
+        assert_eq!(downloads(&drive), 0);
+
+        // New version of the file with the same (no) content
+        drive.change("empty", |f| f.version += 1);
+        drive.add_file("b", ROOT_ID, "b.txt", b"b");
+        drive.clear_requests();
+        assert!(daemon.sync().unwrap());
+        assert!(daemon.sync().unwrap());
+        assert_eq!(downloads(&drive), 0);
+        assert_eq!(fs::read(local.join("empty.txt")).unwrap(), b"");
+        assert_eq!(fs::read(local.join("b.txt")).unwrap(), b"b");
+    }
+
+    #[test]
+    fn shortcut_to_a_file_is_followed_or_linked() {
+        let drive = FakeDrive::new();
+        drive.add_folder("shared", "elsewhere", "Shared");
+        drive.add_file("target", "shared", "report.txt", b"numbers");
+        drive.add_file("shortcut", ROOT_ID, "Report", b"");
+        drive.change("shortcut", |f| {
+            for (field, value) in [
+                ("mimeType", json!(SHORTCUT_TYPE)),
+                ("shortcutDetails", json!({ "targetId": "target" })),
+                ("md5Checksum", Value::Null),
+                ("size", Value::Null),
+                (
+                    "webViewLink",
+                    json!("https://drive.google.com/file/d/target"),
+                ),
+            ] {
+                f.fields.insert(field.to_string(), value);
+            }
+        });
+
+        let dir = testing::temp_dir("remote-shortcut-follow");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let config = testing::config(&local, "shortcuts = \"follow\"");
+        assert!(testing::remote_daemon(&drive, config, &dir).sync().unwrap());
+        assert_eq!(fs::read(local.join("Report")).unwrap(), b"numbers");
+
+        let dir = testing::temp_dir("remote-shortcut-link");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let config = testing::config(&local, "shortcuts = \"link\"");
+        assert!(testing::remote_daemon(&drive, config, &dir).sync().unwrap());
+        assert!(!local.join("Report").exists());
+        assert_eq!(
+            fs::read_to_string(local.join("Report.url")).unwrap(),
+            "[InternetShortcut]\nURL=https://drive.google.com/file/d/target\n"
+        );
+    }
+
+    #[test]
+    fn changed_files_without_md5_are_downloaded_again() {
+        let dir = testing::temp_dir("remote-no-md5");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_file("timed", ROOT_ID, "timed.bin", b"one");
+        drive.add_file("untimed", ROOT_ID, "untimed.bin", b"one");
+        for id in ["timed", "untimed"] {
+            drive.change(id, |f| {
+                f.fields.insert("md5Checksum".to_string(), Value::Null);
+                f.fields.insert("size".to_string(), Value::Null);
+            });
+        }
+        drive.change("untimed", |f| {
+            f.fields.insert("modifiedTime".to_string(), Value::Null);
+        });
+        let daemon = testing::remote_daemon(&drive, testing::config(&local, ""), &dir);
+        assert!(daemon.sync().unwrap());
+        assert_eq!(fs::read(local.join("timed.bin")).unwrap(), b"one");
+        assert_eq!(fs::read(local.join("untimed.bin")).unwrap(), b"one");
+
+        drive.change("timed", |f| {
+            f.content = b"two".to_vec();
+            let time = json!("2024-02-01T00:00:00.000Z");
+            f.fields.insert("modifiedTime".to_string(), time);
+        });
+        // Only the version tells about the change
+        drive.change("untimed", |f| f.content = b"two".to_vec());
+        assert!(daemon.sync().unwrap());
+
+        assert_eq!(fs::read(local.join("timed.bin")).unwrap(), b"two");
+        assert_eq!(fs::read(local.join("untimed.bin")).unwrap(), b"two");
+    }
+
+    #[test]
+    fn flattened_files_land_in_the_root() {
+        let dir = testing::temp_dir("remote-flatten");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("trip", ROOT_ID, "Trip");
+        drive.add_folder("day1", "trip", "Day 1");
+        drive.add_file("beach", "day1", "photo.jpg", b"beach");
+        drive.add_file("hotel", "trip", "photo.jpg", b"hotel");
+        drive.add_file("top", ROOT_ID, "top.jpg", b"top");
+        let daemon =
+            testing::remote_daemon(&drive, testing::config(&local, "flatten = true"), &dir);
+        assert!(daemon.sync().unwrap());
+
+        let mut names: Vec<_> = fs::read_dir(&local)
+            .unwrap()
+            .map(|e| e.unwrap())
+            .inspect(|e| assert!(e.file_type().unwrap().is_file()))
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["photo (2).jpg", "photo.jpg", "top.jpg"]);
+        let mut photos = vec![
+            fs::read(local.join("photo.jpg")).unwrap(),
+            fs::read(local.join("photo (2).jpg")).unwrap(),
+        ];
+        photos.sort();
+        assert_eq!(photos, vec![b"beach".to_vec(), b"hotel".to_vec()]);
+
+        // Parents are still tracked, so nothing is moved on the next sync
+        let v_list = testing::versions(&dir, &local)
+            .lock()
+            .unwrap()
+            .list()
+            .unwrap();
+        assert_eq!(v_list["beach"].parent_id, "day1");
+        assert_eq!(v_list["hotel"].parent_id, "trip");
+        drive.clear_requests();
+        assert!(daemon.sync().unwrap());
+        assert_eq!(fs::read_dir(&local).unwrap().count(), 3);
+        assert!(!drive.requests().iter().any(|r| r.contains("alt=media")));
+    }
+
+    #[test]
+    fn slow_responses_time_the_cycle_out() {
+        let dir = testing::temp_dir("remote-slow");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("dir", ROOT_ID, "dir");
+        drive.add_file("a", "dir", "a.txt", b"a");
+        let fake = drive.transport();
+        // Listing stalls past the whole cycle budget
+        let client = Client::with_transport(transport::Fake(Box::new(move |req| {
+            if req.url().path() == "/drive/v3/files" {
+                thread::sleep(Duration::from_millis(1100));
+            }
+            (fake.0)(req)
+        })));
+        let config = testing::config(&local, "sync_timeout_secs = 1");
+        let mut daemon = RemoteDaemon::new(
+            config,
+            Arc::new(Mutex::new(client)),
+            testing::versions(&dir, &local),
+            ROOT_ID.to_string(),
+        )
+        .unwrap();
+        daemon.set_parts_dir(dir.join("downloads"));
+
+        let e = daemon.sync().err().unwrap();
+        assert!(
+            matches!(
+                e.chain().find_map(|c| c.downcast_ref::<SyncError>()),
+                Some(SyncError::CycleTimedOut(1))
+            ),
+            "{:#}",
+            e
+        );
+        assert!(!local.join("dir/a.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replaced_folder_is_kept_for_the_grace_period() {
+        let dir = testing::temp_dir("remote-flip-grace");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("x", ROOT_ID, "x");
+        drive.add_file("a", "x", "a.txt", b"a");
+        let config = testing::config(&local, "deletion_grace_secs = 3600");
+        let daemon = testing::remote_daemon(&drive, config, &dir);
+        assert!(daemon.sync().unwrap());
+
+        drive.remove("a");
+        drive.change("x", |f| {
+            f.folder = false;
+            f.content = b"file".to_vec();
+        });
+        // The file isn't restored, so the deletion isn't cancelled by the next sync either
+        assert!(daemon.sync().unwrap());
+        assert!(daemon.sync().unwrap());
+        assert!(local.join("x/a.txt").is_file());
+
+        let deletions = dir.join("deletions.json");
+        let mut deferred: Value = serde_json::from_slice(&fs::read(&deletions).unwrap()).unwrap();
+        let trashed_at = deferred["x"]["trashed_at"].as_i64().unwrap();
+        deferred["x"]["trashed_at"] = (trashed_at - 3600).into();
+        fs::write(&deletions, deferred.to_string()).unwrap();
+        assert!(daemon.sync().unwrap());
+        assert!(!local.join("x").exists());
+        assert!(daemon.sync().unwrap());
+        assert_eq!(fs::read(local.join("x")).unwrap(), b"file");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn renamed_folder_makes_way_for_a_new_one_on_another_page() {
+        let dir = testing::temp_dir("remote-rename-pages");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("old", ROOT_ID, "Photos");
+        drive.add_file("a", "old", "a.jpg", b"a");
+        let client = drive.client();
+        client.lock().unwrap().set_page_size(1);
+        let versions = testing::versions(&dir, &local);
+        let config = testing::config(&local, "");
+        let mut daemon = RemoteDaemon::new(config, client, versions, ROOT_ID.to_string()).unwrap();
+        daemon.set_parts_dir(dir.join("downloads"));
+        assert!(daemon.sync().unwrap());
+
+        // New folder is on the first page, the renamed one on the second
+        drive.change("old", |f| f.name = "Pictures".to_string());
+        drive.add_folder("new", ROOT_ID, "Photos");
+        drive.add_file("b", "new", "b.jpg", b"b");
+        assert!(daemon.sync().unwrap());
+
+        assert_eq!(fs::read(local.join("Pictures/a.jpg")).unwrap(), b"a");
+        assert_eq!(fs::read(local.join("Photos/b.jpg")).unwrap(), b"b");
+        assert!(!local.join("Pictures/b.jpg").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
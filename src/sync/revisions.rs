@@ -0,0 +1,146 @@
+/*
+    Lists the revisions Drive keeps for a file and downloads one of them, so a file that was
+    overwritten by a bad sync can be recovered. Restored content is saved as a copy next to the
+    file, which is then synced like any other new file.
+*/
+use crate::{
+    google_drive::{types::File, Client},
+    sync::{
+        conf_dir,
+        conflicts::split_extension,
+        connect,
+        crypto::{self, Cipher},
+        read_config,
+    },
+};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use clap::ArgMatches;
+use std::{fs, path::PathBuf};
+
+pub fn list(m: &ArgMatches) -> Result<()> {
+    let conf_dir = conf_dir()?;
+    let config = read_config(&conf_dir, None)?;
+    let (client, remote_dir_id) = connect(&conf_dir, &config)?;
+    let client = client.lock().unwrap();
+
+    let path = m.value_of("path").unwrap();
+    let file = find_by_path(&client, &remote_dir_id, path)?;
+    let revisions = client.list_revisions(file.id.as_ref().unwrap())?;
+    if revisions.is_empty() {
+        println!("Drive keeps no revisions of {:?}", path);
+        return Ok(());
+    }
+
+    for rev in revisions {
+        println!(
+            "{}  {}  {} bytes{}",
+            rev.id,
+            rev.modified_time
+                .as_deref()
+                .map_or("unknown time".to_string(), local_time),
+            rev.size.as_deref().unwrap_or("?"),
+            if rev.keep_forever.unwrap_or(false) {
+                "  (kept forever)"
+            } else {
+                ""
+            }
+        );
+    }
+
+    Ok(())
+}
+
+pub fn restore(m: &ArgMatches) -> Result<()> {
+    let conf_dir = conf_dir()?;
+    let config = read_config(&conf_dir, None)?;
+    let (client, remote_dir_id) = connect(&conf_dir, &config)?;
+    let client = client.lock().unwrap();
+
+    let path = m.value_of("path").unwrap();
+    let revision_id = m.value_of("revision").unwrap();
+    let file = find_by_path(&client, &remote_dir_id, path)?;
+    let id = file.id.as_ref().unwrap();
+
+    let revision = client
+        .list_revisions(id)?
+        .into_iter()
+        .find(|r| r.id == revision_id)
+        .with_context(|| {
+            format!(
+                "File {:?} has no revision {}.\nTip: Run `ocean-drive revisions {}` to list them",
+                path, revision_id, path
+            )
+        })?;
+    let content = client
+        .download_revision(id, &revision.id)
+        .with_context(|| format!("Unable to download revision {} of {:?}", revision.id, path))?;
+    if matches!(&revision.md5, Some(md5) if *md5 != format!("{:x}", md5::compute(&content))) {
+        bail!(
+            "Downloaded revision {} of {:?} is damaged, try again",
+            revision.id,
+            path
+        );
+    }
+    // Files encrypted by the app are decrypted the same way as on a regular download
+    let cipher = Cipher::load(&config)?;
+    let content = crypto::open(cipher.as_deref(), content, file.is_encrypted())?;
+
+    let output = match m.value_of("output") {
+        Some(out) => PathBuf::from(out),
+        None => {
+            let local = PathBuf::from(&config.local_dir).join(path.trim_start_matches('/'));
+            let name = file.name.as_deref().unwrap_or("file");
+            let (stem, ext) = split_extension(name);
+            let time = revision
+                .modified_time
+                .as_deref()
+                .map_or(revision.id.clone(), |t| local_time(t).replace(':', "."));
+            local.with_file_name(format!("{} (revision {}){}", stem, time, ext))
+        }
+    };
+    if output.exists() {
+        bail!(
+            "{:?} already exists, choose another path with --output",
+            output
+        );
+    }
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&output, content).with_context(|| format!("Unable to write {:?}", output))?;
+
+    info!(
+        "Revision {} of {:?} is saved to {:?}",
+        revision.id, path, output
+    );
+    Ok(())
+}
+
+/// Finds the file going down from the root one name at a time
+fn find_by_path(client: &Client, root_id: &str, path: &str) -> Result<File> {
+    let mut parent_id = root_id.to_string();
+    let mut target: Option<File> = None;
+    for name in path.split('/').filter(|n| !n.is_empty()) {
+        if let Some(prev) = &target {
+            parent_id = prev.id.clone().unwrap();
+        }
+
+        target = match client.get_file_by_name(name, Some(parent_id.clone()))? {
+            Some(f) if !f.trashed.unwrap_or(false) => Some(f),
+            _ => bail!("No file {:?} found in the drive directory", path),
+        };
+    }
+
+    match target {
+        Some(f) if f.mime_type.as_deref() != Some("application/vnd.google-apps.folder") => Ok(f),
+        _ => bail!("{:?} is a directory, only files have revisions", path),
+    }
+}
+
+fn local_time(rfc3339: &str) -> String {
+    match DateTime::parse_from_rfc3339(rfc3339) {
+        Ok(t) => t.with_timezone(&Local).format("%F %T").to_string(),
+        Err(_) => rfc3339.to_string(),
+    }
+}
@@ -0,0 +1,173 @@
+/*
+    Finds local changes by scanning the directory, for the file systems where change events are not
+    available (e.g. network shares, or when the inotify watch limit is reached). Scans become rarer
+    while nothing changes and frequent again after a change, so big trees are not scanned all the
+    time. Changes are reported as the same events the watcher sends.
+*/
+use notify::DebouncedEvent;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+    thread,
+    time::Duration,
+};
+
+pub const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(5);
+pub const DEFAULT_MAX_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Scans without changes in a row before the interval starts to grow
+const IDLE_SCANS_BEFORE_BACKOFF: u32 = 3;
+
+/// Whether the path is a directory, its size and modification time
+type Snapshot = HashMap<PathBuf, (bool, u64, Option<std::time::SystemTime>)>;
+
+pub struct Scanner {
+    root: PathBuf,
+    min_interval: Duration,
+    max_interval: Duration,
+    interval: Duration,
+    /// Scans without changes since the last change
+    idle_scans: u32,
+    snapshot: Snapshot,
+}
+
+impl Scanner {
+    /// Current state of the directory is taken as the starting point, only changes after it are
+    /// reported
+    pub fn new(root: PathBuf, min_interval: Duration, max_interval: Duration) -> Self {
+        let min_interval = min_interval.min(max_interval);
+        let mut snapshot = Snapshot::new();
+        take_snapshot(&root, &Snapshot::new(), &mut snapshot);
+
+        Self {
+            root,
+            min_interval,
+            max_interval,
+            interval: min_interval,
+            idle_scans: 0,
+            snapshot,
+        }
+    }
+
+    /// Scans the directory in a separate thread until the receiver is gone
+    pub fn start(mut self, tx: Sender<DebouncedEvent>) {
+        thread::spawn(move || loop {
+            thread::sleep(self.interval);
+
+            for event in self.tick() {
+                if tx.send(event).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Scans the directory and sets the interval before the next scan
+    fn tick(&mut self) -> Vec<DebouncedEvent> {
+        let events = self.scan();
+        self.idle_scans = if events.is_empty() {
+            self.idle_scans.saturating_add(1)
+        } else {
+            0
+        };
+        self.interval = next_interval(
+            self.interval,
+            self.idle_scans,
+            self.min_interval,
+            self.max_interval,
+        );
+
+        events
+    }
+
+    /// Compares the directory with the previous scan. New and changed files go before the removed
+    /// ones, so a moved file is found by its content before its old path is removed
+    fn scan(&mut self) -> Vec<DebouncedEvent> {
+        let mut current = Snapshot::new();
+        take_snapshot(&self.root, &self.snapshot, &mut current);
+
+        let mut events = vec![];
+        for (path, stat) in &current {
+            match self.snapshot.get(path) {
+                None => events.push(DebouncedEvent::Create(path.clone())),
+                Some(old) if old != stat && !stat.0 => {
+                    events.push(DebouncedEvent::Write(path.clone()))
+                }
+                _ => {}
+            }
+        }
+        for path in self.snapshot.keys() {
+            if !current.contains_key(path) {
+                events.push(DebouncedEvent::Remove(path.clone()));
+            }
+        }
+
+        self.snapshot = current;
+        events
+    }
+}
+
+/// Interval is doubled after every scan without changes (once there were a few of them), up to
+/// `max`, and reset to `min` after a change
+fn next_interval(current: Duration, idle_scans: u32, min: Duration, max: Duration) -> Duration {
+    if idle_scans < IDLE_SCANS_BEFORE_BACKOFF {
+        return min;
+    }
+
+    (current * 2).clamp(min, max)
+}
+
+/// Contents of the directories that can't be read are taken from the `previous` snapshot, so they
+/// are not reported as removed
+fn take_snapshot(dir: &Path, previous: &Snapshot, snapshot: &mut Snapshot) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            let inside = previous
+                .iter()
+                .filter(|(path, _)| path.starts_with(dir) && *path != dir);
+            snapshot.extend(inside.map(|(path, stat)| (path.clone(), *stat)));
+            return;
+        }
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let meta = match fs::metadata(&path) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+
+        if meta.is_dir() {
+            take_snapshot(&path, previous, snapshot);
+        }
+        snapshot.insert(path, (meta.is_dir(), meta.len(), meta.modified().ok()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing;
+
+    #[test]
+    fn interval_grows_while_nothing_changes() {
+        let root = testing::temp_dir("scanner-backoff");
+        let secs = Duration::from_secs;
+        let mut scanner = Scanner::new(root.clone(), secs(1), secs(8));
+
+        let mut intervals = vec![];
+        for _ in 0..7 {
+            assert!(scanner.tick().is_empty());
+            intervals.push(scanner.interval.as_secs());
+        }
+        assert_eq!(intervals, vec![1, 1, 2, 4, 8, 8, 8]);
+
+        // Change brings the scans back to the shortest interval
+        fs::write(root.join("new.txt"), b"new").unwrap();
+        assert_eq!(scanner.tick().len(), 1);
+        assert_eq!(scanner.interval, secs(1));
+    }
+}
@@ -0,0 +1,189 @@
+/*
+    Cron-like schedule of the syncs, e.g. "0 2 * * *" to sync every night at 2am or
+    "0,30 9-18 * * 1-5" to sync every half an hour during the work hours. Fields are the minute,
+    hour, day of the month, month and weekday (0 or 7 is Sunday) in local time. Every field is
+    "*" or a list of numbers and ranges, with an optional step after "/".
+*/
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone, Timelike};
+
+/// How far the next run is looked for, schedules like "0 0 31 2 *" never run
+const MAX_DAYS_AHEAD: u32 = 5 * 366;
+
+#[derive(Clone)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    /// Sunday is 0
+    weekdays: u64,
+    /// When both the day of the month and the weekday are restricted, the day matches either of
+    /// them, as in cron
+    either_day: bool,
+}
+
+impl Schedule {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            bail!(
+                "Schedule {:?} must have 5 fields: minute, hour, day of the month, month and weekday",
+                expr
+            );
+        }
+
+        let field = |i: usize, name: &str, min: u32, max: u32| {
+            parse_field(fields[i], min, max)
+                .with_context(|| format!("Invalid {} in schedule {:?}", name, expr))
+        };
+        let mut weekdays = field(4, "weekday", 0, 7)?;
+        // Sunday is both 0 and 7
+        if has(weekdays, 7) {
+            weekdays |= 1;
+        }
+        let schedule = Self {
+            minutes: field(0, "minute", 0, 59)?,
+            hours: field(1, "hour", 0, 23)?,
+            days: field(2, "day of the month", 1, 31)?,
+            months: field(3, "month", 1, 12)?,
+            weekdays,
+            either_day: !fields[2].starts_with('*') && !fields[4].starts_with('*'),
+        };
+
+        if schedule.next_after(Local::now()).is_none() {
+            bail!("Schedule {:?} never runs", expr);
+        }
+
+        Ok(schedule)
+    }
+
+    /// Start of the first matching minute after `after`, None if there's none in the next years
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = (after + Duration::minutes(1)).naive_local();
+        let start = start.date().and_hms_opt(start.hour(), start.minute(), 0)?;
+
+        let mut date = start.date();
+        for _ in 0..MAX_DAYS_AHEAD {
+            if self.matches_day(date) {
+                let times = (0..24)
+                    .filter(|h| has(self.hours, *h))
+                    .flat_map(|h| (0..60).map(move |m| (h, m)))
+                    .filter(|(_, m)| has(self.minutes, *m));
+
+                for (hour, minute) in times {
+                    let time = date.and_hms_opt(hour, minute, 0)?;
+                    if time < start {
+                        continue;
+                    }
+                    // Times that are skipped by the daylight saving change don't exist
+                    if let Some(time) = Local.from_local_datetime(&time).earliest() {
+                        return Some(time);
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+
+        None
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        if !has(self.months, date.month()) {
+            return false;
+        }
+
+        let day = has(self.days, date.day());
+        let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+        if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+}
+
+fn has(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+/// Set of the values allowed by the field as bits
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut set = 0;
+
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0)),
+            None => (item, Some(1)),
+        };
+        let step = match step {
+            Some(step) => step,
+            None => bail!("{:?} has an invalid step", item),
+        };
+
+        let number = |s: &str| match s.parse::<u32>() {
+            Ok(n) if (min..=max).contains(&n) => Ok(n),
+            _ => bail!("{:?} is not a number from {} to {}", s, min, max),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (number(from)?, number(to)?),
+                // Single value with a step means from it to the end
+                None if item.contains('/') => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if from > to {
+            bail!("Range {:?} is reversed", range);
+        }
+
+        for value in (from..=to).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        // June 2024 has no daylight saving changes, the 12th is a Wednesday
+        let time = NaiveDate::from_ymd_opt(2024, 6, day)
+            .and_then(|d| d.and_hms_opt(hour, minute, 0))
+            .unwrap();
+        Local.from_local_datetime(&time).unwrap()
+    }
+
+    fn next(expr: &str, after: DateTime<Local>) -> DateTime<Local> {
+        Schedule::parse(expr).unwrap().next_after(after).unwrap()
+    }
+
+    #[test]
+    fn next_run_is_computed_from_the_expression() {
+        assert_eq!(next("0 2 * * *", at(12, 14, 5)), at(13, 2, 0));
+        assert_eq!(next("*/15 * * * *", at(12, 10, 7)), at(12, 10, 15));
+        // Exactly at the scheduled minute, the next one is returned
+        assert_eq!(next("0,30 9-18 * * 1-5", at(12, 9, 0)), at(12, 9, 30));
+        // From Friday evening to Monday morning
+        assert_eq!(next("0,30 9-18 * * 1-5", at(14, 18, 45)), at(17, 9, 0));
+        // Sunday is both 0 and 7
+        assert_eq!(next("0 12 * * 7", at(12, 0, 0)), at(16, 12, 0));
+        // Either the 13th or a Friday
+        assert_eq!(next("0 0 13 * 5", at(12, 0, 0)), at(13, 0, 0));
+        assert_eq!(next("0 0 13 * 5", at(13, 1, 0)), at(14, 0, 0));
+
+        for invalid in [
+            "61 * * * *",
+            "* * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "0 0 31 2 *",
+        ] {
+            assert!(Schedule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+/*
+    Counters of the running app and its shutdown. Ctrl+C or SIGTERM stops waiting for the
+    daemons, and the summary of the session is printed on the way out: uptime, sync cycles,
+    transferred files and errors.
+*/
+use crate::sync::hooks::SyncReport;
+use anyhow::Result;
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+        Once, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often the shutdown request is checked while waiting for the daemons
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static LISTEN: Once = Once::new();
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static METRICS: Metrics = Metrics::new();
+
+struct Metrics {
+    cycles: AtomicU64,
+    /// Total time of the cycles
+    cycles_ms: AtomicU64,
+    downloaded: AtomicU64,
+    downloaded_bytes: AtomicU64,
+    uploaded: AtomicU64,
+    uploaded_bytes: AtomicU64,
+    removed: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            cycles: AtomicU64::new(0),
+            cycles_ms: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            downloaded_bytes: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            uploaded_bytes: AtomicU64::new(0),
+            removed: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    fn record_cycle(&self, duration: Duration, report: &SyncReport, res: &Result<bool>) {
+        let bytes: u64 = report
+            .downloaded
+            .iter()
+            .filter_map(|p| fs::metadata(p).ok())
+            .map(|m| m.len())
+            .sum();
+        let errors = report.failed as u64 + u64::from(res.is_err());
+
+        self.cycles.fetch_add(1, Ordering::Relaxed);
+        self.cycles_ms
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+        self.downloaded
+            .fetch_add(report.downloaded.len() as u64, Ordering::Relaxed);
+        self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.removed
+            .fetch_add(report.removed.len() as u64, Ordering::Relaxed);
+        self.errors.fetch_add(errors, Ordering::Relaxed);
+    }
+
+    fn record_upload(&self, bytes: u64) {
+        self.uploaded.fetch_add(1, Ordering::Relaxed);
+        self.uploaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Lines of the summary of the session that is up for `uptime`
+    fn summary(&self, uptime: Duration) -> [String; 2] {
+        let cycles = self.cycles.load(Ordering::Relaxed);
+        let average = Duration::from_millis(self.cycles_ms.load(Ordering::Relaxed))
+            .checked_div(cycles as u32)
+            .unwrap_or_default();
+
+        [
+            format!(
+                "Session summary: up for {}, {} sync cycles ({:.1}s on average)",
+                format_duration(uptime),
+                cycles,
+                average.as_secs_f64()
+            ),
+            format!(
+                "Downloaded {} files ({}), uploaded {} files ({}), removed {} files, {} errors",
+                self.downloaded.load(Ordering::Relaxed),
+                format_bytes(self.downloaded_bytes.load(Ordering::Relaxed)),
+                self.uploaded.load(Ordering::Relaxed),
+                format_bytes(self.uploaded_bytes.load(Ordering::Relaxed)),
+                self.removed.load(Ordering::Relaxed),
+                self.errors.load(Ordering::Relaxed)
+            ),
+        ]
+    }
+}
+
+/// Counts a finished sync cycle of the remote daemon
+pub fn record_cycle(duration: Duration, report: &SyncReport, res: &Result<bool>) {
+    METRICS.record_cycle(duration, report, res);
+}
+
+pub fn record_upload(bytes: u64) {
+    METRICS.record_upload(bytes);
+}
+
+/// Counts a local change that couldn't be uploaded
+pub fn record_error() {
+    METRICS.errors.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Waits for a daemon to stop. None when all of them are stopped or the app is asked to shut
+/// down, so the caller stops waiting
+pub fn next_stopped<T>(rx: &Receiver<T>) -> Option<T> {
+    LISTEN.call_once(listen_for_shutdown);
+    STARTED.get_or_init(Instant::now);
+
+    while !SHUTDOWN.load(Ordering::Relaxed) {
+        match rx.recv_timeout(SHUTDOWN_CHECK_INTERVAL) {
+            Ok(stopped) => return Some(stopped),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+
+    None
+}
+
+pub fn print_summary() {
+    let uptime = STARTED.get().map_or(Duration::ZERO, |s| s.elapsed());
+    for line in METRICS.summary(uptime) {
+        info!("{}", line);
+    }
+}
+
+#[cfg(unix)]
+fn listen_for_shutdown() {
+    extern "C" fn request_shutdown(_: libc::c_int) {
+        SHUTDOWN.store(true, Ordering::Relaxed);
+    }
+
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // Handler only sets the flag, which is safe to do in a signal handler
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(not(unix))]
+fn listen_for_shutdown() {}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const MIB: u64 = 1024 * 1024;
+    const GIB: u64 = 1024 * MIB;
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=MIB => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ if bytes <= GIB => format!("{:.1} MiB", bytes as f64 / MIB as f64),
+        _ => format!("{:.1} GiB", bytes as f64 / GIB as f64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing;
+    use anyhow::anyhow;
+
+    #[test]
+    fn summary_reflects_the_counters() {
+        let dir = testing::temp_dir("session-summary");
+        fs::write(dir.join("a.txt"), vec![0; 2048]).unwrap();
+        fs::write(dir.join("b.txt"), b"b").unwrap();
+        let metrics = Metrics::new();
+
+        let report = SyncReport {
+            downloaded: vec![dir.join("a.txt"), dir.join("b.txt")],
+            removed: vec![dir.join("old.txt")],
+            failed: 1,
+        };
+        metrics.record_cycle(Duration::from_secs(3), &report, &Ok(false));
+        let failed = Err(anyhow!("offline"));
+        metrics.record_cycle(Duration::from_secs(1), &SyncReport::default(), &failed);
+        metrics.record_upload(10);
+        metrics.record_upload(20);
+
+        assert_eq!(
+            metrics.summary(Duration::from_secs(3725)),
+            [
+                "Session summary: up for 1h 2m, 2 sync cycles (2.0s on average)",
+                "Downloaded 2 files (2.0 KiB), uploaded 2 files (30 B), removed 1 files, 2 errors"
+            ]
+        );
+    }
+}
@@ -0,0 +1,480 @@
+/*
+    Helpers of the tests: a drive that is kept in memory and answers the requests of the client
+    like Google Drive does, and the configs and temporary directories to sync it with.
+*/
+use crate::{
+    google_drive::{
+        errors::DriveError,
+        transport::{Answer, Fake},
+        Client,
+    },
+    setup::Config,
+    sync::{local::LocalDaemon, remote::RemoteDaemon, versions::Versions},
+};
+use anyhow::bail;
+use chrono::DateTime;
+use reqwest::blocking::Request;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+pub const ROOT_ID: &str = "root";
+const FOLDER_MIME: &str = "application/vnd.google-apps.folder";
+const UPLOAD_URL: &str = "https://upload.test/session/";
+
+#[derive(Clone)]
+pub struct FakeFile {
+    pub name: String,
+    pub parent: String,
+    pub folder: bool,
+    pub version: u64,
+    pub content: Vec<u8>,
+    pub trashed: bool,
+    /// Fields of the JSON that are set as is, e.g. mimeType, md5Checksum or modifiedTime
+    pub fields: BTreeMap<String, Value>,
+    /// Body of the 403 response to the download, e.g. to flag the file as abusive
+    pub download_error: Option<String>,
+}
+
+impl FakeFile {
+    fn json(&self, id: &str) -> Value {
+        let mut file = json!({
+            "id": id,
+            "name": self.name,
+            "version": self.version.to_string(),
+            "parents": [self.parent],
+            "trashed": self.trashed,
+            "modifiedTime": "2024-01-01T00:00:00.000Z",
+        });
+        if self.folder {
+            file["mimeType"] = json!(FOLDER_MIME);
+        } else {
+            file["mimeType"] = json!("text/plain");
+            file["md5Checksum"] = json!(format!("{:x}", md5::compute(&self.content)));
+            file["size"] = json!(self.content.len().to_string());
+        }
+        for (field, value) in &self.fields {
+            file[field] = value.clone();
+        }
+
+        file
+    }
+}
+
+#[derive(Default)]
+struct State {
+    files: BTreeMap<String, FakeFile>,
+    /// "METHOD path?query" of every request, the query is decoded
+    requests: Vec<String>,
+    /// Metadata of the resumable uploads and the id of the file they update
+    sessions: HashMap<String, (Value, Option<String>)>,
+    next_id: usize,
+    /// Requests fail like there's no network
+    offline: bool,
+}
+
+/// Drive with only the root folder (`ROOT_ID`) at first
+#[derive(Clone)]
+pub struct FakeDrive(Arc<Mutex<State>>);
+
+impl FakeDrive {
+    pub fn new() -> Self {
+        let drive = Self(Arc::new(Mutex::new(State::default())));
+        drive.insert(ROOT_ID, "", "Root", true, vec![]);
+        drive
+    }
+
+    pub fn add_folder(&self, id: &str, parent: &str, name: &str) {
+        self.insert(id, parent, name, true, vec![]);
+    }
+
+    pub fn add_file(&self, id: &str, parent: &str, name: &str, content: &[u8]) {
+        self.insert(id, parent, name, false, content.to_vec());
+    }
+
+    fn insert(&self, id: &str, parent: &str, name: &str, folder: bool, content: Vec<u8>) {
+        let file = FakeFile {
+            name: name.to_string(),
+            parent: parent.to_string(),
+            folder,
+            version: 1,
+            content,
+            trashed: false,
+            fields: BTreeMap::new(),
+            download_error: None,
+        };
+        self.lock().files.insert(id.to_string(), file);
+    }
+
+    /// Changes the file like a user does, so its version is bumped
+    pub fn change(&self, id: &str, f: impl FnOnce(&mut FakeFile)) {
+        let mut state = self.lock();
+        let file = state.files.get_mut(id).expect("no such fake file");
+        f(file);
+        file.version += 1;
+    }
+
+    /// Deletes the file for good, e.g. when the trash is emptied
+    pub fn remove(&self, id: &str) {
+        self.lock().files.remove(id);
+    }
+
+    pub fn file(&self, id: &str) -> Option<FakeFile> {
+        self.lock().files.get(id).cloned()
+    }
+
+    /// Ids and files that are not trashed
+    pub fn files(&self) -> Vec<(String, FakeFile)> {
+        self.lock()
+            .files
+            .iter()
+            .filter(|(_, f)| !f.trashed)
+            .map(|(id, f)| (id.clone(), f.clone()))
+            .collect()
+    }
+
+    pub fn set_offline(&self, offline: bool) {
+        self.lock().offline = offline;
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.lock().requests.clone()
+    }
+
+    pub fn clear_requests(&self) {
+        self.lock().requests.clear();
+    }
+
+    pub fn client(&self) -> Arc<Mutex<Client>> {
+        Arc::new(Mutex::new(Client::with_transport(self.transport())))
+    }
+
+    /// Transport that sends the requests of the client to this drive
+    pub fn transport(&self) -> Fake {
+        let drive = self.clone();
+        Fake(Box::new(move |req| {
+            if drive.lock().offline {
+                bail!(DriveError::Offline);
+            }
+            Ok(drive.answer(req))
+        }))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn answer(&self, req: &Request) -> Answer {
+        let mut state = self.lock();
+        let query: HashMap<String, String> = req.url().query_pairs().into_owned().collect();
+        let mut pairs: Vec<String> = req
+            .url()
+            .query_pairs()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        pairs.sort();
+        state.requests.push(format!(
+            "{} {}?{}",
+            req.method(),
+            req.url().path(),
+            pairs.join("&")
+        ));
+
+        let body = req
+            .body()
+            .and_then(|b| b.as_bytes())
+            .unwrap_or_default()
+            .to_vec();
+        let meta: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        let url = req.url().as_str();
+        let path = req.url().path().to_string();
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+        match (req.method().as_str(), segments.as_slice()) {
+            ("GET", ["drive", "v3", "about"]) => {
+                ok(json!({ "user": { "emailAddress": "user@example.com" } }))
+            }
+            ("GET", ["drive", "v3", "files"]) => state.list(&query),
+            ("GET", ["drive", "v3", "files", id]) => {
+                let file = match state.files.get(*id) {
+                    Some(f) => f,
+                    None => return not_found(),
+                };
+                if query.get("alt").map(String::as_str) != Some("media") {
+                    return ok(file.json(id));
+                }
+                if let Some(error) = &file.download_error {
+                    // Flagged files are given out only with the acknowledgment
+                    let acknowledged = error.contains("cannotDownloadAbusiveFile")
+                        && query.get("acknowledgeAbuse").map(String::as_str) == Some("true");
+                    if !acknowledged {
+                        return (403, vec![], error.clone().into_bytes());
+                    }
+                }
+
+                let from = req
+                    .headers()
+                    .get("Range")
+                    .and_then(|r| r.to_str().ok())
+                    .and_then(|r| r.strip_prefix("bytes="))
+                    .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+                match from {
+                    Some(from) => {
+                        let len = file.content.len();
+                        let range = format!("bytes {}-{}/{}", from, len.max(1) - 1, len);
+                        (
+                            206,
+                            vec![("Content-Range".to_string(), range)],
+                            file.content[from.min(len)..].to_vec(),
+                        )
+                    }
+                    None => (200, vec![], file.content.clone()),
+                }
+            }
+            ("POST", ["drive", "v3", "files"]) => {
+                let id = state.new_id();
+                let parent = meta["parents"][0].as_str().unwrap_or(ROOT_ID).to_string();
+                let name = meta["name"].as_str().unwrap_or_default().to_string();
+                let file = FakeFile {
+                    name,
+                    parent,
+                    folder: true,
+                    version: 1,
+                    content: vec![],
+                    trashed: false,
+                    fields: BTreeMap::new(),
+                    download_error: None,
+                };
+                let json = file.json(&id);
+                state.files.insert(id, file);
+                ok(json)
+            }
+            ("POST", ["drive", "v3", "files", id, "copy"]) => {
+                let mut file = match state.files.get(*id) {
+                    Some(f) => f.clone(),
+                    None => return not_found(),
+                };
+                let new_id = state.new_id();
+                file.name = meta["name"].as_str().unwrap_or(&file.name).to_string();
+                file.parent = meta["parents"][0]
+                    .as_str()
+                    .unwrap_or(&file.parent)
+                    .to_string();
+                file.version = 1;
+                set_meta(&mut file, &meta);
+                let json = file.json(&new_id);
+                state.files.insert(new_id, file);
+                ok(json)
+            }
+            ("POST", ["upload", "drive", "v3", "files"]) => state.start_session(meta, None),
+            ("PATCH", ["upload", "drive", "v3", "files", id]) => {
+                if !state.files.contains_key(*id) {
+                    return not_found();
+                }
+                if query.get("uploadType").map(String::as_str) == Some("resumable") {
+                    return state.start_session(meta, Some(id.to_string()));
+                }
+
+                let file = state.files.get_mut(*id).unwrap();
+                file.content = body;
+                file.version += 1;
+                ok(file.json(id))
+            }
+            ("PUT", _) if url.starts_with(UPLOAD_URL) => {
+                let session = url.trim_start_matches(UPLOAD_URL).to_string();
+                let (meta, update) = match state.sessions.remove(&session) {
+                    Some(s) => s,
+                    None => return not_found(),
+                };
+                let id = update.unwrap_or_else(|| state.new_id());
+                let file = state.files.entry(id.clone()).or_insert_with(|| FakeFile {
+                    name: String::new(),
+                    parent: ROOT_ID.to_string(),
+                    folder: false,
+                    version: 0,
+                    content: vec![],
+                    trashed: false,
+                    fields: BTreeMap::new(),
+                    download_error: None,
+                });
+                if let Some(name) = meta["name"].as_str() {
+                    file.name = name.to_string();
+                }
+                if let Some(parent) = meta["parents"][0].as_str() {
+                    file.parent = parent.to_string();
+                }
+                set_meta(file, &meta);
+                file.content = body;
+                file.version += 1;
+                ok(file.json(&id))
+            }
+            ("PATCH", ["drive", "v3", "files", id]) => {
+                let file = match state.files.get_mut(*id) {
+                    Some(f) => f,
+                    None => return not_found(),
+                };
+                if let Some(name) = meta["name"].as_str() {
+                    file.name = name.to_string();
+                }
+                if let Some(parent) = query.get("addParents") {
+                    file.parent = parent.clone();
+                }
+                set_meta(file, &meta);
+                file.version += 1;
+                ok(file.json(id))
+            }
+            ("DELETE", ["drive", "v3", "files", id]) => match state.files.remove(*id) {
+                Some(_) => (204, vec![], vec![]),
+                None => not_found(),
+            },
+            _ => (
+                501,
+                vec![],
+                format!("Fake drive can't answer {}", url).into_bytes(),
+            ),
+        }
+    }
+}
+
+impl State {
+    fn new_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("new{}", self.next_id)
+    }
+
+    /// Only the clauses that the app uses are understood: the parent, the name, the starred and
+    /// the modification time filters, other ones are ignored
+    fn list(&self, query: &HashMap<String, String>) -> Answer {
+        let q = query.get("q").cloned().unwrap_or_default();
+        let parent = q
+            .split_once("' in parents")
+            .and_then(|(before, _)| before.rsplit_once('\''))
+            .map(|(_, id)| id.to_string());
+        let name = q
+            .split_once("name = '")
+            .and_then(|(_, after)| after.split_once('\''))
+            .map(|(name, _)| name.to_string());
+        let only_starred = q.contains("starred = true");
+        // Folders and trashed files are listed whatever their time is, see `modified_after`
+        let modified_after = q
+            .split_once("modifiedTime > '")
+            .and_then(|(_, after)| after.split_once('\''))
+            .and_then(|(time, _)| DateTime::parse_from_rfc3339(time).ok());
+
+        let files: Vec<Value> = self
+            .files
+            .iter()
+            .filter(|(_, f)| parent.as_ref().is_none_or(|p| &f.parent == p))
+            .filter(|(_, f)| name.as_ref().is_none_or(|n| &f.name == n))
+            .filter(|(_, f)| {
+                !only_starred || f.folder || f.fields.get("starred") == Some(&json!(true))
+            })
+            .map(|(id, f)| f.json(id))
+            .filter(|f| match modified_after {
+                Some(after) => {
+                    let modified = f["modifiedTime"].as_str().unwrap_or_default();
+                    f["mimeType"] == FOLDER_MIME
+                        || f["trashed"] == true
+                        || DateTime::parse_from_rfc3339(modified).is_ok_and(|m| m > after)
+                }
+                None => true,
+            })
+            .collect();
+
+        let from: usize = query
+            .get("pageToken")
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(0);
+        let size = query
+            .get("pageSize")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(files.len())
+            .max(1);
+        let to = (from + size).min(files.len());
+        let mut page = json!({ "files": files[from..to] });
+        if to < files.len() {
+            page["nextPageToken"] = json!(to.to_string());
+        }
+
+        ok(page)
+    }
+
+    fn start_session(&mut self, meta: Value, update: Option<String>) -> Answer {
+        let session = self.new_id();
+        self.sessions.insert(session.clone(), (meta, update));
+        let location = format!("{}{}", UPLOAD_URL, session);
+
+        (200, vec![("Location".to_string(), location)], vec![])
+    }
+}
+
+/// Description and properties of the upload metadata are saved to the file
+fn set_meta(file: &mut FakeFile, meta: &Value) {
+    for field in ["description", "appProperties"] {
+        if let Some(value) = meta.get(field) {
+            file.fields.insert(field.to_string(), value.clone());
+        }
+    }
+}
+
+fn ok(body: Value) -> Answer {
+    let headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+    (200, headers, body.to_string().into_bytes())
+}
+
+fn not_found() -> Answer {
+    (404, vec![], br#"{"error": {"code": 404}}"#.to_vec())
+}
+
+/// Empty directory that is unique for the test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ocean-drive-{}-{}", name, std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Config that syncs `local_dir` with the root of the fake drive, `extra` is appended to it
+pub fn config(local_dir: &Path, extra: &str) -> Config {
+    let toml = format!(
+        "local_dir = {:?}\n{}\n[drive]\ndir = \"Root\"\n",
+        local_dir.display().to_string(),
+        extra
+    );
+    toml::from_str(&toml).unwrap()
+}
+
+/// Versions of the files in `local_dir`, kept in `state_dir`
+pub fn versions(state_dir: &Path, local_dir: &Path) -> Arc<Mutex<Versions>> {
+    let versions = Versions::new(state_dir.join("versions.json"), local_dir).unwrap();
+    Arc::new(Mutex::new(versions))
+}
+
+/// Remote daemon that syncs `config.local_dir` with the root of the drive, its versions and the
+/// parts of the downloads are kept in `state_dir`
+pub fn remote_daemon(drive: &FakeDrive, config: Config, state_dir: &Path) -> RemoteDaemon {
+    let versions = versions(state_dir, Path::new(&config.local_dir));
+    let mut daemon =
+        RemoteDaemon::new(config, drive.client(), versions, ROOT_ID.to_string()).unwrap();
+    daemon.set_parts_dir(state_dir.join("downloads"));
+    daemon
+}
+
+/// Local daemon that uploads the changes of `config.local_dir` to the root of the drive, its
+/// versions and pending changes are kept in `state_dir`
+pub fn local_daemon(drive: &FakeDrive, config: Config, state_dir: &Path) -> LocalDaemon {
+    let versions = versions(state_dir, Path::new(&config.local_dir));
+    let pending = state_dir.join("pending.json");
+    LocalDaemon::new(
+        config,
+        drive.client(),
+        versions,
+        ROOT_ID.to_string(),
+        pending,
+    )
+    .unwrap()
+}
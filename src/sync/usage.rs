@@ -0,0 +1,251 @@
+/*
+    Space taken by the synced files locally and on the drive, for `usage`. Local side is the synced
+    files from the versions, the drive side is listed the same way as for `diff`. Both are broken
+    down by the top-level folders.
+*/
+use crate::{
+    google_drive::Client,
+    sync::{
+        conf_dir, connect,
+        ignores::Ignores,
+        read_config, session, util,
+        versions::{Versions, VersionsList},
+        NameNormalization,
+    },
+};
+use anyhow::Result;
+use clap::ArgMatches;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Group of the files that are right in the synced directory
+const ROOT_GROUP: &str = ".";
+
+#[derive(Serialize, Default)]
+struct Usage {
+    local: Totals,
+    remote: Totals,
+    /// By the name of the top-level folder
+    folders: BTreeMap<String, FolderUsage>,
+}
+
+#[derive(Serialize, Default)]
+struct FolderUsage {
+    local: Totals,
+    remote: Totals,
+}
+
+#[derive(Serialize, Default)]
+struct Totals {
+    bytes: u64,
+    files: u64,
+    folders: u64,
+}
+
+impl Totals {
+    fn add(&mut self, is_folder: bool, bytes: u64) {
+        if is_folder {
+            self.folders += 1;
+        } else {
+            self.files += 1;
+            self.bytes += bytes;
+        }
+    }
+}
+
+pub fn run(m: &ArgMatches) -> Result<()> {
+    let conf_dir = conf_dir()?;
+    let config = read_config(&conf_dir, None)?;
+    let root = PathBuf::from(&config.local_dir);
+
+    let (client, remote_dir_id) = connect(&conf_dir, &config)?;
+    let client = util::lock_ref_when_free(&client);
+    let v_list = Versions::new(conf_dir.join("versions.json"), &root)?.list()?;
+    let ignores = Ignores::load(
+        &root,
+        config.ignore.as_deref().unwrap_or(&[]),
+        config.sync_hidden.unwrap_or(true),
+    )?;
+    let normalization = config.name_normalization.unwrap_or(NameNormalization::None);
+
+    let mut usage = Usage::default();
+    count_local(&root, &v_list, &mut usage);
+    count_remote(
+        &client,
+        &remote_dir_id,
+        &root,
+        &root,
+        normalization,
+        &ignores,
+        &mut usage,
+    )?;
+
+    if m.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&usage)?);
+        return Ok(());
+    }
+
+    let row = |name: &str, local: &Totals, remote: &Totals| {
+        println!(
+            "  {:<30} {:>12} {:>12}   {} / {} files",
+            name,
+            session::format_bytes(local.bytes),
+            session::format_bytes(remote.bytes),
+            local.files,
+            remote.files
+        );
+    };
+    println!("  {:<30} {:>12} {:>12}", "", "Local", "Drive");
+    row("Total", &usage.local, &usage.remote);
+    println!(
+        "  {:<30} {:>12} {:>12}",
+        "Folders", usage.local.folders, usage.remote.folders
+    );
+    if !usage.folders.is_empty() {
+        println!("\nBy folder:");
+        for (name, folder) in &usage.folders {
+            row(name, &folder.local, &folder.remote);
+        }
+    }
+
+    Ok(())
+}
+
+/// Synced files by their versions, with their current size on the disk
+fn count_local(root: &Path, v_list: &VersionsList, usage: &mut Usage) {
+    for v in v_list.values() {
+        let path = Path::new(&v.path);
+        let relative = match path.strip_prefix(root) {
+            Ok(relative) => relative,
+            Err(_) => continue,
+        };
+        let bytes = match fs::metadata(path) {
+            Ok(meta) => meta.len(),
+            // Not on the disk anymore, so it takes no space
+            Err(_) if !v.is_folder => continue,
+            Err(_) => 0,
+        };
+
+        usage.local.add(v.is_folder, bytes);
+        let group = usage
+            .folders
+            .entry(group_of(relative, v.is_folder))
+            .or_default();
+        group.local.add(v.is_folder, bytes);
+    }
+}
+
+/// Files on the drive as they would be synced. Google Docs have no size, they are counted as empty
+fn count_remote(
+    client: &Client,
+    id: &str,
+    dir: &Path,
+    root: &Path,
+    normalization: NameNormalization,
+    ignores: &Ignores,
+    usage: &mut Usage,
+) -> Result<()> {
+    let list = client.list_files(
+        Some(&format!("'{}' in parents and trashed = false", id)),
+        None,
+    )?;
+
+    for file in list.files {
+        let (file_id, name) = match (file.id.clone(), &file.name) {
+            (Some(id), Some(name)) => (id, normalization.apply(name)),
+            _ => continue,
+        };
+        let is_folder = file.mime_type.as_deref() == Some("application/vnd.google-apps.folder");
+        let path = dir.join(&name);
+        if ignores.is_ignored(&path, is_folder) {
+            continue;
+        }
+
+        if is_folder {
+            count_remote(client, &file_id, &path, root, normalization, ignores, usage)?;
+        }
+        let bytes = file.content_size().unwrap_or(0);
+        usage.remote.add(is_folder, bytes);
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        let group = usage
+            .folders
+            .entry(group_of(relative, is_folder))
+            .or_default();
+        group.remote.add(is_folder, bytes);
+    }
+
+    Ok(())
+}
+
+/// Name of the top-level folder of the path relative to the synced directory
+fn group_of(relative: &Path, is_folder: bool) -> String {
+    let mut components = relative.components();
+    match (components.next(), components.next()) {
+        (Some(first), _) if is_folder => first.as_os_str().to_string_lossy().to_string(),
+        (Some(first), Some(_)) => first.as_os_str().to_string_lossy().to_string(),
+        _ => ROOT_GROUP.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing::{self, FakeDrive, ROOT_ID};
+    use serde_json::json;
+
+    #[test]
+    fn totals_are_computed_over_a_tree() {
+        let dir = testing::temp_dir("usage");
+        let local = dir.join("local");
+        fs::create_dir(&local).unwrap();
+        let drive = FakeDrive::new();
+        drive.add_folder("photos", ROOT_ID, "Photos");
+        drive.add_folder("trip", "photos", "Trip");
+        drive.add_file("a", "photos", "a.jpg", b"aaaaa");
+        drive.add_file("b", "trip", "b.jpg", b"bbb");
+        drive.add_file("notes", ROOT_ID, "notes.txt", b"nn");
+        let config = testing::config(&local, "");
+        assert!(testing::remote_daemon(&drive, config, &dir).sync().unwrap());
+        // Local copy is changed and not uploaded yet
+        fs::write(local.join("notes.txt"), b"nnnn").unwrap();
+
+        let v_list = testing::versions(&dir, &local)
+            .lock()
+            .unwrap()
+            .list()
+            .unwrap();
+        let ignores = Ignores::load(&local, &[], true).unwrap();
+        let client = drive.client();
+        let client = client.lock().unwrap();
+        let mut usage = Usage::default();
+        count_local(&local, &v_list, &mut usage);
+        let normalization = NameNormalization::None;
+        count_remote(
+            &client,
+            ROOT_ID,
+            &local,
+            &local,
+            normalization,
+            &ignores,
+            &mut usage,
+        )
+        .unwrap();
+
+        let totals = |bytes: u64, files: u64, folders: u64| json!({ "bytes": bytes, "files": files, "folders": folders });
+        assert_eq!(
+            serde_json::to_value(&usage).unwrap(),
+            json!({
+                "local": totals(12, 3, 2),
+                "remote": totals(10, 3, 2),
+                "folders": {
+                    ".": { "local": totals(4, 1, 0), "remote": totals(2, 1, 0) },
+                    "Photos": { "local": totals(8, 2, 2), "remote": totals(8, 2, 2) },
+                },
+            })
+        );
+    }
+}
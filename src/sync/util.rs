@@ -1,11 +1,162 @@
-use std::sync::{Arc, Mutex, MutexGuard};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use std::{
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, TryLockError},
+    thread,
+    time::{self, UNIX_EPOCH},
+};
+
+/// How many times a local file operation is tried by default
+pub const DEFAULT_FS_ATTEMPTS: u32 = 3;
+/// Wait before the second attempt, it's doubled for every next one
+const FS_RETRY_DELAY: time::Duration = time::Duration::from_millis(200);
 
 pub fn lock_ref_when_free<T>(r: &Arc<Mutex<T>>) -> MutexGuard<T> {
     loop {
-        if let Ok(v) = r.try_lock() {
-            return v;
+        match r.try_lock() {
+            Ok(v) => return v,
+            // Thread that held the lock has panicked, the data is still usable since the daemon
+            // will be restarted by the watchdog
+            Err(TryLockError::Poisoned(e)) => return e.into_inner(),
+            Err(TryLockError::WouldBlock) => {}
         }
 
         std::thread::sleep(std::time::Duration::from_secs(1));
     }
 }
+
+/// Runs a local file operation, retrying it a few times with growing waits when it fails with
+/// an error that may go away by itself (e.g. file briefly locked by an antivirus). Other errors
+/// are returned right away
+pub fn retry_fs<T>(attempts: u32, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = FS_RETRY_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        match op() {
+            Err(e) if attempt < attempts && is_transient(&e) => {
+                warn!(
+                    "Local file operation failed, retrying in {} ms.\nDetails: {}",
+                    delay.as_millis(),
+                    e
+                );
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Checks if the write failed since there's no space left on the device (ENOSPC)
+pub fn is_disk_full(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOSPC)
+}
+
+/// Checks if the error was caused by missing permissions to a local file or directory
+pub fn is_permission_denied(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<io::Error>(),
+            Some(e) if e.kind() == io::ErrorKind::PermissionDenied
+        )
+    })
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::PermissionDenied | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
+
+/// Path as a string for the versions. Paths that are not valid UTF-8 can't be stored as they are,
+/// so their invalid parts are replaced
+pub fn path_string(path: &Path) -> String {
+    match path.to_str() {
+        Some(p) => p.to_string(),
+        None => {
+            warn!(
+                "Path {:?} is not valid UTF-8, it's saved with the invalid parts replaced",
+                path.display()
+            );
+            path.to_string_lossy().into_owned()
+        }
+    }
+}
+
+/// Returns size and modification time (ms since epoch) of the local file
+pub fn file_stat(f: &Path) -> Option<(u64, i64)> {
+    let meta = fs::metadata(f).ok()?;
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+    Some((meta.len(), mtime.as_millis() as i64))
+}
+
+/// Parses time either as a duration back from now ("30m", "12h", "7d") or as a date ("2021-10-25")
+/// or RFC 3339 timestamp ("2021-10-25T10:00:00Z")
+pub fn parse_since(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(DateTime::from_utc(date.and_hms(0, 0, 0), Utc));
+    }
+
+    let unit_start = value.char_indices().last().map_or(0, |(i, _)| i);
+    let (amount, unit) = value.split_at(unit_start);
+    let amount = match amount.parse::<i64>() {
+        Ok(a) => a,
+        Err(_) => bail!(
+            "Invalid time {:?}. Use a duration like 12h or a date",
+            value
+        ),
+    };
+    let duration = match unit {
+        "s" => Duration::seconds(amount),
+        "m" => Duration::minutes(amount),
+        "h" => Duration::hours(amount),
+        "d" => Duration::days(amount),
+        _ => bail!("Unknown time unit in {:?}. Use s, m, h or d", value),
+    };
+
+    Ok(Utc::now() - duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing;
+
+    #[test]
+    fn rename_is_retried_after_a_transient_error() {
+        let dir = testing::temp_dir("retry-fs");
+        let (from, to) = (dir.join("a.txt"), dir.join("b.txt"));
+        fs::write(&from, b"a").unwrap();
+
+        // File is briefly locked, e.g. by an antivirus
+        let mut calls = 0;
+        retry_fs(DEFAULT_FS_ATTEMPTS, || {
+            calls += 1;
+            if calls == 1 {
+                return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+            }
+            fs::rename(&from, &to)
+        })
+        .unwrap();
+        assert_eq!(calls, 2);
+        assert_eq!(fs::read(&to).unwrap(), b"a");
+
+        // Other errors are not retried
+        let mut calls = 0;
+        let res = retry_fs(DEFAULT_FS_ATTEMPTS, || {
+            calls += 1;
+            fs::rename(&from, &to)
+        });
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(calls, 1);
+    }
+}
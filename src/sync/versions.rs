@@ -1,40 +1,132 @@
 /*
     This struct is used to manage versions.json file
     It can be shared between threads and used with mutex to avoid threads trying to read/write to file simulteniosly
+    Paths are saved relative to the local root, so the file doesn't depend on where the root is.
+    Listed versions have absolute paths.
+    Saving merges the changes made since the list was read (by the same thread) into the current
+    file, so entries that were saved by someone else in the meantime are not overwritten with a
+    stale list.
 */
+use crate::files;
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 use serde_json;
+use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::thread::{self, ThreadId};
 
 /// Represents all data assosiated with file
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Version {
     pub is_folder: bool,
     pub parent_id: String,
     pub version: String,
     pub path: String,
     pub md5: Option<String>,
+    /// Hash of the children ids and versions (only for folders)
+    pub children_hash: Option<String>,
+    /// Link to the file page in Google Drive
+    pub web_link: Option<String>,
+    pub size: Option<u64>,
+    /// Modification time of the local file (ms since epoch)
+    pub mtime: Option<i64>,
+    /// File can't be downloaded (e.g. Google Forms), so it's not synced
+    pub unsupported: Option<bool>,
+    /// Description of the file in Drive. Local files have no place for it, so it's kept here and
+    /// given to the file that is uploaded in place of this one
+    pub description: Option<String>,
+    /// Id of the target when this is a followed shortcut. Files in a followed directory have it
+    /// as their parent
+    pub shortcut_target: Option<String>,
+    /// When the content was changed on the drive (RFC 3339), it tells about changes of the files
+    /// that Drive has no md5 for
+    pub modified_time: Option<String>,
 }
 
-pub type VersionsList = std::collections::HashMap<String, Version>;
+pub type VersionsList = HashMap<String, Version>;
 /// Represents a single item in the array of versions
 pub type VersionsItem = (String, Version);
 
+/// Ids of the synced files by their md5, built from the versions for a batch of changes. So the
+/// files with the same content are found without going through all the versions
+#[derive(Default)]
+pub struct ContentIndex(HashMap<String, Vec<String>>);
+
+impl ContentIndex {
+    pub fn build(l: &VersionsList) -> Self {
+        let mut ids: HashMap<String, Vec<String>> = HashMap::new();
+        for (id, v) in l.iter().filter(|(_, v)| !v.is_folder) {
+            if let Some(md5) = &v.md5 {
+                ids.entry(md5.clone()).or_default().push(id.clone());
+            }
+        }
+
+        Self(ids)
+    }
+
+    pub fn add(&mut self, md5: &str, id: &str) {
+        self.0
+            .entry(md5.to_string())
+            .or_default()
+            .push(id.to_string());
+    }
+
+    /// Synced files with this content, as they are in `l` now
+    fn with_md5<'a>(
+        &'a self,
+        md5: &'a str,
+        l: &'a VersionsList,
+    ) -> impl Iterator<Item = VersionsItem> + 'a {
+        self.0
+            .get(md5)
+            .into_iter()
+            .flatten()
+            .filter_map(move |id| Some((id.clone(), l.get(id)?.clone())))
+            .filter(move |(_, v)| v.md5.as_deref() == Some(md5))
+    }
+
+    /// Finds file by its content hash, which is not on its path anymore (means the file was moved)
+    pub fn find_moved_item(&self, md5: &str, l: &VersionsList) -> Option<VersionsItem> {
+        self.with_md5(md5, l)
+            .find(|(_, v)| !Path::new(&v.path).exists())
+    }
+
+    /// Finds a synced file in the directory with this content that is still on its path
+    pub fn find_duplicate(
+        &self,
+        md5: &str,
+        parent_id: &str,
+        l: &VersionsList,
+    ) -> Option<VersionsItem> {
+        self.with_md5(md5, l)
+            .find(|(_, v)| v.parent_id == parent_id && Path::new(&v.path).is_file())
+    }
+}
+
 pub struct Versions {
     path: PathBuf,
+    /// Local root directory, saved paths are relative to it
+    root: PathBuf,
     is_locked: bool,
+    /// File was corrupted, so versions were started from scratch
+    reset: bool,
+    /// Lists as they were read by the last `list` of every thread, changes of a thread are found
+    /// against its own one on save. Daemons list and save in their own threads
+    bases: HashMap<ThreadId, VersionsList>,
 }
 
 impl Versions {
-    pub fn new(path: PathBuf) -> Result<Self> {
+    pub fn new(path: PathBuf, root: &Path) -> Result<Self> {
         // Check if file accessible
         match fs::OpenOptions::new().create(true).write(true).open(&path) {
             Ok(_) => Ok(Self {
+                reset: Self::set_aside_corrupted(&path)?,
                 path,
+                root: root.to_path_buf(),
                 is_locked: false,
+                bases: HashMap::new(),
             }),
             Err(e) => {
                 bail!("Unable to access versions file, this file is required for program to work.\nDetails: {}", e);
@@ -42,6 +134,39 @@ impl Versions {
         }
     }
 
+    /// Moves the file that can't be parsed to `versions.json.corrupt`, so the app can start with
+    /// empty versions instead of failing. Returns true if the file was moved
+    fn set_aside_corrupted(path: &Path) -> Result<bool> {
+        files::check_size(path)?;
+        let content = fs::read_to_string(path).unwrap_or_default();
+        if content.trim().is_empty() || serde_json::from_str::<VersionsList>(&content).is_ok() {
+            return Ok(false);
+        }
+
+        let mut corrupt = path.as_os_str().to_owned();
+        corrupt.push(".corrupt");
+        fs::rename(path, &corrupt)?;
+        fs::File::create(path)?;
+
+        error!(
+            "Versions file {:?} is corrupted, so the sync state is reset. The old file is moved to {:?}",
+            path.display(),
+            Path::new(&corrupt).display()
+        );
+
+        Ok(true)
+    }
+
+    /// Path of another state file that belongs with the versions
+    pub fn state_file(&self, name: &str) -> PathBuf {
+        self.path.with_file_name(name)
+    }
+
+    /// Checks if the versions were reset because the file was corrupted
+    pub fn was_reset(&self) -> bool {
+        self.reset
+    }
+
     /// Finds item by path field.
     pub fn find_item_by_path(p: PathBuf, l: &VersionsList) -> Option<VersionsItem> {
         let p = p.display().to_string();
@@ -60,10 +185,48 @@ impl Versions {
             break;
         }
 
-        match fs::read_to_string(&self.path) {
+        let list = self.read()?;
+        self.bases.insert(thread::current().id(), list.clone());
+        self.is_locked = true;
+
+        Ok(list)
+    }
+
+    /// Saves the changes made to the listed versions. Entries that were changed in the file since
+    /// the list was read are kept, unless the same entries are changed in `versions` too
+    pub fn save(&mut self, versions: VersionsList) -> Result<()> {
+        let mut merged = self.read()?;
+        let thread = thread::current().id();
+        let base = self.bases.remove(&thread).unwrap_or_default();
+        for (id, v) in &versions {
+            if base.get(id) != Some(v) {
+                merged.insert(id.clone(), v.clone());
+            }
+        }
+        for id in base.keys() {
+            if !versions.contains_key(id) {
+                merged.remove(id);
+            }
+        }
+
+        self.write(merged)?;
+        // Saved list is the base for the next save, if it's saved again without listing
+        self.bases.insert(thread, versions);
+
+        Ok(())
+    }
+
+    /// Reads the file with absolute paths
+    fn read(&self) -> Result<VersionsList> {
+        match files::read_string(&self.path) {
             Ok(content) => match serde_json::from_str::<VersionsList>(content.as_str()) {
-                Ok(r) => {
-                    self.is_locked = true;
+                Ok(mut r) => {
+                    // Absolute paths saved by older versions are kept as they are
+                    for v in r.values_mut() {
+                        if Path::new(&v.path).is_relative() {
+                            v.path = self.absolute(&v.path);
+                        }
+                    }
                     Ok(r)
                 }
                 Err(_) => Ok(VersionsList::new()),
@@ -74,16 +237,30 @@ impl Versions {
         }
     }
 
-    pub fn save(&mut self, versions: VersionsList) -> Result<()> {
+    fn write(&mut self, mut versions: VersionsList) -> Result<()> {
+        // Paths outside of the root can't be relative, so they are saved as they are
+        for v in versions.values_mut() {
+            if let Ok(relative) = Path::new(&v.path).strip_prefix(&self.root) {
+                v.path = relative.display().to_string();
+            }
+        }
+
+        // Written to a temporary file first, so readers never see a half-written file
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
         match fs::OpenOptions::new()
             .create(true)
             .truncate(true)
             .write(true)
-            .open(&self.path)
+            .open(&tmp)
         {
             Ok(mut f) => {
                 let content = serde_json::to_string(&versions)?;
-                match f.write_all(content.as_bytes()) {
+                match f
+                    .write_all(content.as_bytes())
+                    .and_then(|_| fs::rename(&tmp, &self.path))
+                {
                     Ok(_) => {
                         self.is_locked = false;
                         Ok(())
@@ -98,4 +275,143 @@ impl Versions {
             }
         }
     }
+
+    fn absolute(&self, relative: &str) -> String {
+        // Joining an empty path would add a trailing slash to the root
+        if relative.is_empty() {
+            return self.root.display().to_string();
+        }
+
+        self.root.join(relative).display().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn file(path: &Path, md5: &str) -> Version {
+        Version {
+            is_folder: false,
+            parent_id: "dir".to_string(),
+            version: "1".to_string(),
+            path: path.display().to_string(),
+            md5: Some(md5.to_string()),
+            children_hash: None,
+            web_link: None,
+            size: None,
+            mtime: None,
+            unsupported: None,
+            description: None,
+            shortcut_target: None,
+            modified_time: None,
+        }
+    }
+
+    #[test]
+    fn moved_file_is_found_by_content() {
+        let dir = env::temp_dir().join(format!("ocean-drive-contents-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("kept"), "same").unwrap();
+
+        let mut l = VersionsList::new();
+        l.insert("kept".to_string(), file(&dir.join("kept"), "same"));
+        l.insert("moved".to_string(), file(&dir.join("moved"), "same"));
+        l.insert("other".to_string(), file(&dir.join("other"), "other"));
+        let mut index = ContentIndex::build(&l);
+
+        let (id, _) = index.find_moved_item("same", &l).unwrap();
+        assert_eq!(id, "moved");
+        assert_eq!(index.find_duplicate("same", "dir", &l).unwrap().0, "kept");
+        assert!(index.find_duplicate("same", "another dir", &l).is_none());
+        assert!(index.find_moved_item("unknown", &l).is_none());
+
+        // Index is checked against the current list
+        l.remove("moved");
+        assert!(index.find_moved_item("same", &l).is_none());
+        l.insert("new".to_string(), file(&dir.join("new"), "same"));
+        index.add("same", "new");
+        assert_eq!(index.find_moved_item("same", &l).unwrap().0, "new");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_saves_keep_both_entries() {
+        let dir = env::temp_dir().join(format!("ocean-drive-versions-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let versions = Versions::new(dir.join("versions.json"), &dir).unwrap();
+        let versions = std::sync::Arc::new(std::sync::Mutex::new(versions));
+        let listed = std::sync::Arc::new(std::sync::Barrier::new(2));
+
+        let savers: Vec<_> = ["a", "b"]
+            .iter()
+            .map(|id| {
+                let versions = versions.clone();
+                let listed = listed.clone();
+                let path = dir.join(id);
+                thread::spawn(move || {
+                    let mut list = versions.lock().unwrap().list().unwrap();
+                    // Both lists are read before any of them is saved
+                    listed.wait();
+                    list.insert(id.to_string(), file(&path, id));
+                    versions.lock().unwrap().save(list).unwrap();
+                })
+            })
+            .collect();
+        for saver in savers {
+            saver.join().unwrap();
+        }
+
+        let list = versions.lock().unwrap().list().unwrap();
+        assert!(list.contains_key("a") && list.contains_key("b"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn paths_are_saved_relative_to_the_root() {
+        let dir = env::temp_dir().join(format!("ocean-drive-relative-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (root, outside) = (dir.join("root"), Path::new("/elsewhere/c"));
+        // Absolute paths of older versions are read as they are
+        let mut old = VersionsList::new();
+        old.insert("old".to_string(), file(&root.join("old"), "old"));
+        fs::write(
+            dir.join("versions.json"),
+            serde_json::to_string(&old).unwrap(),
+        )
+        .unwrap();
+
+        let mut versions = Versions::new(dir.join("versions.json"), &root).unwrap();
+        let mut l = versions.list().unwrap();
+        assert_eq!(l["old"].path, root.join("old").display().to_string());
+        l.insert("a".to_string(), file(&root.join("docs/a"), "a"));
+        l.insert("root".to_string(), file(&root, "root"));
+        l.insert("c".to_string(), file(outside, "c"));
+        versions.save(l.clone()).unwrap();
+
+        let saved: VersionsList =
+            serde_json::from_str(&fs::read_to_string(dir.join("versions.json")).unwrap()).unwrap();
+        assert_eq!(saved["a"].path, "docs/a");
+        assert_eq!(saved["old"].path, "old");
+        assert_eq!(saved["root"].path, "");
+        assert_eq!(saved["c"].path, "/elsewhere/c");
+
+        // Another root resolves the same file to its own place
+        let moved = dir.join("moved");
+        let read = Versions::new(dir.join("versions.json"), &root)
+            .unwrap()
+            .list()
+            .unwrap();
+        assert_eq!(read, l);
+        let read = Versions::new(dir.join("versions.json"), &moved)
+            .unwrap()
+            .list()
+            .unwrap();
+        assert_eq!(read["a"].path, moved.join("docs/a").display().to_string());
+        assert_eq!(read["root"].path, moved.display().to_string());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
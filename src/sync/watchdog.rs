@@ -0,0 +1,116 @@
+/*
+    Supervises daemon threads: if the daemon returns an error or panics, it's restarted with
+    increasing delay. Watchdog gives up only when the daemon keeps crashing right after restarts.
+*/
+use crate::sync::{errors::SyncError, util};
+use anyhow::{bail, Result};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How many crashes in a row are tolerated before giving up
+const MAX_CRASHES: u32 = 5;
+/// Daemon that worked for this long is considered stable, so its crash counter is reset
+const STABLE_AFTER: Duration = Duration::from_secs(10 * 60);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Names of the daemons that are being restarted right now
+pub type Recovering = Arc<Mutex<Vec<String>>>;
+
+pub fn supervise<F>(name: &str, recovering: Recovering, daemon: F) -> Result<()>
+where
+    F: Fn() -> Result<()> + Send + Sync + 'static,
+{
+    let daemon = Arc::new(daemon);
+    let mut crashes = 0;
+
+    loop {
+        let d = Arc::clone(&daemon);
+        let started = Instant::now();
+        let res = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || d())?
+            .join();
+
+        let cause = match res {
+            Ok(Ok(_)) => return Ok(()),
+            // Restarts would fail the same way
+            Ok(Err(e)) if SyncError::is_fatal(&e) => return Err(e),
+            Ok(Err(e)) => format!("{}", e),
+            Err(panic) => {
+                if let Some(msg) = panic.downcast_ref::<&str>() {
+                    msg.to_string()
+                } else if let Some(msg) = panic.downcast_ref::<String>() {
+                    msg.clone()
+                } else {
+                    String::from("Unknown panic")
+                }
+            }
+        };
+
+        if started.elapsed() >= STABLE_AFTER {
+            crashes = 0;
+        }
+        crashes += 1;
+
+        if crashes > MAX_CRASHES {
+            bail!(
+                "Daemon {:?} crashed {} times in a row. Giving up.\nDetails: {}",
+                name,
+                crashes,
+                cause
+            );
+        }
+
+        let backoff = Duration::from_secs(2u64.pow(crashes)).min(MAX_BACKOFF);
+        error!(
+            "Daemon {:?} crashed ({}/{}). Restarting in {}s.\nDetails: {}",
+            name,
+            crashes,
+            MAX_CRASHES,
+            backoff.as_secs(),
+            cause
+        );
+
+        util::lock_ref_when_free(&recovering).push(name.to_string());
+        thread::sleep(backoff);
+        util::lock_ref_when_free(&recovering).retain(|n| n != name);
+
+        info!("Restarting daemon {:?}", name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn failed_daemon_is_restarted() {
+        let recovering: Recovering = Arc::new(Mutex::new(vec![]));
+        let runs = Arc::new(AtomicU32::new(0));
+        let r = Arc::clone(&runs);
+
+        supervise("test", Arc::clone(&recovering), move || {
+            if r.fetch_add(1, Ordering::SeqCst) == 0 {
+                bail!("Daemon failed");
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert!(recovering.lock().unwrap().is_empty());
+
+        // Restarts won't fix a missing remote root
+        let runs = Arc::new(AtomicU32::new(0));
+        let r = Arc::clone(&runs);
+        let res = supervise("test", recovering, move || {
+            r.fetch_add(1, Ordering::SeqCst);
+            bail!(SyncError::RemoteRootNotFound("Docs".to_string()))
+        });
+        assert!(res.is_err());
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+}
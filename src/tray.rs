@@ -1,23 +1,65 @@
 // This code is taken from https://github.com/olback/tray-item-rs/blob/master/src/api/linux/mod.rs
 // and was gently adapted for my needs
-use crate::sync::remote::RemoteDaemon;
+use crate::{
+    google_drive::budget::{self, Budget},
+    sync::{power::PowerLimits, Synced},
+    user,
+};
 use anyhow::Result;
-use gtk::prelude::*;
+use gtk::{glib, prelude::*};
 use libappindicator::{AppIndicator, AppIndicatorStatus};
-use std::process::Command;
+use std::{
+    fs,
+    path::Path,
+    process::Command,
+    sync::{Arc, Mutex},
+};
 use webbrowser;
 
+const ICON: &[u8] = include_bytes!("../trayicon.png");
+
+/// Returns path to the tray icon: the configured one if it exists, otherwise the bundled icon is
+/// saved to the cache dir. Empty path means there's no icon
+pub fn icon_path(configured: Option<&str>) -> String {
+    if let Some(path) = configured {
+        if Path::new(path).is_file() {
+            return path.to_string();
+        }
+        warn!("Tray icon {:?} is not found. Using the default one", path);
+    }
+
+    let cached = match user::get_home() {
+        Ok(home) => home.join(".cache/ocean-drive/trayicon.png"),
+        Err(e) => {
+            warn!("Unable to save tray icon: {}", e);
+            return String::new();
+        }
+    };
+
+    if !cached.is_file() {
+        let res =
+            fs::create_dir_all(cached.parent().unwrap()).and_then(|_| fs::write(&cached, ICON));
+
+        if let Err(e) = res {
+            warn!("Unable to save tray icon: {}", e);
+            return String::new();
+        }
+    }
+
+    cached.display().to_string()
+}
+
 pub struct Tray {
     tray: AppIndicator,
     menu: gtk::Menu,
 }
 
 impl Tray {
+    /// One tray is shown for all the synced directories, each of them gets its own menu section
     pub fn setup(
         icon: &str,
-        remote: RemoteDaemon,
-        remote_dir_id: String,
-        local_path: String,
+        synced: Vec<Synced>,
+        recovering: Arc<Mutex<Vec<String>>>,
     ) -> Result<Self> {
         gtk::init()?;
 
@@ -30,50 +72,68 @@ impl Tray {
         version.push_str(env!("CARGO_PKG_VERSION"));
 
         t.add_label(&version).unwrap();
+        for s in synced {
+            t.add_section(s, Arc::clone(&recovering))?;
+        }
+
+        t.add_menu_item("Stop Ocean", || -> Result<()> {
+            gtk::main_quit();
+            println!("Tray: Received stop command. Exitting.");
+            std::process::exit(0);
+        })
+        .unwrap();
+
+        t.set_icon(icon)?;
+
+        Ok(t)
+    }
 
-        t.add_menu_item("Sync now", move || -> Result<()> {
+    fn add_section(&mut self, synced: Synced, recovering: Arc<Mutex<Vec<String>>>) -> Result<()> {
+        let Synced {
+            profile,
+            remote,
+            remote_dir_id,
+            local_path,
+            account,
+        } = synced;
+
+        match (&profile, account) {
+            (Some(profile), Some(account)) => {
+                self.add_label(&format!("{} ({})", profile, account))?
+            }
+            (Some(profile), None) => self.add_label(profile)?,
+            (None, Some(account)) => self.add_label(&account)?,
+            (None, None) => {}
+        }
+        // Daemons of a profile are named "<profile>/remote" and "<profile>/local"
+        let prefix = profile.map(|p| format!("{}/", p));
+        self.add_status_label(recovering, prefix, remote.budget(), remote.power_limits())?;
+
+        self.add_menu_item("Sync now", move || -> Result<()> {
             remote.sync()?;
             Ok(())
         })?;
 
-        t.add_menu_item("Open in browser", move || -> Result<()> {
-
-            match webbrowser::open(&format!(
+        self.add_menu_item("Open in browser", move || -> Result<()> {
+            if let Err(e) = webbrowser::open(&format!(
                 "https://drive.google.com/drive/folders/{}",
                 remote_dir_id
             )) {
-                Err(e) => {
-                    eprintln!("Tray: Unable to open root directory in browser: {}", e) 
-                }
-                _ => {}
-
+                eprintln!("Tray: Unable to open root directory in browser: {}", e)
             }
             Ok(())
         })?;
 
-        t.add_menu_item("Open local folder", move || -> Result<()> {
-            match Command::new("xdg-open")
-                .args([&local_path])
-                .output() {
-                    Err(e) => {
-                        eprintln!("Tray: Failed to open local folder in default file explorer: {}", e);
-                    },
-                    _ => {}
-                }
+        self.add_menu_item("Open local folder", move || -> Result<()> {
+            if let Err(e) = Command::new("xdg-open").args([&local_path]).output() {
+                eprintln!(
+                    "Tray: Failed to open local folder in default file explorer: {}",
+                    e
+                );
+            }
 
             Ok(())
-        })?;
-
-        t.add_menu_item("Stop Ocean", || -> Result<()> {
-            gtk::main_quit();
-            println!("Tray: Received stop command. Exitting.");
-            std::process::exit(0);
         })
-        .unwrap();
-
-        t.set_icon(icon)?;
-
-        Ok(t)
     }
 
     pub fn start(&self) {
@@ -97,6 +157,52 @@ impl Tray {
         Ok(())
     }
 
+    /// Shows whether some of the daemons are being restarted after a crash or syncing is paused
+    /// since the daily budget is used up, the battery is low or the system is busy. Only the
+    /// daemons whose name starts with `prefix` are counted, all of them for None
+    fn add_status_label(
+        &mut self,
+        recovering: Arc<Mutex<Vec<String>>>,
+        prefix: Option<String>,
+        budget: Option<Arc<Budget>>,
+        power: PowerLimits,
+    ) -> Result<()> {
+        let item = gtk::MenuItem::with_label("Status: Syncing");
+        item.set_sensitive(false);
+        self.menu.append(&item);
+        self.menu.show_all();
+        self.tray.set_menu(&mut self.menu);
+
+        glib::timeout_add_seconds_local(1, move || {
+            // Don't block UI if the list is being updated right now
+            if let Ok(names) = recovering.try_lock() {
+                let names: Vec<&str> = names
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|n| match &prefix {
+                        Some(p) => n.starts_with(p.as_str()),
+                        None => true,
+                    })
+                    .collect();
+                if !names.is_empty() {
+                    item.set_label(&format!("Status: Recovering ({})", names.join(", ")));
+                } else if matches!(&budget, Some(b) if b.is_exhausted()) {
+                    item.set_label(&format!(
+                        "Status: Paused until {} (daily budget is used up)",
+                        budget::next_reset().format("%H:%M")
+                    ));
+                } else if let Some(reason) = power.pause_reason() {
+                    item.set_label(&format!("Status: Paused ({})", reason));
+                } else {
+                    item.set_label("Status: Syncing");
+                }
+            }
+            glib::Continue(true)
+        });
+
+        Ok(())
+    }
+
     fn add_menu_item<F>(&mut self, label: &str, cb: F) -> Result<()>
     where
         F: Fn() -> Result<()> + Send + Sync + 'static,
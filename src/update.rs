@@ -0,0 +1,165 @@
+/*
+    Checks GitHub releases for a newer version of the app and replaces the running binary with it.
+    It's run only by the `update` command, never in the background.
+*/
+use anyhow::{bail, Context, Result};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use reqwest::blocking::Client as HttpClient;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{env, fs, os::unix::fs::PermissionsExt};
+
+const LATEST_RELEASE_URL: &str =
+    "https://api.github.com/repos/uwumouse/ocean-drive/releases/latest";
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: Option<String>,
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("update")
+        .about("Replaces the app with the latest release from GitHub.")
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("Only tell whether a newer release is available"),
+        )
+}
+
+pub fn run(m: &ArgMatches) -> Result<()> {
+    let http = HttpClient::builder()
+        .user_agent(concat!("ocean-drive/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+    let release: Release = http
+        .get(LATEST_RELEASE_URL)
+        .send()
+        .and_then(|res| res.error_for_status())
+        .and_then(|res| res.json())
+        .context("Unable to get the latest release from GitHub")?;
+
+    let current = env!("CARGO_PKG_VERSION");
+    if !is_newer(&release.tag_name, current) {
+        info!("Ocean Drive {} is the latest version.", current);
+        return Ok(());
+    }
+
+    info!(
+        "Ocean Drive {} is available (installed {}). {}",
+        release.tag_name.trim_start_matches('v'),
+        current,
+        release.html_url.as_deref().unwrap_or_default()
+    );
+    if m.is_present("check") {
+        return Ok(());
+    }
+
+    install(&http, &release)
+}
+
+/// Downloads the binary for this machine and puts it in place of the running one. Binary is
+/// installed only if its checksum from the release matches
+fn install(http: &HttpClient, release: &Release) -> Result<()> {
+    let binary = release
+        .assets
+        .iter()
+        .find(|a| {
+            a.name.contains("linux")
+                && a.name.contains(env::consts::ARCH)
+                && !a.name.ends_with(".sha256")
+        })
+        .with_context(|| {
+            format!(
+                "Release {} has no binary for linux-{}",
+                release.tag_name,
+                env::consts::ARCH
+            )
+        })?;
+    let checksum = release
+        .assets
+        .iter()
+        .find(|a| a.name == format!("{}.sha256", binary.name))
+        .with_context(|| {
+            format!(
+                "Release {} has no checksum for {}, so it's not installed",
+                release.tag_name, binary.name
+            )
+        })?;
+
+    let expected = download(http, &checksum.browser_download_url)?;
+    let expected = String::from_utf8_lossy(&expected)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    let content = download(http, &binary.browser_download_url)?;
+    if format!("{:x}", Sha256::digest(&content)) != expected {
+        bail!(
+            "Checksum of the downloaded {} doesn't match the release, it's not installed",
+            binary.name
+        );
+    }
+
+    let exe = env::current_exe().context("Unable to find the installed binary")?;
+    // Written next to the binary, so the rename doesn't cross file systems
+    let new_exe = exe.with_extension("new");
+    fs::write(&new_exe, content)
+        .and_then(|_| fs::set_permissions(&new_exe, fs::Permissions::from_mode(0o755)))
+        .and_then(|_| fs::rename(&new_exe, &exe))
+        .with_context(|| format!("Unable to replace {:?}", exe))?;
+
+    info!(
+        "Updated to {}. Restart Ocean Drive to use it",
+        release.tag_name.trim_start_matches('v')
+    );
+    Ok(())
+}
+
+fn download(http: &HttpClient, url: &str) -> Result<Vec<u8>> {
+    let res = http
+        .get(url)
+        .send()
+        .and_then(|res| res.error_for_status())
+        .with_context(|| format!("Unable to download {}", url))?;
+
+    Ok(res.bytes()?.to_vec())
+}
+
+/// Compares versions like "v1.2.10" and "1.2.9" by their numeric parts. Tags that are not
+/// versions are never newer
+fn is_newer(tag: &str, current: &str) -> bool {
+    match (parse_version(tag), parse_version(current)) {
+        (Some(tag), Some(current)) => tag > current,
+        _ => false,
+    }
+}
+
+fn parse_version(v: &str) -> Option<Vec<u64>> {
+    v.trim_start_matches('v')
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newer_release_tag_is_found() {
+        assert!(is_newer("v0.2.0", "0.1.0"));
+        assert!(is_newer("v1.2.10", "1.2.9"));
+        assert!(is_newer("0.1.1", "0.1.0"));
+        assert!(!is_newer("v0.1.0", "0.1.0"));
+        assert!(!is_newer("v0.0.9", "0.1.0"));
+        assert!(!is_newer("nightly", "0.1.0"));
+    }
+}
@@ -7,3 +7,24 @@ pub fn get_home() -> Result<std::path::PathBuf> {
 
     bail!("Unable to locate user home directory");
 }
+
+/// Name of this machine. Kernel's hostname is used on Linux, variables are checked elsewhere
+pub fn get_hostname() -> Result<String> {
+    let from_files = ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .filter_map(|f| std::fs::read_to_string(f).ok());
+    let from_env = ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok());
+
+    match from_files
+        .chain(from_env)
+        .map(|name| name.trim().to_string())
+        .find(|name| !name.is_empty())
+    {
+        Some(name) => Ok(name),
+        None => bail!(
+            "Unable to find out the hostname of this machine. Set `hostname` in the drive config"
+        ),
+    }
+}